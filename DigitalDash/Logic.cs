@@ -1,5 +1,6 @@
 using System;
 using System.Diagnostics;
+using System.Threading;
 using Avalonia.Threading;
 using DigitalDash.Mx5MetricsClient;
 using DigitalDash.UbloxChronoClient;
@@ -11,11 +12,17 @@ public class Logic
     private readonly DispatcherTimer _highSpeedTimer = new();
     private readonly DispatcherTimer _lowSpeedTimer = new();
 
+    // Refresh anyway when no metrics come in, so that the chrono keeps going with the car off
+    private static readonly TimeSpan MetricsWakeUpTimeout = TimeSpan.FromMilliseconds(100);
+
     private readonly IMetrics _metrics;
     private readonly IChrono _chrono;
     private readonly Stopwatch _stopwatch = Stopwatch.StartNew();
 
-    public Logic(bool useMetricsShmClient, bool useChronoShmClient)
+    private event Action? HighSpeedRefresh;
+    private int _highSpeedRefreshPending;
+
+    public Logic(bool useMetricsShmClient, bool useChronoShmClient, bool useMetricsWakeUp)
     {
         _metrics = MetricsFactory.GetMetrics(useMetricsShmClient);
         _chrono = ChronoFactory.GetChrono(useChronoShmClient);
@@ -23,10 +30,53 @@ public class Logic
         _highSpeedTimer.Interval = TimeSpan.FromMilliseconds(1000d/30d); // 30hz
         _lowSpeedTimer.Interval = TimeSpan.FromMilliseconds(1000); // 1hz
         
-        _highSpeedTimer.Start();
+        _highSpeedTimer.Tick += (_, _) => RefreshHighSpeed();
+
+        if (useMetricsWakeUp)
+        {
+            StartMetricsWakeUpThread();
+        }
+        else
+        {
+            _highSpeedTimer.Start();
+        }
+
         _lowSpeedTimer.Start();
     }
 
+    // Refreshes as soon as the service publishes new metrics instead of polling at 30hz
+    private void StartMetricsWakeUpThread()
+    {
+        // ReSharper disable once FunctionNeverReturns
+        new Thread(() =>
+            {
+                while (true)
+                {
+                    _metrics.WaitForUpdate(MetricsWakeUpTimeout);
+
+                    // Skip updates coming in while the UI thread is still busy with the previous one
+                    if (Interlocked.Exchange(ref _highSpeedRefreshPending, 1) == 1)
+                        continue;
+
+                    Dispatcher.UIThread.Post(() =>
+                    {
+                        Volatile.Write(ref _highSpeedRefreshPending, 0);
+                        RefreshHighSpeed();
+                    });
+                }
+            })
+            { IsBackground = true }.Start();
+    }
+
+    private void RefreshHighSpeed()
+    {
+        // Taken first so that every refresh action sees the same snapshots
+        _metrics.Refresh();
+        _chrono.Refresh();
+
+        HighSpeedRefresh?.Invoke();
+    }
+
     public const int CoolantAlertThrC = 100;
     public const int RpmWarningThrPct = 80;
     public const int RpmAlertThrPct = 90;
@@ -47,16 +97,18 @@ public class Logic
     public ushort FrSpeed => _metrics.FrSpeedKmh;
     public ushort RlSpeed => _metrics.RlSpeedKmh;
     public ushort RrSpeed => _metrics.RrSpeedKmh;
+    public bool MetricsLive => _metrics.IsLive;
     public string Stint => $"{(int)_stopwatch.Elapsed.TotalMinutes:00}:{_stopwatch.Elapsed.Seconds:00}";
 
     public int LastSectorDeltaTenths => _chrono.PreviousSectorDeltaTime;
     public uint BestLapTenths => _chrono.BestLapTime;
     public uint LastLapTenths => _chrono.PreviousLapTime;
     public ushort LapCount => _chrono.CurrentLapN;
+    public bool TimingArmed => _chrono.IsArmed;
 
     public void RegisterHighSpeedRefresh(Action action)
     {
-        _highSpeedTimer.Tick += (_, _) => action.Invoke();
+        HighSpeedRefresh += action;
     }
     
     public void RegisterLowSpeedRefresh(Action action)
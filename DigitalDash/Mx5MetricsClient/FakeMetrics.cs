@@ -1,4 +1,5 @@
 using System;
+using System.Threading;
 
 namespace DigitalDash.Mx5MetricsClient;
 
@@ -34,4 +35,16 @@ public class FakeMetrics : IMetrics
     public byte ThrottleValvePositionPct => (byte)_rand.Next(0, 100);
     public byte FuelLevelPct => (byte)_rand.Next(0, 100);
     public byte BrakesPct => (byte)_rand.Next(0, 100);
+    public bool IsLive => true;
+
+    public void Refresh()
+    {
+    }
+
+    public bool WaitForUpdate(TimeSpan timeout)
+    {
+        // Pretend the bus sends at 30hz
+        Thread.Sleep(TimeSpan.FromMilliseconds(1000d/30d));
+        return true;
+    }
 }
\ No newline at end of file
@@ -1,3 +1,5 @@
+using System;
+
 namespace DigitalDash.Mx5MetricsClient;
 
 public interface IMetrics
@@ -16,4 +18,13 @@ public interface IMetrics
     public byte ThrottleValvePositionPct { get; }
     public byte FuelLevelPct { get; }
     public byte BrakesPct { get; }
+
+    // False when the service is gone or not monitoring the bus, the values are then frozen
+    public bool IsLive { get; }
+
+    // Takes a consistent snapshot of the metrics the properties then return
+    public void Refresh();
+
+    // Blocks until new metrics are published, false on timeout
+    public bool WaitForUpdate(TimeSpan timeout);
 }
\ No newline at end of file
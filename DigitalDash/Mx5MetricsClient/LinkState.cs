@@ -0,0 +1,11 @@
+namespace DigitalDash.Mx5MetricsClient;
+
+// mx5_metrics_service's stnobd::LinkState
+public enum LinkState : byte
+{
+    Resetting = 1,
+    Configuring = 2,
+    Monitoring = 3,
+    Sleeping = 4,
+    Disconnected = 5
+}
\ No newline at end of file
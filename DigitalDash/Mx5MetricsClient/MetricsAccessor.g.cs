@@ -0,0 +1,101 @@
+// Generated by mx5_metrics_service --gen-c-header/--gen-csharp, do not edit
+
+using System;
+using DigitalDash.ShmClient;
+
+namespace DigitalDash.Mx5MetricsClient;
+
+public sealed class MetricsAccessor : IDisposable
+{
+    public const uint LayoutVersion = 2;
+
+    private readonly ShmSegment _segment;
+
+    private readonly int _rpm;
+    private readonly int _speedKmh;
+    private readonly int _engineCoolantTempC;
+    private readonly int _intakeAirTempC;
+    private readonly int _flSpeedKmh;
+    private readonly int _frSpeedKmh;
+    private readonly int _rlSpeedKmh;
+    private readonly int _rrSpeedKmh;
+    private readonly int _acceleratorPedalPositionPct;
+    private readonly int _calculatedEngineLoadPct;
+    private readonly int _throttleValvePositionPct;
+    private readonly int _fuelLevelPct;
+    private readonly int _brakesPct;
+    private readonly int _vehicleOff;
+    private readonly int _linkState;
+    private readonly int _producerPid;
+    private readonly int _heartbeatNs;
+    private readonly int _brakesSampleNs;
+    private readonly int _rpmSpeedAccelSampleNs;
+    private readonly int _coolantThrottleIntakeSampleNs;
+    private readonly int _fuelLevelSampleNs;
+    private readonly int _wheelSpeedsSampleNs;
+
+    public MetricsAccessor(string path)
+    {
+        _segment = new ShmSegment(path, LayoutVersion);
+        _rpm = _segment.FieldOffset("rpm", FieldType.U16);
+        _speedKmh = _segment.FieldOffset("speed_kmh", FieldType.U16);
+        _engineCoolantTempC = _segment.FieldOffset("engine_coolant_temp_c", FieldType.I16);
+        _intakeAirTempC = _segment.FieldOffset("intake_air_temp_c", FieldType.I16);
+        _flSpeedKmh = _segment.FieldOffset("fl_speed_kmh", FieldType.U16);
+        _frSpeedKmh = _segment.FieldOffset("fr_speed_kmh", FieldType.U16);
+        _rlSpeedKmh = _segment.FieldOffset("rl_speed_kmh", FieldType.U16);
+        _rrSpeedKmh = _segment.FieldOffset("rr_speed_kmh", FieldType.U16);
+        _acceleratorPedalPositionPct = _segment.FieldOffset("accelerator_pedal_position_pct", FieldType.U8);
+        _calculatedEngineLoadPct = _segment.FieldOffset("calculated_engine_load_pct", FieldType.U8);
+        _throttleValvePositionPct = _segment.FieldOffset("throttle_valve_position_pct", FieldType.U8);
+        _fuelLevelPct = _segment.FieldOffset("fuel_level_pct", FieldType.U8);
+        _brakesPct = _segment.FieldOffset("brakes_pct", FieldType.U8);
+        _vehicleOff = _segment.FieldOffset("vehicle_off", FieldType.Bool);
+        _linkState = _segment.FieldOffset("link_state", FieldType.U8);
+        _producerPid = _segment.FieldOffset("producer_pid", FieldType.U32);
+        _heartbeatNs = _segment.FieldOffset("heartbeat_ns", FieldType.U64);
+        _brakesSampleNs = _segment.FieldOffset("brakes_sample_ns", FieldType.U64);
+        _rpmSpeedAccelSampleNs = _segment.FieldOffset("rpm_speed_accel_sample_ns", FieldType.U64);
+        _coolantThrottleIntakeSampleNs = _segment.FieldOffset("coolant_throttle_intake_sample_ns", FieldType.U64);
+        _fuelLevelSampleNs = _segment.FieldOffset("fuel_level_sample_ns", FieldType.U64);
+        _wheelSpeedsSampleNs = _segment.FieldOffset("wheel_speeds_sample_ns", FieldType.U64);
+    }
+
+    public ushort Rpm => _segment.ReadUInt16(_rpm); // rpm
+    public ushort SpeedKmh => _segment.ReadUInt16(_speedKmh); // km/h
+    public short EngineCoolantTempC => _segment.ReadInt16(_engineCoolantTempC); // degC
+    public short IntakeAirTempC => _segment.ReadInt16(_intakeAirTempC); // degC
+    public ushort FlSpeedKmh => _segment.ReadUInt16(_flSpeedKmh); // km/h
+    public ushort FrSpeedKmh => _segment.ReadUInt16(_frSpeedKmh); // km/h
+    public ushort RlSpeedKmh => _segment.ReadUInt16(_rlSpeedKmh); // km/h
+    public ushort RrSpeedKmh => _segment.ReadUInt16(_rrSpeedKmh); // km/h
+    public byte AcceleratorPedalPositionPct => _segment.ReadByte(_acceleratorPedalPositionPct); // %
+    public byte CalculatedEngineLoadPct => _segment.ReadByte(_calculatedEngineLoadPct); // %
+    public byte ThrottleValvePositionPct => _segment.ReadByte(_throttleValvePositionPct); // %
+    public byte FuelLevelPct => _segment.ReadByte(_fuelLevelPct); // %
+    public byte BrakesPct => _segment.ReadByte(_brakesPct); // %
+    public bool VehicleOff => _segment.ReadBool(_vehicleOff);
+    public byte LinkState => _segment.ReadByte(_linkState);
+    public uint ProducerPid => _segment.ReadUInt32(_producerPid);
+    public ulong HeartbeatNs => _segment.ReadUInt64(_heartbeatNs); // ns
+    public ulong BrakesSampleNs => _segment.ReadUInt64(_brakesSampleNs); // ns
+    public ulong RpmSpeedAccelSampleNs => _segment.ReadUInt64(_rpmSpeedAccelSampleNs); // ns
+    public ulong CoolantThrottleIntakeSampleNs => _segment.ReadUInt64(_coolantThrottleIntakeSampleNs); // ns
+    public ulong FuelLevelSampleNs => _segment.ReadUInt64(_fuelLevelSampleNs); // ns
+    public ulong WheelSpeedsSampleNs => _segment.ReadUInt64(_wheelSpeedsSampleNs); // ns
+
+    public void Refresh()
+    {
+        _segment.Refresh();
+    }
+
+    public bool WaitForUpdate(TimeSpan timeout)
+    {
+        return _segment.WaitForUpdate(timeout);
+    }
+
+    public void Dispose()
+    {
+        _segment.Dispose();
+    }
+}
@@ -1,52 +1,53 @@
 using System;
-using System.IO;
-using System.IO.MemoryMappedFiles;
+using System.Diagnostics;
 
 namespace DigitalDash.Mx5MetricsClient;
 
-/* shared memory structure
- * #[repr(C)]
- * pub struct Metrics {
- *     rpm: u16,
- *     speed_kmh: u16,
- *     engine_coolant_temp_c: i16,
- *     intake_air_temp_c: i16,
- *     fl_speed_kmh: u16,
- *     fr_speed_kmh: u16,
- *     rl_speed_kmh: u16,
- *     rr_speed_kmh: u16,
- *     accelerator_pedal_position_pct: u8,
- *     calculated_engine_load_pct: u8,
- *     throttle_valve_position_pct: u8,
- *     fuel_level_pct: u8,
- *     brakes_pct: u8
- * }
- */
-
-
+// MetricsAccessor is generated from mx5_metrics_service's Metrics struct:
+// mx5_metrics_service --gen-csharp DigitalDash/Mx5MetricsClient/MetricsAccessor.g.cs
 public sealed class ShmMetrics : IMetrics, IDisposable
 {
-    private readonly MemoryMappedViewAccessor _accessor = MemoryMappedFile
-        .CreateFromFile("/dev/shm/mx5metrics", FileMode.Open, null, 0, MemoryMappedFileAccess.Read)
-        .CreateViewAccessor(0, 0, MemoryMappedFileAccess.Read);
+    // The service publishes at least every second
+    private static readonly TimeSpan HeartbeatTimeout = TimeSpan.FromSeconds(3);
+
+    private readonly MetricsAccessor _accessor = new("/dev/shm/mx5metrics");
     
     public ushort RedLine => 7000;
-    public ushort Rpm => _accessor.ReadUInt16(0);
-    public ushort SpeedKmh => _accessor.ReadUInt16(2);
-    public short EngineCoolantTempC => _accessor.ReadInt16(4);
-    public short IntakeAirTempC => _accessor.ReadInt16(6);
-    public ushort FlSpeedKmh => _accessor.ReadByte(8);
-    public ushort FrSpeedKmh => _accessor.ReadByte(10);
-    public ushort RlSpeedKmh => _accessor.ReadByte(12);
-    public ushort RrSpeedKmh => _accessor.ReadByte(14);
-    public byte AcceleratorPedalPositionPct => _accessor.ReadByte(16);
-    public byte CalculatedEngineLoadPct => _accessor.ReadByte(17);
-    public byte ThrottleValvePositionPct => _accessor.ReadByte(18);
-    public byte FuelLevelPct => _accessor.ReadByte(19);
-    public byte BrakesPct => _accessor.ReadByte(20);
+    public ushort Rpm => _accessor.Rpm;
+    public ushort SpeedKmh => _accessor.SpeedKmh;
+    public short EngineCoolantTempC => _accessor.EngineCoolantTempC;
+    public short IntakeAirTempC => _accessor.IntakeAirTempC;
+    public ushort FlSpeedKmh => _accessor.FlSpeedKmh;
+    public ushort FrSpeedKmh => _accessor.FrSpeedKmh;
+    public ushort RlSpeedKmh => _accessor.RlSpeedKmh;
+    public ushort RrSpeedKmh => _accessor.RrSpeedKmh;
+    public byte AcceleratorPedalPositionPct => _accessor.AcceleratorPedalPositionPct;
+    public byte CalculatedEngineLoadPct => _accessor.CalculatedEngineLoadPct;
+    public byte ThrottleValvePositionPct => _accessor.ThrottleValvePositionPct;
+    public byte FuelLevelPct => _accessor.FuelLevelPct;
+    public byte BrakesPct => _accessor.BrakesPct;
+
+    public bool IsLive => (LinkState)_accessor.LinkState == LinkState.Monitoring
+                          && MonotonicNs() - _accessor.HeartbeatNs < (ulong)(HeartbeatTimeout.Ticks * TimeSpan.NanosecondsPerTick);
+
+    // The heartbeat is on CLOCK_MONOTONIC, which is what Stopwatch reads on Linux
+    private static ulong MonotonicNs()
+    {
+        return (ulong)(Stopwatch.GetTimestamp() * (1_000_000_000d / Stopwatch.Frequency));
+    }
+
+    public void Refresh()
+    {
+        _accessor.Refresh();
+    }
+    
+    public bool WaitForUpdate(TimeSpan timeout)
+    {
+        return _accessor.WaitForUpdate(timeout);
+    }
     
     public void Dispose()
     {
         _accessor.Dispose();
     }
-}
\ No newline at end of file
+}
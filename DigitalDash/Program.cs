@@ -11,6 +11,7 @@ class Program
 {
     public static bool UseMetricsShmClient { get; private set; } = false;
     public static bool UseChronoShmClient { get; private set; } = false;
+    public static bool UseMetricsWakeUp { get; private set; } = false;
 
     // Initialization code. Don't use any Avalonia, third-party APIs or any
     // SynchronizationContext-reliant code before AppMain is called: things aren't initialized
@@ -27,6 +28,11 @@ class Program
         {
             UseChronoShmClient = true;
         }
+
+        if (args.Contains("--metrics-wakeup"))
+        {
+            UseMetricsWakeUp = true;
+        }
         
         var builder = BuildAvaloniaApp();
         
@@ -0,0 +1,243 @@
+using System;
+using System.Collections.Generic;
+using System.Diagnostics;
+using System.IO;
+using System.IO.MemoryMappedFiles;
+using System.Runtime.InteropServices;
+using System.Text;
+using System.Threading;
+
+namespace DigitalDash.ShmClient;
+
+/* self describing shared memory segment, see shm_segment/src/lib.rs
+ * #[repr(C)]
+ * pub struct ShmHeader {
+ *     magic: u32, // "MX5S"
+ *     layout_version: u32,
+ *     seq: AtomicU32, // odd while the service is updating the data, futex woken on every update
+ *     field_count: u32,
+ *     data_offset: u32,
+ *     data_size: u32,
+ *     producer_pid: u32,
+ *     reserved: u32 // keeps a following ring header 8 byte aligned
+ * }
+ *
+ * followed by field_count times
+ * #[repr(C)]
+ * pub struct ShmFieldDesc {
+ *     name: [u8; 48], // NUL terminated
+ *     unit: [u8; 8], // NUL terminated
+ *     offset: u32, // from the start of the data
+ *     field_type: u32
+ * }
+ */
+
+public enum FieldType : uint
+{
+    Bool = 1,
+    U8 = 2,
+    I8 = 3,
+    U16 = 4,
+    I16 = 5,
+    U32 = 6,
+    I32 = 7,
+    U64 = 8,
+    I64 = 9,
+    F32 = 10,
+    F64 = 11,
+    Str = 12 // 32 bytes, NUL terminated UTF-8
+}
+
+public sealed class ShmSegment : IDisposable
+{
+    private const uint Magic = 0x5335584D; // "MX5S" little endian
+    private const int MagicOffset = 0;
+    private const int LayoutVersionOffset = 4;
+    private const int SeqOffset = 8;
+    private const int FieldCountOffset = 12;
+    private const int DataOffsetOffset = 16;
+    private const int DataSizeOffset = 20;
+    private const int HeaderSize = 32;
+
+    private const int FieldNameLen = 48;
+    private const int FieldUnitLen = 8;
+    private const int StrLen = 32;
+    private const int FieldDescSize = 64;
+
+    private const int FutexWait = 0;
+
+    // An update takes microseconds, a seq that stays odd this long means the service died mid update
+    private static readonly TimeSpan StuckUpdateTimeout = TimeSpan.FromMilliseconds(100);
+
+    private readonly MemoryMappedViewAccessor _accessor;
+    private readonly IntPtr _seqAddress;
+    private uint _waitedSeq;
+    private readonly int _dataOffset;
+    private readonly Dictionary<string, (int Offset, FieldType Type)> _fields = new();
+
+    // Consistent copy of the data, taken by Refresh
+    private byte[] _snapshot;
+    // What Refresh copies into, so that a torn copy never replaces the snapshot
+    private byte[] _copy;
+
+    public ShmSegment(string path, uint expectedLayoutVersion)
+    {
+        _accessor = MemoryMappedFile
+            .CreateFromFile(path, FileMode.Open, null, 0, MemoryMappedFileAccess.Read)
+            .CreateViewAccessor(0, 0, MemoryMappedFileAccess.Read);
+
+        if (_accessor.ReadUInt32(MagicOffset) != Magic)
+        {
+            throw new InvalidDataException($"{path} isn't a self describing segment, is the service up to date ?");
+        }
+
+        var layoutVersion = _accessor.ReadUInt32(LayoutVersionOffset);
+        if (layoutVersion != expectedLayoutVersion)
+        {
+            // Fields are looked up by name, so this still works as long as the ones we use are there
+            Console.WriteLine($"{path} layout version is {layoutVersion}, expected {expectedLayoutVersion}");
+        }
+
+        var fieldCount = _accessor.ReadUInt32(FieldCountOffset);
+        for (var i = 0; i < fieldCount; i++)
+        {
+            var descOffset = HeaderSize + i * FieldDescSize;
+            var name = ReadCString(descOffset, FieldNameLen);
+            var offset = (int)_accessor.ReadUInt32(descOffset + FieldNameLen + FieldUnitLen);
+            var type = (FieldType)_accessor.ReadUInt32(descOffset + FieldNameLen + FieldUnitLen + 4);
+            _fields[name] = (offset, type);
+        }
+
+        _seqAddress = _accessor.SafeMemoryMappedViewHandle.DangerousGetHandle() + (nint)_accessor.PointerOffset + SeqOffset;
+
+        _dataOffset = (int)_accessor.ReadUInt32(DataOffsetOffset);
+        _snapshot = new byte[_accessor.ReadUInt32(DataSizeOffset)];
+        _copy = new byte[_snapshot.Length];
+    }
+
+    // Offset of a field in the snapshot, refuses to read a field that's missing or changed type
+    public int FieldOffset(string name, FieldType type)
+    {
+        if (!_fields.TryGetValue(name, out var field))
+        {
+            throw new InvalidDataException($"field {name} is missing");
+        }
+
+        if (field.Type != type)
+        {
+            throw new InvalidDataException($"field {name} is {field.Type}, expected {type}");
+        }
+
+        return field.Offset;
+    }
+
+    public bool ReadBool(int offset) => _snapshot[offset] != 0;
+    public byte ReadByte(int offset) => _snapshot[offset];
+    public sbyte ReadSByte(int offset) => (sbyte)_snapshot[offset];
+    public ushort ReadUInt16(int offset) => BitConverter.ToUInt16(_snapshot, offset);
+    public short ReadInt16(int offset) => BitConverter.ToInt16(_snapshot, offset);
+    public uint ReadUInt32(int offset) => BitConverter.ToUInt32(_snapshot, offset);
+    public int ReadInt32(int offset) => BitConverter.ToInt32(_snapshot, offset);
+    public ulong ReadUInt64(int offset) => BitConverter.ToUInt64(_snapshot, offset);
+    public long ReadInt64(int offset) => BitConverter.ToInt64(_snapshot, offset);
+    public float ReadSingle(int offset) => BitConverter.ToSingle(_snapshot, offset);
+    public double ReadDouble(int offset) => BitConverter.ToDouble(_snapshot, offset);
+
+    public string ReadString(int offset)
+    {
+        var end = Array.IndexOf(_snapshot, (byte)0, offset, StrLen);
+        return Encoding.UTF8.GetString(_snapshot, offset, end < 0 ? StrLen : end - offset);
+    }
+
+    // Copies the data out of the seqlock, retrying until the service didn't touch it during the copy.
+    // False when the service died mid update, the last snapshot is then kept.
+    public bool Refresh()
+    {
+        var stopwatch = Stopwatch.StartNew();
+        var spinner = new SpinWait();
+
+        while (true)
+        {
+            var seq = _accessor.ReadUInt32(SeqOffset);
+            if (seq % 2 == 0)
+            {
+                Thread.MemoryBarrier();
+                _accessor.ReadArray(_dataOffset, _copy, 0, _copy.Length);
+                Thread.MemoryBarrier();
+
+                if (_accessor.ReadUInt32(SeqOffset) == seq)
+                {
+                    (_snapshot, _copy) = (_copy, _snapshot);
+                    return true;
+                }
+            }
+
+            if (stopwatch.Elapsed > StuckUpdateTimeout)
+            {
+                return false;
+            }
+
+            // Spins a few times, then yields to let the service finish its update
+            spinner.SpinOnce();
+        }
+    }
+
+    // Sleeps until the service publishes something new since the last call, false on timeout.
+    // Only meant for a single waiting thread.
+    public bool WaitForUpdate(TimeSpan timeout)
+    {
+        var deadline = DateTime.UtcNow + timeout;
+
+        while (true)
+        {
+            var seq = _accessor.ReadUInt32(SeqOffset);
+            if (seq != _waitedSeq && seq % 2 == 0)
+            {
+                _waitedSeq = seq;
+                return true;
+            }
+
+            var left = deadline - DateTime.UtcNow;
+            if (left <= TimeSpan.Zero)
+            {
+                return false;
+            }
+
+            // Returns early on wake ups, signals and a seq that already changed, the loop checks again
+            var timespec = new Timespec { Sec = (nint)left.TotalSeconds, Nsec = (nint)(left.Ticks % TimeSpan.TicksPerSecond * 100) };
+            syscall(FutexSyscallNumber(), _seqAddress, FutexWait, seq, ref timespec, IntPtr.Zero, 0);
+        }
+    }
+
+    [StructLayout(LayoutKind.Sequential)]
+    private struct Timespec
+    {
+        public nint Sec;
+        public nint Nsec;
+    }
+
+    [DllImport("libc", SetLastError = true)]
+    private static extern nint syscall(nint number, IntPtr uaddr, int op, uint val, ref Timespec timeout, IntPtr uaddr2, uint val3);
+
+    private static nint FutexSyscallNumber() => RuntimeInformation.ProcessArchitecture switch
+    {
+        Architecture.X64 => 202,
+        Architecture.Arm64 => 98,
+        Architecture.Arm or Architecture.X86 => 240,
+        var arch => throw new PlatformNotSupportedException($"no futex syscall number for {arch}")
+    };
+
+    private string ReadCString(int offset, int maxLen)
+    {
+        var bytes = new byte[maxLen];
+        _accessor.ReadArray(offset, bytes, 0, maxLen);
+
+        var len = Array.IndexOf(bytes, (byte)0);
+        return Encoding.ASCII.GetString(bytes, 0, len < 0 ? maxLen : len);
+    }
+
+    public void Dispose()
+    {
+        _accessor.Dispose();
+    }
+}
@@ -0,0 +1,56 @@
+// Generated by ublox_chrono_service --gen-c-header/--gen-csharp, do not edit
+
+using System;
+using DigitalDash.ShmClient;
+
+namespace DigitalDash.UbloxChronoClient;
+
+public sealed class ChronoAccessor : IDisposable
+{
+    public const uint LayoutVersion = 2;
+
+    private readonly ShmSegment _segment;
+
+    private readonly int _bestLapTime;
+    private readonly int _previousLapTime;
+    private readonly int _currentLapTime;
+    private readonly int _previousSectorDeltaTime;
+    private readonly int _bestLapN;
+    private readonly int _currentLapN;
+    private readonly int _timingState;
+
+    public ChronoAccessor(string path)
+    {
+        _segment = new ShmSegment(path, LayoutVersion);
+        _bestLapTime = _segment.FieldOffset("best_lap_time", FieldType.U32);
+        _previousLapTime = _segment.FieldOffset("previous_lap_time", FieldType.U32);
+        _currentLapTime = _segment.FieldOffset("current_lap_time", FieldType.U32);
+        _previousSectorDeltaTime = _segment.FieldOffset("previous_sector_delta_time", FieldType.I32);
+        _bestLapN = _segment.FieldOffset("best_lap_n", FieldType.U16);
+        _currentLapN = _segment.FieldOffset("current_lap_n", FieldType.U16);
+        _timingState = _segment.FieldOffset("timing_state", FieldType.U8);
+    }
+
+    public uint BestLapTime => _segment.ReadUInt32(_bestLapTime); // ds
+    public uint PreviousLapTime => _segment.ReadUInt32(_previousLapTime); // ds
+    public uint CurrentLapTime => _segment.ReadUInt32(_currentLapTime); // ds
+    public int PreviousSectorDeltaTime => _segment.ReadInt32(_previousSectorDeltaTime); // ds
+    public ushort BestLapN => _segment.ReadUInt16(_bestLapN);
+    public ushort CurrentLapN => _segment.ReadUInt16(_currentLapN);
+    public byte TimingState => _segment.ReadByte(_timingState);
+
+    public void Refresh()
+    {
+        _segment.Refresh();
+    }
+
+    public bool WaitForUpdate(TimeSpan timeout)
+    {
+        return _segment.WaitForUpdate(timeout);
+    }
+
+    public void Dispose()
+    {
+        _segment.Dispose();
+    }
+}
@@ -12,4 +12,9 @@ public class FakeChrono : IChrono
     public int PreviousSectorDeltaTime => _rand.Next(-1000, 1000);
     public ushort BestLapN => (ushort)_rand.Next(0, 999);
     public ushort CurrentLapN => (ushort)_rand.Next(0, 999);
+    public bool IsArmed => true;
+
+    public void Refresh()
+    {
+    }
 }
\ No newline at end of file
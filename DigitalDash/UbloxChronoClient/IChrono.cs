@@ -8,4 +8,10 @@ public interface IChrono
     public int PreviousSectorDeltaTime { get; }
     public ushort BestLapN { get; }
     public ushort CurrentLapN { get; }
+
+    // False while the gnss fix is too poor to cross the gates, the lap times are then frozen
+    public bool IsArmed { get; }
+
+    // Takes a consistent snapshot of the chrono the properties then return
+    public void Refresh();
 }
\ No newline at end of file
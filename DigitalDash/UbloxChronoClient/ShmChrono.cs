@@ -1,37 +1,29 @@
 using System;
-using System.IO;
-using System.IO.MemoryMappedFiles;
 
 namespace DigitalDash.UbloxChronoClient;
 
-/* shared memory structure
-    struct chrono {
-        // Timescale is tenths of a second
-        uint32_t best_lap_time;
-        uint32_t previous_lap_time;
-        uint32_t current_lap_time;
-        int32_t previous_sector_delta_time;
-        uint16_t best_lap_n;
-        uint16_t current_lap_n;
-    };
- */
-
-
+// ChronoAccessor is generated from ublox_chrono_service's Chrono struct:
+// ublox_chrono_service --gen-csharp DigitalDash/UbloxChronoClient/ChronoAccessor.g.cs
 public sealed class ShmChrono : IChrono, IDisposable
 {
-    private readonly MemoryMappedViewAccessor _accessor = MemoryMappedFile
-        .CreateFromFile("/dev/shm/ubloxchrono", FileMode.Open, null, 0, MemoryMappedFileAccess.Read)
-        .CreateViewAccessor(0, 0, MemoryMappedFileAccess.Read);
-    
-    public uint BestLapTime => _accessor.ReadUInt32(0);
-    public uint PreviousLapTime => _accessor.ReadUInt32(4);
-    public uint CurrentLapTime => _accessor.ReadUInt32(8);
-    public int PreviousSectorDeltaTime => _accessor.ReadInt32(12);
-    public ushort BestLapN => _accessor.ReadUInt16(16);
-    public ushort CurrentLapN => _accessor.ReadUInt16(18);
+    private readonly ChronoAccessor _accessor = new("/dev/shm/ubloxchrono");
+
+    // Timescale is tenths of a second
+    public uint BestLapTime => _accessor.BestLapTime;
+    public uint PreviousLapTime => _accessor.PreviousLapTime;
+    public uint CurrentLapTime => _accessor.CurrentLapTime;
+    public int PreviousSectorDeltaTime => _accessor.PreviousSectorDeltaTime;
+    public ushort BestLapN => _accessor.BestLapN;
+    public ushort CurrentLapN => _accessor.CurrentLapN;
+    public bool IsArmed => (TimingState)_accessor.TimingState == TimingState.Armed;
+
+    public void Refresh()
+    {
+        _accessor.Refresh();
+    }
     
     public void Dispose()
     {
         _accessor.Dispose();
     }
-}
\ No newline at end of file
+}
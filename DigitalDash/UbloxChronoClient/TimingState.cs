@@ -0,0 +1,8 @@
+namespace DigitalDash.UbloxChronoClient;
+
+// ublox_chrono_service's chrono::TimingState
+public enum TimingState : byte
+{
+    Armed = 1,
+    Degraded = 2
+}
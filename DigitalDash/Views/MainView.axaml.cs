@@ -4,8 +4,28 @@ namespace DigitalDash.Views;
 
 public partial class MainView : UserControl
 {
+    private const double StaleOpacity = 0.3;
+
+    private readonly Logic _logic = App.Logic;
+
     public MainView()
     {
         InitializeComponent();
+
+        _logic.RegisterLowSpeedRefresh(Refresh);
+    }
+
+    // Grey the metrics out while they're frozen, the chrono doesn't depend on them
+    private void Refresh()
+    {
+        var opacity = _logic.MetricsLive ? 1 : StaleOpacity;
+
+        foreach (var control in new Control[] { RpmBar, Fuel, RpmSpeed, Temperatures, WheelSpeeds, DriverInputs })
+        {
+            control.Opacity = opacity;
+        }
+
+        // Nor while the fix is too poor to time the laps
+        Chrono.Opacity = _logic.TimingArmed ? 1 : StaleOpacity;
     }
 }
\ No newline at end of file
@@ -12,6 +12,7 @@ public partial class Stn1110Simulator
     private CancellationTokenSource _cts = null!;
     private Task _monitoringTask = null!;
     private readonly Random _random = new();
+    private readonly DateTime _startedAt = DateTime.UtcNow;
 
     public Stn1110Simulator(string portName, int baudRate)
     {
@@ -64,11 +65,27 @@ public partial class Stn1110Simulator
             {
                 RespondToATS0();
             }
-            else if (line.StartsWith("STFPA"))
+            else if (line == "ATRV")
+            {
+                RespondToATRV();
+            }
+            else if (line == "STCTS1")
+            {
+                RespondToSTCTS();
+            }
+            else if (line.StartsWith("STSLU"))
+            {
+                RespondToSTSLU();
+            }
+            else if (line == "STSLEEP")
+            {
+                RespondToSTSLEEP();
+            }
+            else if (line.StartsWith("STFPA") || line.StartsWith("STFBA"))
             {
                 HandleSTFPA(line);
             }
-            else if (line == "STM")
+            else if (line == "STM" || line == "STMA")
             {
                 HandleSTM();
             }
@@ -109,6 +126,30 @@ public partial class Stn1110Simulator
         Write(">");
     }
 
+    private void RespondToATRV()
+    {
+        WriteLine("14.1V");
+        Write(">");
+    }
+
+    private void RespondToSTCTS()
+    {
+        WriteLine("OK");
+        Write(">");
+    }
+
+    private void RespondToSTSLU()
+    {
+        WriteLine("OK");
+        Write(">");
+    }
+
+    private void RespondToSTSLEEP()
+    {
+        Console.WriteLine("Going to sleep");
+        WriteLine("OK");
+    }
+
     private void HandleSTFPA(string cmd)
     {
         var m = CanFilterRegex().Match(cmd);
@@ -150,11 +191,11 @@ public partial class Stn1110Simulator
                     WriteLine($"{data:X16}");
                 }*/
 
-                WriteLine("0850138000000000000");
-                WriteLine("20113480000571C5400");
-                WriteLine("2407F46007F46000000");
-                WriteLine("4307F00000000000000");
-                WriteLine("4B0AA00BB00CC00DD00");
+                WriteLine(Timestamp() + "0850138000000000000");
+                WriteLine(Timestamp() + "20113480000571C5400");
+                WriteLine(Timestamp() + "2407F46007F46000000");
+                WriteLine(Timestamp() + "4307F00000000000000");
+                WriteLine(Timestamp() + "4B0AA00BB00CC00DD00");
 
                 
                 await Task.Delay(100, cancellationToken);
@@ -165,6 +206,13 @@ public partial class Stn1110Simulator
         }
     }
 
+    // ms since power on, wrapping at 16 bits
+    private string Timestamp()
+    {
+        var ms = (long)(DateTime.UtcNow - _startedAt).TotalMilliseconds;
+        return $"{ms & 0xFFFF:X4}";
+    }
+
     private void WriteLine(string text)
     {
         Console.WriteLine($"out:{text}");
@@ -186,6 +234,6 @@ public partial class Stn1110Simulator
         return line;
     }
 
-    [GeneratedRegex("STFPA([0-9A-F]{3}),[0-9A-F]{3}")]
+    [GeneratedRegex("STF[PB]A([0-9A-F]{3}),[0-9A-F]{3}")]
     private static partial Regex CanFilterRegex();
 }
@@ -0,0 +1,99 @@
+// Generated by mx5_metrics_service --gen-c-header/--gen-csharp, do not edit
+
+#ifndef MX5_METRICS_H
+#define MX5_METRICS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifndef SHM_SEGMENT_H
+#define SHM_SEGMENT_H
+
+#define SHM_MAGIC 0x5335584Du // "MX5S"
+#define SHM_RING_MAGIC 0x5235584Du // "MX5R"
+
+struct shm_header {
+    uint32_t magic;
+    uint32_t layout_version;
+    uint32_t seq; // odd while the producer updates the data
+    uint32_t field_count;
+    uint32_t data_offset;
+    uint32_t data_size;
+    uint32_t producer_pid;
+    uint32_t reserved;
+};
+
+struct shm_field_desc {
+    char name[48];
+    char unit[8];
+    uint32_t offset;
+    uint32_t field_type;
+};
+
+// Follows the field table of a SHM_RING_MAGIC segment
+struct shm_ring_header {
+    uint32_t capacity;
+    uint32_t entry_size;
+    uint64_t period_ns;
+    uint64_t write_index; // entries pushed so far
+};
+
+#endif // SHM_SEGMENT_H
+
+#define MX5_METRICS_LAYOUT_VERSION 2
+#define MX5_METRICS_FIELD_COUNT 22
+#define MX5_METRICS_DATA_OFFSET 1440
+
+struct mx5_metrics {
+    uint16_t rpm; // rpm
+    uint16_t speed_kmh; // km/h
+    int16_t engine_coolant_temp_c; // degC
+    int16_t intake_air_temp_c; // degC
+    uint16_t fl_speed_kmh; // km/h
+    uint16_t fr_speed_kmh; // km/h
+    uint16_t rl_speed_kmh; // km/h
+    uint16_t rr_speed_kmh; // km/h
+    uint8_t accelerator_pedal_position_pct; // %
+    uint8_t calculated_engine_load_pct; // %
+    uint8_t throttle_valve_position_pct; // %
+    uint8_t fuel_level_pct; // %
+    uint8_t brakes_pct; // %
+    bool vehicle_off;
+    uint8_t link_state;
+    uint8_t _pad0[1];
+    uint32_t producer_pid;
+    uint8_t _pad1[4];
+    uint64_t heartbeat_ns; // ns
+    uint64_t brakes_sample_ns; // ns
+    uint64_t rpm_speed_accel_sample_ns; // ns
+    uint64_t coolant_throttle_intake_sample_ns; // ns
+    uint64_t fuel_level_sample_ns; // ns
+    uint64_t wheel_speeds_sample_ns; // ns
+};
+
+_Static_assert(sizeof(struct mx5_metrics) == 80, "mx5_metrics size");
+_Static_assert(offsetof(struct mx5_metrics, rpm) == 0, "rpm offset");
+_Static_assert(offsetof(struct mx5_metrics, speed_kmh) == 2, "speed_kmh offset");
+_Static_assert(offsetof(struct mx5_metrics, engine_coolant_temp_c) == 4, "engine_coolant_temp_c offset");
+_Static_assert(offsetof(struct mx5_metrics, intake_air_temp_c) == 6, "intake_air_temp_c offset");
+_Static_assert(offsetof(struct mx5_metrics, fl_speed_kmh) == 8, "fl_speed_kmh offset");
+_Static_assert(offsetof(struct mx5_metrics, fr_speed_kmh) == 10, "fr_speed_kmh offset");
+_Static_assert(offsetof(struct mx5_metrics, rl_speed_kmh) == 12, "rl_speed_kmh offset");
+_Static_assert(offsetof(struct mx5_metrics, rr_speed_kmh) == 14, "rr_speed_kmh offset");
+_Static_assert(offsetof(struct mx5_metrics, accelerator_pedal_position_pct) == 16, "accelerator_pedal_position_pct offset");
+_Static_assert(offsetof(struct mx5_metrics, calculated_engine_load_pct) == 17, "calculated_engine_load_pct offset");
+_Static_assert(offsetof(struct mx5_metrics, throttle_valve_position_pct) == 18, "throttle_valve_position_pct offset");
+_Static_assert(offsetof(struct mx5_metrics, fuel_level_pct) == 19, "fuel_level_pct offset");
+_Static_assert(offsetof(struct mx5_metrics, brakes_pct) == 20, "brakes_pct offset");
+_Static_assert(offsetof(struct mx5_metrics, vehicle_off) == 21, "vehicle_off offset");
+_Static_assert(offsetof(struct mx5_metrics, link_state) == 22, "link_state offset");
+_Static_assert(offsetof(struct mx5_metrics, producer_pid) == 24, "producer_pid offset");
+_Static_assert(offsetof(struct mx5_metrics, heartbeat_ns) == 32, "heartbeat_ns offset");
+_Static_assert(offsetof(struct mx5_metrics, brakes_sample_ns) == 40, "brakes_sample_ns offset");
+_Static_assert(offsetof(struct mx5_metrics, rpm_speed_accel_sample_ns) == 48, "rpm_speed_accel_sample_ns offset");
+_Static_assert(offsetof(struct mx5_metrics, coolant_throttle_intake_sample_ns) == 56, "coolant_throttle_intake_sample_ns offset");
+_Static_assert(offsetof(struct mx5_metrics, fuel_level_sample_ns) == 64, "fuel_level_sample_ns offset");
+_Static_assert(offsetof(struct mx5_metrics, wheel_speeds_sample_ns) == 72, "wheel_speeds_sample_ns offset");
+
+#endif // MX5_METRICS_H
@@ -0,0 +1,68 @@
+// Generated by ublox_chrono_service --gen-c-header/--gen-csharp, do not edit
+
+#ifndef UBLOX_CHRONO_H
+#define UBLOX_CHRONO_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifndef SHM_SEGMENT_H
+#define SHM_SEGMENT_H
+
+#define SHM_MAGIC 0x5335584Du // "MX5S"
+#define SHM_RING_MAGIC 0x5235584Du // "MX5R"
+
+struct shm_header {
+    uint32_t magic;
+    uint32_t layout_version;
+    uint32_t seq; // odd while the producer updates the data
+    uint32_t field_count;
+    uint32_t data_offset;
+    uint32_t data_size;
+    uint32_t producer_pid;
+    uint32_t reserved;
+};
+
+struct shm_field_desc {
+    char name[48];
+    char unit[8];
+    uint32_t offset;
+    uint32_t field_type;
+};
+
+// Follows the field table of a SHM_RING_MAGIC segment
+struct shm_ring_header {
+    uint32_t capacity;
+    uint32_t entry_size;
+    uint64_t period_ns;
+    uint64_t write_index; // entries pushed so far
+};
+
+#endif // SHM_SEGMENT_H
+
+#define UBLOX_CHRONO_LAYOUT_VERSION 2
+#define UBLOX_CHRONO_FIELD_COUNT 7
+#define UBLOX_CHRONO_DATA_OFFSET 480
+
+struct ublox_chrono {
+    uint32_t best_lap_time; // ds
+    uint32_t previous_lap_time; // ds
+    uint32_t current_lap_time; // ds
+    int32_t previous_sector_delta_time; // ds
+    uint16_t best_lap_n;
+    uint16_t current_lap_n;
+    uint8_t timing_state;
+    uint8_t _pad0[3];
+};
+
+_Static_assert(sizeof(struct ublox_chrono) == 24, "ublox_chrono size");
+_Static_assert(offsetof(struct ublox_chrono, best_lap_time) == 0, "best_lap_time offset");
+_Static_assert(offsetof(struct ublox_chrono, previous_lap_time) == 4, "previous_lap_time offset");
+_Static_assert(offsetof(struct ublox_chrono, current_lap_time) == 8, "current_lap_time offset");
+_Static_assert(offsetof(struct ublox_chrono, previous_sector_delta_time) == 12, "previous_sector_delta_time offset");
+_Static_assert(offsetof(struct ublox_chrono, best_lap_n) == 16, "best_lap_n offset");
+_Static_assert(offsetof(struct ublox_chrono, current_lap_n) == 18, "current_lap_n offset");
+_Static_assert(offsetof(struct ublox_chrono, timing_state) == 20, "timing_state offset");
+
+#endif // UBLOX_CHRONO_H
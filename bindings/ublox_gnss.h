@@ -0,0 +1,88 @@
+// Generated by ublox_chrono_service --gen-c-header/--gen-csharp, do not edit
+
+#ifndef UBLOX_GNSS_H
+#define UBLOX_GNSS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifndef SHM_SEGMENT_H
+#define SHM_SEGMENT_H
+
+#define SHM_MAGIC 0x5335584Du // "MX5S"
+#define SHM_RING_MAGIC 0x5235584Du // "MX5R"
+
+struct shm_header {
+    uint32_t magic;
+    uint32_t layout_version;
+    uint32_t seq; // odd while the producer updates the data
+    uint32_t field_count;
+    uint32_t data_offset;
+    uint32_t data_size;
+    uint32_t producer_pid;
+    uint32_t reserved;
+};
+
+struct shm_field_desc {
+    char name[48];
+    char unit[8];
+    uint32_t offset;
+    uint32_t field_type;
+};
+
+// Follows the field table of a SHM_RING_MAGIC segment
+struct shm_ring_header {
+    uint32_t capacity;
+    uint32_t entry_size;
+    uint64_t period_ns;
+    uint64_t write_index; // entries pushed so far
+};
+
+#endif // SHM_SEGMENT_H
+
+#define UBLOX_GNSS_LAYOUT_VERSION 3
+#define UBLOX_GNSS_FIELD_COUNT 17
+#define UBLOX_GNSS_DATA_OFFSET 1120
+
+struct ublox_gnss {
+    uint64_t sample_ns; // ns
+    uint64_t utc_ns; // ns
+    int32_t lon; // 1e-7deg
+    int32_t lat; // 1e-7deg
+    uint32_t h_acc_mm; // mm
+    int32_t g_speed_mm_s; // mm/s
+    int32_t head_mot; // 1e-5deg
+    uint32_t s_acc_mm_s; // mm/s
+    uint8_t fix_type;
+    uint8_t num_sv;
+    bool fix_ok;
+    uint8_t prot_ver_major;
+    uint8_t prot_ver_minor;
+    uint8_t max_nav_rate_hz; // Hz
+    char sw_version[32];
+    char hw_version[32];
+    char module[32];
+    uint8_t _pad0[2];
+};
+
+_Static_assert(sizeof(struct ublox_gnss) == 144, "ublox_gnss size");
+_Static_assert(offsetof(struct ublox_gnss, sample_ns) == 0, "sample_ns offset");
+_Static_assert(offsetof(struct ublox_gnss, utc_ns) == 8, "utc_ns offset");
+_Static_assert(offsetof(struct ublox_gnss, lon) == 16, "lon offset");
+_Static_assert(offsetof(struct ublox_gnss, lat) == 20, "lat offset");
+_Static_assert(offsetof(struct ublox_gnss, h_acc_mm) == 24, "h_acc_mm offset");
+_Static_assert(offsetof(struct ublox_gnss, g_speed_mm_s) == 28, "g_speed_mm_s offset");
+_Static_assert(offsetof(struct ublox_gnss, head_mot) == 32, "head_mot offset");
+_Static_assert(offsetof(struct ublox_gnss, s_acc_mm_s) == 36, "s_acc_mm_s offset");
+_Static_assert(offsetof(struct ublox_gnss, fix_type) == 40, "fix_type offset");
+_Static_assert(offsetof(struct ublox_gnss, num_sv) == 41, "num_sv offset");
+_Static_assert(offsetof(struct ublox_gnss, fix_ok) == 42, "fix_ok offset");
+_Static_assert(offsetof(struct ublox_gnss, prot_ver_major) == 43, "prot_ver_major offset");
+_Static_assert(offsetof(struct ublox_gnss, prot_ver_minor) == 44, "prot_ver_minor offset");
+_Static_assert(offsetof(struct ublox_gnss, max_nav_rate_hz) == 45, "max_nav_rate_hz offset");
+_Static_assert(offsetof(struct ublox_gnss, sw_version) == 46, "sw_version offset");
+_Static_assert(offsetof(struct ublox_gnss, hw_version) == 78, "hw_version offset");
+_Static_assert(offsetof(struct ublox_gnss, module) == 110, "module offset");
+
+#endif // UBLOX_GNSS_H
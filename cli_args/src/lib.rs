@@ -0,0 +1,40 @@
+// The few things the binaries' flag parsing shares: a flag's value and the files generated on request
+use std::fs;
+
+// Value following a flag, e.g. the path in --gen-c-header path
+pub fn arg_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter().position(|arg| arg == flag)
+        .map(|i| args.get(i + 1).unwrap_or_else(|| panic!("{} needs a value", flag)).as_str())
+}
+
+// Writes what each given flag generates to the path following it, true when there was any
+pub fn write_generated(args: &[String], outputs: &[(&str, &dyn Fn() -> String)]) -> bool {
+    let mut wrote = false;
+
+    for (flag, generate) in outputs {
+        if let Some(path) = arg_value(args, flag) {
+            fs::write(path, generate()).unwrap_or_else(|e| panic!("{} {}: {}", flag, path, e));
+            wrote = true;
+        }
+    }
+
+    wrote
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn finds_the_value_after_the_flag() {
+        let args = args(&["service", "--port", "/dev/ttyACM0", "--persist", "bbr"]);
+
+        assert_eq!(arg_value(&args, "--port"), Some("/dev/ttyACM0"));
+        assert_eq!(arg_value(&args, "--persist"), Some("bbr"));
+        assert_eq!(arg_value(&args, "--socket"), None);
+    }
+}
@@ -0,0 +1,143 @@
+// STN hardware filters for 11 bit CAN ids.
+// A msg gets through when it matches at least one pass filter and no block filter,
+// a filter matches when (msg id & mask) == (filter id & mask).
+// Flow control filters (STFCP) are only needed for ISO-TP requests, not for the broadcast msgs we monitor.
+
+const CAN_ID_MAX: u16 = 0x7ff;
+const CAN_ID_EXACT_MASK: u16 = 0xfff;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CanFilter {
+    Pass { id: u16, mask: u16 },
+    Block { id: u16, mask: u16 }
+}
+
+impl CanFilter {
+    pub fn cmd(&self) -> String {
+        match self {
+            CanFilter::Pass { id, mask } => format!("STFPA{:03X},{:03X}\r", id, mask),
+            CanFilter::Block { id, mask } => format!("STFBA{:03X},{:03X}\r", id, mask)
+        }
+    }
+}
+
+fn matches(filter_id: u16, filter_mask: u16, can_id: u16) -> bool {
+    can_id & filter_mask == filter_id & filter_mask
+}
+
+// What the STN lets through, for checking generated filters
+#[cfg(test)]
+pub fn passes(filters: &[CanFilter], can_id: u16) -> bool {
+    let mut has_pass_filters = false;
+    let mut passed = false;
+
+    for filter in filters {
+        match *filter {
+            CanFilter::Pass { id, mask } => {
+                has_pass_filters = true;
+                passed |= matches(id, mask, can_id);
+            }
+            CanFilter::Block { id, mask } => {
+                if matches(id, mask, can_id) {
+                    return false;
+                }
+            }
+        }
+    }
+
+    // Without any pass filter, everything that isn't blocked gets through
+    passed || !has_pass_filters
+}
+
+// Ids that get through the pass filters although we didn't ask for them
+fn unwanted_can_ids(pass_filters: &[(u16, u16)], can_ids: &[u16]) -> Vec<u16> {
+    (0..=CAN_ID_MAX)
+        .filter(|can_id| !can_ids.contains(can_id))
+        .filter(|&can_id| pass_filters.iter().any(|&(id, mask)| matches(id, mask, can_id)))
+        .collect()
+}
+
+fn cmd_count(pass_filters: &[(u16, u16)], can_ids: &[u16]) -> usize {
+    // Every unwanted id costs a block filter
+    pass_filters.len() + unwanted_can_ids(pass_filters, can_ids).len()
+}
+
+// Generates the filters letting exactly can_ids through, with as few STN cmds as possible.
+// Starting with one exact pass filter per id, pass filters are merged two by two into a wider id/mask pair
+// as long as the pass filters plus the block filters needed for the extra ids they let through get cheaper.
+pub fn generate_can_filters(can_ids: &[u16]) -> Vec<CanFilter> {
+    let mut pass_filters: Vec<(u16, u16)> = can_ids.iter()
+        .map(|&can_id| (can_id, CAN_ID_EXACT_MASK))
+        .collect();
+
+    pass_filters.sort();
+    pass_filters.dedup();
+
+    loop {
+        let current_cmd_count = cmd_count(&pass_filters, can_ids);
+        let mut best_merge: Option<(usize, Vec<(u16, u16)>)> = None;
+
+        for i in 0..pass_filters.len() {
+            for j in i + 1..pass_filters.len() {
+                let (id_i, mask_i) = pass_filters[i];
+                let (id_j, mask_j) = pass_filters[j];
+
+                // Don't care about the bits where the two filters differ
+                let mask = mask_i & mask_j & !(id_i ^ id_j);
+
+                let mut merged = pass_filters.clone();
+                merged.remove(j);
+                merged[i] = (id_i & mask, mask);
+
+                // Fewer pass filters for the same cmd count is still a win
+                let merged_cmd_count = cmd_count(&merged, can_ids);
+                if merged_cmd_count <= current_cmd_count
+                    && best_merge.as_ref().is_none_or(|(best_cmd_count, _)| merged_cmd_count < *best_cmd_count) {
+                    best_merge = Some((merged_cmd_count, merged));
+                }
+            }
+        }
+
+        match best_merge {
+            Some((_, merged)) => pass_filters = merged,
+            None => break
+        }
+    }
+
+    let block_filters = unwanted_can_ids(&pass_filters, can_ids).into_iter()
+        .map(|id| CanFilter::Block { id, mask: CAN_ID_EXACT_MASK });
+
+    pass_filters.into_iter()
+        .map(|(id, mask)| CanFilter::Pass { id, mask })
+        .chain(block_filters)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_ids_differing_by_one_bit() {
+        let filters = generate_can_filters(&[0x430, 0x4b0]);
+
+        assert_eq!(filters, vec![CanFilter::Pass { id: 0x430, mask: 0xf7f }]);
+        assert_eq!(filters[0].cmd(), "STFPA430,F7F\r");
+    }
+
+    #[test]
+    fn blocks_the_gap_in_a_range() {
+        let can_ids = [0x100, 0x101, 0x102, 0x104, 0x105, 0x106, 0x107];
+
+        let filters = generate_can_filters(&can_ids);
+
+        assert_eq!(filters, vec![
+            CanFilter::Pass { id: 0x100, mask: 0xff8 },
+            CanFilter::Block { id: 0x103, mask: 0xfff }
+        ]);
+
+        for can_id in 0..=CAN_ID_MAX {
+            assert_eq!(passes(&filters, can_id), can_ids.contains(&can_id), "can id {:#x}", can_id);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::os::fd::AsFd;
+use std::time::{Duration, Instant};
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg, Termios};
+use crate::stnobd::{CanFrame, CanFrameHandler};
+
+// Bytes that changed this recently are highlighted as "changing now"
+const HIGHLIGHT_DURATION: Duration = Duration::from_secs(1);
+
+const COLOR_CHANGING: &str = "\x1b[1;31m";
+const COLOR_CHANGED: &str = "\x1b[33m";
+const COLOR_RESET: &str = "\x1b[0m";
+const CLEAR_SCREEN: &str = "\x1b[H\x1b[2J";
+
+struct IdStats {
+    count: u64,
+    count_at_last_refresh: u64,
+    rate_hz: f32,
+    first_seen: Instant,
+    last_seen: Instant,
+    dlc: u8,
+    data: [u8; 8],
+    // Bits that flipped since the last mark
+    changed_bits: [u8; 8],
+    byte_changed_at: [Option<Instant>; 8]
+}
+
+pub struct CanDiscovery {
+    started_at: Instant,
+    last_refresh_at: Instant,
+    ids: BTreeMap<u16, IdStats>,
+    status: String
+}
+
+impl CanDiscovery {
+    pub fn new() -> CanDiscovery {
+        CanDiscovery {
+            started_at: Instant::now(),
+            last_refresh_at: Instant::now(),
+            ids: BTreeMap::new(),
+            status: String::new()
+        }
+    }
+
+    // Forget about the changes seen so far, so that only what changes
+    // from now on (e.g. pressing a pedal) gets highlighted
+    pub fn mark(&mut self) {
+        for stats in self.ids.values_mut() {
+            stats.changed_bits = [0; 8];
+            stats.byte_changed_at = [None; 8];
+        }
+
+        self.status = format!("marked at {:.1}s", self.started_at.elapsed().as_secs_f32());
+    }
+
+    pub fn render(&mut self) -> String {
+        let now = Instant::now();
+        let refresh_secs = now.duration_since(self.last_refresh_at).as_secs_f32();
+        self.last_refresh_at = now;
+
+        let msg_count: u64 = self.ids.values().map(|stats| stats.count).sum();
+
+        let mut out = String::from(CLEAR_SCREEN);
+
+        let _ = writeln!(out, "CAN discovery - {} ids, {} msgs, {:.0}s    [m] mark  [e] export dbc  [q] quit",
+                         self.ids.len(), msg_count, self.started_at.elapsed().as_secs_f32());
+        let _ = writeln!(out, " id dlc     rate    count   first    last  data                     changed bits");
+
+        for (id, stats) in self.ids.iter_mut() {
+            if refresh_secs > 0.0 {
+                stats.rate_hz = (stats.count - stats.count_at_last_refresh) as f32 / refresh_secs;
+                stats.count_at_last_refresh = stats.count;
+            }
+
+            let _ = write!(out, "{:03X} {:>3} {:>6.1}hz {:>8} {:>6.1}s {:>6.1}s  ",
+                           id, stats.dlc, stats.rate_hz, stats.count,
+                           stats.first_seen.duration_since(self.started_at).as_secs_f32(),
+                           now.duration_since(stats.last_seen).as_secs_f32());
+
+            for i in 0..8 {
+                if i >= stats.dlc as usize {
+                    out.push_str("   ");
+                    continue;
+                }
+
+                let color = match stats.byte_changed_at[i] {
+                    Some(changed_at) if now.duration_since(changed_at) < HIGHLIGHT_DURATION => COLOR_CHANGING,
+                    Some(_) => COLOR_CHANGED,
+                    None => ""
+                };
+
+                let _ = write!(out, "{}{:02X}{} ", color, stats.data[i], COLOR_RESET);
+            }
+
+            out.push(' ');
+            for changed_bits in &stats.changed_bits[..stats.dlc as usize] {
+                let _ = write!(out, "{:02X} ", changed_bits);
+            }
+            out.push('\n');
+        }
+
+        let _ = writeln!(out, "{}", self.status);
+
+        out
+    }
+
+    pub fn export_dbc(&mut self, path: &str) -> io::Result<()> {
+        let mut dbc = String::from("VERSION \"\"\n\nNS_ :\n\nBS_:\n\nBU_:\n\n");
+        let mut comments = String::new();
+
+        for (id, stats) in &self.ids {
+            let _ = writeln!(dbc, "BO_ {} MSG_{:03X}: {} Vector__XXX", id, id, stats.dlc);
+
+            // One placeholder signal per byte that changed, to be refined by hand
+            for (i, changed_bits) in stats.changed_bits[..stats.dlc as usize].iter().enumerate() {
+                if *changed_bits != 0 {
+                    let _ = writeln!(dbc, " SG_ MSG_{:03X}_B{} : {}|8@1+ (1,0) [0|255] \"\" Vector__XXX", id, i, i * 8);
+                }
+            }
+            dbc.push('\n');
+
+            let _ = writeln!(comments, "CM_ BO_ {} \"{:.1}hz, changed bits {:02X?}\";", id, stats.rate_hz, stats.changed_bits);
+        }
+
+        dbc.push_str(&comments);
+
+        fs::write(path, dbc)?;
+
+        self.status = format!("exported {} ids to {}", self.ids.len(), path);
+        Ok(())
+    }
+}
+
+impl CanFrameHandler for CanDiscovery {
+    fn handle_can_frame(&mut self, frame: &CanFrame) {
+        let now = Instant::now();
+
+        let stats = self.ids.entry(frame.id).or_insert_with(|| IdStats {
+            count: 0,
+            count_at_last_refresh: 0,
+            rate_hz: 0.0,
+            first_seen: now,
+            last_seen: now,
+            dlc: frame.dlc,
+            data: frame.data,
+            changed_bits: [0; 8],
+            byte_changed_at: [None; 8]
+        });
+
+        for i in 0..frame.dlc as usize {
+            let flipped_bits = stats.data[i] ^ frame.data[i];
+            if flipped_bits != 0 {
+                stats.changed_bits[i] |= flipped_bits;
+                stats.byte_changed_at[i] = Some(now);
+            }
+        }
+
+        stats.count += 1;
+        stats.last_seen = now;
+        stats.dlc = frame.dlc;
+        stats.data = frame.data;
+    }
+}
+
+// Puts stdin in non-canonical mode without echo so that single key presses can be read,
+// the previous settings are restored on drop
+pub struct RawTerminal {
+    original: Option<Termios>
+}
+
+impl RawTerminal {
+    pub fn new() -> RawTerminal {
+        let stdin = io::stdin();
+
+        // stdin might not be a terminal, keys then only get through line by line
+        let original = match tcgetattr(stdin.as_fd()) {
+            Ok(original) => original,
+            Err(_) => return RawTerminal { original: None }
+        };
+
+        let mut tty = original.clone();
+        tty.local_flags &= !(LocalFlags::ICANON | LocalFlags::ECHO);
+
+        tcsetattr(stdin.as_fd(), SetArg::TCSANOW, &tty)
+            .expect("tcsetattr stdin");
+
+        RawTerminal {
+            original: Some(original)
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if let Some(original) = &self.original {
+            let _ = tcsetattr(io::stdin().as_fd(), SetArg::TCSANOW, original);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(id: u16, data: &[u8]) -> CanFrame {
+        let mut frame = CanFrame { id, dlc: data.len() as u8, data: [0; 8], sample_ns: 0 };
+        frame.data[..data.len()].copy_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn accumulates_the_changed_bits() {
+        let mut discovery = CanDiscovery::new();
+
+        discovery.handle_can_frame(&frame(0x201, &[0x00, 0x10, 0xff]));
+        discovery.handle_can_frame(&frame(0x201, &[0x01, 0x10, 0xff]));
+        discovery.handle_can_frame(&frame(0x201, &[0x02, 0x10, 0x7f]));
+        discovery.handle_can_frame(&frame(0x430, &[0xaa]));
+
+        let stats = &discovery.ids[&0x201];
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.dlc, 3);
+        assert_eq!(stats.data[..3], [0x02, 0x10, 0x7f]);
+        assert_eq!(stats.changed_bits, [0x03, 0x00, 0x80, 0, 0, 0, 0, 0]);
+        assert!(stats.byte_changed_at[0].is_some() && stats.byte_changed_at[1].is_none());
+
+        // The first msg of an id is no change
+        let stats = &discovery.ids[&0x430];
+        assert_eq!(stats.count, 1);
+        assert_eq!(stats.changed_bits, [0; 8]);
+
+        // A shorter msg only compares its own bytes
+        discovery.handle_can_frame(&frame(0x430, &[]));
+        assert_eq!(discovery.ids[&0x430].dlc, 0);
+        assert_eq!(discovery.ids[&0x430].changed_bits, [0; 8]);
+    }
+
+    #[test]
+    fn mark_forgets_the_changes() {
+        let mut discovery = CanDiscovery::new();
+
+        discovery.handle_can_frame(&frame(0x201, &[0x00, 0x00]));
+        discovery.handle_can_frame(&frame(0x201, &[0x01, 0x00]));
+        discovery.mark();
+
+        let stats = &discovery.ids[&0x201];
+        assert_eq!(stats.changed_bits, [0; 8]);
+        assert_eq!(stats.byte_changed_at, [None; 8]);
+        assert_eq!(stats.count, 2);
+
+        // Only what changes after the mark shows up
+        discovery.handle_can_frame(&frame(0x201, &[0x01, 0x40]));
+        assert_eq!(discovery.ids[&0x201].changed_bits[..2], [0x00, 0x40]);
+    }
+
+    #[test]
+    fn exports_a_signal_per_changed_byte() {
+        let path = format!("{}/mx5_discovery_{}.dbc", std::env::temp_dir().display(), std::process::id());
+        let mut discovery = CanDiscovery::new();
+
+        discovery.handle_can_frame(&frame(0x201, &[0x00, 0x00, 0x00]));
+        discovery.handle_can_frame(&frame(0x201, &[0x00, 0x08, 0x01]));
+        discovery.handle_can_frame(&frame(0x430, &[0xaa, 0xbb]));
+
+        discovery.export_dbc(&path).unwrap();
+        let dbc = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = dbc.lines().filter(|line| line.starts_with("BO_") || line.starts_with(" SG_")).collect();
+        assert_eq!(lines, [
+            "BO_ 513 MSG_201: 3 Vector__XXX",
+            " SG_ MSG_201_B1 : 8|8@1+ (1,0) [0|255] \"\" Vector__XXX",
+            " SG_ MSG_201_B2 : 16|8@1+ (1,0) [0|255] \"\" Vector__XXX",
+            "BO_ 1072 MSG_430: 2 Vector__XXX"
+        ]);
+        assert!(dbc.contains("CM_ BO_ 513 \"0.0hz, changed bits [00, 08, 01, 00, 00, 00, 00, 00]\";"));
+        assert_eq!(discovery.status, format!("exported 2 ids to {}", path));
+    }
+}
@@ -1,40 +1,131 @@
 mod stnobd;
 mod metrics;
-mod shm_metrics;
+mod discovery;
+mod can_filter;
 
-use std::collections::VecDeque;
-use log::{debug, info};
+use std::env;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+use cli_args::{arg_value, write_generated};
+use log::{debug, error, info};
 use nix::sys::signal::{self, sigprocmask, Signal};
 use nix::sys::signalfd::{SigSet, SignalFd};
 use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
 use nix::sys::termios::BaudRate;
-use crate::shm_metrics::ShmMetrics;
-use crate::stnobd::{Stnobd, STNOBD_CFG_DISABLE_ECHO, STNOBD_CFG_DISABLE_SPACES, STNOBD_CFG_ENABLE_HEADER, STNOBD_CFG_FILTER_BRAKES, STNOBD_CFG_FILTER_COOLANT_THROTTLE_INTAKE, STNOBD_CFG_FILTER_FUEL_LEVEL, STNOBD_CFG_FILTER_RPM_SPEED_ACCEL, STNOBD_CFG_FILTER_WHEEL_SPEEDS};
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
+use shm_segment::{generate_c_header, generate_csharp_accessor, monotonic_ns, ShmRing, ShmSegment};
+use telemetry_socket::TelemetryServer;
+use crate::can_filter::generate_can_filters;
+use crate::discovery::{CanDiscovery, RawTerminal};
+use crate::metrics::{Metrics, HANDLED_CAN_IDS};
+use crate::stnobd::{MonitoringMode, PortEvent, SleepConfig, Stnobd, WatchdogConfig, STNOBD_CFG_DISABLE_ECHO, STNOBD_CFG_DISABLE_SPACES, STNOBD_CFG_ENABLE_HEADER, STNOBD_CFG_ENABLE_TIMESTAMPS, STNOBD_CFG_ENABLE_UART_WAKEUP};
 
 const SHM_NAME: &str = "/mx5metrics";
+const HISTORY_SHM_NAME: &str = "/mx5history";
+const GENERATOR: &str = "mx5_metrics_service --gen-c-header/--gen-csharp";
+
+const DEFAULT_BUS_IDLE_TIMEOUT_S: u64 = 10;
+const VEHICLE_OFF_VOLTAGE: f32 = 13.0; // alternator charges well above this
+const WAKE_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_STN_RSP_TIMEOUT_S: u64 = 5;
+
+const DISCOVERY_REFRESH_INTERVAL: Duration = Duration::from_millis(200);
+const DISCOVERY_DBC_PATH: &str = "mx5_discovery.dbc";
+
+const DEFAULT_HISTORY_RATE_HZ: u32 = 20;
+// The metrics don't change faster than the STN polls them
+const MAX_HISTORY_RATE_HZ: u32 = 100;
+
+enum EpollEventId {
+    Signal,
+    Stnobd,
+    StnobdTimer,
+    Stdin,
+    DiscoveryRefresh,
+    HistoryTimer,
+    Telemetry
+}
+
+// The history ring keeps duration worth of metrics sampled at rate_hz
+struct HistoryConfig {
+    duration: Duration,
+    rate_hz: u32
+}
+
+impl HistoryConfig {
+    fn period(&self) -> Duration {
+        Duration::from_secs(1) / self.rate_hz
+    }
+
+    fn capacity(&self) -> usize {
+        (self.duration.as_secs_f64() * self.rate_hz as f64).ceil() as usize
+    }
+}
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    // Regenerate the reader bindings from the Metrics layout instead of running
+    if gen_bindings(&args) {
+        return;
+    }
+
+    // Sniff every CAN msg on the bus instead of publishing metrics
+    let discover = args.iter().any(|arg| arg == "--discover");
+
+    // Also keep the last --history seconds of metrics in a ring, off unless asked for
+    let history_cfg = arg_value(&args, "--history").map(|secs| {
+        let secs: u64 = secs.parse().expect("--history seconds");
+        assert!(secs > 0, "--history must be 1 s or more");
+
+        let rate_hz = arg_value(&args, "--history-hz").map_or(DEFAULT_HISTORY_RATE_HZ, |hz| hz.parse().expect("--history-hz rate"));
+        assert!((1..=MAX_HISTORY_RATE_HZ).contains(&rate_hz), "--history-hz must be 1 to {} hz", MAX_HISTORY_RATE_HZ);
+
+        HistoryConfig { duration: Duration::from_secs(secs), rate_hz }
+    });
+
+    // A bus silent for this long gets its battery voltage checked, then the watchdog restarts monitoring
+    // if the vehicle is on, escalating to a reset and a port reopen while it stays silent
+    let bus_idle_timeout = timeout_arg(&args, "--bus-idle-timeout", DEFAULT_BUS_IDLE_TIMEOUT_S);
+    // How long the STN gets to answer a cmd before the watchdog resets it
+    let rsp_timeout = timeout_arg(&args, "--rsp-timeout", DEFAULT_STN_RSP_TIMEOUT_S);
+
+    // Also stream the metrics to the clients of a Unix socket at this path
+    let socket_path = arg_value(&args, "--socket");
+
+    // Keep the log quiet in discovery mode, it would mess up the terminal view
     let env = env_logger::Env::default()
-        .filter_or("RUST_LOG", "info");
+        .filter_or("RUST_LOG", if discover { "warn" } else { "info" });
 
     env_logger::init_from_env(env);
 
-    enum EpollEventId {
-        Signal,
-        Stnobd
+    let mut cmds: Vec<String> = [
+        STNOBD_CFG_DISABLE_ECHO,
+        STNOBD_CFG_ENABLE_HEADER,
+        STNOBD_CFG_DISABLE_SPACES,
+        STNOBD_CFG_ENABLE_TIMESTAMPS,
+        STNOBD_CFG_ENABLE_UART_WAKEUP
+    ].map(String::from).into();
+
+    if !discover {
+        // Only let through what the decoder handles
+        cmds.extend(generate_can_filters(&HANDLED_CAN_IDS).iter().map(|filter| filter.cmd()));
     }
 
-    let mut cmds = VecDeque::new();
-    cmds.push_back(STNOBD_CFG_DISABLE_ECHO);
-    cmds.push_back(STNOBD_CFG_ENABLE_HEADER);
-    cmds.push_back(STNOBD_CFG_DISABLE_SPACES);
-    cmds.push_back(STNOBD_CFG_FILTER_BRAKES);
-    cmds.push_back(STNOBD_CFG_FILTER_RPM_SPEED_ACCEL);
-    cmds.push_back(STNOBD_CFG_FILTER_COOLANT_THROTTLE_INTAKE);
-    cmds.push_back(STNOBD_CFG_FILTER_FUEL_LEVEL);
-    cmds.push_back(STNOBD_CFG_FILTER_WHEEL_SPEEDS);
+    let monitoring_mode = if discover { MonitoringMode::All } else { MonitoringMode::Filtered };
+
+    let sleep_cfg = SleepConfig {
+        bus_idle_timeout,
+        vehicle_off_voltage: VEHICLE_OFF_VOLTAGE,
+        wake_probe_interval: WAKE_PROBE_INTERVAL
+    };
 
-    let mut stnobd = Stnobd::new("/dev/pts/3", BaudRate::B921600, cmds);
+    let watchdog_cfg = WatchdogConfig {
+        rsp_timeout
+    };
+
+    let mut stnobd = Stnobd::new("/dev/pts/3", BaudRate::B921600, monitoring_mode, cmds, sleep_cfg, watchdog_cfg);
 
     let sfd = setup_signal_handler();
 
@@ -47,10 +138,74 @@ fn main() {
     epoll.add(stnobd.get_fd(), EpollEvent::new(EpollFlags::EPOLLIN, EpollEventId::Stnobd as u64))
         .expect("epoll add stnobd");
 
-    stnobd.send_reset_cmd();
+    epoll.add(stnobd.get_timer_fd(), EpollEvent::new(EpollFlags::EPOLLIN, EpollEventId::StnobdTimer as u64))
+        .expect("epoll add stnobd timer");
+
+    let event = stnobd.start();
+    handle_port_event(&epoll, &stnobd, event);
+
+    if discover {
+        run_discovery(&epoll, &sfd, &mut stnobd);
+    }
+    else {
+        run_metrics(&epoll, &sfd, &mut stnobd, history_cfg, socket_path);
+    }
+
+    info!("Shutting down ....");
+
+    drop(stnobd);
+
+    info!("Bye :)");
+}
+
+fn timeout_arg(args: &[String], name: &str, default_secs: u64) -> Duration {
+    let secs = arg_value(args, name).map_or(default_secs, |secs| secs.parse().unwrap_or_else(|_| panic!("{} seconds", name)));
+    assert!(secs > 0, "{} must be 1 s or more", name);
+
+    Duration::from_secs(secs)
+}
+
+fn gen_bindings(args: &[String]) -> bool {
+    write_generated(args, &[
+        ("--gen-c-header", &|| generate_c_header::<Metrics>("mx5_metrics", GENERATOR)),
+        ("--gen-csharp", &|| generate_csharp_accessor::<Metrics>("DigitalDash.Mx5MetricsClient", "MetricsAccessor", GENERATOR))
+    ])
+}
+
+fn run_metrics(epoll: &Epoll, sfd: &SignalFd, stnobd: &mut Stnobd, history_cfg: Option<HistoryConfig>, socket_path: Option<&str>) {
+    // Decoded in place, then published to the readers as a whole
+    let mut metrics = Metrics::new();
+
+    let mut shm = ShmSegment::new(SHM_NAME, &metrics);
+
+    // Sampled on its own timer, so that the entries are evenly spaced whatever the CAN traffic
+    let mut history = history_cfg.map(|cfg| {
+        let ring = ShmRing::<Metrics>::new(HISTORY_SHM_NAME, cfg.capacity(), cfg.period());
+
+        let timer = TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::empty())
+            .expect("timerfd");
+
+        timer.set(Expiration::Interval(TimeSpec::from_duration(cfg.period())), TimerSetTimeFlags::empty())
+            .expect("timerfd set");
+
+        epoll.add(&timer, EpollEvent::new(EpollFlags::EPOLLIN, EpollEventId::HistoryTimer as u64))
+            .expect("epoll add history timer");
+
+        info!("Keeping {} s of history at {} hz at /dev/shm{}", cfg.duration.as_secs(), cfg.rate_hz, HISTORY_SHM_NAME);
+
+        (ring, timer)
+    });
+
+    let mut telemetry = socket_path.map(|path| {
+        let server = TelemetryServer::new(path, &metrics);
+
+        epoll.add(server.get_fd(), EpollEvent::new(EpollFlags::EPOLLIN, EpollEventId::Telemetry as u64))
+            .expect("epoll add telemetry");
+
+        info!("Streaming at {}", path);
 
-    let mut shm = ShmMetrics::new(SHM_NAME);
-    let mut metrics = &mut shm.metrics;
+        server
+    });
 
     info!("Ready at /dev/shm{}", SHM_NAME);
 
@@ -61,21 +216,142 @@ fn main() {
             .expect("epoll wait");
 
         if events[0].data() == EpollEventId::Signal as u64 {
-            handle_signal(&sfd);
+            handle_signal(sfd);
             break;
         }
 
+        // Streams what was last published, nothing new to publish
+        if events[0].data() == EpollEventId::Telemetry as u64 {
+            telemetry.as_mut().unwrap().handle_events();
+            continue;
+        }
+
         if events[0].data() == EpollEventId::Stnobd as u64 {
-            stnobd.handle_incoming_stnobd_msg(&mut metrics);
+            let event = if events[0].events().intersects(EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR) {
+                stnobd.handle_hangup()
+            }
+            else {
+                stnobd.handle_incoming_stnobd_msg(&mut metrics)
+            };
+            handle_port_event(epoll, stnobd, event);
+        }
+
+        if events[0].data() == EpollEventId::StnobdTimer as u64 {
+            let event = stnobd.handle_timer();
+            handle_port_event(epoll, stnobd, event);
+        }
+
+        metrics.set_vehicle_off(stnobd.is_vehicle_off());
+        metrics.set_link_state(stnobd.link_state());
+        metrics.set_heartbeat(monotonic_ns());
+
+        if events[0].data() == EpollEventId::HistoryTimer as u64 {
+            let (ring, timer) = history.as_mut().unwrap();
+
+            timer.wait()
+                .expect("timerfd wait");
+
+            ring.push(monotonic_ns(), &metrics);
+        }
+
+        shm.publish(&metrics);
+
+        if let Some(server) = telemetry.as_mut() {
+            server.update(&metrics);
         }
     }
+}
 
-    info!("Shutting down ....");
+fn run_discovery(epoll: &Epoll, sfd: &SignalFd, stnobd: &mut Stnobd) {
+    let mut discovery = CanDiscovery::new();
 
-    drop(stnobd);
-    drop(shm);
+    let terminal = RawTerminal::new();
+    let stdin = io::stdin();
 
-    info!("Bye :)");
+    epoll.add(&stdin, EpollEvent::new(EpollFlags::EPOLLIN, EpollEventId::Stdin as u64))
+        .expect("epoll add stdin");
+
+    let refresh_timer = TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::empty())
+        .expect("timerfd");
+
+    refresh_timer.set(Expiration::Interval(TimeSpec::from_duration(DISCOVERY_REFRESH_INTERVAL)), TimerSetTimeFlags::empty())
+        .expect("timerfd set");
+
+    epoll.add(&refresh_timer, EpollEvent::new(EpollFlags::EPOLLIN, EpollEventId::DiscoveryRefresh as u64))
+        .expect("epoll add discovery refresh timer");
+
+    let mut events = [EpollEvent::empty()];
+
+    loop {
+        epoll.wait(&mut events, EpollTimeout::NONE)
+            .expect("epoll wait");
+
+        if events[0].data() == EpollEventId::Signal as u64 {
+            handle_signal(sfd);
+            break;
+        }
+
+        if events[0].data() == EpollEventId::Stnobd as u64 {
+            let event = if events[0].events().intersects(EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR) {
+                stnobd.handle_hangup()
+            }
+            else {
+                stnobd.handle_incoming_stnobd_msg(&mut discovery)
+            };
+            handle_port_event(epoll, stnobd, event);
+        }
+
+        if events[0].data() == EpollEventId::StnobdTimer as u64 {
+            let event = stnobd.handle_timer();
+            handle_port_event(epoll, stnobd, event);
+        }
+
+        if events[0].data() == EpollEventId::DiscoveryRefresh as u64 {
+            refresh_timer.wait()
+                .expect("timerfd wait");
+
+            let mut stdout = io::stdout();
+            let _ = stdout.write_all(discovery.render().as_bytes());
+            let _ = stdout.flush();
+        }
+
+        if events[0].data() == EpollEventId::Stdin as u64 {
+            let mut key = [0u8; 1];
+            match stdin.lock().read(&mut key) {
+                Ok(1) if key[0] == b'q' => break,
+                Ok(1) if key[0] == b'm' => discovery.mark(),
+                Ok(1) if key[0] == b'e' => {
+                    if let Err(e) = discovery.export_dbc(DISCOVERY_DBC_PATH) {
+                        error!("couldn't export {}: {}", DISCOVERY_DBC_PATH, e);
+                    }
+                }
+                Ok(0) => {
+                    // stdin is closed, stop listening to it
+                    epoll.delete(&stdin)
+                        .expect("epoll delete stdin");
+                }
+                Ok(_) => {}
+                Err(e) => panic!("Error reading stdin: {}", e)
+            }
+        }
+    }
+
+    drop(terminal);
+}
+
+// A lost port leaves epoll until it's reopened, so that its hangups don't keep waking us up
+fn handle_port_event(epoll: &Epoll, stnobd: &Stnobd, event: Option<PortEvent>) {
+    match event {
+        Some(PortEvent::Lost) => {
+            epoll.delete(stnobd.get_fd())
+                .expect("epoll delete stnobd");
+        }
+        Some(PortEvent::Reopened) => {
+            epoll.add(stnobd.get_fd(), EpollEvent::new(EpollFlags::EPOLLIN, EpollEventId::Stnobd as u64))
+                .expect("epoll add reopened stnobd");
+        }
+        None => {}
+    }
 }
 
 fn setup_signal_handler() -> SignalFd {
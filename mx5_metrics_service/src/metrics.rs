@@ -1,4 +1,6 @@
 use log::{debug, error};
+use shm_segment::{shm_field, ShmData, ShmField};
+use crate::stnobd::{CanFrame, CanFrameHandler, LinkState};
 
 const CAN_ID_BRAKES: u16 = 0x085; // 100hz
 const CAN_ID_RPM_SPEED_ACCEL: u16 = 0x201; // 100hz
@@ -6,6 +8,15 @@ const CAN_ID_COOLANT_THROTTLE_INTAKE: u16 = 0x240; // 10hz
 const CAN_ID_FUEL_LEVEL: u16 = 0x430; // 10 hz
 const CAN_ID_WHEEL_SPEEDS: u16 =  0x4b0; // 100hz
 
+// Every CAN ID handle_can_msg decodes, the STN filters are generated from these
+pub const HANDLED_CAN_IDS: [u16; 5] = [
+    CAN_ID_BRAKES,
+    CAN_ID_RPM_SPEED_ACCEL,
+    CAN_ID_COOLANT_THROTTLE_INTAKE,
+    CAN_ID_FUEL_LEVEL,
+    CAN_ID_WHEEL_SPEEDS
+];
+
 // masks and shifts assume little endian
 const BRAKE_PRESSURE_MASK: u64 = 0xff_ff_00_00_00_00_00_00; // 6-7
 const BRAKE_PRESSURE_BIT_SHIFT: usize = 6 * 8;
@@ -17,7 +28,7 @@ const SPEED_MASK: u64 = 0xff_ff_00_00; // 2-3
 const SPEED_BIT_SHIFT: usize = 2 * 8;
 
 const ACCEL_MASK: u64 = 0xff_00; // 1
-const ACCEL_BIT_SHIFT: usize = 1 * 8;
+const ACCEL_BIT_SHIFT: usize = 8;
 
 const ENGINE_LOAD_MASK: u64 = 0xff_00_00_00_00_00_00_00; // 7
 const ENGINE_LOAD_BIT_SHIFT: usize = 7 * 8;
@@ -55,6 +66,7 @@ const PCT_DIV: f32 = 2.55;
 const TEMP_OFFSET: i16 = 40;
 
 #[repr(C)]
+#[derive(Default, Clone, Copy)]
 pub struct Metrics {
     rpm: u16,
     speed_kmh: u16,
@@ -68,22 +80,62 @@ pub struct Metrics {
     calculated_engine_load_pct: u8,
     throttle_valve_position_pct: u8,
     fuel_level_pct: u8,
-    brakes_pct: u8
+    brakes_pct: u8,
+    vehicle_off: bool,
+    // stnobd::LinkState, 0 before the first publish
+    link_state: u8,
+    producer_pid: u32,
+    // When the metrics were last published, at least every second even with a silent bus.
+    // A heartbeat older than that means the service is gone, the values are then frozen.
+    heartbeat_ns: u64,
+    // When each CAN msg was last sampled, ns on the host CLOCK_MONOTONIC
+    brakes_sample_ns: u64,
+    rpm_speed_accel_sample_ns: u64,
+    coolant_throttle_intake_sample_ns: u64,
+    fuel_level_sample_ns: u64,
+    wheel_speeds_sample_ns: u64
 }
 
 impl Metrics {
-    pub fn handle_can_msg(&mut self, can_id: u16, can_data: u64) {
+    pub fn new() -> Metrics {
+        Metrics {
+            producer_pid: std::process::id(),
+            ..Metrics::default()
+        }
+    }
+
+    pub fn set_vehicle_off(&mut self, vehicle_off: bool) {
+        self.vehicle_off = vehicle_off;
+    }
+
+    pub fn set_link_state(&mut self, link_state: LinkState) {
+        self.link_state = link_state as u8;
+    }
+
+    pub fn set_heartbeat(&mut self, heartbeat_ns: u64) {
+        self.heartbeat_ns = heartbeat_ns;
+    }
+
+    // Returns false for CAN IDs we don't decode
+    pub fn handle_can_msg(&mut self, can_id: u16, can_data: u64, sample_ns: u64) -> bool {
         match can_id {
-            CAN_ID_BRAKES => self.handle_brakes(can_data),
-            CAN_ID_RPM_SPEED_ACCEL => self.handle_rpm_speed_accel(can_data),
-            CAN_ID_COOLANT_THROTTLE_INTAKE => self.handle_load_coolant_throttle_intake(can_data),
-            CAN_ID_FUEL_LEVEL => self.handle_fuel_level(can_data),
-            CAN_ID_WHEEL_SPEEDS => self.handle_wheel_speeds(can_data),
-            _ => error!("Unhandled CAN ID: {:#x}", can_id)
+            CAN_ID_BRAKES => self.handle_brakes(can_data, sample_ns),
+            CAN_ID_RPM_SPEED_ACCEL => self.handle_rpm_speed_accel(can_data, sample_ns),
+            CAN_ID_COOLANT_THROTTLE_INTAKE => self.handle_load_coolant_throttle_intake(can_data, sample_ns),
+            CAN_ID_FUEL_LEVEL => self.handle_fuel_level(can_data, sample_ns),
+            CAN_ID_WHEEL_SPEEDS => self.handle_wheel_speeds(can_data, sample_ns),
+            _ => {
+                error!("Unhandled CAN ID: {:#x}", can_id);
+                return false;
+            }
         }
+
+        true
     }
 
-    fn handle_brakes(&mut self, can_data: u64) {
+    fn handle_brakes(&mut self, can_data: u64, sample_ns: u64) {
+        self.brakes_sample_ns = sample_ns;
+
         let mut brake_pressure = ((can_data & BRAKE_PRESSURE_MASK) >> BRAKE_PRESSURE_BIT_SHIFT) as i16;
         brake_pressure -= BRAKE_PRESSURE_OFFSET;
 
@@ -91,10 +143,12 @@ impl Metrics {
         // Limit to min 0
         self.brakes_pct = (brake_pressure.max(0) as f32 * BRAKE_PRESSURE_COEF) as u8;
 
-        debug!("brakes {} %", self.brakes_pct)
+        debug!("brakes {} % @{} ns", self.brakes_pct, sample_ns)
     }
 
-    fn handle_rpm_speed_accel(&mut self, can_data: u64) {
+    fn handle_rpm_speed_accel(&mut self, can_data: u64, sample_ns: u64) {
+        self.rpm_speed_accel_sample_ns = sample_ns;
+
         let rpm = ((can_data & RPM_MASK) >> RPM_BIT_SHIFT) as u16;
         self.rpm = rpm / RPM_DIV;
 
@@ -104,11 +158,13 @@ impl Metrics {
         let accel = ((can_data & ACCEL_MASK) >> ACCEL_BIT_SHIFT) as u8;
         self.accelerator_pedal_position_pct = accel / ACCEL_DIV;
 
-        debug!("rpm {}, speed {} kmh, accel {} %",
-            self.rpm, self.speed_kmh, self.accelerator_pedal_position_pct);
+        debug!("rpm {}, speed {} kmh, accel {} % @{} ns",
+            self.rpm, self.speed_kmh, self.accelerator_pedal_position_pct, sample_ns);
     }
 
-    fn handle_load_coolant_throttle_intake(&mut self, can_data: u64) {
+    fn handle_load_coolant_throttle_intake(&mut self, can_data: u64, sample_ns: u64) {
+        self.coolant_throttle_intake_sample_ns = sample_ns;
+
         let engine_load = ((can_data & ENGINE_LOAD_MASK) >> ENGINE_LOAD_BIT_SHIFT) as u8;
         self.calculated_engine_load_pct = raw_to_pct(engine_load);
 
@@ -122,19 +178,23 @@ impl Metrics {
         self.intake_air_temp_c = raw_to_temp(intake_temp);
 
 
-        debug!("engine {} %, coolant {} °C, throttle {} %, intake {} °C",
+        debug!("engine {} %, coolant {} °C, throttle {} %, intake {} °C @{} ns",
                self.calculated_engine_load_pct, self.engine_coolant_temp_c,
-               self.throttle_valve_position_pct, self.intake_air_temp_c);
+               self.throttle_valve_position_pct, self.intake_air_temp_c, sample_ns);
     }
 
-    fn handle_fuel_level(&mut self, can_data: u64) {
+    fn handle_fuel_level(&mut self, can_data: u64, sample_ns: u64) {
+        self.fuel_level_sample_ns = sample_ns;
+
         let fuel_level = ((can_data & FUEL_LEVEL_MASK) >> FUEL_LEVEL_BIT_SHIFT) as u8;
         self.fuel_level_pct = raw_to_pct(fuel_level);
 
-        debug!("fuel {} %", self.fuel_level_pct);
+        debug!("fuel {} % @{} ns", self.fuel_level_pct, sample_ns);
     }
 
-    fn handle_wheel_speeds(&mut self, can_data: u64) {
+    fn handle_wheel_speeds(&mut self, can_data: u64, sample_ns: u64) {
+        self.wheel_speeds_sample_ns = sample_ns;
+
         let fl = ((can_data & FL_SPEED_MASK) >> FL_SPEED_BIT_SHIFT) as u16;
         self.fl_speed_kmh = raw_speed_to_kmh(fl);
 
@@ -147,8 +207,45 @@ impl Metrics {
         let rr = (can_data & RR_SPEED_MASK) as u16;
         self.rr_speed_kmh = raw_speed_to_kmh(rr);
 
-        debug!("fl {} fr {} rl {} rr {} kmh\n",
-               self.fl_speed_kmh, self.fr_speed_kmh, self.rl_speed_kmh, self.rr_speed_kmh);
+        debug!("fl {} fr {} rl {} rr {} kmh @{} ns",
+               self.fl_speed_kmh, self.fr_speed_kmh, self.rl_speed_kmh, self.rr_speed_kmh, sample_ns);
+    }
+}
+
+impl ShmData for Metrics {
+    const LAYOUT_VERSION: u32 = 2;
+
+    fn fields() -> Vec<ShmField> {
+        vec![
+            shm_field!(Metrics, rpm, "rpm"),
+            shm_field!(Metrics, speed_kmh, "km/h"),
+            shm_field!(Metrics, engine_coolant_temp_c, "degC"),
+            shm_field!(Metrics, intake_air_temp_c, "degC"),
+            shm_field!(Metrics, fl_speed_kmh, "km/h"),
+            shm_field!(Metrics, fr_speed_kmh, "km/h"),
+            shm_field!(Metrics, rl_speed_kmh, "km/h"),
+            shm_field!(Metrics, rr_speed_kmh, "km/h"),
+            shm_field!(Metrics, accelerator_pedal_position_pct, "%"),
+            shm_field!(Metrics, calculated_engine_load_pct, "%"),
+            shm_field!(Metrics, throttle_valve_position_pct, "%"),
+            shm_field!(Metrics, fuel_level_pct, "%"),
+            shm_field!(Metrics, brakes_pct, "%"),
+            shm_field!(Metrics, vehicle_off, ""),
+            shm_field!(Metrics, link_state, ""),
+            shm_field!(Metrics, producer_pid, ""),
+            shm_field!(Metrics, heartbeat_ns, "ns"),
+            shm_field!(Metrics, brakes_sample_ns, "ns"),
+            shm_field!(Metrics, rpm_speed_accel_sample_ns, "ns"),
+            shm_field!(Metrics, coolant_throttle_intake_sample_ns, "ns"),
+            shm_field!(Metrics, fuel_level_sample_ns, "ns"),
+            shm_field!(Metrics, wheel_speeds_sample_ns, "ns")
+        ]
+    }
+}
+
+impl CanFrameHandler for Metrics {
+    fn handle_can_frame(&mut self, frame: &CanFrame) {
+        self.handle_can_msg(frame.id, u64::from_be_bytes(frame.data), frame.sample_ns);
     }
 }
 
@@ -163,4 +260,76 @@ fn raw_to_pct(raw: u8) -> u8 {
 
 fn raw_to_temp(raw: i16) -> i16 {
     raw - TEMP_OFFSET
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use shm_client::{MetricsClient, MetricsHistoryClient, MetricsSnapshot};
+    use shm_segment::{generate_c_header, generate_csharp_accessor, ShmRing, ShmSegment};
+    use crate::can_filter::{generate_can_filters, passes};
+
+    #[test]
+    fn can_filters_pass_exactly_the_handled_can_ids() {
+        let filters = generate_can_filters(&HANDLED_CAN_IDS);
+        let mut metrics = Metrics::default();
+
+        for can_id in 0..=0x7ff {
+            assert_eq!(passes(&filters, can_id), metrics.handle_can_msg(can_id, 0, 0), "can id {:#x}", can_id);
+        }
+    }
+
+    // Regenerate them with --gen-c-header and --gen-csharp when this fails
+    #[test]
+    fn checked_in_bindings_match_the_metrics_layout() {
+        assert_eq!(generate_c_header::<Metrics>("mx5_metrics", crate::GENERATOR),
+                   include_str!("../../bindings/mx5_metrics.h"));
+        assert_eq!(generate_csharp_accessor::<Metrics>("DigitalDash.Mx5MetricsClient", "MetricsAccessor", crate::GENERATOR),
+                   include_str!("../../DigitalDash/Mx5MetricsClient/MetricsAccessor.g.cs"));
+    }
+
+    #[test]
+    fn shm_client_reads_the_published_metrics() {
+        const NAME: &str = "/mx5metrics_client_test";
+
+        let mut metrics = Metrics::new();
+        let mut shm = ShmSegment::new(NAME, &metrics);
+        let mut client = MetricsClient::open_name(NAME).unwrap();
+
+        assert_eq!(client.read(), Ok(MetricsSnapshot { producer_pid: std::process::id(), ..MetricsSnapshot::default() }));
+
+        metrics.handle_can_msg(CAN_ID_WHEEL_SPEEDS, 0x2af8_2b5c_2bc0_2c24, 42);
+        metrics.set_vehicle_off(true);
+        metrics.set_link_state(LinkState::Sleeping);
+        metrics.set_heartbeat(1_000_000_000);
+        shm.publish(&metrics);
+
+        let snapshot = client.read().unwrap();
+        assert_eq!((snapshot.fl_speed_kmh, snapshot.fr_speed_kmh, snapshot.rl_speed_kmh, snapshot.rr_speed_kmh),
+                   (metrics.fl_speed_kmh, metrics.fr_speed_kmh, metrics.rl_speed_kmh, metrics.rr_speed_kmh));
+        assert_eq!(snapshot.wheel_speeds_sample_ns, 42);
+        assert!(snapshot.vehicle_off);
+        assert_eq!(shm_client::LinkState::from_u8(snapshot.link_state), Some(shm_client::LinkState::Sleeping));
+        assert!(snapshot.is_live(2_000_000_000));
+        assert!(!snapshot.is_live(5_000_000_000));
+    }
+
+    #[test]
+    fn shm_client_reads_the_metrics_history() {
+        const NAME: &str = "/mx5history_client_test";
+
+        let mut metrics = Metrics::default();
+        let mut ring = ShmRing::new(NAME, 2, Duration::from_millis(50));
+        let mut client = MetricsHistoryClient::open_name(NAME).unwrap();
+
+        for sample_ns in 1..=3 {
+            metrics.handle_can_msg(CAN_ID_BRAKES, 0, sample_ns);
+            ring.push(sample_ns * 1000, &metrics);
+        }
+
+        let history = client.read_all().unwrap();
+        assert_eq!(history.iter().map(|entry| (entry.timestamp_ns, entry.snapshot.brakes_sample_ns)).collect::<Vec<_>>(),
+                   vec![(2000, 2), (3000, 3)]);
+    }
+}
@@ -2,11 +2,13 @@ use std::str;
 use std::collections::VecDeque;
 use std::os::fd::OwnedFd;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use log::{debug, error, info, trace, warn};
 use nix::sys::termios::BaudRate;
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
 use serial_port::SerialPort;
-use crate::metrics::Metrics;
+use shm_segment::monotonic_ns;
 
 fn contains_slice(haystack: &[u8], needle: &[u8]) -> bool {
     if needle.len() > haystack.len() {
@@ -19,41 +21,189 @@ fn contains_slice(haystack: &[u8], needle: &[u8]) -> bool {
 pub const STNOBD_CFG_DISABLE_ECHO: &str = "ATE0\r";
 pub const STNOBD_CFG_ENABLE_HEADER: &str = "ATH1\r";
 pub const STNOBD_CFG_DISABLE_SPACES: &str = "ATS0\r";
-pub const STNOBD_CFG_FILTER_BRAKES: &str = "STFPA085,FFF\r";
-pub const STNOBD_CFG_FILTER_RPM_SPEED_ACCEL: &str = "STFPA201,FFF\r";
-pub const STNOBD_CFG_FILTER_COOLANT_THROTTLE_INTAKE: &str = "STFPA240,FFF\r";
-pub const STNOBD_CFG_FILTER_FUEL_LEVEL: &str = "STFPA430,FFF\r";
-pub const STNOBD_CFG_FILTER_WHEEL_SPEEDS: &str = "STFPA4B0,FFF\r";
-
+pub const STNOBD_CFG_ENABLE_UART_WAKEUP: &str = "STSLU OFF,ON\r";
+pub const STNOBD_CFG_ENABLE_TIMESTAMPS: &str = "STCTS1\r";
 
+const TIMESTAMP_STR_LEN: usize = 4; // ms, wraps every 65.536s
 const CAN_ID_STR_LEN: usize = 3;
 const CAN_DATA_STR_LEN: usize = 16;
-const MON_RSP_LEN: usize = CAN_ID_STR_LEN + CAN_DATA_STR_LEN + 1 /* CR */;
+const MON_RSP_BUF_LEN: usize = 64;
+
+const TIMER_INTERVAL: Duration = Duration::from_secs(1);
+const WAKE_UP_DELAY: Duration = Duration::from_millis(100);
+const MAX_CLOCK_DRIFT_PPM: u64 = 100;
+
+#[derive(PartialEq, Debug)]
+pub struct CanFrame {
+    pub id: u16,
+    pub dlc: u8,
+    pub data: [u8; 8],
+    // When the STN got the msg, on the host CLOCK_MONOTONIC
+    pub sample_ns: u64
+}
+
+// What the link to the STN is doing, as published to the readers
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LinkState {
+    Resetting = 1,
+    Configuring = 2,
+    // Including the short pauses to check the battery voltage
+    Monitoring = 3,
+    // Vehicle off, waiting for the bus to wake up
+    Sleeping = 4,
+    Disconnected = 5
+}
+
+// What the caller has to do with the port's fd in its epoll
+#[derive(PartialEq, Debug)]
+pub enum PortEvent {
+    // The port failed or the watchdog gave up on it, it's reopened later on
+    Lost,
+    // A new fd replaces the lost one
+    Reopened
+}
+
+pub trait CanFrameHandler {
+    fn handle_can_frame(&mut self, frame: &CanFrame);
+}
+
+pub enum MonitoringMode {
+    // Only the CAN msgs passing the configured filters
+    Filtered,
+    // Every CAN msg on the bus
+    All
+}
+
+pub struct SleepConfig {
+    // No CAN msg for this long while monitoring triggers a battery voltage check,
+    // a silent bus with a healthy battery is handled by the watchdog
+    pub bus_idle_timeout: Duration,
+    // A silent bus and a battery voltage below this means the vehicle is off
+    pub vehicle_off_voltage: f32,
+    // How often a sleeping STN is woken up to look for CAN traffic again
+    pub wake_probe_interval: Duration
+}
+
+pub struct WatchdogConfig {
+    // How long the STN gets to answer a cmd before we try to recover it
+    pub rsp_timeout: Duration
+}
+
+#[derive(Default, PartialEq, Debug)]
+struct RecoveryCounters {
+    monitoring_restarts: u32,
+    resets: u32,
+    port_reopens: u32
+}
+
+// Maps the STN's wrapping ms timestamps onto the host CLOCK_MONOTONIC
+#[derive(Default)]
+struct StnClock {
+    last_stn_ms: Option<u16>,
+    wraps: u64,
+    offset_ns: i64,
+    last_rx_ns: Option<u64>
+}
+
+impl StnClock {
+    fn map_to_monotonic_ns(&mut self, stn_ms: u16, rx_ns: u64) -> u64 {
+        if self.last_stn_ms.is_some_and(|last_stn_ms| stn_ms < last_stn_ms) {
+            self.wraps += 1;
+        }
+        self.last_stn_ms = Some(stn_ms);
+
+        let stn_ns = ((self.wraps << 16) + stn_ms as u64) * 1_000_000;
+
+        // A msg can't be read before the STN got it, so the smallest rx - stn difference
+        // is the best estimate of the offset between both clocks.
+        // Let the estimate creep up as fast as the clocks can drift apart.
+        let rx_offset_ns = rx_ns as i64 - stn_ns as i64;
+        self.offset_ns = match self.last_rx_ns {
+            Some(last_rx_ns) => {
+                let max_drift_ns = (rx_ns - last_rx_ns) * MAX_CLOCK_DRIFT_PPM / 1_000_000;
+                rx_offset_ns.min(self.offset_ns + max_drift_ns as i64)
+            }
+            None => rx_offset_ns
+        };
+        self.last_rx_ns = Some(rx_ns);
+
+        (stn_ns as i64 + self.offset_ns) as u64
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum State {
+    Idle,
+    Resetting,
+    Configuring,
+    Monitoring,
+    StoppingMonitoring,
+    ReadingVoltage,
+    Sleeping,
+    Disconnected
+}
+
+// Escalating steps to get a silent STN going again
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+enum Recovery {
+    RestartMonitoring,
+    Reset,
+    ReopenPort
+}
 
 pub struct Stnobd {
     serial_port: SerialPort,
-    reset_in_progress: bool,
-    must_configure: bool,
-    in_monitoring_mode: bool,
-    cfg_cmds: VecDeque<&'static str>,
-    mon_rsp_buf: [u8; MON_RSP_LEN],
-    mon_rsp_pos: usize
+    port_name: String,
+    baud: BaudRate,
+    monitoring_mode: MonitoringMode,
+    timer: TimerFd,
+    sleep_cfg: SleepConfig,
+    watchdog_cfg: WatchdogConfig,
+    next_recovery: Recovery,
+    recovery_counters: RecoveryCounters,
+    state: State,
+    state_since: Instant,
+    last_can_msg_at: Instant,
+    vehicle_off: bool,
+    cfg_cmds: Vec<String>,
+    pending_cfg_cmds: VecDeque<String>,
+    mon_rsp_buf: [u8; MON_RSP_BUF_LEN],
+    mon_rsp_pos: usize,
+    stn_clock: StnClock
 }
 
 impl Stnobd {
-    pub fn new(port_name: &str, baud: BaudRate, cmds: VecDeque<&'static str>) -> Stnobd {
-        let sp = SerialPort::new(port_name);
-        sp.set_access_exclusive();
-        sp.configure(1, 1, baud);
+    pub fn new(port_name: &str, baud: BaudRate, monitoring_mode: MonitoringMode, cmds: Vec<String>,
+               sleep_cfg: SleepConfig, watchdog_cfg: WatchdogConfig) -> Stnobd {
+        let sp = open_port(port_name, baud)
+            .expect("open serial");
+
+        let timer = TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::empty())
+            .expect("timerfd");
+
+        timer.set(Expiration::Interval(TimeSpec::from_duration(TIMER_INTERVAL)), TimerSetTimeFlags::empty())
+            .expect("timerfd set");
 
         Stnobd {
             serial_port: sp,
-            reset_in_progress: false,
-            must_configure: false,
-            in_monitoring_mode: false,
+            port_name: port_name.to_string(),
+            baud,
+            monitoring_mode,
+            timer,
+            sleep_cfg,
+            watchdog_cfg,
+            next_recovery: Recovery::RestartMonitoring,
+            recovery_counters: RecoveryCounters::default(),
+            state: State::Idle,
+            state_since: Instant::now(),
+            last_can_msg_at: Instant::now(),
+            vehicle_off: false,
             cfg_cmds: cmds,
-            mon_rsp_buf: [0; 20],
-            mon_rsp_pos: 0
+            pending_cfg_cmds: VecDeque::new(),
+            mon_rsp_buf: [0; MON_RSP_BUF_LEN],
+            mon_rsp_pos: 0,
+            stn_clock: StnClock::default()
         }
     }
 
@@ -61,23 +211,46 @@ impl Stnobd {
         &self.serial_port.fd
     }
 
-    fn send_cfg_cmd(&mut self) {
-        match self.cfg_cmds.pop_front() {
+    pub fn get_timer_fd(&self) -> &TimerFd {
+        &self.timer
+    }
+
+    pub fn is_vehicle_off(&self) -> bool {
+        self.vehicle_off
+    }
+
+    pub fn link_state(&self) -> LinkState {
+        match self.state {
+            State::Idle | State::Resetting => LinkState::Resetting,
+            State::Configuring => LinkState::Configuring,
+            State::Monitoring | State::StoppingMonitoring | State::ReadingVoltage => LinkState::Monitoring,
+            State::Sleeping => LinkState::Sleeping,
+            State::Disconnected => LinkState::Disconnected
+        }
+    }
+
+    fn set_state(&mut self, state: State) {
+        trace!("state {:?} -> {:?}", self.state, state);
+        self.state = state;
+        self.state_since = Instant::now();
+    }
+
+    fn send_cfg_cmd(&mut self) -> nix::Result<()> {
+        match self.pending_cfg_cmds.pop_front() {
             // Send next command
             Some(cmd) => {
                 debug!("sending cfg cmd '{}'", &cmd[..cmd.len() - 1] /* omit CR */);
-                self.serial_port.write(cmd.as_bytes());
+                self.serial_port.write(cmd.as_bytes())
             }
             // Or start monitoring once all commands were sent
             None => {
                 info!("config sent");
-                self.must_configure = false;
-                self.start_monitoring_mode();
+                self.start_monitoring_mode()
             }
         }
     }
 
-    fn handle_cfg_rsp(&mut self) {
+    fn handle_cfg_rsp(&mut self) -> nix::Result<()> {
         const CFG_ACK: &str = "OK\r>";
 
         let mut buf: [u8; CFG_ACK.len()] = [0; CFG_ACK.len()];
@@ -85,53 +258,136 @@ impl Stnobd {
         // Wait for full response (> prompt char can lag behind initial startup msg chars)
         sleep(Duration::from_millis(100));
 
-        let c = self.serial_port.read(&mut buf);
+        let c = self.serial_port.read(&mut buf)?;
 
         // TODO retry
         if c != buf.len() || !contains_slice(&buf, CFG_ACK.as_bytes()) {
             error!("didnt get expected cfg ack : len {}, '{}'", c, String::from_utf8_lossy(&buf));
         }
 
-        self.serial_port.flush_all();
+        self.serial_port.flush_all()?;
         self.send_cfg_cmd()
     }
 
-    fn start_monitoring_mode(&mut self) {
-        const CMD: &str = "STM\r";
+    fn start_monitoring_mode(&mut self) -> nix::Result<()> {
+        let cmd = match self.monitoring_mode {
+            MonitoringMode::Filtered => "STM\r",
+            MonitoringMode::All => "STMA\r"
+        };
 
         // Get rid of any existing unwanted bytes
-        self.serial_port.flush_all();
+        self.serial_port.flush_all()?;
+        self.mon_rsp_pos = 0;
+        // The STN timestamps restart after a reset
+        self.stn_clock = StnClock::default();
 
         info!("starting monitoring mode");
-        self.serial_port.write(CMD.as_bytes());
+        self.serial_port.write(cmd.as_bytes())?;
 
-        self.in_monitoring_mode = true;
+        // Give the bus a full idle timeout before suspecting the vehicle is off
+        self.last_can_msg_at = Instant::now();
+        self.set_state(State::Monitoring);
+
+        Ok(())
     }
 
-    fn stop_monitoring_mode(&mut self) {
+    fn stop_monitoring_mode(&mut self) -> nix::Result<()> {
         const CMD: &str = "\r";
 
         info!("stopping monitoring mode");
-        self.serial_port.write(CMD.as_bytes());
+        self.serial_port.write(CMD.as_bytes())?;
+
+        self.set_state(State::StoppingMonitoring);
 
-        self.in_monitoring_mode = false;
+        Ok(())
     }
 
-    pub fn send_reset_cmd(&mut self) {
+    fn handle_stop_monitoring_rsp(&mut self) -> nix::Result<()> {
+        const CMD: &str = "ATRV\r";
+
+        // Wait for the full STOPPED response and prompt, we don't need any of it
+        sleep(Duration::from_millis(100));
+        self.serial_port.flush_all()?;
+
+        debug!("reading battery voltage");
+        self.serial_port.write(CMD.as_bytes())?;
+
+        self.set_state(State::ReadingVoltage);
+
+        Ok(())
+    }
+
+    fn handle_voltage_rsp(&mut self) -> nix::Result<()> {
+        let mut buf: [u8; 16] = [0; 16];
+
+        // Wait for full response
+        sleep(Duration::from_millis(100));
+
+        let c = self.serial_port.read(&mut buf)?;
+        self.serial_port.flush_all()?;
+
+        match parse_voltage(&buf[..c]) {
+            Some(voltage) if voltage < self.sleep_cfg.vehicle_off_voltage => {
+                if !self.vehicle_off {
+                    info!("bus is silent and battery is at {:.1}V, vehicle is off", voltage);
+                    self.vehicle_off = true;
+                }
+                self.enter_low_power_mode()
+            }
+            Some(voltage) => {
+                warn!("bus is silent but battery is at {:.1}V, vehicle should be on", voltage);
+                if self.vehicle_off {
+                    info!("battery is back at {:.1}V, vehicle is on", voltage);
+                    self.vehicle_off = false;
+                }
+                self.recover(Recovery::RestartMonitoring)
+            }
+            None => {
+                warn!("couldn't read battery voltage: '{}'", String::from_utf8_lossy(&buf[..c]));
+                self.start_monitoring_mode()
+            }
+        }
+    }
+
+    fn enter_low_power_mode(&mut self) -> nix::Result<()> {
+        const CMD: &str = "STSLEEP\r";
+
+        info!("putting STN to sleep");
+        self.serial_port.write(CMD.as_bytes())?;
+
+        self.set_state(State::Sleeping);
+
+        Ok(())
+    }
+
+    fn wake_up(&mut self) -> nix::Result<()> {
+        const CMD: &str = "\r";
+
+        // Any uart activity wakes the STN up, but the chars that woke it are lost
+        info!("waking STN up to look for CAN traffic");
+        self.serial_port.write(CMD.as_bytes())?;
+        sleep(WAKE_UP_DELAY);
+
+        self.send_reset_cmd()
+    }
+
+    fn send_reset_cmd(&mut self) -> nix::Result<()> {
         const CMD: &str = "ATZ\r";
 
         // Get rid of any existing unwanted bytes
-        self.serial_port.flush_all();
+        self.serial_port.flush_all()?;
 
-        self.serial_port.write(CMD.as_bytes());
+        self.serial_port.write(CMD.as_bytes())?;
 
-        self.reset_in_progress = true;
-        self.must_configure = true;
+        self.pending_cfg_cmds = VecDeque::from(self.cfg_cmds.clone());
+        self.set_state(State::Resetting);
 
         info!("STN reset in progress");
+
+        Ok(())
     }
 
-    fn handle_reset_rsp(&mut self) {
+    fn handle_reset_rsp(&mut self) -> nix::Result<()> {
         // TODO : the startup msg might be chopped when reading and we'd miss it
 
         const STARTUP_MSG: &str = "ELM327";
@@ -142,101 +398,491 @@ impl Stnobd {
         sleep(Duration::from_millis(100));
 
         // Read a bunch of bytes in the hope of finding the STN startup msg
-        let c = self.serial_port.read(&mut buf);
+        let c = self.serial_port.read(&mut buf)?;
 
         if c < STARTUP_MSG.len() {
             warn!("not enough bytes to contain STN startup msg");
-            return;
+            return Ok(());
         }
 
         if contains_slice(&buf, STARTUP_MSG.as_bytes())
         {
             // We got the STN startup message, reset is complete
-            self.reset_in_progress = false;
+            self.set_state(State::Configuring);
             // Get rid of any existing unwanted bytes
-            self.serial_port.flush_all();
+            self.serial_port.flush_all()?;
 
             info!("STN reset done, sending config");
 
-            self.send_cfg_cmd();
+            return self.send_cfg_cmd();
         }
-    }
 
-    fn handle_monitoring_rsp(&mut self, metrics: &mut Metrics) {
-        let remaining_rsp_len = MON_RSP_LEN - self.mon_rsp_pos;
+        Ok(())
+    }
 
-        let c = self.serial_port.read(&mut self.mon_rsp_buf[self.mon_rsp_pos..]);
+    fn handle_monitoring_rsp<H: CanFrameHandler>(&mut self, handler: &mut H) -> nix::Result<()> {
+        let c = self.serial_port.read(&mut self.mon_rsp_buf[self.mon_rsp_pos..])?;
+        let rx_ns = monotonic_ns();
 
         trace!("{}", String::from_utf8_lossy(&self.mon_rsp_buf[self.mon_rsp_pos..self.mon_rsp_pos + c]));
 
-        if c != remaining_rsp_len {
-            trace!("partial rsp : got {}, expected {}", c, remaining_rsp_len);
-            self.mon_rsp_pos += c;
-            return // Let's continue, next read might complete the response
+        self.mon_rsp_pos += c;
+
+        // Monitoring responses end with \r, a read can hold several of them
+        // or a partial one that the next read will complete
+        while let Some(cr_index) = self.mon_rsp_buf[..self.mon_rsp_pos].iter().position(|x| *x == b'\r') {
+            match parse_can_frame(&self.mon_rsp_buf[..cr_index]) {
+                Some((stn_ms, mut frame)) => {
+                    frame.sample_ns = self.stn_clock.map_to_monotonic_ns(stn_ms, rx_ns);
+
+                    trace!("can msg id {:#x} data {:02x?} sampled at {} ns ({} ns before rx)",
+                           frame.id, &frame.data[..frame.dlc as usize], frame.sample_ns, rx_ns - frame.sample_ns);
+                    handler.handle_can_frame(&frame);
+
+                    self.last_can_msg_at = Instant::now();
+                    self.next_recovery = Recovery::RestartMonitoring;
+                    if self.vehicle_off {
+                        info!("CAN traffic is back, vehicle is on");
+                        self.vehicle_off = false;
+                    }
+                }
+                None if cr_index == 0 => {} // Empty line
+                None => {
+                    warn!("got invalid monitoring response: '{}'", String::from_utf8_lossy(&self.mon_rsp_buf[..cr_index]));
+                }
+            }
+
+            // Move everything after \r to the start of the buffer
+            self.mon_rsp_buf.copy_within(cr_index + 1..self.mon_rsp_pos, 0);
+            self.mon_rsp_pos -= cr_index + 1;
         }
 
-        // Monitoring responses should end with \r
-        match self.mon_rsp_buf.iter().rposition(|x| *x == b'\r') {
-            Some(cr_index) => {
-                // Got a complete response
-                if cr_index == MON_RSP_LEN - 1 {
-                    let can_id_str = unsafe { str::from_utf8_unchecked(&self.mon_rsp_buf[..CAN_ID_STR_LEN]) };
-                    let can_id = u16::from_str_radix(can_id_str, 16).unwrap();
+        if self.mon_rsp_pos == self.mon_rsp_buf.len() {
+            // Buffer is full but still missing \r, probably some garbage
+            // Reset read pos, hoping to get a proper response eventually
+            warn!("got invalid monitoring response: missing cr");
+            self.mon_rsp_pos = 0;
+        }
 
-                    let can_data_str = unsafe { str::from_utf8_unchecked(&self.mon_rsp_buf[CAN_ID_STR_LEN..CAN_ID_STR_LEN + CAN_DATA_STR_LEN]) };
-                    let can_data = u64::from_str_radix(can_data_str, 16).unwrap();
+        Ok(())
+    }
 
-                    trace!("can msg id {:#x} data {:#x}", can_id, can_data);
-                    metrics.handle_can_msg(can_id, can_data);
+    pub fn start(&mut self) -> Option<PortEvent> {
+        let state = self.state;
+        let res = self.send_reset_cmd();
+        self.port_event(state, res)
+    }
 
-                    // Reset read pos
-                    self.mon_rsp_pos = 0;
-                }
-                // Response looks valid but misaligned
-                else {
-                    warn!("got misaligned monitoring response");
-                    let after_cr_index = cr_index + 1;
-                    let after_cr_len = MON_RSP_LEN - after_cr_index;
-
-                    // Move everything after \r to the start of the buffer
-                    // and advance pos
-                    self.mon_rsp_buf.copy_within(after_cr_index..after_cr_index + after_cr_len, 0);
-                    self.mon_rsp_pos = after_cr_len;
-                }
+    pub fn handle_incoming_stnobd_msg<H: CanFrameHandler>(&mut self, handler: &mut H) -> Option<PortEvent> {
+        let state = self.state;
+        let res = match self.state {
+            State::Resetting => self.handle_reset_rsp(),
+            State::Configuring => self.handle_cfg_rsp(),
+            State::Monitoring => self.handle_monitoring_rsp(handler),
+            State::StoppingMonitoring => self.handle_stop_monitoring_rsp(),
+            State::ReadingVoltage => self.handle_voltage_rsp(),
+            State::Sleeping => {
+                // Sleep cmd ack or noise from the STN going down
+                trace!("ignoring stn msg while {:?}", self.state);
+                self.serial_port.flush_all()
             }
-            None => {
-                // Response is missing \r, probably some garbage
-                // Reset read pos, hoping to get a proper response eventually
-                warn!("got invalid monitoring response: missing cr");
-                self.mon_rsp_pos = 0;
+            State::Disconnected => {
+                // The port is out of epoll until it's reopened, don't touch it
+                trace!("ignoring stn msg while {:?}", self.state);
+                Ok(())
+            }
+            State::Idle => {
+                error!("got unhandled stn msg");
+                self.serial_port.flush_all()
+            }
+        };
+        self.port_event(state, res)
+    }
+
+    // The port hung up, e.g. the STN was unplugged
+    pub fn handle_hangup(&mut self) -> Option<PortEvent> {
+        let state = self.state;
+        error!("serial port {} hung up", self.port_name);
+        self.disconnect();
+        self.port_event(state, Ok(()))
+    }
+
+    fn recover(&mut self, min_recovery: Recovery) -> nix::Result<()> {
+        // Keep escalating as long as no CAN msg makes it through
+        let recovery = if self.next_recovery > min_recovery { self.next_recovery } else { min_recovery };
+        let counters = &mut self.recovery_counters;
+
+        match recovery {
+            Recovery::RestartMonitoring => {
+                counters.monitoring_restarts += 1;
+                warn!("watchdog: restarting monitoring mode (restarts {}, resets {}, port reopens {})",
+                      counters.monitoring_restarts, counters.resets, counters.port_reopens);
+                self.next_recovery = Recovery::Reset;
+                self.start_monitoring_mode()
+            }
+            Recovery::Reset => {
+                counters.resets += 1;
+                warn!("watchdog: resetting STN (restarts {}, resets {}, port reopens {})",
+                      counters.monitoring_restarts, counters.resets, counters.port_reopens);
+                self.next_recovery = Recovery::ReopenPort;
+                self.send_reset_cmd()
+            }
+            Recovery::ReopenPort => {
+                self.disconnect();
+                Ok(())
             }
         }
     }
 
-    pub fn handle_incoming_stnobd_msg(&mut self, metrics: &mut Metrics)
-    {
-        if self.reset_in_progress {
-            return self.handle_reset_rsp();
+    fn disconnect(&mut self) {
+        let counters = &mut self.recovery_counters;
+        counters.port_reopens += 1;
+        warn!("reopening serial port (restarts {}, resets {}, port reopens {})",
+              counters.monitoring_restarts, counters.resets, counters.port_reopens);
+        // The port is actually reopened on the next timer tick
+        self.set_state(State::Disconnected);
+    }
+
+    // Tells the caller when the port has to leave or join epoll
+    fn port_event(&mut self, state: State, res: nix::Result<()>) -> Option<PortEvent> {
+        if let Err(e) = res {
+            error!("serial port {} failed while {:?}: {}", self.port_name, self.state, e);
+            self.disconnect();
         }
 
-        if self.must_configure {
-            return self.handle_cfg_rsp();
+        match (state, self.state) {
+            (State::Disconnected, State::Disconnected) => None,
+            (_, State::Disconnected) => Some(PortEvent::Lost),
+            (State::Disconnected, _) => Some(PortEvent::Reopened),
+            _ => None
         }
+    }
 
-        if self.in_monitoring_mode {
-            return self.handle_monitoring_rsp(metrics);
+    fn reopen_port(&mut self) -> nix::Result<()> {
+        // Release the port so that our own exclusive access doesn't lock us out,
+        // the old fd may well be gone with the device
+        let _ = self.serial_port.set_access_nonexclusive();
+
+        match open_port(&self.port_name, self.baud) {
+            Ok(sp) => self.serial_port = sp,
+            Err(e) => {
+                // Tried again on the next tick
+                error!("couldn't reopen {}: {}", self.port_name, e);
+                return Ok(());
+            }
         }
 
-        self.serial_port.flush_all();
-        error!("got unhandled stn msg");
+        info!("reopened {}", self.port_name);
+        self.send_reset_cmd()
+    }
+
+    pub fn handle_timer(&mut self) -> Option<PortEvent> {
+        self.timer.wait()
+            .expect("timerfd wait");
+
+        self.check_timeouts()
+    }
+
+    fn check_timeouts(&mut self) -> Option<PortEvent> {
+        let state = self.state;
+        let res = match self.state {
+            State::Monitoring if self.last_can_msg_at.elapsed() >= self.sleep_cfg.bus_idle_timeout => {
+                info!("no CAN msg for {:?}, checking battery voltage", self.sleep_cfg.bus_idle_timeout);
+                self.stop_monitoring_mode()
+            }
+            State::Sleeping if self.state_since.elapsed() >= self.sleep_cfg.wake_probe_interval => {
+                self.wake_up()
+            }
+            State::Resetting | State::Configuring | State::StoppingMonitoring | State::ReadingVoltage
+                if self.state_since.elapsed() >= self.watchdog_cfg.rsp_timeout => {
+                warn!("watchdog: no rsp from STN for {:?} while {:?}", self.watchdog_cfg.rsp_timeout, self.state);
+                // Restarting monitoring mode won't help a STN that stopped answering
+                self.recover(Recovery::Reset)
+            }
+            State::Disconnected => self.reopen_port(),
+            _ => Ok(())
+        };
+        self.port_event(state, res)
+    }
+}
+
+fn open_port(port_name: &str, baud: BaudRate) -> nix::Result<SerialPort> {
+    let sp = SerialPort::open(port_name)?;
+    sp.set_access_exclusive()?;
+    sp.configure(1, 1, baud)?;
+    Ok(sp)
+}
+
+// Returns the STN timestamp along with the msg, its sample time is left for the caller to map
+fn parse_can_frame(line: &[u8]) -> Option<(u16, CanFrame)> {
+    // With timestamps and headers on and spaces off, a msg is its timestamp,
+    // its 11 bit id and up to 8 data bytes
+    const HEADER_STR_LEN: usize = TIMESTAMP_STR_LEN + CAN_ID_STR_LEN;
+
+    if line.len() < HEADER_STR_LEN || line.len() > HEADER_STR_LEN + CAN_DATA_STR_LEN
+        || !(line.len() - HEADER_STR_LEN).is_multiple_of(2) || !line.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+
+    let line = str::from_utf8(line).ok()?;
+
+    let stn_ms = u16::from_str_radix(&line[..TIMESTAMP_STR_LEN], 16).ok()?;
+
+    let mut frame = CanFrame {
+        id: u16::from_str_radix(&line[TIMESTAMP_STR_LEN..HEADER_STR_LEN], 16).ok()?,
+        dlc: ((line.len() - HEADER_STR_LEN) / 2) as u8,
+        data: [0; 8],
+        sample_ns: 0
+    };
+
+    for (i, byte) in frame.data[..frame.dlc as usize].iter_mut().enumerate() {
+        let pos = HEADER_STR_LEN + i * 2;
+        *byte = u8::from_str_radix(&line[pos..pos + 2], 16).ok()?;
     }
+
+    Some((stn_ms, frame))
+}
+
+fn parse_voltage(rsp: &[u8]) -> Option<f32> {
+    // ATRV responds with something like "12.6V\r\r>"
+    let rsp = str::from_utf8(rsp).ok()?;
+    let (voltage, _) = rsp.trim_start().split_once('V')?;
+    voltage.parse().ok()
 }
 
 impl Drop for Stnobd {
     fn drop(&mut self) {
-        if self.in_monitoring_mode {
-            self.stop_monitoring_mode()
+        // Best effort, the port may be gone already
+        if self.state == State::Monitoring {
+            let _ = self.stop_monitoring_mode();
+        }
+        let _ = self.serial_port.set_access_nonexclusive();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+    use nix::pty::openpty;
+    use nix::unistd::{read, ttyname, write};
+    use super::*;
+
+    const MS: u64 = 1_000_000;
+
+    #[test]
+    fn parses_atrv_responses() {
+        assert_eq!(parse_voltage(b"12.6V\r\r>"), Some(12.6));
+        assert_eq!(parse_voltage(b"\r12.6V"), Some(12.6));
+        assert_eq!(parse_voltage(b"12.6\r\r>"), None);
+        assert_eq!(parse_voltage(b">"), None);
+        assert_eq!(parse_voltage(b"?\r\r>"), None);
+        assert_eq!(parse_voltage(b"STOPPED\r\r>"), None);
+        assert_eq!(parse_voltage(b"\xff\xfeV"), None);
+    }
+
+    #[test]
+    fn parses_timestamped_frames() {
+        assert_eq!(parse_can_frame(b"1A2B201"), Some((0x1a2b, CanFrame { id: 0x201, dlc: 0, data: [0; 8], sample_ns: 0 })));
+        assert_eq!(parse_can_frame(b"ffff4300102030405060708"), Some((0xffff, CanFrame {
+            id: 0x430,
+            dlc: 8,
+            data: [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08],
+            sample_ns: 0
+        })));
+
+        for dlc in 0..=8 {
+            let line = format!("0010420{}", "ab".repeat(dlc));
+            let (_, frame) = parse_can_frame(line.as_bytes()).expect(&line);
+            assert_eq!(frame.dlc as usize, dlc);
+            assert!(frame.data[..dlc].iter().all(|&byte| byte == 0xab));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        // Too short, an odd count of data digits, more than 8 bytes
+        assert_eq!(parse_can_frame(b""), None);
+        assert_eq!(parse_can_frame(b"001042"), None);
+        assert_eq!(parse_can_frame(b"00104201"), None);
+        assert_eq!(parse_can_frame(b"0010420010203040506070"), None);
+        assert_eq!(parse_can_frame(b"00104200102030405060708090"), None);
+        // Not hex
+        assert_eq!(parse_can_frame(b"0010420 01"), None);
+        assert_eq!(parse_can_frame(b"0010420zz"), None);
+        assert_eq!(parse_can_frame(b"BUFFER FULL"), None);
+        assert_eq!(parse_can_frame(b"001042\xc3\xa9"), None);
+    }
+
+    #[test]
+    fn follows_the_stn_clock_over_its_wrap() {
+        let rx_ns = 1_000_000 * MS;
+        let mut clock = StnClock::default();
+
+        assert_eq!(clock.map_to_monotonic_ns(65000, rx_ns), rx_ns);
+        // 536 ms to the wrap and 200 past it
+        assert_eq!(clock.map_to_monotonic_ns(200, rx_ns + 736 * MS), rx_ns + 736 * MS);
+        assert_eq!(clock.map_to_monotonic_ns(300, rx_ns + 836 * MS), rx_ns + 836 * MS);
+    }
+
+    #[test]
+    fn keeps_the_smallest_rx_delay() {
+        let t_ns = 1_000_000 * MS;
+        let mut clock = StnClock::default();
+
+        // Sampled 10 ms apart, read 5, 1 then 3 ms later
+        assert_eq!(clock.map_to_monotonic_ns(0, t_ns + 5 * MS), t_ns + 5 * MS);
+        assert_eq!(clock.map_to_monotonic_ns(10, t_ns + 11 * MS), t_ns + 11 * MS);
+        // The later delay only lets the offset creep up by the max drift over the 12 ms since
+        let drift_ns = 12 * MS * MAX_CLOCK_DRIFT_PPM / 1_000_000;
+        assert_eq!(clock.map_to_monotonic_ns(20, t_ns + 23 * MS), t_ns + 21 * MS + drift_ns);
+    }
+
+    impl CanFrameHandler for Vec<u16> {
+        fn handle_can_frame(&mut self, frame: &CanFrame) {
+            self.push(frame.id);
+        }
+    }
+
+    // A Stnobd talking to a STN played on the other end of a pty
+    struct Bench {
+        stnobd: Stnobd,
+        stn: OwnedFd,
+        _port: OwnedFd,
+        frames: Vec<u16>
+    }
+
+    impl Bench {
+        // Times out on every timer tick, no cfg cmds to go through after a reset
+        fn new() -> Bench {
+            let pty = openpty(None, None).unwrap();
+            let port_name = ttyname(&pty.slave).unwrap();
+
+            let sleep_cfg = SleepConfig {
+                bus_idle_timeout: Duration::ZERO,
+                vehicle_off_voltage: 13.0,
+                wake_probe_interval: Duration::ZERO
+            };
+            let watchdog_cfg = WatchdogConfig { rsp_timeout: Duration::ZERO };
+            let stnobd = Stnobd::new(port_name.to_str().unwrap(), BaudRate::B921600, MonitoringMode::Filtered,
+                                     Vec::new(), sleep_cfg, watchdog_cfg);
+
+            Bench { stnobd, stn: pty.master, _port: pty.slave, frames: Vec::new() }
+        }
+
+        fn receive(&mut self, bytes: &[u8]) -> Option<PortEvent> {
+            write(&self.stn, bytes).unwrap();
+            self.stnobd.handle_incoming_stnobd_msg(&mut self.frames)
         }
-        self.serial_port.set_access_nonexclusive();
+
+        // What the service sent to the STN
+        fn sent(&self) -> Vec<u8> {
+            let mut buf = [0u8; 64];
+            let n = read(self.stn.as_raw_fd(), &mut buf).unwrap();
+            buf[..n].to_vec()
+        }
+
+        fn reset_to_monitoring(&mut self) {
+            assert_eq!(self.sent(), b"ATZ\r");
+            assert_eq!(self.receive(b"ELM327 v1.4b\r\r>"), None);
+            assert_eq!(self.sent(), b"STM\r");
+        }
+
+        // Any char wakes the STN up, it's then reset and monitors again
+        fn wake_probe(&mut self) {
+            assert_eq!(self.stnobd.check_timeouts(), None);
+            // Written 100 ms apart, they don't always make it into the same read
+            let mut sent = self.sent();
+            if sent == b"\r" {
+                sent.extend(self.sent());
+            }
+            assert_eq!(sent, b"\rATZ\r");
+            assert_eq!(self.receive(b"ELM327 v1.4b\r\r>"), None);
+            assert_eq!(self.sent(), b"STM\r");
+        }
+
+        // The bus goes silent, the battery is then read at this voltage
+        fn silence(&mut self, atrv_rsp: &[u8]) {
+            assert_eq!(self.stnobd.check_timeouts(), None);
+            assert_eq!(self.sent(), b"\r");
+            assert_eq!(self.receive(b"STOPPED\r\r>"), None);
+            assert_eq!(self.sent(), b"ATRV\r");
+            assert_eq!(self.receive(atrv_rsp), None);
+        }
+    }
+
+    #[test]
+    fn escalates_recovery_while_the_bus_stays_silent() {
+        let mut bench = Bench::new();
+
+        assert_eq!(bench.stnobd.start(), None);
+        bench.reset_to_monitoring();
+
+        // First monitoring is restarted
+        bench.silence(b"14.2V\r\r>");
+        assert_eq!(bench.sent(), b"STM\r");
+        assert_eq!(bench.stnobd.recovery_counters, RecoveryCounters { monitoring_restarts: 1, resets: 0, port_reopens: 0 });
+
+        // Then the STN is reset
+        bench.silence(b"14.2V\r\r>");
+        assert_eq!(bench.sent(), b"ATZ\r");
+        assert_eq!(bench.stnobd.recovery_counters, RecoveryCounters { monitoring_restarts: 1, resets: 1, port_reopens: 0 });
+
+        // And the port reopened when the reset isn't answered
+        assert_eq!(bench.stnobd.check_timeouts(), Some(PortEvent::Lost));
+        assert_eq!(bench.stnobd.link_state(), LinkState::Disconnected);
+        assert_eq!(bench.stnobd.recovery_counters, RecoveryCounters { monitoring_restarts: 1, resets: 1, port_reopens: 1 });
+
+        assert_eq!(bench.stnobd.check_timeouts(), Some(PortEvent::Reopened));
+        bench.reset_to_monitoring();
+
+        // A CAN msg making it through starts over from a monitoring restart
+        assert_eq!(bench.receive(b"00102010102\r"), None);
+        assert_eq!(bench.frames, vec![0x201]);
+
+        bench.silence(b"14.2V\r\r>");
+        assert_eq!(bench.sent(), b"STM\r");
+        assert_eq!(bench.stnobd.recovery_counters, RecoveryCounters { monitoring_restarts: 2, resets: 1, port_reopens: 1 });
+    }
+
+    #[test]
+    fn sleeps_while_the_vehicle_is_off() {
+        let mut bench = Bench::new();
+
+        assert_eq!(bench.stnobd.start(), None);
+        bench.reset_to_monitoring();
+
+        // A silent bus with a low battery puts the STN to sleep
+        bench.silence(b"12.1V\r\r>");
+        assert_eq!(bench.sent(), b"STSLEEP\r");
+        assert!(bench.stnobd.is_vehicle_off());
+        assert_eq!(bench.stnobd.link_state(), LinkState::Sleeping);
+
+        // Its acks and noise are ignored while it sleeps
+        assert_eq!(bench.receive(b"OK\r>"), None);
+        assert_eq!(bench.stnobd.link_state(), LinkState::Sleeping);
+
+        // The wake probe finds the bus still silent with a low battery, back to sleep
+        bench.wake_probe();
+        assert!(bench.stnobd.is_vehicle_off());
+        bench.silence(b"12.1V\r\r>");
+        assert_eq!(bench.sent(), b"STSLEEP\r");
+        assert!(bench.stnobd.is_vehicle_off());
+
+        // A charging battery means the vehicle is on again, monitoring restarts
+        bench.wake_probe();
+        bench.silence(b"14.2V\r\r>");
+        assert_eq!(bench.sent(), b"STM\r");
+        assert!(!bench.stnobd.is_vehicle_off());
+        assert_eq!(bench.stnobd.link_state(), LinkState::Monitoring);
+
+        // So does CAN traffic after a wake probe
+        bench.silence(b"12.1V\r\r>");
+        assert_eq!(bench.sent(), b"STSLEEP\r");
+        assert!(bench.stnobd.is_vehicle_off());
+
+        bench.wake_probe();
+        assert_eq!(bench.receive(b"00104300102\r"), None);
+        assert!(!bench.stnobd.is_vehicle_off());
+        assert_eq!(bench.frames, vec![0x430]);
     }
 }
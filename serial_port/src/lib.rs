@@ -2,6 +2,7 @@ use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 use std::path::Path;
 use nix::fcntl::{open, OFlag};
 use nix::{ioctl_none_bad, libc};
+use nix::errno::Errno;
 use nix::sys::stat::Mode;
 use nix::sys::termios::{cfmakeraw, cfsetspeed, tcflush, tcgetattr, tcsetattr, BaudRate, ControlFlags, FlushArg, InputFlags, OutputFlags, SetArg, SpecialCharacterIndices};
 use nix::unistd::{read, write};
@@ -25,26 +26,27 @@ pub struct SerialPort {
 
 impl SerialPort {
     pub fn new(port_name: &str) -> SerialPort {
-        let fd = open(Path::new(port_name), OFlag::O_RDWR | OFlag::O_NOCTTY, Mode::empty())
-            .expect("open serial");
-        SerialPort {
+        SerialPort::open(port_name)
+            .expect("open serial")
+    }
+
+    pub fn open(port_name: &str) -> nix::Result<SerialPort> {
+        let fd = open(Path::new(port_name), OFlag::O_RDWR | OFlag::O_NOCTTY, Mode::empty())?;
+        Ok(SerialPort {
             fd: unsafe { OwnedFd::from_raw_fd(fd) }
-        }
+        })
     }
 
-    pub fn set_access_exclusive(&self) {
+    pub fn set_access_exclusive(&self) -> nix::Result<()> {
         set_tiocexcl(&self.fd)
-            .expect("ioctl TIOCEXCL");
     }
 
-    pub fn set_access_nonexclusive(&self) {
+    pub fn set_access_nonexclusive(&self) -> nix::Result<()> {
         set_tiocnxcl(&self.fd)
-            .expect("ioctl TIOCNXCL");
     }
 
-    pub fn configure(&self, vtime: u8, vmin: u8, baud: BaudRate) {
-        let mut tty = tcgetattr(&self.fd)
-            .expect("tcgetattr");
+    pub fn configure(&self, vtime: u8, vmin: u8, baud: BaudRate) -> nix::Result<()> {
+        let mut tty = tcgetattr(&self.fd)?;
 
         /*
          * Disable any special handling of received bytes
@@ -74,31 +76,29 @@ impl SerialPort {
         tty.control_chars[SpecialCharacterIndices::VTIME as usize] = vmin;
 
         // Set in/out baud rate
-        cfsetspeed(&mut tty, baud)
-            .expect("cfsetspeed");
+        cfsetspeed(&mut tty, baud)?;
 
         tcsetattr(&self.fd, SetArg::TCSANOW, &tty)
-            .expect("tcsetattr");
     }
 
-    pub fn flush_all(&self) {
+    pub fn flush_all(&self) -> nix::Result<()> {
         tcflush(&self.fd, FlushArg::TCIOFLUSH)
-            .expect("tcflush");
     }
 
-    pub fn write(&self, buf: &[u8]) {
-        let c = write(&self.fd, buf)
-            .expect("write");
+    // The cmds are short enough for the tty to take them in one go, anything less is an error
+    pub fn write(&self, buf: &[u8]) -> nix::Result<()> {
+        let c = write(&self.fd, buf)?;
 
         if c != buf.len() {
-            panic!("incomplete write (actual {} expected {})\n", c, buf.len());
+            return Err(Errno::EIO);
         }
+
+        Ok(())
     }
 
-    pub fn read(&self, buf: &mut [u8]) -> usize {
+    pub fn read(&self, buf: &mut [u8]) -> nix::Result<usize> {
         let fd = self.fd.as_raw_fd();
         read(fd, buf)
-            .expect("read")
     }
 }
 
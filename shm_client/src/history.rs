@@ -0,0 +1,202 @@
+use std::time::{Duration, Instant};
+use shm_segment::{FieldType, SHM_RING_MAGIC};
+use crate::reader::ShmReader;
+use crate::snapshots::ShmValue;
+use crate::{MetricsSnapshot, ShmClientError, Snapshot};
+
+// A snapshot a producer also keeps a history ring of
+pub trait History: Snapshot {
+    const HISTORY_SHM_NAME: &'static str;
+}
+
+impl History for MetricsSnapshot {
+    const HISTORY_SHM_NAME: &'static str = "/mx5history";
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct HistoryEntry<S> {
+    // Counts every push since the producer started, consecutive entries have consecutive indexes
+    pub index: u64,
+    // When the producer pushed it, ns on CLOCK_MONOTONIC
+    pub timestamp_ns: u64,
+    pub snapshot: S
+}
+
+pub struct HistoryClient<S: History> {
+    reader: ShmReader,
+    offsets: Vec<usize>,
+    timestamp_offset: usize,
+    data: Vec<u8>,
+    // Index of the first entry read_new didn't return yet
+    next_index: u64,
+    _snapshot: std::marker::PhantomData<S>
+}
+
+pub type MetricsHistoryClient = HistoryClient<MetricsSnapshot>;
+
+impl<S: History> HistoryClient<S> {
+    pub fn open() -> Result<HistoryClient<S>, ShmClientError> {
+        HistoryClient::open_name(S::HISTORY_SHM_NAME)
+    }
+
+    pub fn open_name(name: &str) -> Result<HistoryClient<S>, ShmClientError> {
+        let reader = ShmReader::open(name, SHM_RING_MAGIC, S::LAYOUT_VERSION)?;
+
+        let offsets = S::fields().into_iter()
+            .map(|(name, field_type)| reader.field_offset(name, field_type))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let timestamp_offset = reader.field_offset("timestamp_ns", FieldType::U64)?;
+
+        Ok(HistoryClient {
+            reader,
+            offsets,
+            timestamp_offset,
+            data: Vec::new(),
+            next_index: 0,
+            _snapshot: std::marker::PhantomData
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.reader.ring_header().capacity as usize
+    }
+
+    // Time between two entries
+    pub fn period(&self) -> Duration {
+        Duration::from_nanos(self.reader.ring_header().period_ns)
+    }
+
+    // Every entry still in the ring, oldest first
+    pub fn read_all(&mut self) -> Result<Vec<HistoryEntry<S>>, ShmClientError> {
+        self.read_from(0)
+    }
+
+    // Entries pushed since the last read, the ones already overwritten are skipped
+    pub fn read_new(&mut self) -> Result<Vec<HistoryEntry<S>>, ShmClientError> {
+        self.read_from(self.next_index)
+    }
+
+    // Waits for entries newer than the last read ones, empty on timeout
+    pub fn read_next(&mut self, timeout: Duration) -> Result<Vec<HistoryEntry<S>>, ShmClientError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            // seq first, a push after it changes it and doesn't let the wait sleep
+            let seq = self.reader.seq();
+
+            let entries = self.read_new()?;
+            if !entries.is_empty() {
+                return Ok(entries);
+            }
+
+            let now = Instant::now();
+            if now >= deadline || !self.reader.wait_for_update(seq, deadline - now)? {
+                return Ok(Vec::new());
+            }
+        }
+    }
+
+    fn read_from(&mut self, from: u64) -> Result<Vec<HistoryEntry<S>>, ShmClientError> {
+        let (start, end) = self.reader.read_ring(from, &mut self.data)?;
+        self.next_index = end;
+
+        let entries = self.data.chunks_exact(self.reader.ring_header().entry_size as usize)
+            .zip(start..end)
+            .map(|(entry, index)| HistoryEntry {
+                index,
+                timestamp_ns: u64::read(entry, self.timestamp_offset),
+                snapshot: S::from_data(entry, &self.offsets)
+            })
+            .collect();
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use shm_segment::{ShmRing, ShmSegment};
+    use crate::test_fixtures::{self, Chrono};
+    use crate::ChronoSnapshot;
+    use super::*;
+
+    // Only the metrics have a history, let the chrono snapshot have one for the tests
+    impl History for ChronoSnapshot {
+        const HISTORY_SHM_NAME: &'static str = "/shm_client_history_default_test";
+    }
+
+    fn chrono(current_lap_time: u32) -> Chrono {
+        Chrono { current_lap_time, ..test_fixtures::chrono() }
+    }
+
+    fn lap_times(entries: &[HistoryEntry<ChronoSnapshot>]) -> Vec<u32> {
+        entries.iter().map(|entry| entry.snapshot.current_lap_time).collect()
+    }
+
+    #[test]
+    fn follows_the_write_index() {
+        const NAME: &str = "/shm_client_history_test";
+
+        let mut ring = ShmRing::<Chrono>::new(NAME, 4, Duration::from_millis(100));
+        let mut client = HistoryClient::<ChronoSnapshot>::open_name(NAME).unwrap();
+
+        assert_eq!(client.capacity(), 4);
+        assert_eq!(client.period(), Duration::from_millis(100));
+        assert_eq!(client.read_all(), Ok(Vec::new()));
+
+        ring.push(1000, &chrono(1));
+        ring.push(2000, &chrono(2));
+
+        let entries = client.read_new().unwrap();
+        assert_eq!(entries.iter().map(|entry| (entry.index, entry.timestamp_ns)).collect::<Vec<_>>(), vec![(0, 1000), (1, 2000)]);
+        assert_eq!(entries[1].snapshot, ChronoSnapshot {
+            best_lap_time: 1234,
+            previous_lap_time: 1250,
+            current_lap_time: 2,
+            previous_sector_delta_time: -7,
+            best_lap_n: 3,
+            current_lap_n: 4,
+            timing_state: 1
+        });
+
+        assert_eq!(client.read_new(), Ok(Vec::new()));
+
+        // A slow reader loses what was overwritten, not its place
+        for i in 3..=8 {
+            ring.push(i * 1000, &chrono(i as u32));
+        }
+        assert_eq!(lap_times(&client.read_new().unwrap()), vec![5, 6, 7, 8]);
+        assert_eq!(lap_times(&client.read_all().unwrap()), vec![5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn read_next_wakes_up_on_push() {
+        let mut ring = ShmRing::<Chrono>::new(ChronoSnapshot::HISTORY_SHM_NAME, 16, Duration::from_millis(10));
+        let mut client = HistoryClient::<ChronoSnapshot>::open().unwrap();
+
+        assert_eq!(client.read_next(Duration::from_millis(10)), Ok(Vec::new()));
+
+        let pusher = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            ring.push(1, &chrono(1));
+            ring
+        });
+
+        let started_at = Instant::now();
+        assert_eq!(lap_times(&client.read_next(Duration::from_secs(5)).unwrap()), vec![1]);
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+
+        drop(pusher.join().unwrap());
+        assert_eq!(client.read_next(Duration::from_millis(10)), Err(ShmClientError::ProducerGone));
+    }
+
+    #[test]
+    fn plain_and_ring_segments_dont_mix() {
+        const NAME: &str = "/shm_client_history_magic_test";
+
+        let _shm = ShmSegment::new(NAME, &chrono(1));
+        assert_eq!(HistoryClient::<ChronoSnapshot>::open_name(NAME).err(), Some(ShmClientError::BadMagic));
+    }
+}
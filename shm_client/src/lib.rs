@@ -0,0 +1,284 @@
+// Read only clients for the /mx5metrics, /ubloxchrono and /ubloxgnss shared memory segments.
+//
+//     let mut client = MetricsClient::open()?;
+//     let metrics = client.read()?;
+//
+// A client checks the segment header and looks its fields up in the field table when it opens,
+// every read then returns a consistent snapshot or tells that the producer went away,
+// in which case the client should be opened again once the service is back.
+// read_next blocks until the producer publishes something new instead of polling.
+//
+// HistoryClient follows a history ring the same way, e.g. MetricsHistoryClient::open()?.read_all()?
+// returns the last seconds of metrics, oldest first.
+//
+// Source reads a segment whose producer may not be running yet or restart, reopening it as needed.
+
+mod history;
+mod reader;
+mod snapshots;
+mod source;
+#[cfg(any(test, feature = "test-fixtures"))]
+pub mod test_fixtures;
+
+use std::fmt;
+use std::time::Duration;
+use nix::errno::Errno;
+use shm_segment::{FieldType, SHM_MAGIC};
+use crate::reader::ShmReader;
+
+pub use crate::history::{History, HistoryClient, HistoryEntry, MetricsHistoryClient};
+pub use crate::snapshots::{ChronoSnapshot, GnssSnapshot, LinkState, MetricsSnapshot, TimingState, METRICS_HEARTBEAT_TIMEOUT};
+pub use crate::source::Source;
+
+#[derive(Debug, PartialEq)]
+pub enum ShmClientError {
+    Open(Errno),
+    Wait(Errno),
+    // Not a self describing segment, or one being rewritten
+    BadMagic,
+    LayoutVersion { expected: u32, actual: u32 },
+    MissingField(&'static str),
+    FieldType { name: &'static str, expected: FieldType, actual: u32 },
+    // The segment was removed or replaced, or its producer died mid update
+    ProducerGone
+}
+
+impl fmt::Display for ShmClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShmClientError::Open(errno) => write!(f, "can't open the segment: {}", errno),
+            ShmClientError::Wait(errno) => write!(f, "can't wait for an update: {}", errno),
+            ShmClientError::BadMagic => write!(f, "not a self describing segment"),
+            ShmClientError::LayoutVersion { expected, actual } =>
+                write!(f, "layout version is {}, expected {}", actual, expected),
+            ShmClientError::MissingField(name) => write!(f, "field {} is missing", name),
+            ShmClientError::FieldType { name, expected, actual } =>
+                write!(f, "field {} has type {}, expected {:?}", name, actual, expected),
+            ShmClientError::ProducerGone => write!(f, "the producer went away")
+        }
+    }
+}
+
+impl std::error::Error for ShmClientError {}
+
+// A plain copy of a segment's data, filled from the fields it names
+pub trait Snapshot: Sized {
+    const SHM_NAME: &'static str;
+    const LAYOUT_VERSION: u32;
+
+    fn fields() -> Vec<(&'static str, FieldType)>;
+
+    // offsets are in fields() order
+    fn from_data(data: &[u8], offsets: &[usize]) -> Self;
+
+    // {"<field>":<value>,..}
+    fn to_json(&self) -> String;
+}
+
+pub struct ShmClient<S: Snapshot> {
+    reader: ShmReader,
+    offsets: Vec<usize>,
+    data: Vec<u8>,
+    // Seq of the last read, None before the first one
+    seq: Option<u32>,
+    _snapshot: std::marker::PhantomData<S>
+}
+
+pub type MetricsClient = ShmClient<MetricsSnapshot>;
+pub type ChronoClient = ShmClient<ChronoSnapshot>;
+pub type GnssClient = ShmClient<GnssSnapshot>;
+
+impl<S: Snapshot> ShmClient<S> {
+    pub fn open() -> Result<ShmClient<S>, ShmClientError> {
+        ShmClient::open_name(S::SHM_NAME)
+    }
+
+    pub fn open_name(name: &str) -> Result<ShmClient<S>, ShmClientError> {
+        let reader = ShmReader::open(name, SHM_MAGIC, S::LAYOUT_VERSION)?;
+
+        let offsets = S::fields().into_iter()
+            .map(|(name, field_type)| reader.field_offset(name, field_type))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let data = vec![0; reader.data_size()];
+
+        Ok(ShmClient {
+            reader,
+            offsets,
+            data,
+            seq: None,
+            _snapshot: std::marker::PhantomData
+        })
+    }
+
+    pub fn read(&mut self) -> Result<S, ShmClientError> {
+        self.seq = Some(self.reader.read(&mut self.data)?);
+        Ok(S::from_data(&self.data, &self.offsets))
+    }
+
+    // Waits for a snapshot newer than the last read one, None on timeout
+    pub fn read_next(&mut self, timeout: Duration) -> Result<Option<S>, ShmClientError> {
+        if let Some(seq) = self.seq {
+            if !self.reader.wait_for_update(seq, timeout)? {
+                return Ok(None);
+            }
+        }
+
+        self.read().map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+    use std::process::Command;
+    use std::thread;
+    use std::time::Instant;
+    use nix::fcntl::OFlag;
+    use nix::sys::mman::{mmap, munmap, shm_open, MapFlags, ProtFlags};
+    use nix::sys::stat::Mode;
+    use shm_segment::{shm_field, ShmData, ShmField, ShmHeader, ShmSegment};
+    use crate::test_fixtures::{self, Chrono};
+    use super::*;
+
+    // Same fields in another order and with a bumped version
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct ChronoV2 {
+        current_lap_n: u16,
+        best_lap_n: u16,
+        best_lap_time: u32
+    }
+
+    impl ShmData for ChronoV2 {
+        const LAYOUT_VERSION: u32 = ChronoSnapshot::LAYOUT_VERSION + 1;
+
+        fn fields() -> Vec<ShmField> {
+            vec![
+                shm_field!(ChronoV2, current_lap_n, ""),
+                shm_field!(ChronoV2, best_lap_n, ""),
+                shm_field!(ChronoV2, best_lap_time, "ds")
+            ]
+        }
+    }
+
+    fn chrono(current_lap_n: u16) -> Chrono {
+        Chrono { current_lap_n, ..test_fixtures::chrono() }
+    }
+
+    #[test]
+    fn reads_what_the_producer_publishes() {
+        const NAME: &str = "/shm_client_read_test";
+
+        let mut shm = ShmSegment::new(NAME, &chrono(4));
+        let mut client = ChronoClient::open_name(NAME).unwrap();
+
+        assert_eq!(client.read(), Ok(ChronoSnapshot {
+            best_lap_time: 1234,
+            previous_lap_time: 1250,
+            current_lap_time: 321,
+            previous_sector_delta_time: -7,
+            best_lap_n: 3,
+            current_lap_n: 4,
+            timing_state: 1
+        }));
+
+        shm.publish(&chrono(5));
+        assert_eq!(client.read().unwrap().current_lap_n, 5);
+    }
+
+    #[test]
+    fn refuses_another_layout_version() {
+        const NAME: &str = "/shm_client_version_test";
+
+        let _shm = ShmSegment::new(NAME, &ChronoV2 { current_lap_n: 1, best_lap_n: 1, best_lap_time: 1 });
+
+        assert_eq!(ChronoClient::open_name(NAME).err(),
+                   Some(ShmClientError::LayoutVersion { expected: 2, actual: 3 }));
+    }
+
+    #[test]
+    fn tells_when_the_producer_goes_away() {
+        const NAME: &str = "/shm_client_gone_test";
+
+        let shm = ShmSegment::new(NAME, &chrono(1));
+        let mut client = ChronoClient::open_name(NAME).unwrap();
+        assert!(client.read().is_ok());
+
+        drop(shm);
+        assert_eq!(client.read(), Err(ShmClientError::ProducerGone));
+
+        // A restarted producer creates a new segment, the old mapping must not be trusted
+        let _shm = ShmSegment::new(NAME, &chrono(1));
+        assert_eq!(client.read(), Err(ShmClientError::ProducerGone));
+        assert!(ChronoClient::open_name(NAME).unwrap().read().is_ok());
+    }
+
+    // Hands the segment over to another process, as if it had created it
+    fn set_producer_pid(name: &str, pid: u32) {
+        let fd = shm_open(name, OFlag::O_RDWR, Mode::empty()).unwrap();
+        let len = NonZeroUsize::new(size_of::<ShmHeader>()).unwrap();
+
+        unsafe {
+            let base = mmap(None, len, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE, MapFlags::MAP_SHARED, &fd, 0).unwrap();
+            (*(base.as_ptr() as *mut ShmHeader)).producer_pid = pid;
+            munmap(base, len.get()).unwrap();
+        }
+    }
+
+    #[test]
+    fn tells_when_the_producer_is_killed() {
+        const NAME: &str = "/shm_client_killed_test";
+
+        // A killed producer doesn't get to unlink its segment
+        let _shm = ShmSegment::new(NAME, &chrono(1));
+        let mut producer = Command::new("sleep").arg("10").spawn().unwrap();
+        set_producer_pid(NAME, producer.id());
+
+        let mut client = ChronoClient::open_name(NAME).unwrap();
+        assert!(client.read().is_ok());
+
+        producer.kill().unwrap();
+        producer.wait().unwrap();
+        assert_eq!(client.read(), Err(ShmClientError::ProducerGone));
+        assert_eq!(client.read_next(Duration::ZERO), Err(ShmClientError::ProducerGone));
+    }
+
+    #[test]
+    fn read_next_wakes_up_on_publish() {
+        const NAME: &str = "/shm_client_wake_up_test";
+
+        let mut shm = ShmSegment::new(NAME, &chrono(1));
+        let mut client = ChronoClient::open_name(NAME).unwrap();
+
+        // Nothing read yet, the current snapshot is new
+        assert_eq!(client.read_next(Duration::ZERO).unwrap().unwrap().current_lap_n, 1);
+        assert_eq!(client.read_next(Duration::from_millis(10)), Ok(None));
+
+        let publisher = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            shm.publish(&chrono(2));
+            shm
+        });
+
+        let started_at = Instant::now();
+        assert_eq!(client.read_next(Duration::from_secs(5)).unwrap().unwrap().current_lap_n, 2);
+        assert!(started_at.elapsed() < Duration::from_secs(1));
+
+        drop(publisher.join().unwrap());
+        assert_eq!(client.read_next(Duration::from_millis(10)), Err(ShmClientError::ProducerGone));
+    }
+
+    #[test]
+    fn snapshots_render_as_json() {
+        let snapshot = ChronoSnapshot { best_lap_time: 1234, previous_sector_delta_time: -7, current_lap_n: 4, ..ChronoSnapshot::default() };
+
+        assert_eq!(snapshot.to_json(), concat!("{\"best_lap_time\":1234,\"previous_lap_time\":0,\"current_lap_time\":0,",
+                                               "\"previous_sector_delta_time\":-7,\"best_lap_n\":0,\"current_lap_n\":4,\"timing_state\":0}"));
+    }
+
+    #[test]
+    fn fails_to_open_a_missing_segment() {
+        assert_eq!(ChronoClient::open_name("/shm_client_missing_test").err(), Some(ShmClientError::Open(Errno::ENOENT)));
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::ffi::{c_void, CStr};
+use std::num::NonZeroUsize;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{fence, Ordering};
+use std::time::{Duration, Instant};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::mman::{mmap, munmap, shm_open, MapFlags, ProtFlags};
+use nix::sys::signal::kill;
+use nix::sys::stat::{fstat, stat, Mode};
+use nix::unistd::Pid;
+use shm_segment::{futex_wait, ring_header_offset, FieldType, ShmFieldDesc, ShmHeader, ShmRingHeader, SHM_RING_MAGIC};
+use crate::ShmClientError;
+
+// An update takes microseconds, a seq that stays odd this long means the producer died mid update
+const STUCK_UPDATE_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Where Linux keeps the shm_open segments
+const SHM_DIR: &str = "/dev/shm/";
+
+pub struct ShmReader {
+    // Where the segment we mapped should still be, and its inode
+    path: String,
+    ino: u64,
+    _fd: OwnedFd,
+    base: NonNull<c_void>,
+    len: usize,
+    magic: u32,
+    fields: HashMap<String, (usize, u32)>,
+    data_offset: usize,
+    data_size: usize,
+    // Null unless it's a ring segment
+    ring_header: *const ShmRingHeader,
+    // What the field offsets are relative to, the data or a ring entry
+    record_size: usize
+}
+
+// The mapping is read only and only read through the seqlock
+unsafe impl Send for ShmReader {}
+
+impl ShmReader {
+    // magic is SHM_MAGIC for a plain segment, SHM_RING_MAGIC for a ring
+    pub fn open(name: &str, magic: u32, expected_layout_version: u32) -> Result<ShmReader, ShmClientError> {
+        let fd = shm_open(name, OFlag::O_RDONLY, Mode::empty())
+            .map_err(ShmClientError::Open)?;
+
+        let st = fstat(fd.as_raw_fd())
+            .map_err(ShmClientError::Open)?;
+
+        let len = st.st_size as usize;
+        if len < size_of::<ShmHeader>() {
+            return Err(ShmClientError::BadMagic);
+        }
+
+        let base = unsafe {
+            mmap(None, NonZeroUsize::new(len).unwrap(), ProtFlags::PROT_READ, MapFlags::MAP_SHARED, &fd, 0)
+                .map_err(ShmClientError::Open)?
+        };
+
+        let mut reader = ShmReader {
+            path: String::from(SHM_DIR) + name.trim_start_matches('/'),
+            ino: st.st_ino,
+            _fd: fd,
+            base,
+            len,
+            magic,
+            fields: HashMap::new(),
+            data_offset: 0,
+            data_size: 0,
+            ring_header: ptr::null(),
+            record_size: 0
+        };
+
+        // The producer writes the magic last, the rest of the header can be trusted once it is there
+        if reader.magic() != magic {
+            return Err(ShmClientError::BadMagic);
+        }
+        fence(Ordering::Acquire);
+
+        let header = reader.header();
+
+        if header.layout_version != expected_layout_version {
+            return Err(ShmClientError::LayoutVersion { expected: expected_layout_version, actual: header.layout_version });
+        }
+
+        let field_count = header.field_count as usize;
+        let data_offset = header.data_offset as usize;
+        let data_size = header.data_size as usize;
+
+        let mut table_end = size_of::<ShmHeader>() + field_count * size_of::<ShmFieldDesc>();
+        if magic == SHM_RING_MAGIC {
+            table_end += size_of::<ShmRingHeader>();
+        }
+
+        if table_end > data_offset || data_offset + data_size > len {
+            return Err(ShmClientError::BadMagic);
+        }
+
+        reader.record_size = data_size;
+
+        if magic == SHM_RING_MAGIC {
+            let ring_header = unsafe { &*(reader.base.as_ptr().byte_add(ring_header_offset(field_count)) as *const ShmRingHeader) };
+            let entry_size = ring_header.entry_size as usize;
+
+            if ring_header.capacity == 0 || ring_header.capacity as usize * entry_size != data_size {
+                return Err(ShmClientError::BadMagic);
+            }
+
+            reader.ring_header = ring_header;
+            reader.record_size = entry_size;
+        }
+
+        let field_descs = unsafe {
+            std::slice::from_raw_parts(reader.base.as_ptr().byte_add(size_of::<ShmHeader>()) as *const ShmFieldDesc, field_count)
+        };
+
+        let fields = field_descs.iter()
+            .filter_map(|desc| {
+                let name = CStr::from_bytes_until_nul(&desc.name).ok()?.to_str().ok()?;
+                Some((String::from(name), (desc.offset as usize, desc.field_type)))
+            })
+            .collect();
+
+        reader.fields = fields;
+        reader.data_offset = data_offset;
+        reader.data_size = data_size;
+
+        Ok(reader)
+    }
+
+    pub fn data_size(&self) -> usize {
+        self.data_size
+    }
+
+    pub fn seq(&self) -> u32 {
+        self.header().seq.load(Ordering::Acquire)
+    }
+
+    pub fn ring_header(&self) -> &ShmRingHeader {
+        assert!(!self.ring_header.is_null(), "not a ring segment");
+        unsafe { &*self.ring_header }
+    }
+
+    pub fn field_offset(&self, name: &'static str, field_type: FieldType) -> Result<usize, ShmClientError> {
+        let &(offset, actual) = self.fields.get(name)
+            .ok_or(ShmClientError::MissingField(name))?;
+
+        if actual != field_type as u32 {
+            return Err(ShmClientError::FieldType { name, expected: field_type, actual });
+        }
+
+        if offset + field_type.size() > self.record_size {
+            return Err(ShmClientError::MissingField(name));
+        }
+
+        Ok(offset)
+    }
+
+    // Sleeps until the producer publishes something newer than seq, false on timeout
+    pub fn wait_for_update(&self, seq: u32, timeout: Duration) -> Result<bool, ShmClientError> {
+        let word = &self.header().seq;
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let current = word.load(Ordering::Acquire);
+            if current != seq && current.is_multiple_of(2) {
+                return Ok(true);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                // A producer that went away doesn't wake anybody up anymore
+                self.check_producer()?;
+                return Ok(false);
+            }
+
+            futex_wait(word, current, deadline - now)
+                .map_err(ShmClientError::Wait)?;
+        }
+    }
+
+    // Copies the data out of the seqlock, retrying until the producer didn't touch it during the copy.
+    // Returns the seq the copy was taken at.
+    pub fn read(&self, data: &mut [u8]) -> Result<u32, ShmClientError> {
+        self.check_producer()?;
+
+        let seq = &self.header().seq;
+        let data_ptr = unsafe { self.base.as_ptr().byte_add(self.data_offset) as *const u8 };
+        let started_at = Instant::now();
+
+        loop {
+            let before = seq.load(Ordering::Acquire);
+
+            if before.is_multiple_of(2) {
+                unsafe {
+                    ptr::copy_nonoverlapping(data_ptr, data.as_mut_ptr(), self.data_size);
+                }
+
+                fence(Ordering::Acquire);
+                if seq.load(Ordering::Relaxed) == before {
+                    return Ok(before);
+                }
+            }
+
+            if started_at.elapsed() > STUCK_UPDATE_TIMEOUT {
+                return Err(ShmClientError::ProducerGone);
+            }
+
+            std::hint::spin_loop();
+        }
+    }
+
+    // Copies the ring entries from index from on, as many as are still there, into data.
+    // Returns the index of the first entry copied and the write index the copy ends at.
+    pub fn read_ring(&self, from: u64, data: &mut Vec<u8>) -> Result<(u64, u64), ShmClientError> {
+        self.check_producer()?;
+
+        let seq = &self.header().seq;
+        let ring_header = self.ring_header();
+        let capacity = ring_header.capacity as u64;
+        let entry_size = self.record_size;
+        let entries_ptr = unsafe { self.base.as_ptr().byte_add(self.data_offset) as *const u8 };
+        let started_at = Instant::now();
+
+        loop {
+            let before = seq.load(Ordering::Acquire);
+
+            if before.is_multiple_of(2) {
+                let end = ring_header.write_index.load(Ordering::Acquire);
+                let start = from.min(end).max(end.saturating_sub(capacity));
+
+                data.resize((end - start) as usize * entry_size, 0);
+                for (i, index) in (start..end).enumerate() {
+                    let slot = (index % capacity) as usize;
+                    unsafe {
+                        ptr::copy_nonoverlapping(entries_ptr.add(slot * entry_size), data.as_mut_ptr().add(i * entry_size), entry_size);
+                    }
+                }
+
+                // Each push started since may have overwritten the oldest entry left,
+                // counting from end is conservative when end already includes some of them
+                fence(Ordering::Acquire);
+                let started = seq.load(Ordering::Relaxed).wrapping_sub(before).div_ceil(2) as u64;
+                let valid_from = (end + started).saturating_sub(capacity).max(start);
+
+                if valid_from <= end {
+                    data.drain(..(valid_from - start) as usize * entry_size);
+                    return Ok((valid_from, end));
+                }
+            }
+
+            if started_at.elapsed() > STUCK_UPDATE_TIMEOUT {
+                return Err(ShmClientError::ProducerGone);
+            }
+
+            std::hint::spin_loop();
+        }
+    }
+
+    // A producer unlinks its segment when it stops and creates a new one when it starts,
+    // and hides the magic while it rewrites a segment left over by a crash
+    fn check_producer(&self) -> Result<(), ShmClientError> {
+        match stat(self.path.as_str()) {
+            Ok(st) if st.st_ino == self.ino => {}
+            _ => return Err(ShmClientError::ProducerGone)
+        }
+
+        if self.magic() != self.magic {
+            return Err(ShmClientError::ProducerGone);
+        }
+
+        // A killed producer leaves its segment behind, EPERM is a producer run by another user
+        if kill(Pid::from_raw(self.header().producer_pid as i32), None) == Err(Errno::ESRCH) {
+            return Err(ShmClientError::ProducerGone);
+        }
+
+        Ok(())
+    }
+
+    fn header(&self) -> &ShmHeader {
+        unsafe { &*(self.base.as_ptr() as *const ShmHeader) }
+    }
+
+    fn magic(&self) -> u32 {
+        unsafe { ptr::read_volatile(&self.header().magic) }
+    }
+}
+
+impl Drop for ShmReader {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = munmap(self.base, self.len);
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::time::Duration;
+use shm_segment::{ShmFieldType, ShmStr};
+
+// A field value read from the copied data
+pub trait ShmValue: ShmFieldType {
+    fn read(data: &[u8], offset: usize) -> Self;
+
+    fn to_json(&self) -> String;
+}
+
+impl ShmValue for bool {
+    fn read(data: &[u8], offset: usize) -> bool {
+        data[offset] != 0
+    }
+
+    fn to_json(&self) -> String {
+        self.to_string()
+    }
+}
+
+macro_rules! impl_shm_value {
+    ($($rust_type:ty),*) => {
+        $(impl ShmValue for $rust_type {
+            fn read(data: &[u8], offset: usize) -> $rust_type {
+                <$rust_type>::from_ne_bytes(data[offset..offset + size_of::<$rust_type>()].try_into().unwrap())
+            }
+
+            // JSON has no NaN or infinity
+            #[allow(clippy::unnecessary_cast)]
+            fn to_json(&self) -> String {
+                if (*self as f64).is_finite() { self.to_string() } else { String::from("null") }
+            }
+        })*
+    };
+}
+
+impl_shm_value!(u8, i8, u16, i16, u32, i32, u64, i64, f32, f64);
+
+impl ShmValue for ShmStr {
+    fn read(data: &[u8], offset: usize) -> ShmStr {
+        ShmStr::from_bytes(&data[offset..])
+    }
+
+    fn to_json(&self) -> String {
+        let mut out = String::from("\"");
+
+        for c in self.as_str().chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c)
+            }
+        }
+
+        out.push('"');
+        out
+    }
+}
+
+// Declares a snapshot struct whose fields are read by name from a segment
+macro_rules! snapshot {
+    ($(#[$meta:meta])* $name:ident, $shm_name:expr, $layout_version:expr, { $($(#[$field_meta:meta])* $field:ident: $field_type:ty),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialEq, Debug, Default)]
+        pub struct $name {
+            $($(#[$field_meta])* pub $field: $field_type),*
+        }
+
+        impl $crate::Snapshot for $name {
+            const SHM_NAME: &'static str = $shm_name;
+            const LAYOUT_VERSION: u32 = $layout_version;
+
+            fn fields() -> Vec<(&'static str, shm_segment::FieldType)> {
+                vec![$((stringify!($field), <$field_type as ShmFieldType>::FIELD_TYPE)),*]
+            }
+
+            fn from_data(data: &[u8], offsets: &[usize]) -> $name {
+                let mut offsets = offsets.iter();
+                $name {
+                    $($field: <$field_type as ShmValue>::read(data, *offsets.next().unwrap())),*
+                }
+            }
+
+            fn to_json(&self) -> String {
+                let fields: Vec<String> = vec![$(format!("\"{}\":{}", stringify!($field), self.$field.to_json())),*];
+                format!("{{{}}}", fields.join(","))
+            }
+        }
+    };
+}
+
+snapshot!(
+    // mx5_metrics_service's Metrics
+    MetricsSnapshot, "/mx5metrics", 2, {
+    rpm: u16,
+    speed_kmh: u16,
+    engine_coolant_temp_c: i16,
+    intake_air_temp_c: i16,
+    fl_speed_kmh: u16,
+    fr_speed_kmh: u16,
+    rl_speed_kmh: u16,
+    rr_speed_kmh: u16,
+    accelerator_pedal_position_pct: u8,
+    calculated_engine_load_pct: u8,
+    throttle_valve_position_pct: u8,
+    fuel_level_pct: u8,
+    brakes_pct: u8,
+    vehicle_off: bool,
+    // A LinkState
+    link_state: u8,
+    producer_pid: u32,
+    // Refreshed on every publish, at least every second
+    heartbeat_ns: u64,
+    // When each CAN msg was last sampled, ns on CLOCK_MONOTONIC
+    brakes_sample_ns: u64,
+    rpm_speed_accel_sample_ns: u64,
+    coolant_throttle_intake_sample_ns: u64,
+    fuel_level_sample_ns: u64,
+    wheel_speeds_sample_ns: u64
+});
+
+snapshot!(
+    // ublox_chrono_service's Chrono, times are tenths of a second
+    ChronoSnapshot, "/ubloxchrono", 2, {
+    best_lap_time: u32,
+    previous_lap_time: u32,
+    current_lap_time: u32,
+    previous_sector_delta_time: i32,
+    best_lap_n: u16,
+    current_lap_n: u16,
+    // A TimingState
+    timing_state: u8
+});
+
+snapshot!(
+    // ublox_chrono_service's Gnss, the latest NAV-PVT solution
+    GnssSnapshot, "/ubloxgnss", 3, {
+    // When it was received, ns on CLOCK_MONOTONIC
+    sample_ns: u64,
+    // ns since the Unix epoch, 0 until the receiver knows it
+    utc_ns: u64,
+    // 1e-7 deg
+    lon: i32,
+    lat: i32,
+    h_acc_mm: u32,
+    g_speed_mm_s: i32,
+    // Heading of motion, 1e-5 deg
+    head_mot: i32,
+    s_acc_mm_s: u32,
+    // 0 no fix, 1 dead reckoning, 2 2D, 3 3D, 4 GNSS + dead reckoning, 5 time only
+    fix_type: u8,
+    num_sv: u8,
+    fix_ok: bool,
+    // The receiver's protocol version and highest navigation rate, 0 until it told them
+    prot_ver_major: u8,
+    prot_ver_minor: u8,
+    max_nav_rate_hz: u8,
+    // From the receiver's MON-VER, empty until it answered
+    sw_version: ShmStr,
+    hw_version: ShmStr,
+    // e.g. MAX-M10S, empty when the receiver doesn't give it
+    module: ShmStr
+});
+
+// mx5_metrics_service publishes at least every second, a heartbeat older than this means it's gone
+pub const METRICS_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// mx5_metrics_service's stnobd::LinkState
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LinkState {
+    Resetting = 1,
+    Configuring = 2,
+    Monitoring = 3,
+    Sleeping = 4,
+    Disconnected = 5
+}
+
+impl LinkState {
+    pub fn from_u8(link_state: u8) -> Option<LinkState> {
+        [LinkState::Resetting, LinkState::Configuring, LinkState::Monitoring, LinkState::Sleeping, LinkState::Disconnected]
+            .into_iter()
+            .find(|known| *known as u8 == link_state)
+    }
+}
+
+// ublox_chrono_service's chrono::TimingState
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TimingState {
+    Armed = 1,
+    Degraded = 2
+}
+
+impl TimingState {
+    pub fn from_u8(timing_state: u8) -> Option<TimingState> {
+        [TimingState::Armed, TimingState::Degraded]
+            .into_iter()
+            .find(|known| *known as u8 == timing_state)
+    }
+}
+
+impl ChronoSnapshot {
+    // Whether the gnss fix was good enough to cross the gates, the lap times are frozen otherwise
+    pub fn is_armed(&self) -> bool {
+        TimingState::from_u8(self.timing_state) == Some(TimingState::Armed)
+    }
+}
+
+impl MetricsSnapshot {
+    // Whether the service was still publishing at now_ns, ns on CLOCK_MONOTONIC.
+    // The values of a live service can still be stale, the *_sample_ns tell when each was last updated.
+    pub fn is_live(&self, now_ns: u64) -> bool {
+        now_ns.saturating_sub(self.heartbeat_ns) < METRICS_HEARTBEAT_TIMEOUT.as_nanos() as u64
+    }
+}
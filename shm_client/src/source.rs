@@ -0,0 +1,56 @@
+use log::debug;
+use crate::{ShmClient, Snapshot};
+
+// A segment that may come and go, for the readers that outlive its producer.
+// Opens it on first use and again after its producer went away.
+pub struct Source<S: Snapshot> {
+    name: String,
+    client: Option<ShmClient<S>>
+}
+
+impl<S: Snapshot> Source<S> {
+    pub fn new(name: &str) -> Source<S> {
+        Source { name: String::from(name), client: None }
+    }
+
+    // None while the producer isn't there
+    pub fn read(&mut self) -> Option<S> {
+        if self.client.is_none() {
+            self.client = ShmClient::open_name(&self.name).ok();
+        }
+
+        match self.client.as_mut()?.read() {
+            Ok(snapshot) => Some(snapshot),
+            Err(e) => {
+                debug!("{}: {}", self.name, e);
+                self.client = None;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shm_segment::ShmSegment;
+    use crate::test_fixtures::{chrono, Chrono};
+    use crate::ChronoSnapshot;
+    use super::*;
+
+    #[test]
+    fn reopens_after_the_producer_restarted() {
+        const NAME: &str = "/shm_client_source_test";
+
+        let mut source = Source::<ChronoSnapshot>::new(NAME);
+        assert_eq!(source.read(), None);
+
+        let shm = ShmSegment::new(NAME, &chrono());
+        assert_eq!(source.read().unwrap().current_lap_n, 4);
+
+        drop(shm);
+        let _shm = ShmSegment::new(NAME, &Chrono { current_lap_n: 5, ..chrono() });
+        // The read that finds the old segment gone, then the new one
+        assert_eq!(source.read(), None);
+        assert_eq!(source.read().unwrap().current_lap_n, 5);
+    }
+}
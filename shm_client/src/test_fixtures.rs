@@ -0,0 +1,44 @@
+// A stand-in for the chrono service's segment, for the readers' tests to publish
+use shm_segment::{shm_field, ShmData, ShmField};
+use crate::{ChronoSnapshot, Snapshot};
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Chrono {
+    pub best_lap_time: u32,
+    pub previous_lap_time: u32,
+    pub current_lap_time: u32,
+    pub previous_sector_delta_time: i32,
+    pub best_lap_n: u16,
+    pub current_lap_n: u16,
+    pub timing_state: u8
+}
+
+impl ShmData for Chrono {
+    const LAYOUT_VERSION: u32 = ChronoSnapshot::LAYOUT_VERSION;
+
+    fn fields() -> Vec<ShmField> {
+        vec![
+            shm_field!(Chrono, best_lap_time, "ds"),
+            shm_field!(Chrono, previous_lap_time, "ds"),
+            shm_field!(Chrono, current_lap_time, "ds"),
+            shm_field!(Chrono, previous_sector_delta_time, "ds"),
+            shm_field!(Chrono, best_lap_n, ""),
+            shm_field!(Chrono, current_lap_n, ""),
+            shm_field!(Chrono, timing_state, "")
+        ]
+    }
+}
+
+// The 4th lap of a session, timing armed
+pub fn chrono() -> Chrono {
+    Chrono {
+        best_lap_time: 1234,
+        previous_lap_time: 1250,
+        current_lap_time: 321,
+        previous_sector_delta_time: -7,
+        best_lap_n: 3,
+        current_lap_n: 4,
+        timing_state: 1
+    }
+}
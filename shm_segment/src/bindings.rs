@@ -0,0 +1,302 @@
+// Reader bindings generated from a ShmData field table, so that readers in other languages
+// get their offsets from the real layout instead of a hand-copied one.
+
+use std::fmt::Write as _;
+use crate::{data_offset, FieldType, ShmData, ShmField, FIELD_NAME_LEN, FIELD_UNIT_LEN, SHM_MAGIC, SHM_RING_MAGIC, SHM_STR_LEN};
+
+impl FieldType {
+    fn c_type(&self) -> &'static str {
+        match self {
+            FieldType::Bool => "bool",
+            FieldType::U8 => "uint8_t",
+            FieldType::I8 => "int8_t",
+            FieldType::U16 => "uint16_t",
+            FieldType::I16 => "int16_t",
+            FieldType::U32 => "uint32_t",
+            FieldType::I32 => "int32_t",
+            FieldType::U64 => "uint64_t",
+            FieldType::I64 => "int64_t",
+            FieldType::F32 => "float",
+            FieldType::F64 => "double",
+            FieldType::Str => "char"
+        }
+    }
+
+    fn c_declaration(&self, name: &str) -> String {
+        match self {
+            FieldType::Str => format!("char {}[{}]", name, SHM_STR_LEN),
+            _ => format!("{} {}", self.c_type(), name)
+        }
+    }
+
+    // C# type and the ShmSegment method reading it
+    fn csharp_type(&self) -> (&'static str, &'static str) {
+        match self {
+            FieldType::Bool => ("bool", "ReadBool"),
+            FieldType::U8 => ("byte", "ReadByte"),
+            FieldType::I8 => ("sbyte", "ReadSByte"),
+            FieldType::U16 => ("ushort", "ReadUInt16"),
+            FieldType::I16 => ("short", "ReadInt16"),
+            FieldType::U32 => ("uint", "ReadUInt32"),
+            FieldType::I32 => ("int", "ReadInt32"),
+            FieldType::U64 => ("ulong", "ReadUInt64"),
+            FieldType::I64 => ("long", "ReadInt64"),
+            FieldType::F32 => ("float", "ReadSingle"),
+            FieldType::F64 => ("double", "ReadDouble"),
+            FieldType::Str => ("string", "ReadString")
+        }
+    }
+}
+
+fn sorted_fields<T: ShmData>() -> Vec<ShmField> {
+    let mut fields = T::fields();
+    fields.sort_by_key(|field| field.offset);
+    fields
+}
+
+fn pascal_case(snake_case: &str) -> String {
+    snake_case.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map_or(String::new(), |first| first.to_ascii_uppercase().to_string() + chars.as_str())
+        })
+        .collect()
+}
+
+fn camel_case(snake_case: &str) -> String {
+    let pascal_case = pascal_case(snake_case);
+    pascal_case[..1].to_ascii_lowercase() + &pascal_case[1..]
+}
+
+// C header declaring the segment header, the field table entries and the data struct,
+// with explicit padding and static asserts on the offsets
+pub fn generate_c_header<T: ShmData>(struct_name: &str, generator: &str) -> String {
+    let guard = format!("{}_H", struct_name.to_ascii_uppercase());
+    let mut out = String::new();
+
+    let _ = writeln!(out, "// Generated by {}, do not edit", generator);
+    let _ = writeln!(out);
+    let _ = writeln!(out, "#ifndef {}", guard);
+    let _ = writeln!(out, "#define {}", guard);
+    let _ = writeln!(out);
+    let _ = writeln!(out, "#include <stdbool.h>");
+    let _ = writeln!(out, "#include <stddef.h>");
+    let _ = writeln!(out, "#include <stdint.h>");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "#ifndef SHM_SEGMENT_H");
+    let _ = writeln!(out, "#define SHM_SEGMENT_H");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "#define SHM_MAGIC 0x{:08X}u // \"MX5S\"", SHM_MAGIC);
+    let _ = writeln!(out, "#define SHM_RING_MAGIC 0x{:08X}u // \"MX5R\"", SHM_RING_MAGIC);
+    let _ = writeln!(out);
+    let _ = writeln!(out, "struct shm_header {{");
+    let _ = writeln!(out, "    uint32_t magic;");
+    let _ = writeln!(out, "    uint32_t layout_version;");
+    let _ = writeln!(out, "    uint32_t seq; // odd while the producer updates the data");
+    let _ = writeln!(out, "    uint32_t field_count;");
+    let _ = writeln!(out, "    uint32_t data_offset;");
+    let _ = writeln!(out, "    uint32_t data_size;");
+    let _ = writeln!(out, "    uint32_t producer_pid;");
+    let _ = writeln!(out, "    uint32_t reserved;");
+    let _ = writeln!(out, "}};");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "struct shm_field_desc {{");
+    let _ = writeln!(out, "    char name[{}];", FIELD_NAME_LEN);
+    let _ = writeln!(out, "    char unit[{}];", FIELD_UNIT_LEN);
+    let _ = writeln!(out, "    uint32_t offset;");
+    let _ = writeln!(out, "    uint32_t field_type;");
+    let _ = writeln!(out, "}};");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "// Follows the field table of a SHM_RING_MAGIC segment");
+    let _ = writeln!(out, "struct shm_ring_header {{");
+    let _ = writeln!(out, "    uint32_t capacity;");
+    let _ = writeln!(out, "    uint32_t entry_size;");
+    let _ = writeln!(out, "    uint64_t period_ns;");
+    let _ = writeln!(out, "    uint64_t write_index; // entries pushed so far");
+    let _ = writeln!(out, "}};");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "#endif // SHM_SEGMENT_H");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "#define {}_LAYOUT_VERSION {}", struct_name.to_ascii_uppercase(), T::LAYOUT_VERSION);
+    let _ = writeln!(out, "#define {}_FIELD_COUNT {}", struct_name.to_ascii_uppercase(), T::fields().len());
+    let _ = writeln!(out, "#define {}_DATA_OFFSET {}", struct_name.to_ascii_uppercase(), data_offset::<T>(T::fields().len()));
+    let _ = writeln!(out);
+    let _ = writeln!(out, "struct {} {{", struct_name);
+
+    let mut pos = 0;
+    let mut pad_n = 0;
+    let mut pad = |out: &mut String, pos: usize, offset: usize| {
+        if offset > pos {
+            let _ = writeln!(out, "    uint8_t _pad{}[{}];", pad_n, offset - pos);
+            pad_n += 1;
+        }
+    };
+
+    for field in sorted_fields::<T>() {
+        pad(&mut out, pos, field.offset);
+
+        let unit = if field.unit.is_empty() { String::new() } else { format!(" // {}", field.unit) };
+        let _ = writeln!(out, "    {};{}", field.field_type.c_declaration(field.name), unit);
+
+        pos = field.offset + field.field_type.size();
+    }
+    pad(&mut out, pos, size_of::<T>());
+
+    let _ = writeln!(out, "}};");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "_Static_assert(sizeof(struct {}) == {}, \"{} size\");", struct_name, size_of::<T>(), struct_name);
+
+    for field in sorted_fields::<T>() {
+        let _ = writeln!(out, "_Static_assert(offsetof(struct {}, {}) == {}, \"{} offset\");",
+                         struct_name, field.name, field.offset, field.name);
+    }
+
+    let _ = writeln!(out);
+    let _ = writeln!(out, "#endif // {}", guard);
+
+    out
+}
+
+// C# class exposing every field as a property read from the last ShmSegment snapshot,
+// offsets are looked up by name so a layout change is refused instead of read as garbage
+pub fn generate_csharp_accessor<T: ShmData>(namespace: &str, class_name: &str, generator: &str) -> String {
+    let fields = sorted_fields::<T>();
+    let mut out = String::new();
+
+    let _ = writeln!(out, "// Generated by {}, do not edit", generator);
+    let _ = writeln!(out);
+    let _ = writeln!(out, "using System;");
+    let _ = writeln!(out, "using DigitalDash.ShmClient;");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "namespace {};", namespace);
+    let _ = writeln!(out);
+    let _ = writeln!(out, "public sealed class {} : IDisposable", class_name);
+    let _ = writeln!(out, "{{");
+    let _ = writeln!(out, "    public const uint LayoutVersion = {};", T::LAYOUT_VERSION);
+    let _ = writeln!(out);
+    let _ = writeln!(out, "    private readonly ShmSegment _segment;");
+    let _ = writeln!(out);
+
+    for field in &fields {
+        let _ = writeln!(out, "    private readonly int _{};", camel_case(field.name));
+    }
+
+    let _ = writeln!(out);
+    let _ = writeln!(out, "    public {}(string path)", class_name);
+    let _ = writeln!(out, "    {{");
+    let _ = writeln!(out, "        _segment = new ShmSegment(path, LayoutVersion);");
+
+    for field in &fields {
+        let _ = writeln!(out, "        _{} = _segment.FieldOffset(\"{}\", FieldType.{:?});", camel_case(field.name), field.name, field.field_type);
+    }
+
+    let _ = writeln!(out, "    }}");
+    let _ = writeln!(out);
+
+    for field in &fields {
+        let (csharp_type, read_method) = field.field_type.csharp_type();
+        let unit = if field.unit.is_empty() { String::new() } else { format!(" // {}", field.unit) };
+        let _ = writeln!(out, "    public {} {} => _segment.{}(_{});{}",
+                         csharp_type, pascal_case(field.name), read_method, camel_case(field.name), unit);
+    }
+
+    let _ = writeln!(out);
+    let _ = writeln!(out, "    public void Refresh()");
+    let _ = writeln!(out, "    {{");
+    let _ = writeln!(out, "        _segment.Refresh();");
+    let _ = writeln!(out, "    }}");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "    public bool WaitForUpdate(TimeSpan timeout)");
+    let _ = writeln!(out, "    {{");
+    let _ = writeln!(out, "        return _segment.WaitForUpdate(timeout);");
+    let _ = writeln!(out, "    }}");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "    public void Dispose()");
+    let _ = writeln!(out, "    {{");
+    let _ = writeln!(out, "        _segment.Dispose();");
+    let _ = writeln!(out, "    }}");
+    let _ = writeln!(out, "}}");
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{shm_field, ShmStr};
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Sample {
+        flag: bool,
+        speed_kmh: u16,
+        sample_ns: u64
+    }
+
+    impl ShmData for Sample {
+        const LAYOUT_VERSION: u32 = 2;
+
+        fn fields() -> Vec<ShmField> {
+            vec![
+                shm_field!(Sample, flag, ""),
+                shm_field!(Sample, speed_kmh, "km/h"),
+                shm_field!(Sample, sample_ns, "ns")
+            ]
+        }
+    }
+
+    #[test]
+    fn c_header_pads_to_the_rust_layout() {
+        let header = generate_c_header::<Sample>("sample", "test");
+
+        assert!(header.contains(concat!(
+            "struct sample {\n",
+            "    bool flag;\n",
+            "    uint8_t _pad0[1];\n",
+            "    uint16_t speed_kmh; // km/h\n",
+            "    uint8_t _pad1[4];\n",
+            "    uint64_t sample_ns; // ns\n",
+            "};\n")), "{}", header);
+        assert!(header.contains("#define SAMPLE_LAYOUT_VERSION 2\n"), "{}", header);
+        assert!(header.contains("_Static_assert(sizeof(struct sample) == 16, \"sample size\");\n"), "{}", header);
+        assert!(header.contains("_Static_assert(offsetof(struct sample, sample_ns) == 8, \"sample_ns offset\");\n"), "{}", header);
+    }
+
+    #[test]
+    fn csharp_accessor_reads_every_field_by_name() {
+        let accessor = generate_csharp_accessor::<Sample>("Dash", "SampleAccessor", "test");
+
+        assert!(accessor.contains("    public const uint LayoutVersion = 2;\n"), "{}", accessor);
+        assert!(accessor.contains("        _speedKmh = _segment.FieldOffset(\"speed_kmh\", FieldType.U16);\n"), "{}", accessor);
+        assert!(accessor.contains("    public ushort SpeedKmh => _segment.ReadUInt16(_speedKmh); // km/h\n"), "{}", accessor);
+        assert!(accessor.contains("    public ulong SampleNs => _segment.ReadUInt64(_sampleNs); // ns\n"), "{}", accessor);
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Named {
+        id: u16,
+        name: ShmStr
+    }
+
+    impl ShmData for Named {
+        const LAYOUT_VERSION: u32 = 1;
+
+        fn fields() -> Vec<ShmField> {
+            vec![
+                shm_field!(Named, id, ""),
+                shm_field!(Named, name, "")
+            ]
+        }
+    }
+
+    #[test]
+    fn strings_are_char_arrays() {
+        let header = generate_c_header::<Named>("named", "test");
+        assert!(header.contains("    uint16_t id;\n    char name[32];\n};\n"), "{}", header);
+
+        let accessor = generate_csharp_accessor::<Named>("Dash", "NamedAccessor", "test");
+        assert!(accessor.contains("        _name = _segment.FieldOffset(\"name\", FieldType.Str);\n"), "{}", accessor);
+        assert!(accessor.contains("    public string Name => _segment.ReadString(_name);\n"), "{}", accessor);
+    }
+}
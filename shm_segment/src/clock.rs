@@ -0,0 +1,9 @@
+use nix::time::{clock_gettime, ClockId};
+
+// Host CLOCK_MONOTONIC in ns, the timescale every sample time is published in
+pub fn monotonic_ns() -> u64 {
+    let now = clock_gettime(ClockId::CLOCK_MONOTONIC)
+        .expect("clock_gettime");
+
+    now.tv_sec() as u64 * 1_000_000_000 + now.tv_nsec() as u64
+}
@@ -0,0 +1,33 @@
+// Lets readers sleep until the next publish instead of polling,
+// the seqlock counter doubles as the futex word the producer wakes them on.
+// Not FUTEX_PRIVATE, the word is shared between processes.
+
+use std::ptr;
+use std::sync::atomic::AtomicU32;
+use std::time::Duration;
+use nix::errno::Errno;
+use nix::libc;
+
+pub fn futex_wake_all(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE, i32::MAX, ptr::null::<libc::timespec>(), ptr::null::<u32>(), 0);
+    }
+}
+
+// Sleeps while word still holds expected, until woken or timed out.
+// Returns early, without telling why, on spurious wake ups and signals, so callers check the word again.
+pub fn futex_wait(word: &AtomicU32, expected: u32, timeout: Duration) -> Result<(), Errno> {
+    let timeout = libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long
+    };
+
+    let res = unsafe {
+        libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAIT, expected, &timeout as *const libc::timespec, ptr::null::<u32>(), 0)
+    };
+
+    match Errno::result(res) {
+        Ok(_) | Err(Errno::EAGAIN) | Err(Errno::EINTR) | Err(Errno::ETIMEDOUT) => Ok(()),
+        Err(e) => Err(e)
+    }
+}
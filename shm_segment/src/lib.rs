@@ -0,0 +1,455 @@
+// Self describing shared memory segments.
+// A segment starts with a header and a field table describing the data that follows,
+// so that readers can check they know the layout, or look the fields up by name, before reading.
+//
+// | ShmHeader | field_count x ShmFieldDesc | padding | data (data_size bytes, at data_offset) |
+//
+// The data is published through a seqlock: header.seq is odd while the producer updates the data,
+// readers copy the data and retry when seq was odd or changed during the copy.
+// Every publish also wakes the readers sleeping in futex_wait on header.seq.
+//
+// ShmRing keeps a history of snapshots instead of the latest one, see ring.rs.
+
+mod bindings;
+mod clock;
+mod futex;
+mod ring;
+
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU32, Ordering};
+use log::trace;
+use nix::fcntl::OFlag;
+use nix::libc::off_t;
+use nix::sys::mman::{mmap, shm_open, shm_unlink, MapFlags, ProtFlags};
+use nix::sys::stat::Mode;
+use nix::unistd::ftruncate;
+use crate::futex::futex_wake_all;
+
+pub use crate::bindings::{generate_c_header, generate_csharp_accessor};
+pub use crate::clock::monotonic_ns;
+pub use crate::futex::futex_wait;
+pub use crate::ring::{ring_data_offset, ring_entry_fields, ring_header_offset, ShmRing, ShmRingEntry, ShmRingHeader, SHM_RING_MAGIC};
+
+pub const SHM_MAGIC: u32 = u32::from_le_bytes(*b"MX5S");
+
+pub const FIELD_NAME_LEN: usize = 48;
+pub const FIELD_UNIT_LEN: usize = 8;
+
+#[repr(C)]
+pub struct ShmHeader {
+    pub magic: u32,
+    // Bumped whenever the data layout changes
+    pub layout_version: u32,
+    pub seq: AtomicU32,
+    pub field_count: u32,
+    pub data_offset: u32,
+    pub data_size: u32,
+    // Lets readers tell a producer that was killed from one that's just quiet
+    pub producer_pid: u32,
+    // Keeps the ring header that can follow the field table 8 byte aligned
+    pub reserved: u32
+}
+
+#[repr(C)]
+pub struct ShmFieldDesc {
+    // NUL terminated
+    pub name: [u8; FIELD_NAME_LEN],
+    pub unit: [u8; FIELD_UNIT_LEN],
+    // From the start of the data
+    pub offset: u32,
+    pub field_type: u32
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FieldType {
+    Bool = 1,
+    U8 = 2,
+    I8 = 3,
+    U16 = 4,
+    I16 = 5,
+    U32 = 6,
+    I32 = 7,
+    U64 = 8,
+    I64 = 9,
+    F32 = 10,
+    F64 = 11,
+    // A ShmStr
+    Str = 12
+}
+
+impl FieldType {
+    pub fn size(&self) -> usize {
+        match self {
+            FieldType::Bool | FieldType::U8 | FieldType::I8 => 1,
+            FieldType::U16 | FieldType::I16 => 2,
+            FieldType::U32 | FieldType::I32 | FieldType::F32 => 4,
+            FieldType::U64 | FieldType::I64 | FieldType::F64 => 8,
+            FieldType::Str => SHM_STR_LEN
+        }
+    }
+}
+
+pub trait ShmFieldType {
+    const FIELD_TYPE: FieldType;
+}
+
+macro_rules! impl_shm_field_type {
+    ($($rust_type:ty => $field_type:ident),*) => {
+        $(impl ShmFieldType for $rust_type {
+            const FIELD_TYPE: FieldType = FieldType::$field_type;
+        })*
+    };
+}
+
+impl_shm_field_type!(bool => Bool, u8 => U8, i8 => I8, u16 => U16, i16 => I16, u32 => U32, i32 => I32,
+    u64 => U64, i64 => I64, f32 => F32, f64 => F64, ShmStr => Str);
+
+pub const SHM_STR_LEN: usize = 32;
+
+// A short text field, NUL terminated, longer strings are cut
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq)]
+pub struct ShmStr([u8; SHM_STR_LEN]);
+
+impl ShmStr {
+    pub fn new(s: &str) -> ShmStr {
+        let mut len = s.len().min(SHM_STR_LEN - 1);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        let mut buf = [0; SHM_STR_LEN];
+        buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+        ShmStr(buf)
+    }
+
+    // From the bytes of a Str field
+    pub fn from_bytes(bytes: &[u8]) -> ShmStr {
+        ShmStr(bytes[..SHM_STR_LEN].try_into().unwrap())
+    }
+
+    // Up to the NUL, or to what's valid UTF-8 of it
+    pub fn as_str(&self) -> &str {
+        let bytes = &self.0[..self.0.iter().position(|&b| b == 0).unwrap_or(SHM_STR_LEN)];
+
+        match std::str::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(e) => std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap()
+        }
+    }
+}
+
+impl Default for ShmStr {
+    fn default() -> ShmStr {
+        ShmStr([0; SHM_STR_LEN])
+    }
+}
+
+impl fmt::Debug for ShmStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+pub struct ShmField {
+    pub name: &'static str,
+    pub offset: usize,
+    pub field_type: FieldType,
+    pub unit: &'static str
+}
+
+// Lets shm_field! get the field type from the struct definition instead of repeating it
+pub fn field_type_of<D, F: ShmFieldType>(_: fn(&D) -> &F) -> FieldType {
+    F::FIELD_TYPE
+}
+
+// Describes a field of a ShmData struct, with its offset and type taken from the real layout
+#[macro_export]
+macro_rules! shm_field {
+    ($data:ty, $field:ident, $unit:expr) => {
+        $crate::ShmField {
+            name: stringify!($field),
+            offset: std::mem::offset_of!($data, $field),
+            field_type: $crate::field_type_of(|data: &$data| &data.$field),
+            unit: $unit
+        }
+    };
+}
+
+// A #[repr(C)] struct published in a segment
+pub trait ShmData: Copy + 'static {
+    const LAYOUT_VERSION: u32;
+
+    fn fields() -> Vec<ShmField>;
+}
+
+fn c_str<const N: usize>(s: &str) -> [u8; N] {
+    assert!(s.len() < N, "{} doesn't fit in {} bytes", s, N);
+
+    let mut buf = [0; N];
+    buf[..s.len()].copy_from_slice(s.as_bytes());
+    buf
+}
+
+pub fn data_offset<T: ShmData>(field_count: usize) -> usize {
+    let table_end = size_of::<ShmHeader>() + field_count * size_of::<ShmFieldDesc>();
+    table_end.next_multiple_of(align_of::<T>())
+}
+
+// Creates or reuses the segment and maps size bytes of it.
+// A segment left over by a previous run may still have readers, it's hidden from them until write_header.
+unsafe fn create_mapping(name: &str, size: usize) -> *mut u8 {
+    let shm_size = NonZeroUsize::new(size).unwrap();
+
+    let mode_755 = Mode::S_IRWXU | Mode::S_IRGRP | Mode::S_IXGRP | Mode::S_IROTH | Mode::S_IXOTH;
+    let shm_fd = shm_open(name, OFlag::O_CREAT | OFlag::O_RDWR, mode_755)
+        .expect("shm_open");
+
+    ftruncate(&shm_fd, shm_size.get() as off_t)
+        .expect("ftruncate");
+
+    let base = mmap(None, shm_size, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE, MapFlags::MAP_SHARED, &shm_fd, 0)
+        .expect("mmap")
+        .as_ptr() as *mut u8;
+
+    ptr::write_volatile(&mut (*(base as *mut ShmHeader)).magic, 0);
+    fence(Ordering::Release);
+
+    base
+}
+
+unsafe fn write_field_table(base: *mut u8, fields: &[ShmField]) {
+    let field_descs = base.add(size_of::<ShmHeader>()) as *mut ShmFieldDesc;
+
+    for (i, field) in fields.iter().enumerate() {
+        field_descs.add(i).write(ShmFieldDesc {
+            name: c_str(field.name),
+            unit: c_str(field.unit),
+            offset: field.offset as u32,
+            field_type: field.field_type as u32
+        });
+    }
+}
+
+// Writes the header once everything else is in place, readers trust the segment once they see the magic
+unsafe fn write_header(base: *mut u8, magic: u32, header: ShmHeader) -> &'static ShmHeader {
+    let header_ptr = base as *mut ShmHeader;
+    header_ptr.write(header);
+
+    fence(Ordering::Release);
+    ptr::write_volatile(&mut (*header_ptr).magic, magic);
+
+    &*header_ptr
+}
+
+pub struct ShmSegment<T: ShmData> {
+    name: &'static str,
+    header: &'static ShmHeader,
+    data: &'static mut T
+}
+
+impl<T: ShmData> ShmSegment<T> {
+    pub fn new(name: &'static str, data: &T) -> ShmSegment<T> {
+        let fields = T::fields();
+        let data_offset = data_offset::<T>(fields.len());
+
+        unsafe {
+            let base = create_mapping(name, data_offset + size_of::<T>());
+            let data_ptr = base.add(data_offset) as *mut T;
+
+            write_field_table(base, &fields);
+            data_ptr.write(*data);
+
+            let header = write_header(base, SHM_MAGIC, ShmHeader {
+                magic: 0,
+                layout_version: T::LAYOUT_VERSION,
+                seq: AtomicU32::new(0),
+                field_count: fields.len() as u32,
+                data_offset: data_offset as u32,
+                data_size: size_of::<T>() as u32,
+                producer_pid: std::process::id(),
+                reserved: 0
+            });
+
+            ShmSegment {
+                name,
+                header,
+                data: &mut *data_ptr
+            }
+        }
+    }
+
+    // Publishes a whole snapshot at once
+    pub fn publish(&mut self, data: &T) {
+        let seq = self.header.seq.load(Ordering::Relaxed);
+
+        // Odd seq tells the readers an update is in progress,
+        // the fence keeps the data writes from moving before it
+        self.header.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        unsafe {
+            ptr::write_volatile(self.data, *data);
+        }
+
+        self.header.seq.store(seq.wrapping_add(2), Ordering::Release);
+
+        futex_wake_all(&self.header.seq);
+    }
+}
+
+impl<T: ShmData> Drop for ShmSegment<T> {
+    fn drop(&mut self) {
+        trace!("shm unlink {}", self.name);
+        shm_unlink(self.name)
+            .expect("shm_unlink");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::thread;
+    use super::*;
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy, PartialEq, Debug)]
+    struct Sample {
+        flag: bool,
+        speed_kmh: u16,
+        sample_ns: u64,
+        delta: i32
+    }
+
+    impl ShmData for Sample {
+        const LAYOUT_VERSION: u32 = 3;
+
+        fn fields() -> Vec<ShmField> {
+            vec![
+                shm_field!(Sample, flag, ""),
+                shm_field!(Sample, speed_kmh, "km/h"),
+                shm_field!(Sample, sample_ns, "ns"),
+                shm_field!(Sample, delta, "ds")
+            ]
+        }
+    }
+
+    struct Mapping {
+        header: &'static ShmHeader,
+        field_descs: &'static [ShmFieldDesc],
+        data: &'static Sample
+    }
+
+    fn map_read_only(name: &str) -> Mapping {
+        let shm_fd = shm_open(name, OFlag::O_RDONLY, Mode::empty())
+            .expect("shm_open");
+
+        let shm_size = data_offset::<Sample>(Sample::fields().len()) + size_of::<Sample>();
+
+        unsafe {
+            let base = mmap(None, NonZeroUsize::new(shm_size).unwrap(), ProtFlags::PROT_READ, MapFlags::MAP_SHARED, &shm_fd, 0)
+                .expect("mmap")
+                .as_ptr() as *const u8;
+
+            let header = &*(base as *const ShmHeader);
+
+            Mapping {
+                header,
+                field_descs: std::slice::from_raw_parts(base.add(size_of::<ShmHeader>()) as *const ShmFieldDesc, header.field_count as usize),
+                data: &*(base.add(header.data_offset as usize) as *const Sample)
+            }
+        }
+    }
+
+    // What a reader does: retry until the copy is consistent
+    fn read_sample(mapping: &Mapping) -> Sample {
+        loop {
+            let seq = mapping.header.seq.load(Ordering::Acquire);
+            if seq % 2 == 1 {
+                continue;
+            }
+
+            let sample = unsafe { ptr::read_volatile(mapping.data) };
+
+            fence(Ordering::Acquire);
+            if mapping.header.seq.load(Ordering::Relaxed) == seq {
+                return sample;
+            }
+        }
+    }
+
+    #[test]
+    fn strings_are_cut_to_fit_their_nul() {
+        assert_eq!(ShmStr::new("ROM SPG 5.10 (7b202e)").as_str(), "ROM SPG 5.10 (7b202e)");
+        assert_eq!(ShmStr::default().as_str(), "");
+
+        let long = "x".repeat(SHM_STR_LEN);
+        assert_eq!(ShmStr::new(&long).as_str(), &long[..SHM_STR_LEN - 1]);
+
+        // Not in the middle of a char
+        let accented = "é".repeat(SHM_STR_LEN / 2);
+        assert_eq!(ShmStr::new(&accented).as_str(), "é".repeat(SHM_STR_LEN / 2 - 1));
+
+        assert_eq!(ShmStr::from_bytes(&[b'M', 0xff, 0, b'x'].repeat(8)).as_str(), "M");
+    }
+
+    #[test]
+    fn header_describes_the_data_layout() {
+        const NAME: &str = "/shm_segment_header_test";
+
+        let initial = Sample { flag: true, speed_kmh: 42, sample_ns: 7, delta: -3 };
+        let _shm = ShmSegment::new(NAME, &initial);
+
+        let mapping = map_read_only(NAME);
+
+        assert_eq!(mapping.header.magic, SHM_MAGIC);
+        assert_eq!(mapping.header.layout_version, 3);
+        assert_eq!(mapping.header.data_size as usize, size_of::<Sample>());
+        assert_eq!(mapping.header.producer_pid, std::process::id());
+        assert_eq!(mapping.header.data_offset as usize % align_of::<Sample>(), 0);
+        assert_eq!(*mapping.data, initial);
+
+        let fields: Vec<_> = mapping.field_descs.iter()
+            .map(|desc| (
+                CStr::from_bytes_until_nul(&desc.name).unwrap().to_str().unwrap(),
+                CStr::from_bytes_until_nul(&desc.unit).unwrap().to_str().unwrap(),
+                desc.offset as usize,
+                desc.field_type))
+            .collect();
+
+        assert_eq!(fields, vec![
+            ("flag", "", std::mem::offset_of!(Sample, flag), FieldType::Bool as u32),
+            ("speed_kmh", "km/h", std::mem::offset_of!(Sample, speed_kmh), FieldType::U16 as u32),
+            ("sample_ns", "ns", std::mem::offset_of!(Sample, sample_ns), FieldType::U64 as u32),
+            ("delta", "ds", std::mem::offset_of!(Sample, delta), FieldType::I32 as u32)
+        ]);
+    }
+
+    #[test]
+    fn readers_never_see_a_half_published_snapshot() {
+        const NAME: &str = "/shm_segment_seqlock_test";
+
+        let idle = Sample::default();
+        let driving = Sample { flag: true, speed_kmh: u16::MAX, sample_ns: u64::MAX, delta: i32::MIN };
+
+        let mut shm = ShmSegment::new(NAME, &idle);
+        let mapping = map_read_only(NAME);
+
+        let reader = thread::spawn(move || {
+            for _ in 0..100_000 {
+                let sample = read_sample(&mapping);
+                assert!(sample == idle || sample == driving, "torn read {:?}", sample);
+            }
+        });
+
+        while !reader.is_finished() {
+            shm.publish(&driving);
+            shm.publish(&idle);
+        }
+
+        reader.join().unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+// A segment holding the last capacity snapshots of a ShmData, each stamped with when it was pushed,
+// so that readers can draw a history without sampling the live segment themselves.
+//
+// | ShmHeader | field_count x ShmFieldDesc | ShmRingHeader | padding | capacity x ShmRingEntry<T> (at data_offset) |
+//
+// The magic is SHM_RING_MAGIC so that a plain segment reader refuses it, the field table describes one entry
+// (timestamp_ns then the fields of T) and data_size covers all of them.
+// Every push goes through the header seqlock like a publish: readers copy the entries they want,
+// then drop the ones pushes started since may have overwritten.
+
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::ptr;
+use std::time::Duration;
+use log::trace;
+use nix::sys::mman::shm_unlink;
+use crate::futex::futex_wake_all;
+use crate::{create_mapping, write_field_table, write_header, ShmData, ShmField, ShmFieldDesc, ShmHeader, ShmFieldType};
+
+pub const SHM_RING_MAGIC: u32 = u32::from_le_bytes(*b"MX5R");
+
+// Right after the field table
+#[repr(C)]
+pub struct ShmRingHeader {
+    pub capacity: u32,
+    pub entry_size: u32,
+    // Time between two pushes
+    pub period_ns: u64,
+    // Entries pushed so far, the newest one is in slot (write_index - 1) % capacity
+    pub write_index: AtomicU64
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ShmRingEntry<T: ShmData> {
+    pub timestamp_ns: u64,
+    pub data: T
+}
+
+pub fn ring_header_offset(field_count: usize) -> usize {
+    size_of::<ShmHeader>() + field_count * size_of::<ShmFieldDesc>()
+}
+
+pub fn ring_data_offset<T: ShmData>(field_count: usize) -> usize {
+    let ring_header_end = ring_header_offset(field_count) + size_of::<ShmRingHeader>();
+    ring_header_end.next_multiple_of(align_of::<ShmRingEntry<T>>())
+}
+
+// The entry fields, with offsets from the start of an entry
+pub fn ring_entry_fields<T: ShmData>() -> Vec<ShmField> {
+    let data_offset = std::mem::offset_of!(ShmRingEntry<T>, data);
+
+    let timestamp = ShmField {
+        name: "timestamp_ns",
+        offset: std::mem::offset_of!(ShmRingEntry<T>, timestamp_ns),
+        field_type: u64::FIELD_TYPE,
+        unit: "ns"
+    };
+
+    std::iter::once(timestamp)
+        .chain(T::fields().into_iter().map(|field| ShmField { offset: data_offset + field.offset, ..field }))
+        .collect()
+}
+
+pub struct ShmRing<T: ShmData> {
+    name: &'static str,
+    header: &'static ShmHeader,
+    ring_header: &'static ShmRingHeader,
+    entries: *mut ShmRingEntry<T>
+}
+
+// Only the ring owner writes the entries
+unsafe impl<T: ShmData + Send> Send for ShmRing<T> {}
+
+impl<T: ShmData> ShmRing<T> {
+    pub fn new(name: &'static str, capacity: usize, period: Duration) -> ShmRing<T> {
+        assert!(capacity > 0, "empty ring");
+
+        let fields = ring_entry_fields::<T>();
+        let ring_header_offset = ring_header_offset(fields.len());
+        let data_offset = ring_data_offset::<T>(fields.len());
+        let data_size = capacity * size_of::<ShmRingEntry<T>>();
+
+        unsafe {
+            let base = create_mapping(name, data_offset + data_size);
+            let ring_header = base.add(ring_header_offset) as *mut ShmRingHeader;
+
+            write_field_table(base, &fields);
+
+            ring_header.write(ShmRingHeader {
+                capacity: capacity as u32,
+                entry_size: size_of::<ShmRingEntry<T>>() as u32,
+                period_ns: period.as_nanos() as u64,
+                write_index: AtomicU64::new(0)
+            });
+
+            let header = write_header(base, SHM_RING_MAGIC, ShmHeader {
+                magic: 0,
+                layout_version: T::LAYOUT_VERSION,
+                seq: AtomicU32::new(0),
+                field_count: fields.len() as u32,
+                data_offset: data_offset as u32,
+                data_size: data_size as u32,
+                producer_pid: std::process::id(),
+                reserved: 0
+            });
+
+            ShmRing {
+                name,
+                header,
+                ring_header: &*ring_header,
+                entries: base.add(data_offset) as *mut ShmRingEntry<T>
+            }
+        }
+    }
+
+    // Overwrites the oldest entry once the ring is full
+    pub fn push(&mut self, timestamp_ns: u64, data: &T) {
+        let seq = self.header.seq.load(Ordering::Relaxed);
+        let write_index = self.ring_header.write_index.load(Ordering::Relaxed);
+        let slot = (write_index % self.ring_header.capacity as u64) as usize;
+
+        self.header.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        unsafe {
+            ptr::write_volatile(self.entries.add(slot), ShmRingEntry { timestamp_ns, data: *data });
+        }
+
+        // Readers that see the new index see the entry behind it
+        self.ring_header.write_index.store(write_index + 1, Ordering::Release);
+        self.header.seq.store(seq.wrapping_add(2), Ordering::Release);
+
+        futex_wake_all(&self.header.seq);
+    }
+}
+
+impl<T: ShmData> Drop for ShmRing<T> {
+    fn drop(&mut self) {
+        trace!("shm unlink {}", self.name);
+        shm_unlink(self.name)
+            .expect("shm_unlink");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{c_void, CStr};
+    use std::num::NonZeroUsize;
+    use std::thread;
+    use nix::fcntl::OFlag;
+    use nix::sys::mman::{mmap, shm_open, MapFlags, ProtFlags};
+    use nix::sys::stat::Mode;
+    use super::*;
+    use crate::{shm_field, FieldType};
+
+    #[repr(C)]
+    #[derive(Default, Clone, Copy, PartialEq, Debug)]
+    struct Sample {
+        speed_kmh: u16,
+        rpm: u16
+    }
+
+    impl ShmData for Sample {
+        const LAYOUT_VERSION: u32 = 1;
+
+        fn fields() -> Vec<ShmField> {
+            vec![
+                shm_field!(Sample, speed_kmh, "km/h"),
+                shm_field!(Sample, rpm, "rpm")
+            ]
+        }
+    }
+
+    const CAPACITY: usize = 8;
+
+    struct Mapping {
+        header: &'static ShmHeader,
+        field_descs: &'static [ShmFieldDesc],
+        ring_header: &'static ShmRingHeader,
+        entries: &'static [ShmRingEntry<Sample>]
+    }
+
+    fn map_read_only(name: &str) -> Mapping {
+        let shm_fd = shm_open(name, OFlag::O_RDONLY, Mode::empty())
+            .expect("shm_open");
+
+        let field_count = ring_entry_fields::<Sample>().len();
+        let shm_size = ring_data_offset::<Sample>(field_count) + CAPACITY * size_of::<ShmRingEntry<Sample>>();
+
+        unsafe {
+            let base = mmap(None, NonZeroUsize::new(shm_size).unwrap(), ProtFlags::PROT_READ, MapFlags::MAP_SHARED, &shm_fd, 0)
+                .expect("mmap")
+                .as_ptr() as *const c_void;
+
+            let header = &*(base as *const ShmHeader);
+
+            Mapping {
+                header,
+                field_descs: std::slice::from_raw_parts(base.byte_add(size_of::<ShmHeader>()) as *const ShmFieldDesc, field_count),
+                ring_header: &*(base.byte_add(ring_header_offset(field_count)) as *const ShmRingHeader),
+                entries: std::slice::from_raw_parts(base.byte_add(header.data_offset as usize) as *const ShmRingEntry<Sample>, CAPACITY)
+            }
+        }
+    }
+
+    // What a reader does: copy the newest entries, then keep the ones no push started since could have overwritten
+    fn read_entries(mapping: &Mapping) -> Vec<(u64, Sample)> {
+        loop {
+            let before = mapping.header.seq.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                continue;
+            }
+
+            let end = mapping.ring_header.write_index.load(Ordering::Acquire);
+            let start = end.saturating_sub(CAPACITY as u64);
+
+            let entries: Vec<_> = (start..end)
+                .map(|i| unsafe { ptr::read_volatile(&mapping.entries[i as usize % CAPACITY]) })
+                .map(|entry| (entry.timestamp_ns, entry.data))
+                .collect();
+
+            fence(Ordering::Acquire);
+            let started = mapping.header.seq.load(Ordering::Relaxed).wrapping_sub(before).div_ceil(2) as u64;
+            let valid_from = (end + started).saturating_sub(CAPACITY as u64);
+
+            if valid_from < end {
+                return entries[(valid_from.max(start) - start) as usize..].to_vec();
+            }
+        }
+    }
+
+    #[test]
+    fn ring_header_and_fields_describe_an_entry() {
+        const NAME: &str = "/shm_ring_header_test";
+
+        let _ring = ShmRing::<Sample>::new(NAME, CAPACITY, Duration::from_millis(50));
+        let mapping = map_read_only(NAME);
+
+        assert_eq!(mapping.header.magic, SHM_RING_MAGIC);
+        assert_eq!(mapping.header.data_size as usize, CAPACITY * size_of::<ShmRingEntry<Sample>>());
+        assert_eq!(mapping.ring_header.capacity as usize, CAPACITY);
+        assert_eq!(mapping.ring_header.entry_size as usize, size_of::<ShmRingEntry<Sample>>());
+        assert_eq!(mapping.ring_header.period_ns, 50_000_000);
+        assert_eq!(mapping.ring_header.write_index.load(Ordering::Relaxed), 0);
+
+        let fields: Vec<_> = mapping.field_descs.iter()
+            .map(|desc| (CStr::from_bytes_until_nul(&desc.name).unwrap().to_str().unwrap(), desc.offset as usize, desc.field_type))
+            .collect();
+
+        assert_eq!(fields, vec![
+            ("timestamp_ns", 0, FieldType::U64 as u32),
+            ("speed_kmh", 8, FieldType::U16 as u32),
+            ("rpm", 10, FieldType::U16 as u32)
+        ]);
+    }
+
+    #[test]
+    fn push_overwrites_the_oldest_entry() {
+        const NAME: &str = "/shm_ring_push_test";
+
+        let mut ring = ShmRing::<Sample>::new(NAME, CAPACITY, Duration::from_millis(50));
+        let mapping = map_read_only(NAME);
+
+        for i in 0..3 {
+            ring.push(i, &Sample { speed_kmh: i as u16, rpm: 0 });
+        }
+        assert_eq!(read_entries(&mapping).iter().map(|(ts, _)| *ts).collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        for i in 3..20 {
+            ring.push(i, &Sample { speed_kmh: i as u16, rpm: 0 });
+        }
+        assert_eq!(read_entries(&mapping).iter().map(|(ts, _)| *ts).collect::<Vec<_>>(), (12..20).collect::<Vec<_>>());
+        assert_eq!(mapping.ring_header.write_index.load(Ordering::Relaxed), 20);
+    }
+
+    #[test]
+    fn readers_never_see_an_overwritten_entry() {
+        const NAME: &str = "/shm_ring_seqlock_test";
+
+        let mut ring = ShmRing::<Sample>::new(NAME, CAPACITY, Duration::ZERO);
+        let mapping = map_read_only(NAME);
+
+        let reader = thread::spawn(move || {
+            for _ in 0..20_000 {
+                // Every entry carries its timestamp in both fields and follows the previous one
+                let entries = read_entries(&mapping);
+                for (i, (ts, sample)) in entries.iter().enumerate() {
+                    assert_eq!((sample.speed_kmh, sample.rpm), (*ts as u16, *ts as u16), "torn entry");
+                    assert_eq!(*ts, entries[0].0 + i as u64, "overwritten entry");
+                }
+            }
+        });
+
+        let mut ts = 0;
+        while !reader.is_finished() {
+            ring.push(ts, &Sample { speed_kmh: ts as u16, rpm: ts as u16 });
+            ts += 1;
+        }
+
+        reader.join().unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+// The few bits of HTTP/1.1 the server needs: reading a GET request head and writing a whole response.
+
+use std::io::{self, BufRead};
+
+const MAX_HEAD_LEN: usize = 8192;
+
+#[derive(PartialEq, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    // Names are lowercase
+    pub headers: Vec<(String, String)>
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn query_value(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+
+    // Connection: upgrade plus Upgrade: websocket
+    pub fn is_websocket_upgrade(&self) -> bool {
+        let upgrade = self.header("upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+        let connection = self.header("connection")
+            .is_some_and(|value| value.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")));
+
+        upgrade && connection
+    }
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+// Reads up to the empty line ending the head, a body is never expected
+pub fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Request> {
+    let mut lines = Vec::new();
+    let mut head_len = 0;
+
+    loop {
+        let mut line = String::new();
+        let n = reader.read_line(&mut line)?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        head_len += n;
+        if head_len > MAX_HEAD_LEN {
+            return Err(invalid("request head too long"));
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line.to_string());
+    }
+
+    let mut request_line = lines.first().ok_or_else(|| invalid("no request line"))?.split(' ');
+
+    let (Some(method), Some(target), Some(version)) = (request_line.next(), request_line.next(), request_line.next()) else {
+        return Err(invalid("bad request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid("not HTTP/1.x"));
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let query = query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (name.to_string(), value.to_string())
+        })
+        .collect();
+
+    let headers = lines[1..].iter()
+        .map(|line| {
+            let (name, value) = line.split_once(':').ok_or_else(|| invalid("bad header"))?;
+            Ok((name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        headers
+    })
+}
+
+pub fn response(status: &str, content_type: &str, body: &str) -> Vec<u8> {
+    format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+            status, content_type, body.len(), body).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_a_websocket_upgrade_request() {
+        let head = "GET /stream?rate=5&x HTTP/1.1\r\nHost: mx5.local\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+                    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+        let request = read_request(&mut head.as_bytes()).unwrap();
+
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("GET", "/stream"));
+        assert_eq!(request.query_value("rate"), Some("5"));
+        assert_eq!(request.query_value("x"), Some(""));
+        assert_eq!(request.header("sec-websocket-key"), Some("dGhlIHNhbXBsZSBub25jZQ=="));
+        assert!(request.is_websocket_upgrade());
+
+        assert!(read_request(&mut "GET /\r\n\r\n".as_bytes()).is_err());
+        assert!(read_request(&mut "GET / HTTP/1.1\r\nHost".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,342 @@
+// Serves the metrics and lap times to phones and laptops on the Pi's Wi-Fi, straight from the shm segments:
+//
+//     GET /          a live page
+//     GET /snapshot  {"metrics":{..}|null,"chrono":{..}|null}, null while the service isn't running
+//     GET /stream    a WebSocket sending the same object plus "seq" at ?rate=<hz>, --rate by default
+//
+// Every connection gets its own thread and its own shm clients, there are only ever a few of them.
+
+mod http;
+mod websocket;
+
+use std::env;
+use std::io::{self, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use cli_args::arg_value;
+use log::{debug, info, warn};
+use shm_client::{ChronoSnapshot, MetricsSnapshot, Snapshot, Source};
+use crate::http::Request;
+use crate::websocket::{ClientFrame, FrameReader};
+
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:8080";
+const DEFAULT_RATE_HZ: u32 = 10;
+const MAX_RATE_HZ: u32 = 50;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// A client that can't take a frame for this long is gone
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+
+const INDEX_HTML: &str = include_str!("index.html");
+
+struct Config {
+    metrics_shm_name: String,
+    chrono_shm_name: String,
+    rate_hz: u32
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let env = env_logger::Env::default()
+        .filter_or("RUST_LOG", "info");
+
+    env_logger::init_from_env(env);
+
+    let listen_addr = arg_value(&args, "--listen").unwrap_or(DEFAULT_LISTEN_ADDR);
+
+    let rate_hz = arg_value(&args, "--rate")
+        .map_or(DEFAULT_RATE_HZ, |hz| hz.parse().expect("--rate hz"));
+    assert!((1..=MAX_RATE_HZ).contains(&rate_hz), "--rate must be 1 to {} hz", MAX_RATE_HZ);
+
+    let config = Config {
+        metrics_shm_name: String::from(MetricsSnapshot::SHM_NAME),
+        chrono_shm_name: String::from(ChronoSnapshot::SHM_NAME),
+        rate_hz
+    };
+
+    let listener = TcpListener::bind(listen_addr)
+        .expect("bind");
+
+    info!("Serving on http://{}, streaming at {} hz", listen_addr, rate_hz);
+
+    serve(listener, config);
+}
+
+fn serve(listener: TcpListener, config: Config) {
+    let config = Arc::new(config);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("accept: {}", e);
+                continue;
+            }
+        };
+
+        let config = config.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().map_or(String::from("?"), |addr| addr.to_string());
+
+            if let Err(e) = handle_connection(stream, &config) {
+                debug!("{}: {}", peer, e);
+            }
+        });
+    }
+}
+
+fn json_or_null<S: Snapshot>(snapshot: Option<S>) -> String {
+    snapshot.map_or(String::from("null"), |snapshot| snapshot.to_json())
+}
+
+struct Sources {
+    metrics: Source<MetricsSnapshot>,
+    chrono: Source<ChronoSnapshot>
+}
+
+impl Sources {
+    fn new(config: &Config) -> Sources {
+        Sources {
+            metrics: Source::new(&config.metrics_shm_name),
+            chrono: Source::new(&config.chrono_shm_name)
+        }
+    }
+
+    // A segment is null while its producer isn't there
+    fn read_json(&mut self) -> String {
+        format!("\"metrics\":{},\"chrono\":{}", json_or_null(self.metrics.read()), json_or_null(self.chrono.read()))
+    }
+}
+
+fn handle_connection(stream: TcpStream, config: &Config) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+
+    let request = match http::read_request(&mut reader) {
+        Ok(request) => request,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            return stream.write_all(&http::response("400 Bad Request", "text/plain", &format!("{}\n", e)));
+        }
+        Err(e) => return Err(e)
+    };
+
+    debug!("{} {}", request.method, request.path);
+
+    if request.method != "GET" {
+        return stream.write_all(&http::response("405 Method Not Allowed", "text/plain", "GET only\n"));
+    }
+
+    match request.path.as_str() {
+        "/" => stream.write_all(&http::response("200 OK", "text/html; charset=utf-8", INDEX_HTML)),
+        "/snapshot" => {
+            let body = format!("{{{}}}\n", Sources::new(config).read_json());
+            stream.write_all(&http::response("200 OK", "application/json", &body))
+        }
+        "/stream" => handle_stream(stream, &request, config),
+        _ => stream.write_all(&http::response("404 Not Found", "text/plain", "not found\n"))
+    }
+}
+
+fn stream_rate(request: &Request, config: &Config) -> Option<u32> {
+    match request.query_value("rate") {
+        Some(rate) => rate.parse().ok().filter(|rate| (1..=MAX_RATE_HZ).contains(rate)),
+        None => Some(config.rate_hz)
+    }
+}
+
+fn handle_stream(mut stream: TcpStream, request: &Request, config: &Config) -> io::Result<()> {
+    let key = match request.header("sec-websocket-key") {
+        Some(key) if request.is_websocket_upgrade() => key,
+        _ => return stream.write_all(&http::response("426 Upgrade Required", "text/plain", "WebSocket only\n"))
+    };
+
+    let Some(rate_hz) = stream_rate(request, config) else {
+        let body = format!("rate must be 1 to {} hz\n", MAX_RATE_HZ);
+        return stream.write_all(&http::response("400 Bad Request", "text/plain", &body));
+    };
+
+    stream.write_all(websocket::handshake_response(key).as_bytes())?;
+
+    info!("{}: streaming at {} hz", stream.peer_addr()?, rate_hz);
+
+    let period = Duration::from_secs(1) / rate_hz;
+    let mut sources = Sources::new(config);
+    let mut frames = FrameReader::new();
+    let mut next_frame_at = Instant::now();
+    let mut seq: u64 = 0;
+
+    loop {
+        let now = Instant::now();
+
+        if now >= next_frame_at {
+            let frame = format!("{{\"seq\":{},{}}}", seq, sources.read_json());
+            stream.write_all(&websocket::text_frame(&frame))?;
+            seq += 1;
+
+            // Skip the ticks a slow write made us miss rather than bursting
+            next_frame_at += period;
+            if next_frame_at < now {
+                next_frame_at = now + period;
+            }
+            continue;
+        }
+
+        // Listen to the client until the next frame is due
+        stream.set_read_timeout(Some(next_frame_at - now))?;
+
+        match frames.read(&mut stream) {
+            Ok(client_frames) => {
+                for frame in client_frames {
+                    match frame {
+                        ClientFrame::Close => {
+                            debug!("{}: closed", stream.peer_addr()?);
+                            return stream.write_all(&websocket::close_frame());
+                        }
+                        ClientFrame::Ping(payload) => websocket::pong(&mut stream, &payload)?,
+                        ClientFrame::Other => {}
+                    }
+                }
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => {}
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::SocketAddr;
+    use shm_client::test_fixtures::chrono;
+    use shm_segment::ShmSegment;
+    use super::*;
+
+    const CHRONO_JSON: &str = concat!("{\"best_lap_time\":1234,\"previous_lap_time\":1250,\"current_lap_time\":321,",
+                                      "\"previous_sector_delta_time\":-7,\"best_lap_n\":3,\"current_lap_n\":4,\"timing_state\":1}");
+
+    // No metrics producer, the chrono segment under chrono_shm_name
+    fn start_server(chrono_shm_name: &str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let config = Config {
+            metrics_shm_name: String::from("/telemetry_http_no_metrics_test"),
+            chrono_shm_name: String::from(chrono_shm_name),
+            rate_hz: 20
+        };
+
+        thread::spawn(move || serve(listener, config));
+        addr
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    // An unmasked server frame: (opcode, payload)
+    fn read_frame(stream: &mut impl Read) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).unwrap();
+
+        let len = match head[1] {
+            126 => {
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            }
+            len => len as usize
+        };
+
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0f, payload)
+    }
+
+    #[test]
+    fn serves_a_json_snapshot() {
+        const NAME: &str = "/telemetry_http_snapshot_test";
+
+        let addr = start_server(NAME);
+
+        let response = get(addr, "/snapshot");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n{\"metrics\":null,\"chrono\":null}\n"), "{}", response);
+
+        let _shm = ShmSegment::new(NAME, &chrono());
+        let response = get(addr, "/snapshot");
+        assert!(response.ends_with(&format!("\r\n\r\n{{\"metrics\":null,\"chrono\":{}}}\n", CHRONO_JSON)), "{}", response);
+
+        assert!(get(addr, "/").contains("<html"));
+        assert!(get(addr, "/nope").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(get(addr, "/stream").starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+    }
+
+    #[test]
+    fn streams_over_a_websocket() {
+        const NAME: &str = "/telemetry_http_stream_test";
+
+        let _shm = ShmSegment::new(NAME, &chrono());
+        let addr = start_server(NAME);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET /stream?rate=50 HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+
+        // The frames too, it may have buffered the first one along with the handshake
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut head = Vec::new();
+        loop {
+            let mut line = String::new();
+            io::BufRead::read_line(&mut reader, &mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            head.push(line);
+        }
+        assert_eq!(head[0], "HTTP/1.1 101 Switching Protocols\r\n");
+        assert!(head.contains(&String::from("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n")));
+
+        let started_at = Instant::now();
+        for seq in 0..5 {
+            let (opcode, payload) = read_frame(&mut reader);
+            assert_eq!(opcode, 0x1);
+            assert_eq!(String::from_utf8(payload).unwrap(), format!("{{\"seq\":{},\"metrics\":null,\"chrono\":{}}}", seq, CHRONO_JSON));
+        }
+        // 50 hz, the first frame goes out right away
+        assert!(started_at.elapsed() >= Duration::from_millis(70));
+
+        // A masked close, the server answers with its own and hangs up
+        stream.write_all(&[0x88, 0x80, 1, 2, 3, 4]).unwrap();
+        loop {
+            let (opcode, _) = read_frame(&mut reader);
+            if opcode == 0x8 {
+                break;
+            }
+        }
+        assert_eq!(reader.read(&mut [0u8; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_a_bad_rate() {
+        let addr = start_server("/telemetry_http_rate_test");
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET /stream?rate=500 HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: x\r\n\r\n").unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+        assert!(response.ends_with("rate must be 1 to 50 hz\n"));
+    }
+}
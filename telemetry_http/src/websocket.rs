@@ -0,0 +1,256 @@
+// Just enough of RFC 6455 to push text frames to a browser: the handshake, unmasked server frames,
+// and telling when the client closes.
+
+use std::io::{self, Read, Write};
+
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+const FIN: u8 = 0x80;
+// What a control frame can carry, the stream takes no data frames
+const MAX_CLIENT_PAYLOAD_LEN: usize = 125;
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in msg.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6)
+            };
+
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (hi, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *hi = hi.wrapping_add(x);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (chunk, hi) in digest.chunks_exact_mut(4).zip(h) {
+        chunk.copy_from_slice(&hi.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::new();
+
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &byte)| n | (byte as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            }
+            else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+// Sec-WebSocket-Accept for the client's Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key.trim(), HANDSHAKE_GUID).as_bytes()))
+}
+
+pub fn handshake_response(key: &str) -> String {
+    format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key))
+}
+
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![FIN | opcode];
+
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    frame.extend_from_slice(payload);
+    frame
+}
+
+pub fn text_frame(text: &str) -> Vec<u8> {
+    encode_frame(OPCODE_TEXT, text.as_bytes())
+}
+
+pub fn close_frame() -> Vec<u8> {
+    encode_frame(OPCODE_CLOSE, &[])
+}
+
+// Client frames, which are always masked
+pub struct FrameReader {
+    buf: Vec<u8>
+}
+
+pub enum ClientFrame {
+    Close,
+    Ping(Vec<u8>),
+    // Anything else, the stream doesn't take input
+    Other
+}
+
+impl FrameReader {
+    pub fn new() -> FrameReader {
+        FrameReader { buf: Vec::new() }
+    }
+
+    // Reads what's available, WouldBlock/TimedOut when nothing is
+    pub fn read<R: Read>(&mut self, reader: &mut R) -> io::Result<Vec<ClientFrame>> {
+        let mut chunk = [0u8; 512];
+        let n = reader.read(&mut chunk)?;
+
+        if n == 0 {
+            return Ok(vec![ClientFrame::Close]);
+        }
+
+        self.buf.extend_from_slice(&chunk[..n]);
+
+        let mut frames = Vec::new();
+        while let Some((frame, len)) = self.parse() {
+            self.buf.drain(..len);
+            frames.push(frame);
+        }
+
+        Ok(frames)
+    }
+
+    fn parse(&self) -> Option<(ClientFrame, usize)> {
+        let buf = &self.buf;
+        if buf.len() < 2 {
+            return None;
+        }
+
+        let opcode = buf[0] & 0x0f;
+        let masked = buf[1] & 0x80 != 0;
+
+        let (payload_len, mut pos) = match buf[1] & 0x7f {
+            126 => (u16::from_be_bytes(buf.get(2..4)?.try_into().unwrap()) as usize, 4),
+            127 => (u64::from_be_bytes(buf.get(2..10)?.try_into().unwrap()) as usize, 10),
+            len => (len as usize, 2)
+        };
+
+        // Nothing we'd take is that long, close rather than buffering it
+        if payload_len > MAX_CLIENT_PAYLOAD_LEN {
+            return Some((ClientFrame::Close, buf.len()));
+        }
+
+        let mask = if masked {
+            let mask: [u8; 4] = buf.get(pos..pos + 4)?.try_into().unwrap();
+            pos += 4;
+            mask
+        }
+        else {
+            [0; 4]
+        };
+
+        let payload: Vec<u8> = buf.get(pos..pos.checked_add(payload_len)?)?.iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+
+        let frame = match opcode {
+            OPCODE_CLOSE => ClientFrame::Close,
+            OPCODE_PING => ClientFrame::Ping(payload),
+            _ => ClientFrame::Other
+        };
+
+        Some((frame, pos + payload_len))
+    }
+}
+
+pub fn pong<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&encode_frame(OPCODE_PONG, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_the_rfc_sample_key() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+    }
+
+    #[test]
+    fn reads_masked_client_frames() {
+        // "Hello" masked, from RFC 6455 5.7, then a close frame split over two reads
+        let hello = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let close = [0x88, 0x80, 1, 2, 3, 4];
+
+        let mut reader = FrameReader::new();
+        let frames = reader.read(&mut &[&hello[..], &close[..3]].concat()[..]).unwrap();
+        assert!(matches!(frames[..], [ClientFrame::Other]));
+
+        let frames = reader.read(&mut &close[3..]).unwrap();
+        assert!(matches!(frames[..], [ClientFrame::Close]));
+    }
+
+    #[test]
+    fn closes_on_long_client_frames() {
+        // A masked 64 KiB binary frame, the close comes with its header
+        let mut reader = FrameReader::new();
+        let frames = reader.read(&mut &[0x82, 0xff, 0, 0, 0, 0, 0, 1, 0, 0, 1, 2, 3, 4][..]).unwrap();
+        assert!(matches!(frames[..], [ClientFrame::Close]));
+
+        // Ping payloads are capped too
+        let mut reader = FrameReader::new();
+        let frames = reader.read(&mut &[&[0x89, 0xfe, 0, 126, 1, 2, 3, 4][..], &[0; 126][..]].concat()[..]).unwrap();
+        assert!(matches!(frames[..], [ClientFrame::Close]));
+
+        let mut reader = FrameReader::new();
+        let frames = reader.read(&mut &[&[0x89, 0xfd, 1, 2, 3, 4][..], &[0; 125][..]].concat()[..]).unwrap();
+        assert!(matches!(&frames[..], [ClientFrame::Ping(payload)] if payload.len() == 125));
+    }
+
+    #[test]
+    fn encodes_long_text_frames() {
+        let frame = text_frame(&"x".repeat(300));
+        assert_eq!(&frame[..4], &[0x81, 126, 0x01, 0x2c]);
+        assert_eq!(frame.len(), 4 + 300);
+    }
+}
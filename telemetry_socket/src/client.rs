@@ -0,0 +1,304 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+use nix::sys::timerfd::TimerFd;
+use shm_segment::{FieldType, ShmField, ShmStr};
+
+const MAX_REQUEST_LEN: usize = 1024;
+const MAX_RATE_HZ: u32 = 100;
+
+// Frames waiting for a slow reader, the oldest is dropped to make room for a new one
+const MAX_QUEUED_FRAMES: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Json,
+    Binary
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Subscription {
+    pub format: Format,
+    pub period: Duration,
+    // Indexes in the server's field table, in the order the client asked for them
+    pub fields: Vec<usize>
+}
+
+// subscribe <json|binary> <rate_hz> [signal ...], every signal when none is given
+pub fn parse_request(line: &str, fields: &[ShmField]) -> Result<Subscription, String> {
+    let mut words = line.split_ascii_whitespace();
+
+    if words.next() != Some("subscribe") {
+        return Err(String::from("expected subscribe <json|binary> <rate_hz> [signal ...]"));
+    }
+
+    let format = match words.next() {
+        Some("json") => Format::Json,
+        Some("binary") => Format::Binary,
+        _ => return Err(String::from("format must be json or binary"))
+    };
+
+    let rate_hz = words.next()
+        .and_then(|rate| rate.parse::<u32>().ok())
+        .filter(|rate| (1..=MAX_RATE_HZ).contains(rate))
+        .ok_or_else(|| format!("rate must be 1 to {} hz", MAX_RATE_HZ))?;
+
+    let mut subscribed = words
+        .map(|name| fields.iter().position(|field| field.name == name).ok_or_else(|| format!("unknown signal {}", name)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if subscribed.is_empty() {
+        subscribed = (0..fields.len()).collect();
+    }
+
+    Ok(Subscription {
+        format,
+        period: Duration::from_secs(1) / rate_hz,
+        fields: subscribed
+    })
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c)
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+pub fn type_name(field_type: FieldType) -> String {
+    format!("{:?}", field_type).to_ascii_lowercase()
+}
+
+// The first line a subscriber gets, naming the fields of every frame in order
+pub fn encode_fields_line(fields: &[&ShmField]) -> Vec<u8> {
+    let fields = fields.iter()
+        .map(|field| format!("{{\"name\":{},\"type\":\"{}\",\"unit\":{}}}",
+                             json_string(field.name), type_name(field.field_type), json_string(field.unit)))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!("{{\"fields\":[{}]}}\n", fields).into_bytes()
+}
+
+pub fn encode_error_line(error: &str) -> Vec<u8> {
+    format!("{{\"error\":{}}}\n", json_string(error)).into_bytes()
+}
+
+fn json_value(field_type: FieldType, bytes: &[u8]) -> String {
+    match field_type {
+        FieldType::Bool => (bytes[0] != 0).to_string(),
+        FieldType::U8 => bytes[0].to_string(),
+        FieldType::I8 => (bytes[0] as i8).to_string(),
+        FieldType::U16 => u16::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+        FieldType::I16 => i16::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+        FieldType::U32 => u32::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+        FieldType::I32 => i32::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+        FieldType::U64 => u64::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+        FieldType::I64 => i64::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+        FieldType::F32 => json_float(f32::from_ne_bytes(bytes.try_into().unwrap()) as f64),
+        FieldType::F64 => json_float(f64::from_ne_bytes(bytes.try_into().unwrap())),
+        FieldType::Str => json_string(ShmStr::from_bytes(bytes).as_str())
+    }
+}
+
+fn json_float(value: f64) -> String {
+    if value.is_finite() { value.to_string() } else { String::from("null") }
+}
+
+// {"seq":..,"timestamp_ns":..,"<field>":<value>,..}\n
+pub fn encode_json_frame(seq: u64, timestamp_ns: u64, values: &[(&ShmField, &[u8])]) -> Vec<u8> {
+    let mut out = format!("{{\"seq\":{},\"timestamp_ns\":{}", seq, timestamp_ns);
+
+    for (field, bytes) in values {
+        let _ = write!(out, ",{}:{}", json_string(field.name), json_value(field.field_type, bytes));
+    }
+
+    out.push_str("}\n");
+    out.into_bytes()
+}
+
+// u32 length of the rest | u64 seq | u64 timestamp_ns | the values packed in order, little endian
+pub fn encode_binary_frame(seq: u64, timestamp_ns: u64, values: &[(&ShmField, &[u8])]) -> Vec<u8> {
+    let len = 2 * size_of::<u64>() + values.iter().map(|(_, bytes)| bytes.len()).sum::<usize>();
+
+    let mut out = Vec::with_capacity(size_of::<u32>() + len);
+    out.extend_from_slice(&(len as u32).to_le_bytes());
+    out.extend_from_slice(&seq.to_le_bytes());
+    out.extend_from_slice(&timestamp_ns.to_le_bytes());
+
+    for (_, bytes) in values {
+        // Native is little endian on every target we run on
+        out.extend_from_slice(bytes);
+    }
+
+    out
+}
+
+pub struct Client {
+    pub stream: UnixStream,
+    request: Vec<u8>,
+    pub subscription: Option<Subscription>,
+    pub timer: Option<TimerFd>,
+    // Frames along with whether they may be dropped, the fields line may not
+    queue: VecDeque<(Vec<u8>, bool)>,
+    // Bytes of the front frame already sent
+    sent: usize,
+    pub seq: u64,
+    pub dropped: u64,
+    // Whether the client socket is polled for EPOLLOUT
+    pub waiting_writable: bool
+}
+
+impl Client {
+    pub fn new(stream: UnixStream) -> Client {
+        Client {
+            stream,
+            request: Vec::new(),
+            subscription: None,
+            timer: None,
+            queue: VecDeque::new(),
+            sent: 0,
+            seq: 0,
+            dropped: 0,
+            waiting_writable: false
+        }
+    }
+
+    // Reads what the client sent, returns its request line once complete.
+    // Err when the client hung up or sent garbage.
+    pub fn read_request(&mut self) -> io::Result<Option<String>> {
+        let mut buf = [0u8; 256];
+
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => {
+                    // Anything after the request is ignored
+                    if self.subscription.is_none() {
+                        self.request.extend_from_slice(&buf[..n]);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e)
+            }
+        }
+
+        if self.subscription.is_some() {
+            return Ok(None);
+        }
+
+        match self.request.iter().position(|&b| b == b'\n') {
+            Some(end) => {
+                let line = String::from_utf8_lossy(&self.request[..end]).into_owned();
+                self.request.clear();
+                Ok(Some(line))
+            }
+            None if self.request.len() > MAX_REQUEST_LEN => Err(io::Error::from(io::ErrorKind::InvalidData)),
+            None => Ok(None)
+        }
+    }
+
+    pub fn queue(&mut self, frame: Vec<u8>, droppable: bool) {
+        if self.queue.len() >= MAX_QUEUED_FRAMES {
+            // The front frame can't be dropped once partly sent
+            let skip = if self.sent > 0 { 1 } else { 0 };
+
+            if let Some(i) = self.queue.iter().skip(skip).position(|(_, droppable)| *droppable) {
+                self.queue.remove(skip + i);
+                self.dropped += 1;
+            }
+        }
+
+        self.queue.push_back((frame, droppable));
+    }
+
+    // Writes as much as the socket takes without blocking, returns true once the queue is empty
+    pub fn flush(&mut self) -> io::Result<bool> {
+        while let Some((frame, _)) = self.queue.front() {
+            match self.stream.write(&frame[self.sent..]) {
+                Ok(n) => {
+                    self.sent += n;
+                    if self.sent == frame.len() {
+                        self.queue.pop_front();
+                        self.sent = 0;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e)
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Vec<ShmField> {
+        vec![
+            ShmField { name: "rpm", offset: 0, field_type: FieldType::U16, unit: "rpm" },
+            ShmField { name: "vehicle_off", offset: 2, field_type: FieldType::Bool, unit: "" }
+        ]
+    }
+
+    #[test]
+    fn parses_subscribe_requests() {
+        assert_eq!(parse_request("subscribe json 10 vehicle_off rpm", &fields()), Ok(Subscription {
+            format: Format::Json,
+            period: Duration::from_millis(100),
+            fields: vec![1, 0]
+        }));
+        assert_eq!(parse_request("subscribe binary 1", &fields()).unwrap().fields, vec![0, 1]);
+
+        assert_eq!(parse_request("subscribe json 10 speed", &fields()), Err(String::from("unknown signal speed")));
+        assert_eq!(parse_request("subscribe json 0", &fields()), Err(String::from("rate must be 1 to 100 hz")));
+        assert_eq!(parse_request("subscribe xml 10", &fields()), Err(String::from("format must be json or binary")));
+        assert!(parse_request("unsubscribe", &fields()).is_err());
+    }
+
+    #[test]
+    fn encodes_frames() {
+        let fields = fields();
+        let values: Vec<(&ShmField, &[u8])> = vec![(&fields[0], &[0xd2, 0x04]), (&fields[1], &[1])];
+
+        assert_eq!(String::from_utf8(encode_json_frame(3, 42, &values)).unwrap(),
+                   "{\"seq\":3,\"timestamp_ns\":42,\"rpm\":1234,\"vehicle_off\":true}\n");
+
+        assert_eq!(encode_binary_frame(3, 42, &values), [
+            &19u32.to_le_bytes()[..], &3u64.to_le_bytes(), &42u64.to_le_bytes(), &[0xd2, 0x04, 1]
+        ].concat());
+
+        assert_eq!(String::from_utf8(encode_fields_line(&[&fields[0]])).unwrap(),
+                   "{\"fields\":[{\"name\":\"rpm\",\"type\":\"u16\",\"unit\":\"rpm\"}]}\n");
+        assert_eq!(String::from_utf8(encode_error_line("unknown signal \"x\"")).unwrap(),
+                   "{\"error\":\"unknown signal \\\"x\\\"\"}\n");
+    }
+
+    #[test]
+    fn strings_stop_at_their_nul() {
+        let module = ShmField { name: "module", offset: 0, field_type: FieldType::Str, unit: "" };
+        let mut bytes = [0; shm_segment::SHM_STR_LEN];
+        bytes[..8].copy_from_slice(b"MAX-M10S");
+        let values: Vec<(&ShmField, &[u8])> = vec![(&module, &bytes)];
+
+        assert_eq!(String::from_utf8(encode_json_frame(0, 0, &values)).unwrap(),
+                   "{\"seq\":0,\"timestamp_ns\":0,\"module\":\"MAX-M10S\"}\n");
+        assert_eq!(String::from_utf8(encode_fields_line(&[&module])).unwrap(),
+                   "{\"fields\":[{\"name\":\"module\",\"type\":\"str\",\"unit\":\"\"}]}\n");
+    }
+}
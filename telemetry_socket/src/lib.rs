@@ -0,0 +1,442 @@
+// Streams a ShmData struct over a Unix stream socket, for the readers that can't map the shm segments.
+//
+// A client connects and sends one request line:
+//
+//     subscribe <json|binary> <rate_hz> [signal ...]
+//
+// naming the fields it wants, every field when none is given, at 1 to 100 hz.
+// It then gets a JSON line naming the fields, {"fields":[{"name":..,"type":"u16","unit":..},..]},
+// or {"error":..} before the server hangs up, followed by a frame every period:
+//
+// - json: {"seq":..,"timestamp_ns":..,"<field>":<value>,..} lines
+// - binary: u32 length of the rest | u64 seq | u64 timestamp_ns | the values packed in order, little endian
+//
+// seq counts the frames of the subscription, a gap means frames were dropped for a slow reader.
+// timestamp_ns is when the frame was taken, ns on CLOCK_MONOTONIC.
+//
+// The server never blocks its service's loop: it lives behind its own epoll fd,
+// and a reader that doesn't keep up loses its oldest frames instead of holding the newest ones back.
+
+mod client;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::fd::{AsFd, OwnedFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use log::{debug, info, warn};
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
+use nix::sys::socket::{setsockopt, sockopt};
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
+use shm_segment::{monotonic_ns, ShmData, ShmField};
+use crate::client::{encode_binary_frame, encode_error_line, encode_fields_line, encode_json_frame, parse_request, Client, Format};
+
+// Small enough that a slow reader gets fresh frames from our queue instead of stale ones from the kernel
+const CLIENT_SNDBUF: usize = 4096;
+
+const LISTENER_ID: u64 = u64::MAX;
+
+// Each client has a socket and a timer in the epoll set
+fn socket_id(client_id: u64) -> u64 {
+    client_id << 1
+}
+
+fn timer_id(client_id: u64) -> u64 {
+    client_id << 1 | 1
+}
+
+pub struct TelemetryServer<T: ShmData> {
+    path: PathBuf,
+    listener: UnixListener,
+    epoll: Epoll,
+    fields: Vec<ShmField>,
+    data: T,
+    clients: HashMap<u64, Client>,
+    next_client_id: u64
+}
+
+impl<T: ShmData> TelemetryServer<T> {
+    pub fn new(path: &str, data: &T) -> TelemetryServer<T> {
+        // Left over by a previous run
+        let _ = fs::remove_file(path);
+
+        let listener = UnixListener::bind(path)
+            .expect("bind telemetry socket");
+
+        listener.set_nonblocking(true)
+            .expect("nonblocking telemetry socket");
+
+        let epoll = Epoll::new(EpollCreateFlags::empty())
+            .expect("epoll");
+
+        epoll.add(&listener, EpollEvent::new(EpollFlags::EPOLLIN, LISTENER_ID))
+            .expect("epoll add telemetry socket");
+
+        TelemetryServer {
+            path: PathBuf::from(path),
+            listener,
+            epoll,
+            fields: T::fields(),
+            data: *data,
+            clients: HashMap::new(),
+            next_client_id: 0
+        }
+    }
+
+    // Readable whenever the server has something to do, handle_events should then be called
+    pub fn get_fd(&self) -> &OwnedFd {
+        &self.epoll.0
+    }
+
+    // The next frames are taken from data
+    pub fn update(&mut self, data: &T) {
+        self.data = *data;
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn handle_events(&mut self) {
+        let mut events = [EpollEvent::empty(); 16];
+
+        loop {
+            let n = self.epoll.wait(&mut events, EpollTimeout::ZERO)
+                .expect("epoll wait");
+
+            if n == 0 {
+                break;
+            }
+
+            for event in &events[..n] {
+                match event.data() {
+                    LISTENER_ID => self.accept(),
+                    id if id & 1 == 1 => self.handle_timer(id >> 1),
+                    id => self.handle_socket(id >> 1, event.events())
+                }
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("telemetry accept: {}", e);
+                    break;
+                }
+            };
+
+            stream.set_nonblocking(true)
+                .expect("nonblocking telemetry client");
+
+            if let Err(e) = setsockopt(&stream, sockopt::SndBuf, &CLIENT_SNDBUF) {
+                warn!("telemetry client SO_SNDBUF: {}", e);
+            }
+
+            let client_id = self.next_client_id;
+            self.next_client_id += 1;
+
+            self.epoll.add(&stream, EpollEvent::new(EpollFlags::EPOLLIN, socket_id(client_id)))
+                .expect("epoll add telemetry client");
+
+            debug!("telemetry client {} connected", client_id);
+            self.clients.insert(client_id, Client::new(stream));
+        }
+    }
+
+    fn handle_socket(&mut self, client_id: u64, events: EpollFlags) {
+        if events.contains(EpollFlags::EPOLLOUT) && self.clients.contains_key(&client_id) {
+            self.flush(client_id);
+        }
+
+        if !events.intersects(EpollFlags::EPOLLIN | EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR) {
+            return;
+        }
+
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+
+        let request = match client.read_request() {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(e) => {
+                debug!("telemetry client {} gone: {}", client_id, e);
+                self.close(client_id);
+                return;
+            }
+        };
+
+        match parse_request(&request, &self.fields) {
+            Ok(subscription) => {
+                let fields: Vec<_> = subscription.fields.iter().map(|&i| &self.fields[i]).collect();
+                client.queue(encode_fields_line(&fields), false);
+
+                let timer = TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::TFD_NONBLOCK)
+                    .expect("timerfd");
+
+                timer.set(Expiration::Interval(TimeSpec::from_duration(subscription.period)), TimerSetTimeFlags::empty())
+                    .expect("timerfd set");
+
+                self.epoll.add(&timer, EpollEvent::new(EpollFlags::EPOLLIN, timer_id(client_id)))
+                    .expect("epoll add telemetry client timer");
+
+                info!("telemetry client {} subscribed to '{}'", client_id, request.trim());
+                client.subscription = Some(subscription);
+                client.timer = Some(timer);
+                self.flush(client_id);
+            }
+            Err(error) => {
+                debug!("telemetry client {} bad request '{}': {}", client_id, request.trim(), error);
+                // Best effort, the client is closed either way
+                client.queue(encode_error_line(&error), false);
+                let _ = client.flush();
+                self.close(client_id);
+            }
+        }
+    }
+
+    fn handle_timer(&mut self, client_id: u64) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+
+        if let Some(timer) = &client.timer {
+            // Nonblocking, only clears the expirations
+            let _ = timer.wait();
+        }
+
+        let Some(subscription) = &client.subscription else {
+            return;
+        };
+
+        let values: Vec<_> = subscription.fields.iter()
+            .map(|&i| (&self.fields[i], field_bytes(&self.data, &self.fields[i])))
+            .collect();
+
+        let frame = match subscription.format {
+            Format::Json => encode_json_frame(client.seq, monotonic_ns(), &values),
+            Format::Binary => encode_binary_frame(client.seq, monotonic_ns(), &values)
+        };
+
+        client.seq += 1;
+        client.queue(frame, true);
+        self.flush(client_id);
+    }
+
+    fn flush(&mut self, client_id: u64) {
+        let client = self.clients.get_mut(&client_id).unwrap();
+
+        let done = match client.flush() {
+            Ok(done) => done,
+            Err(e) => {
+                debug!("telemetry client {} gone: {}", client_id, e);
+                self.close(client_id);
+                return;
+            }
+        };
+
+        // Only wait for the socket to be writable while there's something left to send
+        if done == client.waiting_writable {
+            let flags = if done { EpollFlags::EPOLLIN } else { EpollFlags::EPOLLIN | EpollFlags::EPOLLOUT };
+
+            self.epoll.modify(client.stream.as_fd(), &mut EpollEvent::new(flags, socket_id(client_id)))
+                .expect("epoll modify telemetry client");
+
+            client.waiting_writable = !done;
+        }
+    }
+
+    fn close(&mut self, client_id: u64) {
+        if let Some(client) = self.clients.remove(&client_id) {
+            if client.dropped > 0 {
+                info!("telemetry client {} closed, {} frames dropped", client_id, client.dropped);
+            }
+
+            let _ = self.epoll.delete(&client.stream);
+            if let Some(timer) = &client.timer {
+                let _ = self.epoll.delete(timer);
+            }
+        }
+    }
+}
+
+impl<T: ShmData> Drop for TelemetryServer<T> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Only the bytes of the field, the padding between fields isn't initialized
+fn field_bytes<'a, T: ShmData>(data: &'a T, field: &ShmField) -> &'a [u8] {
+    assert!(field.offset + field.field_type.size() <= size_of::<T>());
+
+    unsafe {
+        std::slice::from_raw_parts((data as *const T as *const u8).add(field.offset), field.field_type.size())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use shm_segment::shm_field;
+    use super::*;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct Sample {
+        rpm: u16,
+        vehicle_off: bool,
+        sample_ns: u64
+    }
+
+    impl ShmData for Sample {
+        const LAYOUT_VERSION: u32 = 1;
+
+        fn fields() -> Vec<ShmField> {
+            vec![
+                shm_field!(Sample, rpm, "rpm"),
+                shm_field!(Sample, vehicle_off, ""),
+                shm_field!(Sample, sample_ns, "ns")
+            ]
+        }
+    }
+
+    fn socket_path(name: &str) -> String {
+        format!("{}/telemetry_socket_{}_{}.sock", std::env::temp_dir().display(), name, std::process::id())
+    }
+
+    // What the service loop does, for a while
+    fn serve(server: &mut TelemetryServer<Sample>, duration: Duration) {
+        let started_at = Instant::now();
+
+        while started_at.elapsed() < duration {
+            server.handle_events();
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn streams_json_lines_of_the_subscribed_signals() {
+        let path = socket_path("json");
+        let mut server = TelemetryServer::new(&path, &Sample { rpm: 850, vehicle_off: false, sample_ns: 7 });
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"subscribe json 50 vehicle_off rpm\n").unwrap();
+        serve(&mut server, Duration::from_millis(30));
+
+        server.update(&Sample { rpm: 3000, vehicle_off: false, sample_ns: 8 });
+        serve(&mut server, Duration::from_millis(100));
+
+        let mut lines = BufReader::new(client).lines();
+
+        assert_eq!(lines.next().unwrap().unwrap(),
+                   "{\"fields\":[{\"name\":\"vehicle_off\",\"type\":\"bool\",\"unit\":\"\"},{\"name\":\"rpm\",\"type\":\"u16\",\"unit\":\"rpm\"}]}");
+
+        let frames: Vec<_> = lines.take(3).map(|line| line.unwrap()).collect();
+        assert!(frames[0].starts_with("{\"seq\":0,\"timestamp_ns\":"), "{}", frames[0]);
+        assert!(frames[0].ends_with(",\"vehicle_off\":false,\"rpm\":850}"), "{}", frames[0]);
+        assert!(frames[2].ends_with(",\"vehicle_off\":false,\"rpm\":3000}"), "{}", frames[2]);
+    }
+
+    #[test]
+    fn streams_length_prefixed_binary_frames() {
+        let path = socket_path("binary");
+        let mut server = TelemetryServer::new(&path, &Sample { rpm: 850, vehicle_off: true, sample_ns: 7 });
+
+        let client = UnixStream::connect(&path).unwrap();
+        (&client).write_all(b"subscribe binary 100\n").unwrap();
+        serve(&mut server, Duration::from_millis(50));
+
+        let mut reader = BufReader::new(client);
+        let mut fields_line = String::new();
+        reader.read_line(&mut fields_line).unwrap();
+        assert!(fields_line.contains("\"name\":\"sample_ns\",\"type\":\"u64\""), "{}", fields_line);
+
+        let mut frame = [0u8; 4 + 8 + 8 + 2 + 1 + 8];
+        reader.read_exact(&mut frame).unwrap();
+
+        assert_eq!(u32::from_le_bytes(frame[..4].try_into().unwrap()), 27);
+        assert_eq!(u64::from_le_bytes(frame[4..12].try_into().unwrap()), 0);
+        assert_eq!(&frame[20..], &[&850u16.to_le_bytes()[..], &[1], &7u64.to_le_bytes()].concat()[..]);
+    }
+
+    #[test]
+    fn refuses_unknown_signals() {
+        let path = socket_path("unknown");
+        let mut server = TelemetryServer::new(&path, &Sample::default());
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"subscribe json 10 rpm speed\n").unwrap();
+        serve(&mut server, Duration::from_millis(10));
+
+        let mut rsp = String::new();
+        client.read_to_string(&mut rsp).unwrap();
+        assert_eq!(rsp, "{\"error\":\"unknown signal speed\"}\n");
+        assert_eq!(server.client_count(), 0);
+    }
+
+    #[test]
+    fn drops_old_frames_for_a_slow_reader() {
+        let path = socket_path("slow");
+        let mut server = TelemetryServer::new(&path, &Sample::default());
+
+        let slow = UnixStream::connect(&path).unwrap();
+        (&slow).write_all(b"subscribe json 100\n").unwrap();
+
+        let mut fast = BufReader::new(UnixStream::connect(&path).unwrap());
+        fast.get_mut().write_all(b"subscribe json 100 rpm\n").unwrap();
+        fast.get_mut().set_nonblocking(true).unwrap();
+
+        // The slow reader doesn't read at all, the loop must keep going and the fast one keep getting frames
+        let mut fast_frames = 0;
+        let started_at = Instant::now();
+
+        while started_at.elapsed() < Duration::from_millis(1500) {
+            let handled_at = Instant::now();
+            server.handle_events();
+            assert!(handled_at.elapsed() < Duration::from_millis(50));
+
+            let mut line = String::new();
+            while fast.read_line(&mut line).is_ok_and(|n| n > 0) {
+                fast_frames += 1;
+                line.clear();
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert!(fast_frames > 100, "{} frames", fast_frames);
+
+        // Now reading, the slow one sees a gap in seq where frames were dropped
+        slow.set_nonblocking(true).unwrap();
+        let mut slow = BufReader::new(slow);
+        let mut seqs = Vec::new();
+        let started_at = Instant::now();
+
+        while started_at.elapsed() < Duration::from_millis(100) {
+            server.handle_events();
+
+            let mut line = String::new();
+            while slow.read_line(&mut line).is_ok_and(|n| n > 0) {
+                if let Some(seq) = line.strip_prefix("{\"seq\":") {
+                    seqs.push(seq.split(',').next().unwrap().parse::<u64>().unwrap());
+                }
+                line.clear();
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert!(seqs.windows(2).any(|pair| pair[1] > pair[0] + 1), "{:?}", seqs);
+        assert_eq!(server.client_count(), 2);
+    }
+}
@@ -0,0 +1,168 @@
+// Sends the metrics and lap times as one UDP datagram at a fixed rate, to a unicast or multicast address,
+// for dash and overlay apps on tablets of the same network. The layout is documented in packet.rs.
+//
+//     telemetry_udp --to 239.255.50.5:5505 --rate 20 --ttl 1
+
+mod packet;
+
+use std::env;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+use cli_args::arg_value;
+use log::{info, warn};
+use shm_client::{ChronoSnapshot, MetricsSnapshot, Snapshot, Source};
+use shm_segment::monotonic_ns;
+use crate::packet::encode_packet;
+
+const DEFAULT_TO_ADDR: &str = "239.255.50.5:5505";
+const DEFAULT_RATE_HZ: u32 = 20;
+const MAX_RATE_HZ: u32 = 100;
+// Stay on the local network
+const DEFAULT_MULTICAST_TTL: u32 = 1;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    let env = env_logger::Env::default()
+        .filter_or("RUST_LOG", "info");
+
+    env_logger::init_from_env(env);
+
+    let to: SocketAddr = arg_value(&args, "--to").unwrap_or(DEFAULT_TO_ADDR)
+        .parse().expect("--to address:port");
+
+    let rate_hz = arg_value(&args, "--rate")
+        .map_or(DEFAULT_RATE_HZ, |hz| hz.parse().expect("--rate hz"));
+    assert!((1..=MAX_RATE_HZ).contains(&rate_hz), "--rate must be 1 to {} hz", MAX_RATE_HZ);
+
+    let ttl = arg_value(&args, "--ttl")
+        .map_or(DEFAULT_MULTICAST_TTL, |ttl| ttl.parse().expect("--ttl hops"));
+
+    let socket = open_socket(to, ttl)
+        .expect("udp socket");
+
+    info!("Sending to {} at {} hz", to, rate_hz);
+
+    let mut sender = Sender::new(socket, to, MetricsSnapshot::SHM_NAME, ChronoSnapshot::SHM_NAME);
+    let period = Duration::from_secs(1) / rate_hz;
+    let mut next_send_at = Instant::now();
+
+    loop {
+        if let Err(e) = sender.send() {
+            // e.g. no route while the Wi-Fi is down, keep going
+            warn!("send to {}: {}", to, e);
+        }
+
+        // Skip the ticks we missed rather than bursting
+        next_send_at += period;
+        let now = Instant::now();
+        if next_send_at < now {
+            next_send_at = now + period;
+        }
+        thread::sleep(next_send_at - now);
+    }
+}
+
+fn open_socket(to: SocketAddr, ttl: u32) -> io::Result<UdpSocket> {
+    let socket = match to {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0")?,
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0")?
+    };
+
+    if to.ip().is_multicast() {
+        match to {
+            SocketAddr::V4(_) => {
+                socket.set_multicast_ttl_v4(ttl)?;
+                // Let a listener on the Pi itself see the packets too
+                socket.set_multicast_loop_v4(true)?;
+            }
+            SocketAddr::V6(_) => socket.set_multicast_loop_v6(true)?
+        }
+    }
+    else if to.is_ipv4() {
+        // Harmless for unicast, needed for a subnet broadcast address
+        socket.set_broadcast(true)?;
+    }
+
+    Ok(socket)
+}
+
+struct Sender {
+    socket: UdpSocket,
+    to: SocketAddr,
+    metrics: Source<MetricsSnapshot>,
+    chrono: Source<ChronoSnapshot>,
+    seq: u32
+}
+
+impl Sender {
+    fn new(socket: UdpSocket, to: SocketAddr, metrics_shm_name: &'static str, chrono_shm_name: &'static str) -> Sender {
+        Sender {
+            socket,
+            to,
+            metrics: Source::new(metrics_shm_name),
+            chrono: Source::new(chrono_shm_name),
+            seq: 0
+        }
+    }
+
+    // Sends one packet with what the segments hold now
+    fn send(&mut self) -> io::Result<()> {
+        let now_ns = monotonic_ns();
+
+        // A segment left behind by a hung service isn't live
+        let metrics = self.metrics.read().filter(|metrics| metrics.is_live(now_ns));
+        let chrono = self.chrono.read();
+
+        let packet = encode_packet(self.seq, now_ns, metrics.as_ref(), chrono.as_ref());
+        self.seq = self.seq.wrapping_add(1);
+
+        self.socket.send_to(&packet, self.to)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shm_client::test_fixtures::chrono;
+    use shm_segment::ShmSegment;
+    use crate::packet::{FLAG_CHRONO, PACKET_LEN};
+    use super::*;
+
+    #[test]
+    fn a_local_listener_gets_the_packets() {
+        const NAME: &str = "/telemetry_udp_chrono_test";
+
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let to = listener.local_addr().unwrap();
+
+        let mut sender = Sender::new(open_socket(to, DEFAULT_MULTICAST_TTL).unwrap(), to, "/telemetry_udp_no_metrics_test", NAME);
+        let mut buf = [0u8; 1500];
+
+        // Nothing running, zeros
+        sender.send().unwrap();
+        let n = listener.recv(&mut buf).unwrap();
+        assert_eq!(n, PACKET_LEN);
+        assert_eq!(&buf[4..12], &[2, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut chrono = chrono();
+        let mut shm = ShmSegment::new(NAME, &chrono);
+
+        sender.send().unwrap();
+        listener.recv(&mut buf).unwrap();
+        assert_eq!(u16::from_le_bytes([buf[6], buf[7]]), FLAG_CHRONO);
+        assert_eq!(u32::from_le_bytes(buf[8..12].try_into().unwrap()), 1);
+        assert_eq!(buf[43], 1);
+        assert_eq!(u32::from_le_bytes(buf[44..48].try_into().unwrap()), 1234);
+        assert_eq!(u16::from_le_bytes([buf[62], buf[63]]), 4);
+
+        chrono.current_lap_n = 5;
+        shm.publish(&chrono);
+        sender.send().unwrap();
+        listener.recv(&mut buf).unwrap();
+        assert_eq!(u16::from_le_bytes([buf[62], buf[63]]), 5);
+    }
+}
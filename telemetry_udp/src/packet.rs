@@ -0,0 +1,138 @@
+// The datagram, 64 bytes, little endian, no padding:
+//
+// offset size
+//      0    4  magic "MX5U"
+//      4    2  u16 PACKET_VERSION
+//      6    2  u16 flags, FLAG_METRICS | FLAG_CHRONO when that part holds live values, zeros otherwise
+//      8    4  u32 seq, +1 every packet
+//     12    8  u64 timestamp_ns, when it was sent, ns on the sender's CLOCK_MONOTONIC
+//     20    2  u16 rpm
+//     22    2  u16 speed_kmh
+//     24    2  i16 engine_coolant_temp_c
+//     26    2  i16 intake_air_temp_c
+//     28    8  u16 fl_speed_kmh, fr_speed_kmh, rl_speed_kmh, rr_speed_kmh
+//     36    1  u8 accelerator_pedal_position_pct
+//     37    1  u8 calculated_engine_load_pct
+//     38    1  u8 throttle_valve_position_pct
+//     39    1  u8 fuel_level_pct
+//     40    1  u8 brakes_pct
+//     41    1  u8 vehicle_off, 0 or 1
+//     42    1  u8 link_state, see LinkState
+//     43    1  u8 timing_state, 1 armed, 2 degraded, from the chrono part (since version 2)
+//     44    4  u32 best_lap_time, tenths of a second
+//     48    4  u32 previous_lap_time
+//     52    4  u32 current_lap_time
+//     56    4  i32 previous_sector_delta_time
+//     60    2  u16 best_lap_n
+//     62    2  u16 current_lap_n
+//
+// Fields are only ever appended or take a reserved byte, with a new PACKET_VERSION;
+// readers should accept longer packets.
+
+use shm_client::{ChronoSnapshot, MetricsSnapshot};
+
+pub const PACKET_MAGIC: [u8; 4] = *b"MX5U";
+pub const PACKET_VERSION: u16 = 2;
+pub const PACKET_LEN: usize = 64;
+
+pub const FLAG_METRICS: u16 = 1 << 0;
+pub const FLAG_CHRONO: u16 = 1 << 1;
+
+pub fn encode_packet(seq: u32, timestamp_ns: u64, metrics: Option<&MetricsSnapshot>, chrono: Option<&ChronoSnapshot>) -> Vec<u8> {
+    let mut flags = 0;
+    if metrics.is_some() {
+        flags |= FLAG_METRICS;
+    }
+    if chrono.is_some() {
+        flags |= FLAG_CHRONO;
+    }
+
+    let mut out = Vec::with_capacity(PACKET_LEN);
+    out.extend_from_slice(&PACKET_MAGIC);
+    out.extend_from_slice(&PACKET_VERSION.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&seq.to_le_bytes());
+    out.extend_from_slice(&timestamp_ns.to_le_bytes());
+
+    let m = metrics.copied().unwrap_or_default();
+    let c = chrono.copied().unwrap_or_default();
+    for value in [m.rpm, m.speed_kmh] {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in [m.engine_coolant_temp_c, m.intake_air_temp_c] {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in [m.fl_speed_kmh, m.fr_speed_kmh, m.rl_speed_kmh, m.rr_speed_kmh] {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    out.extend_from_slice(&[
+        m.accelerator_pedal_position_pct,
+        m.calculated_engine_load_pct,
+        m.throttle_valve_position_pct,
+        m.fuel_level_pct,
+        m.brakes_pct,
+        m.vehicle_off as u8,
+        m.link_state,
+        c.timing_state
+    ]);
+
+    for value in [c.best_lap_time, c.previous_lap_time, c.current_lap_time] {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    out.extend_from_slice(&c.previous_sector_delta_time.to_le_bytes());
+    for value in [c.best_lap_n, c.current_lap_n] {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    debug_assert_eq!(out.len(), PACKET_LEN);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_sit_at_their_documented_offsets() {
+        let metrics = MetricsSnapshot {
+            rpm: 3250,
+            speed_kmh: 87,
+            engine_coolant_temp_c: -5,
+            rr_speed_kmh: 88,
+            fuel_level_pct: 42,
+            vehicle_off: true,
+            link_state: 3,
+            ..MetricsSnapshot::default()
+        };
+        let chrono = ChronoSnapshot {
+            current_lap_time: 1234,
+            previous_sector_delta_time: -7,
+            current_lap_n: 4,
+            timing_state: 2,
+            ..ChronoSnapshot::default()
+        };
+
+        let packet = encode_packet(7, 0x0102030405060708, Some(&metrics), Some(&chrono));
+
+        assert_eq!(packet.len(), PACKET_LEN);
+        assert_eq!(&packet[0..4], b"MX5U");
+        assert_eq!(&packet[4..8], &[2, 0, 3, 0]);
+        assert_eq!(&packet[8..12], &7u32.to_le_bytes());
+        assert_eq!(&packet[12..20], &0x0102030405060708u64.to_le_bytes());
+        assert_eq!(&packet[20..24], &[0xb2, 0x0c, 87, 0]);
+        assert_eq!(&packet[24..26], &(-5i16).to_le_bytes());
+        assert_eq!(&packet[34..36], &88u16.to_le_bytes());
+        assert_eq!(&packet[39..44], &[42, 0, 1, 3, 2]);
+        assert_eq!(&packet[52..56], &1234u32.to_le_bytes());
+        assert_eq!(&packet[56..60], &(-7i32).to_le_bytes());
+        assert_eq!(&packet[62..64], &4u16.to_le_bytes());
+    }
+
+    #[test]
+    fn missing_parts_are_zeros_and_flagged() {
+        let packet = encode_packet(0, 0, None, Some(&ChronoSnapshot::default()));
+
+        assert_eq!(u16::from_le_bytes([packet[6], packet[7]]), FLAG_CHRONO);
+        assert!(packet[20..].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,351 @@
+// The u-blox configuration keys the service knows about, with what they may be set to,
+// and a builder for the CFG-VALSET, VALGET and VALDEL msgs carrying them.
+// Names and ids are those of the u-blox M10 interface description.
+
+use std::fmt;
+use crate::ubx_messages::{cfg_value_size, CfgGetLayer, CfgValdel, CfgValget, CfgValset, UbxMessage};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CfgValues {
+    Bool,
+    // Inclusive, signed keys are negative in the two's complement of their size
+    Range { min: i64, max: i64 },
+    // Named constants
+    Enum(&'static [(&'static str, u64)])
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CfgKey {
+    pub id: u32,
+    pub name: &'static str,
+    pub values: CfgValues
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum CfgError {
+    UnknownKey(String),
+    BadValue { key: &'static str, value: String },
+    OutOfRange { key: &'static str, value: i64 }
+}
+
+impl fmt::Display for CfgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CfgError::UnknownKey(name) => write!(f, "unknown cfg key {}", name),
+            CfgError::BadValue { key, value } => write!(f, "{} can't be {}", key, value),
+            CfgError::OutOfRange { key, value } => write!(f, "{} is out of range at {}", key, value)
+        }
+    }
+}
+
+impl std::error::Error for CfgError {}
+
+impl CfgKey {
+    // Bytes of its value, from the size bits of the id
+    pub fn size(&self) -> usize {
+        cfg_value_size(self.id).expect("cfg key size")
+    }
+
+    // The raw value as a number, sign extended for the keys that can be negative
+    fn number(&self, value: u64) -> i64 {
+        match self.values {
+            CfgValues::Range { min, .. } if min < 0 => {
+                let shift = 64 - self.size() * 8;
+                ((value << shift) as i64) >> shift
+            }
+            _ => value as i64
+        }
+    }
+
+    pub fn check(&self, value: u64) -> Result<(), CfgError> {
+        let valid = match self.values {
+            CfgValues::Bool => value <= 1,
+            CfgValues::Range { min, max } => (min..=max).contains(&self.number(value)),
+            CfgValues::Enum(constants) => constants.iter().any(|(_, constant)| *constant == value)
+        };
+
+        if valid { Ok(()) } else { Err(CfgError::OutOfRange { key: self.name, value: self.number(value) }) }
+    }
+
+    // A value as written in a config file: true or false, a number, or the name of a constant
+    pub fn parse_value(&self, value: &str) -> Result<u64, CfgError> {
+        let bad_value = || CfgError::BadValue { key: self.name, value: value.to_string() };
+
+        let raw = match self.values {
+            CfgValues::Bool => match value {
+                "true" | "1" => 1,
+                "false" | "0" => 0,
+                _ => return Err(bad_value())
+            },
+            CfgValues::Range { .. } => {
+                let number: i64 = value.parse().map_err(|_| bad_value())?;
+                // Truncated to the key's size, check catches what didn't fit
+                let mask = if self.size() == 8 { u64::MAX } else { (1 << (self.size() * 8)) - 1 };
+                let raw = number as u64 & mask;
+                if self.number(raw) != number {
+                    return Err(CfgError::OutOfRange { key: self.name, value: number });
+                }
+                raw
+            }
+            CfgValues::Enum(constants) => constants.iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(value))
+                .map(|(_, constant)| *constant)
+                .or_else(|| value.parse().ok())
+                .ok_or_else(bad_value)?
+        };
+
+        self.check(raw)?;
+        Ok(raw)
+    }
+
+    // For the logs, the constant's name along with its value
+    pub fn format(&self, value: u64) -> String {
+        match self.values {
+            CfgValues::Bool if value <= 1 => (value == 1).to_string(),
+            CfgValues::Enum(constants) => match constants.iter().find(|(_, constant)| *constant == value) {
+                Some((name, _)) => format!("{} ({})", name, value),
+                None => value.to_string()
+            },
+            _ => self.number(value).to_string()
+        }
+    }
+}
+
+pub fn cfg_key(name: &str) -> Result<CfgKey, CfgError> {
+    CFG_KEYS.iter()
+        .find(|key| key.name.eq_ignore_ascii_case(name))
+        .copied()
+        .ok_or_else(|| CfgError::UnknownKey(name.to_string()))
+}
+
+const fn key(id: u32, name: &'static str, values: CfgValues) -> CfgKey {
+    CfgKey { id, name, values }
+}
+
+const fn bool_key(id: u32, name: &'static str) -> CfgKey {
+    key(id, name, CfgValues::Bool)
+}
+
+const fn range_key(id: u32, name: &'static str, min: i64, max: i64) -> CfgKey {
+    key(id, name, CfgValues::Range { min, max })
+}
+
+const U1: (i64, i64) = (0, u8::MAX as i64);
+const U2: (i64, i64) = (0, u16::MAX as i64);
+const U4: (i64, i64) = (0, u32::MAX as i64);
+
+// Msgs per navigation solution on a port
+const fn msgout_key(id: u32, name: &'static str) -> CfgKey {
+    range_key(id, name, U1.0, U1.1)
+}
+
+const FIXMODES: &[(&str, u64)] = &[("2DONLY", 1), ("3DONLY", 2), ("AUTO", 3)];
+const DYNMODELS: &[(&str, u64)] = &[
+    ("PORT", 0), ("STAT", 2), ("PED", 3), ("AUTOMOT", 4), ("SEA", 5),
+    ("AIR1", 6), ("AIR2", 7), ("AIR4", 8), ("WRIST", 9), ("BIKE", 10)
+];
+const UTCSTANDARDS: &[(&str, u64)] = &[("AUTO", 0), ("USNO", 3), ("EU", 5), ("SU", 6), ("NTSC", 7)];
+const TIMEREFS: &[(&str, u64)] = &[("UTC", 0), ("GPS", 1), ("GLO", 2), ("BDS", 3), ("GAL", 4)];
+const STOPBITS: &[(&str, u64)] = &[("HALF", 0), ("ONE", 1), ("ONEHALF", 2), ("TWO", 3)];
+const DATABITS: &[(&str, u64)] = &[("EIGHT", 0), ("SEVEN", 1)];
+const PARITIES: &[(&str, u64)] = &[("NONE", 0), ("ODD", 1), ("EVEN", 2)];
+const ANTSETTINGS: &[(&str, u64)] = &[("UNKNOWN", 0), ("PASSIVE", 1), ("ACTIVE", 2)];
+const PULSE_DEFS: &[(&str, u64)] = &[("PERIOD", 0), ("FREQ", 1)];
+const PULSE_LENGTH_DEFS: &[(&str, u64)] = &[("RATIO", 0), ("LENGTH", 1)];
+
+pub const CFG_NAVSPG_FIXMODE: CfgKey = key(0x20110011, "CFG-NAVSPG-FIXMODE", CfgValues::Enum(FIXMODES));
+pub const CFG_NAVSPG_DYNMODEL: CfgKey = key(0x20110021, "CFG-NAVSPG-DYNMODEL", CfgValues::Enum(DYNMODELS));
+pub const CFG_RATE_MEAS: CfgKey = range_key(0x30210001, "CFG-RATE-MEAS", 25, U2.1);
+pub const CFG_RATE_NAV: CfgKey = range_key(0x30210002, "CFG-RATE-NAV", 1, 128);
+pub const CFG_MSGOUT_UBX_NAV_PVT_UART1: CfgKey = msgout_key(0x20910007, "CFG-MSGOUT-UBX_NAV_PVT_UART1");
+pub const CFG_MSGOUT_UBX_NAV_PVT_USB: CfgKey = msgout_key(0x20910009, "CFG-MSGOUT-UBX_NAV_PVT_USB");
+pub const CFG_USBOUTPROT_UBX: CfgKey = bool_key(0x10780001, "CFG-USBOUTPROT-UBX");
+pub const CFG_USBOUTPROT_NMEA: CfgKey = bool_key(0x10780002, "CFG-USBOUTPROT-NMEA");
+pub const CFG_UART1_BAUDRATE: CfgKey = range_key(0x40520001, "CFG-UART1-BAUDRATE", 4800, 921_600);
+pub const CFG_UART1OUTPROT_UBX: CfgKey = bool_key(0x10740001, "CFG-UART1OUTPROT-UBX");
+pub const CFG_UART1OUTPROT_NMEA: CfgKey = bool_key(0x10740002, "CFG-UART1OUTPROT-NMEA");
+
+pub const CFG_KEYS: &[CfgKey] = &[
+    // NAVSPG, the navigation engine
+    CFG_NAVSPG_FIXMODE,
+    bool_key(0x10110013, "CFG-NAVSPG-INIFIX3D"),
+    key(0x2011001c, "CFG-NAVSPG-UTCSTANDARD", CfgValues::Enum(UTCSTANDARDS)),
+    CFG_NAVSPG_DYNMODEL,
+    // deg
+    range_key(0x201100a4, "CFG-NAVSPG-INFIL_MINELEV", -90, 90),
+    range_key(0x201100aa, "CFG-NAVSPG-INFIL_NCNOTHRS", 0, 63),
+    // 0.1, m
+    range_key(0x301100b1, "CFG-NAVSPG-OUTFIL_PDOP", U2.0, U2.1),
+    range_key(0x301100b3, "CFG-NAVSPG-OUTFIL_PACC", U2.0, U2.1),
+
+    // RATE, ms between measurements and measurements per solution
+    CFG_RATE_MEAS,
+    CFG_RATE_NAV,
+    key(0x20210003, "CFG-RATE-TIMEREF", CfgValues::Enum(TIMEREFS)),
+
+    // MSGOUT
+    CFG_MSGOUT_UBX_NAV_PVT_UART1,
+    CFG_MSGOUT_UBX_NAV_PVT_USB,
+    msgout_key(0x2091002a, "CFG-MSGOUT-UBX_NAV_POSLLH_UART1"),
+    msgout_key(0x2091002c, "CFG-MSGOUT-UBX_NAV_POSLLH_USB"),
+    msgout_key(0x2091001b, "CFG-MSGOUT-UBX_NAV_STATUS_UART1"),
+    msgout_key(0x2091001d, "CFG-MSGOUT-UBX_NAV_STATUS_USB"),
+
+    // USBOUTPROT
+    CFG_USBOUTPROT_UBX,
+    CFG_USBOUTPROT_NMEA,
+
+    // UART1
+    CFG_UART1_BAUDRATE,
+    key(0x20520002, "CFG-UART1-STOPBITS", CfgValues::Enum(STOPBITS)),
+    key(0x20520003, "CFG-UART1-DATABITS", CfgValues::Enum(DATABITS)),
+    key(0x20520004, "CFG-UART1-PARITY", CfgValues::Enum(PARITIES)),
+    bool_key(0x10520005, "CFG-UART1-ENABLED"),
+    bool_key(0x10730001, "CFG-UART1INPROT-UBX"),
+    bool_key(0x10730002, "CFG-UART1INPROT-NMEA"),
+    CFG_UART1OUTPROT_UBX,
+    CFG_UART1OUTPROT_NMEA,
+
+    // SIGNAL, the constellations and their bands
+    bool_key(0x1031001f, "CFG-SIGNAL-GPS_ENA"),
+    bool_key(0x10310001, "CFG-SIGNAL-GPS_L1CA_ENA"),
+    bool_key(0x10310020, "CFG-SIGNAL-SBAS_ENA"),
+    bool_key(0x10310021, "CFG-SIGNAL-GAL_ENA"),
+    bool_key(0x10310007, "CFG-SIGNAL-GAL_E1_ENA"),
+    bool_key(0x10310022, "CFG-SIGNAL-BDS_ENA"),
+    bool_key(0x1031000d, "CFG-SIGNAL-BDS_B1_ENA"),
+    bool_key(0x10310024, "CFG-SIGNAL-QZSS_ENA"),
+    bool_key(0x10310025, "CFG-SIGNAL-GLO_ENA"),
+    bool_key(0x10310018, "CFG-SIGNAL-GLO_L1_ENA"),
+
+    // ITFM, jamming and interference monitor
+    bool_key(0x1041000d, "CFG-ITFM-ENABLE"),
+    range_key(0x20410001, "CFG-ITFM-BBTHRESHOLD", 0, 15),
+    range_key(0x20410002, "CFG-ITFM-CWTHRESHOLD", 0, 31),
+    key(0x20410010, "CFG-ITFM-ANTSETTING", CfgValues::Enum(ANTSETTINGS)),
+
+    // TP, the time pulse, us
+    key(0x20050023, "CFG-TP-PULSE_DEF", CfgValues::Enum(PULSE_DEFS)),
+    key(0x20050030, "CFG-TP-PULSE_LENGTH_DEF", CfgValues::Enum(PULSE_LENGTH_DEFS)),
+    range_key(0x40050002, "CFG-TP-PERIOD_TP1", U4.0, U4.1),
+    range_key(0x40050003, "CFG-TP-PERIOD_LOCK_TP1", U4.0, U4.1),
+    range_key(0x40050004, "CFG-TP-LEN_TP1", U4.0, U4.1),
+    range_key(0x40050005, "CFG-TP-LEN_LOCK_TP1", U4.0, U4.1),
+    bool_key(0x10050007, "CFG-TP-TP1_ENA"),
+    bool_key(0x10050008, "CFG-TP-SYNC_GNSS_TP1"),
+    bool_key(0x10050009, "CFG-TP-USE_LOCKED_TP1"),
+    bool_key(0x1005000a, "CFG-TP-ALIGN_TO_TOW_TP1"),
+    bool_key(0x1005000b, "CFG-TP-POL_TP1"),
+    key(0x2005000c, "CFG-TP-TIMEGRID_TP1", CfgValues::Enum(TIMEREFS))
+];
+
+// Collects checked key values, then encodes them for any layer
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct CfgBuilder {
+    items: Vec<(CfgKey, u64)>
+}
+
+impl CfgBuilder {
+    pub fn new() -> CfgBuilder {
+        CfgBuilder::default()
+    }
+
+    pub fn set(&mut self, key: CfgKey, value: u64) -> Result<&mut CfgBuilder, CfgError> {
+        key.check(value)?;
+        self.items.push((key, value));
+        Ok(self)
+    }
+
+    // A key to read or delete, VALGET and VALDEL don't take values
+    pub fn key(&mut self, key: CfgKey) -> &mut CfgBuilder {
+        self.items.push((key, 0));
+        self
+    }
+
+    pub fn items(&self) -> &[(CfgKey, u64)] {
+        &self.items
+    }
+
+    fn ids(&self) -> Vec<u32> {
+        self.items.iter().map(|(key, _)| key.id).collect()
+    }
+
+    // Layers is a mask of CFG_LAYER_*
+    pub fn valset(&self, layers: u8) -> Vec<u8> {
+        let items = self.items.iter().map(|(key, value)| (key.id, *value)).collect();
+        CfgValset { layers, items }.encode()
+    }
+
+    pub fn valget(&self, layer: CfgGetLayer) -> Vec<u8> {
+        CfgValget::poll(layer, 0, &self.ids())
+    }
+
+    // Only CFG_LAYER_BBR and CFG_LAYER_FLASH
+    pub fn valdel(&self, layers: u8) -> Vec<u8> {
+        CfgValdel { layers, keys: self.ids() }.encode()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ubx::encode_frame;
+    use crate::ubx_messages::{CFG_LAYER_BBR, CFG_LAYER_FLASH, CFG_LAYER_RAM};
+    use super::*;
+
+    #[test]
+    fn keys_agree_with_their_ids() {
+        for (i, key) in CFG_KEYS.iter().enumerate() {
+            assert!(cfg_value_size(key.id).is_some(), "{} size", key.name);
+
+            // Size 1 is the one bit of the booleans
+            assert_eq!((key.id >> 28) & 0x7 == 1, key.values == CfgValues::Bool, "{} is a bool", key.name);
+
+            assert!(CFG_KEYS[i + 1..].iter().all(|other| other.id != key.id && other.name != key.name), "{} twice", key.name);
+        }
+    }
+
+    #[test]
+    fn parses_values_by_type() {
+        assert_eq!(CFG_USBOUTPROT_NMEA.parse_value("false"), Ok(0));
+        assert_eq!(CFG_NAVSPG_DYNMODEL.parse_value("automot"), Ok(4));
+        assert_eq!(CFG_NAVSPG_DYNMODEL.parse_value("4"), Ok(4));
+        assert_eq!(CFG_RATE_MEAS.parse_value("40"), Ok(40));
+
+        let min_elev = cfg_key("CFG-NAVSPG-INFIL_MINELEV").unwrap();
+        assert_eq!(min_elev.parse_value("-5"), Ok(0xfb));
+        assert_eq!(min_elev.format(0xfb), "-5");
+
+        assert_eq!(CFG_NAVSPG_DYNMODEL.parse_value("1"), Err(CfgError::OutOfRange { key: "CFG-NAVSPG-DYNMODEL", value: 1 }));
+        assert_eq!(CFG_RATE_MEAS.parse_value("10"), Err(CfgError::OutOfRange { key: "CFG-RATE-MEAS", value: 10 }));
+        assert_eq!(CFG_RATE_MEAS.parse_value("70000"), Err(CfgError::OutOfRange { key: "CFG-RATE-MEAS", value: 70000 }));
+        assert_eq!(CFG_USBOUTPROT_UBX.parse_value("yes"), Err(CfgError::BadValue { key: "CFG-USBOUTPROT-UBX", value: String::from("yes") }));
+        assert_eq!(cfg_key("CFG-NOPE"), Err(CfgError::UnknownKey(String::from("CFG-NOPE"))));
+
+        assert_eq!(CFG_NAVSPG_DYNMODEL.format(4), "AUTOMOT (4)");
+    }
+
+    #[test]
+    fn builds_every_cfg_msg() {
+        let mut cfg = CfgBuilder::new();
+        cfg.set(CFG_RATE_MEAS, 40).unwrap()
+            .set(CFG_USBOUTPROT_NMEA, 0).unwrap();
+
+        assert_eq!(cfg.set(CFG_RATE_NAV, 0), Err(CfgError::OutOfRange { key: "CFG-RATE-NAV", value: 0 }));
+
+        assert_eq!(cfg.valset(CFG_LAYER_RAM | CFG_LAYER_BBR), encode_frame(0x06, 0x8a, &[
+            0x00, 0x03, 0x00, 0x00,
+            0x01, 0x00, 0x21, 0x30, 0x28, 0x00,
+            0x02, 0x00, 0x78, 0x10, 0x00
+        ]));
+        assert_eq!(cfg.valget(CfgGetLayer::Flash), encode_frame(0x06, 0x8b, &[
+            0x00, 0x02, 0x00, 0x00,
+            0x01, 0x00, 0x21, 0x30,
+            0x02, 0x00, 0x78, 0x10
+        ]));
+        assert_eq!(cfg.valdel(CFG_LAYER_FLASH), encode_frame(0x06, 0x8c, &[
+            0x00, 0x04, 0x00, 0x00,
+            0x01, 0x00, 0x21, 0x30,
+            0x02, 0x00, 0x78, 0x10
+        ]));
+    }
+}
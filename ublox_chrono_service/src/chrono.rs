@@ -0,0 +1,296 @@
+use log::debug;
+use shm_segment::{shm_field, ShmData, ShmField};
+use crate::intersect::{do_intersect, Coord};
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct Chrono {
+    // Timescale is tenths of a second
+    best_lap_time: u32,
+    previous_lap_time: u32,
+    current_lap_time: u32,
+    previous_sector_delta_time: i32,
+    best_lap_n: u16,
+    current_lap_n: u16,
+    // A TimingState
+    timing_state: u8
+}
+
+// Whether the positions are good enough to cross the gates, as published to the readers
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TimingState {
+    Armed = 1,
+    // The positions fail the fix gate, the lap goes on but no gate can be crossed
+    Degraded = 2
+}
+
+impl ShmData for Chrono {
+    const LAYOUT_VERSION: u32 = 2;
+
+    fn fields() -> Vec<ShmField> {
+        vec![
+            shm_field!(Chrono, best_lap_time, "ds"),
+            shm_field!(Chrono, previous_lap_time, "ds"),
+            shm_field!(Chrono, current_lap_time, "ds"),
+            shm_field!(Chrono, previous_sector_delta_time, "ds"),
+            shm_field!(Chrono, best_lap_n, ""),
+            shm_field!(Chrono, current_lap_n, ""),
+            shm_field!(Chrono, timing_state, "")
+        ]
+    }
+}
+
+pub struct GateSegment {
+    a: Coord,
+    b: Coord,
+    previous_time: u32,
+    best_time: u32,
+    best_time_lap_n: u16
+}
+
+impl GateSegment {
+    pub fn new(a: Coord, b: Coord) -> GateSegment {
+        GateSegment { a, b, previous_time: 0, best_time: 0, best_time_lap_n: 0 }
+    }
+}
+
+// Times the laps from timestamped positions, the last gate is the start-finish line
+pub struct LapTimer {
+    chrono: Chrono,
+    gates: Vec<GateSegment>,
+    current_index: usize,
+    previous_pos: Option<Coord>,
+    // ms, on the same clock as the positions
+    sector_start: u64,
+    lap_start: u64,
+    timing_state: TimingState
+}
+
+fn tenths(from_ms: u64, to_ms: u64) -> u32 {
+    (to_ms.saturating_sub(from_ms) / 100) as u32
+}
+
+impl LapTimer {
+    pub fn new(gates: Vec<GateSegment>) -> LapTimer {
+        assert!(!gates.is_empty(), "no gates to time");
+
+        LapTimer {
+            chrono: Chrono { timing_state: TimingState::Degraded as u8, ..Chrono::default() },
+            gates,
+            current_index: 0,
+            previous_pos: None,
+            sector_start: 0,
+            lap_start: 0,
+            timing_state: TimingState::Degraded
+        }
+    }
+
+    pub fn chrono(&self) -> &Chrono {
+        &self.chrono
+    }
+
+    pub fn timing_state(&self) -> TimingState {
+        self.timing_state
+    }
+
+    // A position that failed the fix gate.
+    // The last good one is forgotten, the jump from it to the next good one can't cross a gate either.
+    pub fn degrade(&mut self) {
+        self.previous_pos = None;
+        self.set_timing_state(TimingState::Degraded);
+    }
+
+    fn set_timing_state(&mut self, timing_state: TimingState) {
+        self.timing_state = timing_state;
+        self.chrono.timing_state = timing_state as u8;
+    }
+
+    // A position that passed the fix gate
+    pub fn handle_position(&mut self, pos: Coord, ts_ms: u64) {
+        self.set_timing_state(TimingState::Armed);
+        self.chrono.current_lap_time = tenths(self.lap_start, ts_ms);
+
+        let gate = &mut self.gates[self.current_index];
+
+        let passed_sector_gate = self.previous_pos
+            .is_some_and(|previous_pos| do_intersect(previous_pos, pos, gate.a, gate.b));
+
+        if passed_sector_gate {
+            let sector_time = tenths(self.sector_start, ts_ms);
+            self.sector_start = ts_ms;
+
+            self.chrono.previous_sector_delta_time = sector_time.wrapping_sub(gate.previous_time) as i32;
+
+            if sector_time < gate.best_time || gate.best_time == 0 {
+                gate.best_time = sector_time;
+                gate.best_time_lap_n = self.chrono.current_lap_n;
+            }
+
+            gate.previous_time = sector_time;
+
+            debug!("sector {} diff {}", self.current_index, self.chrono.previous_sector_delta_time);
+
+            let passed_start_finish_line = self.current_index >= self.gates.len() - 1;
+
+            if passed_start_finish_line {
+                if self.chrono.current_lap_time < self.chrono.best_lap_time || self.chrono.best_lap_time == 0 {
+                    self.chrono.best_lap_time = self.chrono.current_lap_time;
+                    self.chrono.best_lap_n = self.chrono.current_lap_n;
+                }
+
+                self.chrono.previous_lap_time = self.chrono.current_lap_time;
+                self.chrono.current_lap_time = 0;
+                self.chrono.current_lap_n += 1;
+
+                self.lap_start = ts_ms;
+                self.current_index = 0;
+            }
+            else {
+                self.current_index += 1;
+            }
+        }
+
+        self.previous_pos = Some(pos);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shm_client::{ChronoClient, ChronoSnapshot};
+    use shm_segment::{generate_c_header, generate_csharp_accessor, ShmSegment};
+    use super::*;
+
+    // Regenerate them with --gen-c-header and --gen-csharp when this fails
+    #[test]
+    fn checked_in_bindings_match_the_chrono_layout() {
+        assert_eq!(generate_c_header::<Chrono>("ublox_chrono", crate::GENERATOR),
+                   include_str!("../../bindings/ublox_chrono.h"));
+        assert_eq!(generate_csharp_accessor::<Chrono>("DigitalDash.UbloxChronoClient", "ChronoAccessor", crate::GENERATOR),
+                   include_str!("../../DigitalDash/UbloxChronoClient/ChronoAccessor.g.cs"));
+    }
+
+    fn coord(lon: i32, lat: i32) -> Coord {
+        Coord { lon, lat }
+    }
+
+    fn gate(a: (i32, i32), b: (i32, i32)) -> GateSegment {
+        GateSegment::new(coord(a.0, a.1), coord(b.0, b.1))
+    }
+
+    // Positions at a time given in tenths like the times they produce
+    fn drive(timer: &mut LapTimer, lon: i32, lat: i32, tenths: u64) {
+        timer.handle_position(coord(lon, lat), tenths * 100);
+    }
+
+    #[test]
+    fn times_sectors_and_laps() {
+        let mut timer = LapTimer::new(vec![gate((1, 2), (3, 4)), gate((5, 6), (7, 8)), gate((-1, -2), (-3, -4))]);
+
+        drive(&mut timer, 2, 1, 123);
+        assert_eq!(timer.chrono.current_lap_time, 123);
+
+        // pass sector 1 gate
+        drive(&mut timer, 2, 5, 456);
+        assert_eq!(timer.chrono.current_lap_time, 456);
+        assert_eq!(timer.chrono.current_lap_n, 0);
+        assert_eq!(timer.chrono.best_lap_time, 0);
+        assert_eq!(timer.chrono.previous_lap_time, 0);
+        assert_eq!(timer.chrono.previous_sector_delta_time, 456);
+        assert_eq!(timer.current_index, 1);
+        assert_eq!((timer.gates[0].previous_time, timer.gates[0].best_time, timer.gates[0].best_time_lap_n), (456, 456, 0));
+
+        // drive in sector 1
+        drive(&mut timer, 4, 7, 1000);
+        assert_eq!(timer.chrono.current_lap_time, 1000);
+        assert_eq!(timer.current_index, 1);
+
+        // pass sector 2 gate
+        drive(&mut timer, 8, 7, 1500);
+        assert_eq!(timer.chrono.current_lap_time, 1500);
+        assert_eq!(timer.current_index, 2);
+        assert_eq!((timer.gates[1].previous_time, timer.gates[1].best_time), (1500 - 456, 1500 - 456));
+
+        // drive in sector 2
+        drive(&mut timer, 2, -3, 2200);
+        assert_eq!(timer.chrono.current_lap_time, 2200);
+        assert_eq!(timer.current_index, 2);
+
+        // pass sector 3 gate, start-finish line
+        drive(&mut timer, -3, -3, 3000);
+        assert_eq!(timer.chrono.current_lap_time, 0);
+        assert_eq!(timer.chrono.current_lap_n, 1);
+        assert_eq!(timer.chrono.best_lap_time, 3000);
+        assert_eq!(timer.chrono.best_lap_n, 0);
+        assert_eq!(timer.chrono.previous_lap_time, 3000);
+        assert_eq!(timer.current_index, 0);
+        assert_eq!((timer.gates[2].previous_time, timer.gates[2].best_time), (3000 - 1500, 3000 - 1500));
+
+        // drive sector 1 then pass its gate, in 200 this time
+        drive(&mut timer, -3, 3, 3100);
+        drive(&mut timer, 4, 3, 3200);
+        assert_eq!(timer.chrono.previous_sector_delta_time, 200 - 456);
+        assert_eq!((timer.gates[0].best_time, timer.gates[0].best_time_lap_n), (200, 1));
+    }
+
+    #[test]
+    fn single_gate() {
+        let mut timer = LapTimer::new(vec![gate((1, 2), (3, 4))]);
+
+        drive(&mut timer, 2, 1, 123);
+        assert_eq!(timer.chrono.current_lap_time, 123);
+
+        drive(&mut timer, 2, 5, 456);
+        assert_eq!(timer.chrono.current_lap_time, 0);
+        assert_eq!(timer.chrono.current_lap_n, 1);
+        assert_eq!((timer.chrono.best_lap_time, timer.chrono.best_lap_n), (456, 0));
+        assert_eq!(timer.chrono.previous_lap_time, 456);
+        assert_eq!(timer.chrono.previous_sector_delta_time, 456);
+
+        // drive the second lap around the gate and cross it again
+        drive(&mut timer, 4, 5, 500);
+        drive(&mut timer, 4, 3, 600);
+        drive(&mut timer, 1, 3, 700);
+
+        let second_lap_time = 700 - 456;
+        assert_eq!(timer.chrono.current_lap_n, 2);
+        assert_eq!((timer.chrono.best_lap_time, timer.chrono.best_lap_n), (second_lap_time, 1));
+        assert_eq!(timer.chrono.previous_lap_time, second_lap_time);
+        assert_eq!(timer.chrono.previous_sector_delta_time, second_lap_time as i32 - 456);
+        assert_eq!((timer.gates[0].previous_time, timer.gates[0].best_time_lap_n), (second_lap_time, 1));
+    }
+
+    #[test]
+    fn a_drop_out_crosses_no_gate() {
+        let mut timer = LapTimer::new(vec![gate((1, 2), (3, 4))]);
+        assert_eq!(timer.timing_state(), TimingState::Degraded);
+
+        drive(&mut timer, 2, 1, 10);
+        assert_eq!(timer.timing_state(), TimingState::Armed);
+        assert_eq!(timer.chrono.timing_state, TimingState::Armed as u8);
+
+        // Lost in the pits then back on the other side of the start-finish line
+        timer.degrade();
+        assert_eq!(timer.chrono.timing_state, TimingState::Degraded as u8);
+        drive(&mut timer, 2, 5, 40);
+        assert_eq!(timer.chrono.current_lap_n, 0);
+        assert_eq!(timer.chrono.current_lap_time, 40);
+
+        // Crossing it for real
+        drive(&mut timer, 2, 1, 500);
+        assert_eq!(timer.chrono.current_lap_n, 1);
+        assert_eq!(timer.chrono.previous_lap_time, 500);
+    }
+
+    #[test]
+    fn shm_client_reads_the_published_chrono() {
+        const NAME: &str = "/ubloxchrono_client_test";
+
+        let chrono = Chrono { best_lap_time: 1234, current_lap_n: 5, timing_state: TimingState::Armed as u8, ..Chrono::default() };
+        let _shm = ShmSegment::new(NAME, &chrono);
+
+        let snapshot = ChronoClient::open_name(NAME).unwrap().read().unwrap();
+        assert_eq!(snapshot, ChronoSnapshot { best_lap_time: 1234, current_lap_n: 5, timing_state: 1, ..ChronoSnapshot::default() });
+        assert!(snapshot.is_armed());
+    }
+}
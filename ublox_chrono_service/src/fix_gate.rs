@@ -0,0 +1,96 @@
+use std::fmt;
+use crate::ubx_messages::{GnssFix, NavPvt};
+
+// What a NAV-PVT solution needs for its position to be timed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FixGate {
+    // Fix2D or Fix3D, the receiver is set to 2D only
+    pub min_fix: GnssFix,
+    pub max_h_acc_mm: u32,
+    pub min_num_sv: u8
+}
+
+impl Default for FixGate {
+    fn default() -> FixGate {
+        FixGate {
+            min_fix: GnssFix::Fix2D,
+            // As the C service did
+            max_h_acc_mm: 3000,
+            min_num_sv: 6
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FixRejected {
+    FixType(GnssFix),
+    // gnssFixOK isn't set, e.g. outside the DOP and accuracy masks
+    FixNotOk,
+    InvalidLlh,
+    HAcc(u32),
+    NumSv(u8)
+}
+
+impl fmt::Display for FixRejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FixRejected::FixType(fix) => write!(f, "fix type {:?}", fix),
+            FixRejected::FixNotOk => write!(f, "fix not ok"),
+            FixRejected::InvalidLlh => write!(f, "invalid lon, lat and height"),
+            FixRejected::HAcc(h_acc_mm) => write!(f, "horizontal accuracy {} mm", h_acc_mm),
+            FixRejected::NumSv(num_sv) => write!(f, "{} satellites", num_sv)
+        }
+    }
+}
+
+impl FixGate {
+    pub fn check(&self, pvt: &NavPvt) -> Result<(), FixRejected> {
+        let fix_ok = match pvt.fix_type {
+            GnssFix::Fix3D | GnssFix::GnssDeadReckoning => true,
+            GnssFix::Fix2D => self.min_fix == GnssFix::Fix2D,
+            // No gnss position at all
+            GnssFix::NoFix | GnssFix::DeadReckoning | GnssFix::TimeOnly => false
+        };
+
+        if !fix_ok {
+            Err(FixRejected::FixType(pvt.fix_type))
+        }
+        else if pvt.flags & NavPvt::FLAGS_GNSS_FIX_OK == 0 {
+            Err(FixRejected::FixNotOk)
+        }
+        else if pvt.flags3 & NavPvt::FLAGS3_INVALID_LLH != 0 {
+            Err(FixRejected::InvalidLlh)
+        }
+        else if pvt.h_acc_mm > self.max_h_acc_mm {
+            Err(FixRejected::HAcc(pvt.h_acc_mm))
+        }
+        else if pvt.num_sv < self.min_num_sv {
+            Err(FixRejected::NumSv(pvt.num_sv))
+        }
+        else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_every_criterion() {
+        let gate = FixGate::default();
+        let good = NavPvt { fix_type: GnssFix::Fix2D, flags: NavPvt::FLAGS_GNSS_FIX_OK, num_sv: 9, h_acc_mm: 1200, ..NavPvt::default() };
+
+        assert_eq!(gate.check(&good), Ok(()));
+        assert_eq!(gate.check(&NavPvt { fix_type: GnssFix::DeadReckoning, ..good }), Err(FixRejected::FixType(GnssFix::DeadReckoning)));
+        assert_eq!(gate.check(&NavPvt { flags: 0, ..good }), Err(FixRejected::FixNotOk));
+        assert_eq!(gate.check(&NavPvt { flags3: NavPvt::FLAGS3_INVALID_LLH, ..good }), Err(FixRejected::InvalidLlh));
+        assert_eq!(gate.check(&NavPvt { h_acc_mm: 3001, ..good }), Err(FixRejected::HAcc(3001)));
+        assert_eq!(gate.check(&NavPvt { num_sv: 5, ..good }), Err(FixRejected::NumSv(5)));
+
+        let gate_3d = FixGate { min_fix: GnssFix::Fix3D, ..gate };
+        assert_eq!(gate_3d.check(&good), Err(FixRejected::FixType(GnssFix::Fix2D)));
+        assert_eq!(gate_3d.check(&NavPvt { fix_type: GnssFix::Fix3D, ..good }), Ok(()));
+    }
+}
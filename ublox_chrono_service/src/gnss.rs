@@ -0,0 +1,207 @@
+use log::warn;
+use shm_segment::{shm_field, ShmData, ShmField, ShmStr};
+use crate::intersect::Coord;
+use crate::receiver::ReceiverInfo;
+use crate::ubx_messages::NavPvt;
+
+// The latest NAV-PVT solution
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct Gnss {
+    // When it was received, ns on CLOCK_MONOTONIC
+    sample_ns: u64,
+    // The solution's UTC time in ns since the Unix epoch, 0 until the receiver knows it
+    utc_ns: u64,
+    lon: i32,
+    lat: i32,
+    h_acc_mm: u32,
+    g_speed_mm_s: i32,
+    // Heading of motion
+    head_mot: i32,
+    s_acc_mm_s: u32,
+    // A GnssFix
+    fix_type: u8,
+    num_sv: u8,
+    fix_ok: bool,
+    // From the receiver's MON-VER, 0 or empty until it answered
+    prot_ver_major: u8,
+    prot_ver_minor: u8,
+    max_nav_rate_hz: u8,
+    sw_version: ShmStr,
+    hw_version: ShmStr,
+    module: ShmStr
+}
+
+impl Gnss {
+    pub fn from_pvt(pvt: &NavPvt, sample_ns: u64, receiver: Option<&ReceiverInfo>) -> Gnss {
+        let (prot_ver_major, prot_ver_minor) = receiver.and_then(|receiver| receiver.prot_ver).unwrap_or_default();
+
+        Gnss {
+            sample_ns,
+            utc_ns: pvt.utc_ns().unwrap_or(0),
+            lon: pvt.lon,
+            lat: pvt.lat,
+            h_acc_mm: pvt.h_acc_mm,
+            g_speed_mm_s: pvt.g_speed_mm_s,
+            head_mot: pvt.head_mot,
+            s_acc_mm_s: pvt.s_acc_mm_s,
+            fix_type: pvt.fix_type as u8,
+            num_sv: pvt.num_sv,
+            fix_ok: pvt.flags & NavPvt::FLAGS_GNSS_FIX_OK != 0,
+            prot_ver_major,
+            prot_ver_minor,
+            max_nav_rate_hz: receiver.map_or(0, |receiver| receiver.max_nav_rate_hz() as u8),
+            sw_version: receiver.map_or_else(ShmStr::default, |receiver| ShmStr::new(&receiver.sw_version)),
+            hw_version: receiver.map_or_else(ShmStr::default, |receiver| ShmStr::new(&receiver.hw_version)),
+            module: receiver.and_then(|receiver| receiver.module.as_deref()).map_or_else(ShmStr::default, ShmStr::new)
+        }
+    }
+
+    pub fn coord(&self) -> Coord {
+        Coord { lon: self.lon, lat: self.lat }
+    }
+}
+
+impl ShmData for Gnss {
+    const LAYOUT_VERSION: u32 = 3;
+
+    fn fields() -> Vec<ShmField> {
+        vec![
+            shm_field!(Gnss, sample_ns, "ns"),
+            shm_field!(Gnss, utc_ns, "ns"),
+            shm_field!(Gnss, lon, "1e-7deg"),
+            shm_field!(Gnss, lat, "1e-7deg"),
+            shm_field!(Gnss, h_acc_mm, "mm"),
+            shm_field!(Gnss, g_speed_mm_s, "mm/s"),
+            shm_field!(Gnss, head_mot, "1e-5deg"),
+            shm_field!(Gnss, s_acc_mm_s, "mm/s"),
+            shm_field!(Gnss, fix_type, ""),
+            shm_field!(Gnss, num_sv, ""),
+            shm_field!(Gnss, fix_ok, ""),
+            shm_field!(Gnss, prot_ver_major, ""),
+            shm_field!(Gnss, prot_ver_minor, ""),
+            shm_field!(Gnss, max_nav_rate_hz, "Hz"),
+            shm_field!(Gnss, sw_version, ""),
+            shm_field!(Gnss, hw_version, ""),
+            shm_field!(Gnss, module, "")
+        ]
+    }
+}
+
+const GPS_WEEK_MS: u64 = 7 * 24 * 3600 * 1000;
+// A step back is only a week rollover when the solutions on both sides are this close across it
+const MAX_ROLLOVER_STEP_MS: u64 = 10_000;
+
+// Turns the receiver's time of week into ms since the first solution, across week rollovers.
+// It's when the receiver took the measurement, unlike the time the msg was read the serial latency doesn't skew it.
+#[derive(Default)]
+pub struct GnssClock {
+    // The previous time of week and the ms it gave
+    previous: Option<(u32, u64)>
+}
+
+impl GnssClock {
+    pub fn ms(&mut self, i_tow_ms: u32) -> u64 {
+        let ms = match self.previous {
+            None => 0,
+            Some((previous_i_tow_ms, previous_ms)) if i_tow_ms >= previous_i_tow_ms => {
+                previous_ms + (i_tow_ms - previous_i_tow_ms) as u64
+            }
+            Some((previous_i_tow_ms, previous_ms)) => {
+                let rollover_step_ms = (GPS_WEEK_MS + i_tow_ms as u64).saturating_sub(previous_i_tow_ms as u64);
+
+                if rollover_step_ms <= MAX_ROLLOVER_STEP_MS {
+                    previous_ms + rollover_step_ms
+                }
+                else {
+                    // Not the end of a week, the receiver's time jumped: go on from where the clock was
+                    warn!("gnss time of week went back from {} ms to {} ms, resyncing", previous_i_tow_ms, i_tow_ms);
+                    previous_ms
+                }
+            }
+        };
+        self.previous = Some((i_tow_ms, ms));
+
+        ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shm_client::{GnssClient, GnssSnapshot};
+    use shm_segment::{generate_c_header, ShmSegment};
+    use crate::ubx_messages::{GnssFix, MonVer};
+    use super::*;
+
+    // Regenerate it with --gen-gnss-c-header when this fails
+    #[test]
+    fn checked_in_bindings_match_the_gnss_layout() {
+        assert_eq!(generate_c_header::<Gnss>("ublox_gnss", crate::GENERATOR),
+                   include_str!("../../bindings/ublox_gnss.h"));
+    }
+
+    #[test]
+    fn shm_client_reads_the_published_gnss() {
+        const NAME: &str = "/ubloxgnss_client_test";
+
+        let pvt = NavPvt {
+            fix_type: GnssFix::Fix3D,
+            flags: NavPvt::FLAGS_GNSS_FIX_OK,
+            num_sv: 11,
+            lon: 61_000_000,
+            lat: 462_000_000,
+            g_speed_mm_s: 27_800,
+            ..NavPvt::default()
+        };
+        let receiver = ReceiverInfo::from_mon_ver(&MonVer {
+            sw_version: String::from("ROM SPG 5.10 (7b202e)"),
+            hw_version: String::from("000A0000"),
+            extensions: vec![String::from("PROTVER=34.10"), String::from("MOD=MAX-M10S")]
+        });
+        let _shm = ShmSegment::new(NAME, &Gnss::from_pvt(&pvt, 42, Some(&receiver)));
+
+        assert_eq!(GnssClient::open_name(NAME).unwrap().read(), Ok(GnssSnapshot {
+            sample_ns: 42,
+            lon: 61_000_000,
+            lat: 462_000_000,
+            g_speed_mm_s: 27_800,
+            fix_type: 3,
+            num_sv: 11,
+            fix_ok: true,
+            prot_ver_major: 34,
+            prot_ver_minor: 10,
+            max_nav_rate_hz: 10,
+            sw_version: ShmStr::new("ROM SPG 5.10 (7b202e)"),
+            hw_version: ShmStr::new("000A0000"),
+            module: ShmStr::new("MAX-M10S"),
+            ..GnssSnapshot::default()
+        }));
+    }
+
+    #[test]
+    fn clock_counts_from_the_first_solution_across_weeks() {
+        let mut clock = GnssClock::default();
+
+        assert_eq!(clock.ms(GPS_WEEK_MS as u32 - 80), 0);
+        assert_eq!(clock.ms(GPS_WEEK_MS as u32 - 40), 40);
+        assert_eq!(clock.ms(0), 80);
+        assert_eq!(clock.ms(40), 120);
+    }
+
+    #[test]
+    fn clock_resyncs_on_a_jump_back_within_the_week() {
+        let mut clock = GnssClock::default();
+
+        assert_eq!(clock.ms(500_000), 0);
+        assert_eq!(clock.ms(500_040), 40);
+        // A receiver that corrected its time, not a new week
+        assert_eq!(clock.ms(200_000), 40);
+        assert_eq!(clock.ms(200_040), 80);
+
+        // Nor is a step back to the start of the week from further off its end
+        let mut clock = GnssClock::default();
+        assert_eq!(clock.ms((GPS_WEEK_MS - 20_000) as u32), 0);
+        assert_eq!(clock.ms(0), 0);
+        assert_eq!(clock.ms(40), 40);
+    }
+}
@@ -0,0 +1,79 @@
+// https://www.geeksforgeeks.org/check-if-two-given-line-segments-intersect/
+
+// 1e-7 deg, as the receiver gives them
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Coord {
+    pub lon: i32,
+    pub lat: i32
+}
+
+#[derive(PartialEq)]
+enum Orientation {
+    Collinear,
+    Clockwise,
+    Counterclockwise
+}
+
+fn on_segment(p: Coord, q: Coord, r: Coord) -> bool {
+    q.lon <= p.lon.max(r.lon) && q.lon >= p.lon.min(r.lon) &&
+        q.lat <= p.lat.max(r.lat) && q.lat >= p.lat.min(r.lat)
+}
+
+fn orientation(p: Coord, q: Coord, r: Coord) -> Orientation {
+    // i64, the products of real coordinates overflow an i32
+    let o = (q.lat as i64 - p.lat as i64) * (r.lon as i64 - q.lon as i64)
+        - (q.lon as i64 - p.lon as i64) * (r.lat as i64 - q.lat as i64);
+
+    match o {
+        0 => Orientation::Collinear,
+        o if o > 0 => Orientation::Clockwise,
+        _ => Orientation::Counterclockwise
+    }
+}
+
+// Whether segment p1q1 crosses or touches segment p2q2
+pub fn do_intersect(p1: Coord, q1: Coord, p2: Coord, q2: Coord) -> bool {
+    let o1 = orientation(p1, q1, p2);
+    let o2 = orientation(p1, q1, q2);
+    let o3 = orientation(p2, q2, p1);
+    let o4 = orientation(p2, q2, q1);
+
+    // General case
+    if o1 != o2 && o3 != o4 {
+        return true;
+    }
+
+    // One end lies on the other segment
+    (o1 == Orientation::Collinear && on_segment(p1, p2, q1)) ||
+        (o2 == Orientation::Collinear && on_segment(p1, q2, q1)) ||
+        (o3 == Orientation::Collinear && on_segment(p2, p1, q2)) ||
+        (o4 == Orientation::Collinear && on_segment(p2, q1, q2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coord(lon: i32, lat: i32) -> Coord {
+        Coord { lon, lat }
+    }
+
+    #[test]
+    fn crossing_and_apart() {
+        assert!(do_intersect(coord(0, 0), coord(4, 4), coord(0, 4), coord(4, 0)));
+        assert!(!do_intersect(coord(0, 0), coord(1, 1), coord(3, 0), coord(3, 4)));
+        // Touching counts
+        assert!(do_intersect(coord(0, 0), coord(2, 2), coord(2, 2), coord(4, 0)));
+        assert!(!do_intersect(coord(0, 0), coord(1, 1), coord(2, 2), coord(3, 3)));
+    }
+
+    #[test]
+    fn real_coordinates_dont_overflow() {
+        // A few meters around a start-finish line at 46.2°N 6.1°E
+        let gate_a = coord(61_000_000, 462_000_000);
+        let gate_b = coord(61_000_400, 462_000_000);
+
+        assert!(do_intersect(coord(61_000_200, 461_999_800), coord(61_000_200, 462_000_200), gate_a, gate_b));
+        assert!(!do_intersect(coord(61_000_600, 461_999_800), coord(61_000_600, 462_000_200), gate_a, gate_b));
+    }
+}
@@ -1,26 +1,79 @@
+mod cfg_keys;
+mod uart_baud;
 mod ublox;
+mod ublox_config;
+mod ubx;
+mod ubx_messages;
+mod chrono;
+mod fix_gate;
+mod gnss;
+mod intersect;
+mod receiver;
 
-use log::{debug, info};
+use std::env;
+use std::fs;
+use std::process;
+use cli_args::{arg_value, write_generated};
+use log::{debug, error, info, warn};
 use nix::sys::signal::{self, sigprocmask, Signal};
 use nix::sys::signalfd::{SigSet, SignalFd};
 use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
 use nix::sys::termios::BaudRate;
-use crate::ublox::Ublox;
+use shm_segment::{generate_c_header, generate_csharp_accessor, monotonic_ns, ShmSegment};
+use telemetry_socket::TelemetryServer;
+use crate::chrono::{Chrono, GateSegment, LapTimer, TimingState};
+use crate::fix_gate::FixGate;
+use crate::gnss::{Gnss, GnssClock};
+use crate::intersect::Coord;
+use crate::uart_baud::BaudSwitcher;
+use crate::ublox::{ConfigPlan, ReceiverPort, Ublox};
+use crate::ublox_config::{parse_cfg_file, parse_layers, ConfigState, KeySet};
+use crate::ubx_messages::{GnssFix, NavPvt, NavStatus, UbxMsg, CFG_LAYER_RAM};
 
 const SHM_NAME: &str = "/ubloxchrono";
+const GNSS_SHM_NAME: &str = "/ubloxgnss";
+const GENERATOR: &str = "ublox_chrono_service --gen-c-header/--gen-csharp";
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    // Regenerate the reader bindings from the Chrono and Gnss layouts instead of running
+    if gen_bindings(&args) {
+        return;
+    }
+
     let env = env_logger::Env::default()
         .filter_or("RUST_LOG", "info");
 
     env_logger::init_from_env(env);
 
+    // Also stream the lap times to the clients of a Unix socket at this path
+    let socket_path = arg_value(&args, "--socket");
+
+    // Don't time laps on a receiver that didn't keep the measurement rate
+    let require_rate = args.iter().any(|arg| arg == "--require-rate");
+
+    let fix_gate = fix_gate_from_args(&args);
+    info!("Timing positions with a {:?} fix or better, {} mm horizontal accuracy or better and {} satellites or more",
+          fix_gate.min_fix, fix_gate.max_h_acc_mm, fix_gate.min_num_sv);
+
     enum EpollEventId {
         Signal,
-        Ublox
+        Ublox,
+        UbloxTimer,
+        Telemetry
     }
 
-    let mut ublox = Ublox::new("/dev/pts/3", BaudRate::B38400);
+    // More receiver keys by name, set after the ones the service needs
+    let cfg_key_sets = arg_value(&args, "--cfg-file").map_or(Vec::new(), |path| {
+        let text = fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("read {}: {}", path, e));
+
+        parse_cfg_file(&text)
+            .unwrap_or_else(|e| panic!("{}: {}", path, e))
+    });
+
+    let mut ublox = ublox_from_args(&args, cfg_key_sets);
 
     let sfd = setup_signal_handler();
 
@@ -33,10 +86,32 @@ fn main() {
     epoll.add(ublox.get_fd(), EpollEvent::new(EpollFlags::EPOLLIN, EpollEventId::Ublox as u64))
         .expect("epoll add ublox");
 
+    epoll.add(ublox.get_timer_fd(), EpollEvent::new(EpollFlags::EPOLLIN, EpollEventId::UbloxTimer as u64))
+        .expect("epoll add ublox timer");
+
     ublox.configure();
 
+    let mut lap_timer = LapTimer::new(track_gates());
+    let mut gnss_clock = GnssClock::default();
+
+    let mut shm = ShmSegment::new(SHM_NAME, lap_timer.chrono());
+    let mut gnss_shm = ShmSegment::new(GNSS_SHM_NAME, &Gnss::default());
+
+    let mut telemetry = socket_path.map(|path| {
+        let server = TelemetryServer::new(path, lap_timer.chrono());
+
+        epoll.add(server.get_fd(), EpollEvent::new(EpollFlags::EPOLLIN, EpollEventId::Telemetry as u64))
+            .expect("epoll add telemetry");
+
+        info!("Streaming at {}", path);
+
+        server
+    });
 
-    info!("Ready at /dev/shm{}", SHM_NAME);
+    let mut ready = false;
+    // Once the receiver is configured
+    let mut timing_laps = false;
+    let mut configuration_failed = false;
 
     let mut events = [EpollEvent::empty()];
 
@@ -50,15 +125,164 @@ fn main() {
         }
 
         if events[0].data() == EpollEventId::Ublox as u64 {
-            //
+            for msg in ublox.handle_incoming_ublox_msg() {
+                let UbxMsg::NavPvt(pvt) = msg else {
+                    handle_ubx_msg(msg);
+                    continue;
+                };
+
+                let gnss = Gnss::from_pvt(&pvt, monotonic_ns(), ublox.receiver());
+                gnss_shm.publish(&gnss);
+
+                let fix = fix_gate.check(&pvt);
+
+                // Time the laps on the receiver's clock rather than when the msg made it through the serial port.
+                // Its time of week can be anything until it has a fix or knows the time.
+                let ts_ms = (fix.is_ok() || pvt.valid & NavPvt::VALID_TIME != 0).then(|| gnss_clock.ms(pvt.i_tow_ms));
+
+                if !timing_laps {
+                    continue;
+                }
+
+                match fix {
+                    Ok(()) => {
+                        if lap_timer.timing_state() == TimingState::Degraded {
+                            info!("Timing armed");
+                        }
+                        lap_timer.handle_position(gnss.coord(), ts_ms.expect("a fix's time"));
+                    }
+                    Err(rejected) => {
+                        if lap_timer.timing_state() == TimingState::Armed {
+                            warn!("Timing degraded: {}", rejected);
+                        }
+                        lap_timer.degrade();
+                    }
+                }
+
+                shm.publish(lap_timer.chrono());
+
+                if let Some(server) = telemetry.as_mut() {
+                    server.update(lap_timer.chrono());
+                }
+            }
+        }
+
+        if events[0].data() == EpollEventId::UbloxTimer as u64 {
+            ublox.handle_timer();
+        }
+
+        match ublox.config_state() {
+            ConfigState::Configured if !ready => {
+                ready = true;
+
+                if require_rate && !ublox.rate_is_configured() {
+                    error!("The receiver doesn't measure at the rate it was set to, not timing laps");
+                }
+                else {
+                    timing_laps = true;
+                }
+
+                info!("Ready at /dev/shm{}", SHM_NAME);
+            }
+            ConfigState::Failed => {
+                error!("Couldn't configure the receiver");
+                configuration_failed = true;
+                break;
+            }
+            _ => {}
+        }
+
+        if events[0].data() == EpollEventId::Telemetry as u64 {
+            telemetry.as_mut().unwrap().handle_events();
         }
     }
 
     info!("Shutting down ....");
 
+    drop(telemetry);
+    drop(gnss_shm);
+    drop(shm);
     drop(ublox);
 
     info!("Bye :)");
+
+    if configuration_failed {
+        process::exit(1);
+    }
+}
+
+// Placeholders until the track's gates can be given, the last one is the start-finish line
+fn track_gates() -> Vec<GateSegment> {
+    (0..3)
+        .map(|_| GateSegment::new(Coord { lon: 1, lat: 1 }, Coord { lon: 2, lat: 2 }))
+        .collect()
+}
+
+fn handle_ubx_msg(msg: UbxMsg) {
+    match msg {
+        UbxMsg::NavStatus(status) => {
+            info!("gps fix {:?}, ok {}, status {}, ttff {}, msss {}", status.gps_fix,
+                  status.flags & NavStatus::FLAGS_GPS_FIX_OK != 0, status.fix_stat, status.ttff_ms, status.msss_ms);
+        }
+        UbxMsg::CfgValget(cfg) => {
+            for (key, value) in cfg.items {
+                debug!("cfg {:#010x} = {}", key, value);
+            }
+        }
+        UbxMsg::AckAck(ack) => debug!("UBX-ACK-ACK class {:#04x} id {:#04x}", ack.class, ack.id),
+        UbxMsg::AckNak(nak) => error!("UBX-ACK-NAK class {:#04x} id {:#04x}", nak.class, nak.id),
+        UbxMsg::Other(frame) => warn!("unhandled ubx msg class {:#04x} id {:#04x}", frame.class, frame.id),
+        msg => debug!("{:?}", msg)
+    }
+}
+
+// --port path, the receiver on usb unless --uart-baud rate says it's wired to its UART1.
+// With --persist bbr,flash the receiver comes up configured after a power cycle instead of flooding NMEA at 1Hz,
+// --factory-reset first brings every key back to its default
+fn ublox_from_args(args: &[String], cfg_key_sets: Vec<KeySet>) -> Ublox {
+    let port_name = arg_value(args, "--port").unwrap_or("/dev/pts/3");
+
+    let persist_layers = arg_value(args, "--persist")
+        .map_or(0, |layers| parse_layers(layers).unwrap_or_else(|e| panic!("--persist: {}", e)));
+    let layers = CFG_LAYER_RAM | persist_layers;
+
+    let link = arg_value(args, "--uart-baud")
+        .map(|rate| BaudSwitcher::new(rate.parse().expect("--uart-baud rate"), layers));
+    let port = if link.is_some() { ReceiverPort::Uart1 } else { ReceiverPort::Usb };
+
+    let factory_reset = args.iter().any(|arg| arg == "--factory-reset");
+    if factory_reset {
+        warn!("Resetting the receiver to its factory configuration");
+    }
+
+    let plan = ConfigPlan { port, layers, factory_reset, extra_key_sets: cfg_key_sets };
+
+    Ublox::new(port_name, BaudRate::B38400, link, plan)
+}
+
+// --min-fix 2d|3d, --max-h-acc mm and --min-sv count, the defaults otherwise
+fn fix_gate_from_args(args: &[String]) -> FixGate {
+    let default = FixGate::default();
+
+    FixGate {
+        min_fix: arg_value(args, "--min-fix").map_or(default.min_fix, |fix| match fix {
+            "2d" => GnssFix::Fix2D,
+            "3d" => GnssFix::Fix3D,
+            _ => panic!("--min-fix must be 2d or 3d")
+        }),
+        max_h_acc_mm: arg_value(args, "--max-h-acc")
+            .map_or(default.max_h_acc_mm, |mm| mm.parse().expect("--max-h-acc mm")),
+        min_num_sv: arg_value(args, "--min-sv")
+            .map_or(default.min_num_sv, |count| count.parse().expect("--min-sv count"))
+    }
+}
+
+fn gen_bindings(args: &[String]) -> bool {
+    write_generated(args, &[
+        ("--gen-c-header", &|| generate_c_header::<Chrono>("ublox_chrono", GENERATOR)),
+        ("--gen-csharp", &|| generate_csharp_accessor::<Chrono>("DigitalDash.UbloxChronoClient", "ChronoAccessor", GENERATOR)),
+        ("--gen-gnss-c-header", &|| generate_c_header::<Gnss>("ublox_gnss", GENERATOR))
+    ])
 }
 
 fn setup_signal_handler() -> SignalFd {
@@ -0,0 +1,115 @@
+use std::fmt;
+use crate::ubx_messages::MonVer;
+
+// CFG-VALSET, VALGET and VALDEL came with it, older receivers only take CFG-MSG, CFG-RATE and the like
+pub const VALSET_PROT_VER: (u8, u8) = (23, 0);
+
+// Highest navigation rate of a module family tracking its default constellations, from the datasheets.
+// CFG-SIGNAL is left alone, a single constellation would solve faster but with fewer satellites.
+const FAMILY_MAX_NAV_RATES: &[(&str, u32)] = &[("M10", 10), ("M9", 25), ("F9", 7), ("M8", 10), ("M7", 10)];
+// For the receivers that don't give their module name
+const HW_MAX_NAV_RATES: &[(&str, u32)] = &[("000A0000", 10), ("00190000", 7), ("00080000", 10), ("00070000", 10)];
+const DEFAULT_MAX_NAV_RATE_HZ: u32 = 5;
+
+// What a receiver told of itself in its MON-VER
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ReceiverInfo {
+    pub sw_version: String,
+    pub hw_version: String,
+    // e.g. SPG 5.10
+    pub fw_version: Option<String>,
+    // major, minor
+    pub prot_ver: Option<(u8, u8)>,
+    // e.g. MAX-M10S
+    pub module: Option<String>
+}
+
+// An extension's value, KEY=value or KEY value for the older firmwares
+fn extension<'a>(extensions: &'a [String], key: &str) -> Option<&'a str> {
+    extensions.iter()
+        .filter_map(|extension| extension.strip_prefix(key))
+        .find_map(|rest| rest.strip_prefix(['=', ' ']))
+        .map(str::trim)
+}
+
+fn parse_prot_ver(value: &str) -> Option<(u8, u8)> {
+    let (major, minor) = value.split_once('.')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+impl ReceiverInfo {
+    pub fn from_mon_ver(ver: &MonVer) -> ReceiverInfo {
+        ReceiverInfo {
+            sw_version: ver.sw_version.clone(),
+            hw_version: ver.hw_version.clone(),
+            fw_version: extension(&ver.extensions, "FWVER").map(String::from),
+            prot_ver: extension(&ver.extensions, "PROTVER").and_then(parse_prot_ver),
+            module: extension(&ver.extensions, "MOD").map(String::from)
+        }
+    }
+
+    // A receiver that doesn't give its protocol version predates it
+    pub fn uses_valset(&self) -> bool {
+        self.prot_ver.is_some_and(|prot_ver| prot_ver >= VALSET_PROT_VER)
+    }
+
+    pub fn max_nav_rate_hz(&self) -> u32 {
+        let by_module = self.module.as_ref().and_then(|module| FAMILY_MAX_NAV_RATES.iter()
+            .find(|(family, _)| module.contains(family)));
+        let by_hw = || HW_MAX_NAV_RATES.iter().find(|(hw_version, _)| *hw_version == self.hw_version);
+
+        by_module.or_else(by_hw).map_or(DEFAULT_MAX_NAV_RATE_HZ, |&(_, hz)| hz)
+    }
+}
+
+impl fmt::Display for ReceiverInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}, sw {}, hw {}", self.module.as_deref().unwrap_or("unknown module"), self.sw_version, self.hw_version)?;
+
+        if let Some(fw_version) = &self.fw_version {
+            write!(f, ", fw {}", fw_version)?;
+        }
+
+        match self.prot_ver {
+            Some((major, minor)) => write!(f, ", protocol {}.{:02}", major, minor),
+            None => write!(f, ", unknown protocol")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mon_ver(hw_version: &str, extensions: &[&str]) -> MonVer {
+        MonVer {
+            sw_version: String::from("ROM SPG 5.10 (7b202e)"),
+            hw_version: hw_version.to_string(),
+            extensions: extensions.iter().map(|extension| extension.to_string()).collect()
+        }
+    }
+
+    #[test]
+    fn reads_the_extensions() {
+        let info = ReceiverInfo::from_mon_ver(&mon_ver("000A0000", &["FWVER=SPG 5.10", "PROTVER=34.10", "MOD=MAX-M10S"]));
+
+        assert_eq!(info.prot_ver, Some((34, 10)));
+        assert!(info.uses_valset());
+        assert_eq!(info.max_nav_rate_hz(), 10);
+        assert_eq!(info.to_string(), "MAX-M10S, sw ROM SPG 5.10 (7b202e), hw 000A0000, fw SPG 5.10, protocol 34.10");
+    }
+
+    #[test]
+    fn older_receivers() {
+        // An M8 with the older extension format and no module name
+        let m8 = ReceiverInfo::from_mon_ver(&mon_ver("00080000", &["PROTVER 18.00", "GPS;GLO;GAL;BDS"]));
+        assert_eq!(m8.prot_ver, Some((18, 0)));
+        assert!(!m8.uses_valset());
+        assert_eq!(m8.max_nav_rate_hz(), 10);
+
+        let unknown = ReceiverInfo::from_mon_ver(&mon_ver("00040007", &[]));
+        assert!(!unknown.uses_valset());
+        assert_eq!(unknown.max_nav_rate_hz(), DEFAULT_MAX_NAV_RATE_HZ);
+        assert_eq!(unknown.to_string(), "unknown module, sw ROM SPG 5.10 (7b202e), hw 00040007, unknown protocol");
+    }
+}
@@ -0,0 +1,237 @@
+use std::iter;
+use std::time::{Duration, Instant};
+use log::{debug, error, info, warn};
+use nix::sys::termios::BaudRate;
+use crate::cfg_keys::{CfgBuilder, CFG_UART1_BAUDRATE};
+use crate::receiver::ReceiverInfo;
+use crate::ubx_messages::{CfgPrt, MonVer, UbxMessage, UbxMsg};
+
+// Where a receiver's UART1 may have been left, its default first
+pub const UART_BAUD_RATES: [u32; 8] = [38400, 9600, 115200, 230400, 460800, 921600, 57600, 19200];
+
+// A receiver answers a MON-VER well within it at any rate
+pub const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+// For the receiver to send the switch's ack at the old rate before the port changes
+pub const SWITCH_DELAY: Duration = Duration::from_millis(250);
+// Times through every rate without an answer
+pub const MAX_PROBE_ROUNDS: u32 = 2;
+// A receiver that answers but doesn't come back at the new rate
+pub const MAX_SWITCHES: u32 = 3;
+
+pub fn termios_baud(rate: u32) -> Option<BaudRate> {
+    match rate {
+        9600 => Some(BaudRate::B9600),
+        19200 => Some(BaudRate::B19200),
+        38400 => Some(BaudRate::B38400),
+        57600 => Some(BaudRate::B57600),
+        115200 => Some(BaudRate::B115200),
+        230400 => Some(BaudRate::B230400),
+        460800 => Some(BaudRate::B460800),
+        921600 => Some(BaudRate::B921600),
+        _ => None
+    }
+}
+
+// What to do to the local port: change its rate first when given, then send the cmd
+#[derive(Clone, PartialEq, Debug)]
+pub struct LinkStep {
+    pub baud: Option<u32>,
+    pub cmd: Vec<u8>
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LinkState {
+    Idle,
+    // Asking the receiver its baud rate at one rate after the other
+    Probing,
+    // Waiting for it to take the new rate
+    Switching,
+    Synced,
+    Failed
+}
+
+// Finds the rate the receiver's UART1 is at and sets it to the target rate, along with the local port.
+// The wanted rate is tried first, a receiver that kept it from BBR or flash answers right away.
+// It's probed with a MON-VER, which every receiver answers, then switched with a CFG-VALSET
+// or a CFG-PRT on the receivers before protocol 23.
+pub struct BaudSwitcher {
+    target: u32,
+    // Of the VALSET, to keep the rate over power cycles.
+    // A CFG-PRT only goes to RAM, the CFG-CFG that ends the older receivers' configuration saves it.
+    layers: u8,
+    rates: Vec<u32>,
+    index: usize,
+    rounds: u32,
+    switches: u32,
+    sent_at: Instant,
+    state: LinkState
+}
+
+impl BaudSwitcher {
+    pub fn new(target: u32, layers: u8) -> BaudSwitcher {
+        assert!(termios_baud(target).is_some(), "unsupported uart baud rate {}", target);
+
+        BaudSwitcher {
+            target,
+            layers,
+            rates: iter::once(target).chain(UART_BAUD_RATES.into_iter().filter(|&rate| rate != target)).collect(),
+            index: 0,
+            rounds: 0,
+            switches: 0,
+            sent_at: Instant::now(),
+            state: LinkState::Idle
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    fn rate(&self) -> u32 {
+        self.rates[self.index]
+    }
+
+    pub fn start(&mut self, now: Instant) -> LinkStep {
+        self.rounds = 0;
+        self.switches = 0;
+        self.probe(0, now)
+    }
+
+    fn probe(&mut self, index: usize, now: Instant) -> LinkStep {
+        self.index = index;
+        self.state = LinkState::Probing;
+        self.sent_at = now;
+
+        LinkStep { baud: Some(self.rate()), cmd: MonVer::poll() }
+    }
+
+    pub fn handle_msg(&mut self, msg: &UbxMsg, now: Instant) -> Option<LinkStep> {
+        if self.state != LinkState::Probing {
+            return None;
+        }
+
+        // Only a complete answer, bytes at the wrong rate can't pass the checksum
+        let UbxMsg::MonVer(ver) = msg else { return None };
+
+        if self.rate() == self.target {
+            info!("receiver uart at {} baud", self.target);
+            self.state = LinkState::Synced;
+            return None;
+        }
+
+        if self.switches >= MAX_SWITCHES {
+            error!("receiver uart stays at {} baud, it didn't take {}", self.rate(), self.target);
+            self.state = LinkState::Failed;
+            return None;
+        }
+
+        info!("receiver uart answered at {} baud, switching it to {}", self.rate(), self.target);
+
+        let cmd = if ReceiverInfo::from_mon_ver(ver).uses_valset() {
+            let mut cfg = CfgBuilder::new();
+            cfg.set(CFG_UART1_BAUDRATE, self.target as u64).expect("uart baud rate");
+            cfg.valset(self.layers)
+        }
+        else {
+            CfgPrt::uart1(self.target).encode()
+        };
+
+        self.switches += 1;
+        self.state = LinkState::Switching;
+        self.sent_at = now;
+        Some(LinkStep { baud: None, cmd })
+    }
+
+    pub fn handle_timer(&mut self, now: Instant) -> Option<LinkStep> {
+        let elapsed = now.duration_since(self.sent_at);
+
+        match self.state {
+            // The target rate comes first
+            LinkState::Switching if elapsed >= SWITCH_DELAY => Some(self.probe(0, now)),
+            LinkState::Probing if elapsed >= PROBE_TIMEOUT => {
+                let next = (self.index + 1) % self.rates.len();
+
+                if next == 0 {
+                    self.rounds += 1;
+                    if self.rounds >= MAX_PROBE_ROUNDS {
+                        error!("no answer from the receiver uart at any baud rate");
+                        self.state = LinkState::Failed;
+                        return None;
+                    }
+                    warn!("no answer from the receiver uart at any baud rate, trying again");
+                }
+
+                debug!("no answer at {} baud, trying {}", self.rate(), self.rates[next]);
+                Some(self.probe(next, now))
+            }
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ubx_messages::{CfgValset, CFG_LAYER_BBR, CFG_LAYER_RAM};
+    use super::*;
+
+    fn probe(baud: u32) -> Option<LinkStep> {
+        Some(LinkStep { baud: Some(baud), cmd: MonVer::poll() })
+    }
+
+    fn answer(prot_ver: &str) -> UbxMsg {
+        UbxMsg::MonVer(MonVer {
+            sw_version: String::from("ROM SPG 5.10 (7b202e)"),
+            hw_version: String::from("000A0000"),
+            extensions: vec![format!("PROTVER={}", prot_ver)]
+        })
+    }
+
+    #[test]
+    fn finds_the_receiver_and_switches_it() {
+        let now = Instant::now();
+        let mut switcher = BaudSwitcher::new(115200, CFG_LAYER_RAM | CFG_LAYER_BBR);
+
+        assert_eq!(Some(switcher.start(now)), probe(115200));
+        assert_eq!(switcher.handle_timer(now + PROBE_TIMEOUT / 2), None);
+        assert_eq!(switcher.handle_timer(now + PROBE_TIMEOUT), probe(38400));
+
+        let at = now + PROBE_TIMEOUT * 2;
+        assert_eq!(switcher.handle_msg(&answer("34.10"), at), Some(LinkStep {
+            baud: None,
+            cmd: CfgValset { layers: CFG_LAYER_RAM | CFG_LAYER_BBR, items: vec![(CFG_UART1_BAUDRATE.id, 115200)] }.encode()
+        }));
+        assert_eq!(switcher.state(), LinkState::Switching);
+
+        assert_eq!(switcher.handle_timer(at + SWITCH_DELAY), probe(115200));
+        assert_eq!(switcher.handle_msg(&answer("34.10"), at + SWITCH_DELAY), None);
+        assert_eq!(switcher.state(), LinkState::Synced);
+    }
+
+    #[test]
+    fn switches_older_receivers_with_cfg_prt() {
+        let now = Instant::now();
+        let mut switcher = BaudSwitcher::new(115200, CFG_LAYER_RAM);
+
+        switcher.start(now);
+        assert_eq!(switcher.handle_timer(now + PROBE_TIMEOUT), probe(38400));
+
+        let at = now + PROBE_TIMEOUT;
+        assert_eq!(switcher.handle_msg(&answer("18.00"), at), Some(LinkStep { baud: None, cmd: CfgPrt::uart1(115200).encode() }));
+        assert_eq!(switcher.handle_timer(at + SWITCH_DELAY), probe(115200));
+    }
+
+    #[test]
+    fn fails_without_answer_at_any_rate() {
+        let mut at = Instant::now();
+        let mut switcher = BaudSwitcher::new(38400, CFG_LAYER_RAM);
+        switcher.start(at);
+
+        for _ in 1..UART_BAUD_RATES.len() * MAX_PROBE_ROUNDS as usize {
+            at += PROBE_TIMEOUT;
+            assert!(switcher.handle_timer(at).is_some());
+        }
+
+        assert_eq!(switcher.handle_timer(at + PROBE_TIMEOUT), None);
+        assert_eq!(switcher.state(), LinkState::Failed);
+    }
+}
@@ -1,100 +1,125 @@
 use std::os::fd::OwnedFd;
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
 use nix::sys::termios::BaudRate;
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
 use serial_port::SerialPort;
-use crate::ublox::ReadProgress::SearchForSync;
-
-const UBX_SYNC_CHAR_1: u8 = 0xb5;
-const UBX_SYNC_CHAR_2: u8 = 0x62;
-
-const UBX_SYNC_LEN: usize = 2;
-const UBX_CLASS_LEN: usize = 1;
-const UBX_ID_LEN: usize = 1;
-const UBX_LEN_LEN: usize = 2;
-const UBX_HEADER_LEN: usize = UBX_CLASS_LEN + UBX_ID_LEN + UBX_LEN_LEN;
-const UBX_CK_LEN: usize = 2;
-const UBX_MIN_LEN: usize = UBX_SYNC_LEN + UBX_HEADER_LEN + UBX_CK_LEN;
-
-const UBX_CLASS_OFFSET: usize = UBX_SYNC_LEN;
-const UBX_ID_OFFSET: usize = UBX_CLASS_OFFSET + UBX_CLASS_LEN;
-const UBX_LEN_OFFSET: usize = UBX_ID_OFFSET + UBX_ID_LEN;
-const UBX_PAYLOAD_OFFSET: usize = UBX_LEN_OFFSET + UBX_LEN_LEN;
-
-const CLASS_NAV: u8 = 0x01;
-const CLASS_ACK: u8 = 0x05;
-const CLASS_CFG: u8 = 0x06;
-
-const ID_NAV_POSLLH: u8 = 0x02;
-const ID_CFG_VALSET: u8 = 0x8a;
-
-const CFG_USBOUTPROT_UBX: u32 = 0x10780001;
-const CFG_USBOUTPROT_NMEA: u32 = 0x10780002;
-const CFG_NAVSPG_FIXMODE: u32 = 0x20110011;
-const CFG_NAVSPG_DYNMODEL: u32 = 0x20110021;
-const CFG_MSGOUT_UBX_NAV_POSLLH_USB: u32 = 0x2091002c;
-const CFG_MSGOUT_UBX_NAV_STATUS_USB: u32 = 0x2091001d;
-const CFG_RATE_MEAS: u32 = 0x30210001;
-const CFG_RATE_NAV: u32 = 0x30210002;
-
-const CFG_VERSION: u8 = 0;
-const CFG_LAYER_RAM: u8 = 1;
-
-const CFG_NAVSPG_FIXMODE_2DONLY: u8 = 1;
-const CFG_NAVSPG_DYNMODEL_AUTOMOT: u8 = 4;
-
-fn set_bool_cfg(cmd: &mut Vec<u8>, cfg: u32, val: bool) {
-    set_u8_cfg(cmd, cfg, val as u8);
+use crate::cfg_keys::{CfgBuilder, CFG_MSGOUT_UBX_NAV_PVT_UART1, CFG_MSGOUT_UBX_NAV_PVT_USB, CFG_NAVSPG_DYNMODEL, CFG_NAVSPG_FIXMODE, CFG_RATE_MEAS, CFG_RATE_NAV, CFG_UART1OUTPROT_NMEA, CFG_UART1OUTPROT_UBX, CFG_USBOUTPROT_NMEA, CFG_USBOUTPROT_UBX};
+use crate::uart_baud::{termios_baud, BaudSwitcher, LinkState, LinkStep};
+use crate::receiver::ReceiverInfo;
+use crate::ublox_config::{CfgMismatch, ConfigState, Configurator, KeySet, ACK_TIMEOUT, MAX_CFG_ATTEMPTS};
+use crate::ubx::UbxFramer;
+use crate::ubx_messages::{CfgCfg, CfgMsg, CfgNav5, CfgRate, MonVer, NavPvt, UbxMessage, UbxMsg, CFG_CFG_DEV_BBR, CFG_CFG_DEV_FLASH, CFG_LAYER_BBR, CFG_LAYER_FLASH};
+
+const CFG_NAVSPG_FIXMODE_2DONLY: u64 = 1;
+const CFG_NAVSPG_DYNMODEL_AUTOMOT: u64 = 4;
+// Between measurements, or longer on the modules that can't solve that often
+const MEAS_RATE_MS: u16 = 25;
+// GPS time, the default of CFG-RATE
+const CFG_RATE_TIME_REF_GPS: u16 = 1;
+// The standard NMEA msgs a receiver outputs by default, CFG-VALSET turns the whole protocol off instead
+const NMEA_CLASS: u8 = 0xf0;
+const NMEA_DEFAULT_MSGS: [(&str, u8); 6] = [("GGA", 0x00), ("GLL", 0x01), ("GSA", 0x02), ("GSV", 0x03), ("RMC", 0x04), ("VTG", 0x05)];
+
+// Checks the ack timeouts
+const TIMER_INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct Ublox {
+    serial_port: SerialPort,
+    framer: UbxFramer,
+    timer: TimerFd,
+    // On a uart, finds and sets the baud rate before configuring
+    link: Option<BaudSwitcher>,
+    plan: ConfigPlan,
+    // Sending the factory reset, once the link is up. The receiver comes back at its default baud rate after it
+    reset: Option<Configurator>,
+    // Waiting for the MON-VER, the attempts and when the last poll went out
+    version_request: Option<(u32, Instant)>,
+    receiver: Option<ReceiverInfo>,
+    // Once the receiver's commands are known
+    configurator: Option<Configurator>
 }
 
-fn set_u8_cfg(cmd: &mut Vec<u8>, cfg: u32, val: u8) {
-    cmd.append(&mut Vec::from(cfg.to_le_bytes()));
-    cmd.push(val);
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ReceiverPort {
+    Usb,
+    Uart1
 }
 
-fn set_u16_cfg(cmd: &mut Vec<u8>, cfg: u32, val: u16) {
-    cmd.append(&mut Vec::from(cfg.to_le_bytes()));
-    cmd.append(&mut Vec::from(val.to_le_bytes()));
+// How to configure the receiver, once its MON-VER told which commands it takes
+pub struct ConfigPlan {
+    pub port: ReceiverPort,
+    // Of the service's keys, RAM and any of BBR and flash
+    pub layers: u8,
+    pub factory_reset: bool,
+    // Set after the service's, e.g. from a cfg file
+    pub extra_key_sets: Vec<KeySet>
 }
 
-fn fletcher8(buffer: &[u8]) -> u16 {
-    let mut ck_a: u8 = 0;
-    let mut ck_b: u8 = 0;
+impl ConfigPlan {
+    // Without a MON-VER, a current receiver is assumed
+    fn key_sets(&mut self, receiver: Option<&ReceiverInfo>) -> Vec<KeySet> {
+        let meas_rate_ms = meas_rate_ms(receiver);
 
-    for &e in buffer {
-        ck_a = ck_a.wrapping_add(e);
-        ck_b = ck_b.wrapping_add(ck_a);
-    }
+        let mut key_sets = Vec::new();
 
-    u16::from_be_bytes([ck_a, ck_b])
-}
+        if receiver.is_none_or(ReceiverInfo::uses_valset) {
+            key_sets.extend(service_key_sets(self.port, self.layers, meas_rate_ms));
+            key_sets.append(&mut self.extra_key_sets);
+        }
+        else {
+            warn!("The receiver predates CFG-VALSET, configuring it with CFG-MSG, CFG-NAV5 and CFG-RATE");
+            if !self.extra_key_sets.is_empty() {
+                warn!("Skipping the cfg file keys, they need CFG-VALSET");
+            }
+            key_sets.extend(legacy_key_sets(self.layers, meas_rate_ms));
+        }
 
-enum ReadProgress {
-    SearchForSync,
-    HandleHeaderPayload,
-    VerifyCk
+        key_sets
+    }
 }
 
-pub struct Ublox {
-    serial_port: SerialPort,
-    buf: [u8; 256],
-    buf_read_pos: usize,
-    buf_read_count: usize,
-    buf_read_progress: ReadProgress,
-    buf_msg_start: usize
+fn meas_rate_ms(receiver: Option<&ReceiverInfo>) -> u16 {
+    let Some(receiver) = receiver else {
+        return MEAS_RATE_MS;
+    };
+
+    let max_nav_rate_hz = receiver.max_nav_rate_hz();
+    let fastest_ms = 1000u32.div_ceil(max_nav_rate_hz) as u16;
+
+    if fastest_ms > MEAS_RATE_MS {
+        info!("The receiver solves at {} Hz at most, measuring every {} ms", max_nav_rate_hz, fastest_ms);
+    }
+
+    MEAS_RATE_MS.max(fastest_ms)
 }
 
 impl Ublox {
-    pub fn new(port_name: &str, baud: BaudRate) -> Ublox {
+    // The link's rate replaces baud
+    pub fn new(port_name: &str, baud: BaudRate, link: Option<BaudSwitcher>, plan: ConfigPlan) -> Ublox {
         let sp = SerialPort::new(port_name);
-        sp.set_access_exclusive();
-        sp.configure(1, 1, baud);
+        sp.set_access_exclusive()
+            .expect("ioctl TIOCEXCL");
+        sp.configure(1, 1, baud)
+            .expect("configure serial");
+
+        let timer = TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::empty())
+            .expect("timerfd");
+
+        timer.set(Expiration::Interval(TimeSpec::from_duration(TIMER_INTERVAL)), TimerSetTimeFlags::empty())
+            .expect("timerfd set");
 
         Ublox {
             serial_port: sp,
-            buf: [0; 256],
-            buf_read_pos: 0,
-            buf_read_count: UBX_MIN_LEN,
-            buf_read_progress: SearchForSync,
-            buf_msg_start: 0
+            framer: UbxFramer::new(),
+            timer,
+            link,
+            plan,
+            reset: None,
+            version_request: None,
+            receiver: None,
+            configurator: None
         }
     }
 
@@ -102,141 +127,393 @@ impl Ublox {
         &self.serial_port.fd
     }
 
-    pub fn configure(&self) {
-        let mut cmd = vec![
-            UBX_SYNC_CHAR_1,
-            UBX_SYNC_CHAR_2,
-            CLASS_CFG,
-            ID_CFG_VALSET
-        ];
+    pub fn get_timer_fd(&self) -> &TimerFd {
+        &self.timer
+    }
 
-        let mut payload = vec![
-            CFG_VERSION,
-            CFG_LAYER_RAM,
-            0, 0 // reserved
-        ];
+    pub fn config_state(&self) -> ConfigState {
+        match self.link_state() {
+            LinkState::Failed => ConfigState::Failed,
+            _ => self.reset.as_ref().or(self.configurator.as_ref()).map_or(ConfigState::Idle, Configurator::state)
+        }
+    }
 
-        // enable UBX, disable NMEA over usb
-        set_bool_cfg(&mut payload, CFG_USBOUTPROT_UBX, true);
-        set_bool_cfg(&mut payload, CFG_USBOUTPROT_NMEA, false);
+    // What the receiver told of itself, once it answered the MON-VER
+    pub fn receiver(&self) -> Option<&ReceiverInfo> {
+        self.receiver.as_ref()
+    }
 
-        // set fix mode to 2d, automotive dynamic profile
-        set_u8_cfg(&mut payload, CFG_NAVSPG_FIXMODE, CFG_NAVSPG_FIXMODE_2DONLY);
-        set_u8_cfg(&mut payload, CFG_NAVSPG_DYNMODEL, CFG_NAVSPG_DYNMODEL_AUTOMOT);
+    fn link_state(&self) -> LinkState {
+        self.link.as_ref().map_or(LinkState::Synced, BaudSwitcher::state)
+    }
 
-        // set gnss measurements to 25hz
-        set_u8_cfg(&mut payload, CFG_MSGOUT_UBX_NAV_POSLLH_USB, 1);
-        set_u16_cfg(&mut payload, CFG_RATE_MEAS, 25);
-        set_u16_cfg(&mut payload, CFG_RATE_NAV, 1);
+    // Once the link is up, asks the receiver's version then sends the key sets it takes one by one
+    // as they are acked, see config_state
+    pub fn configure(&mut self) {
+        match self.link.as_mut() {
+            Some(link) => {
+                let step = link.start(Instant::now());
+                self.apply(step);
+            }
+            None => self.link_up()
+        }
+    }
 
-        // set len in header and append payload
-        let payload_len = payload.len() as u16;
-        cmd.append(&mut Vec::from(payload_len.to_le_bytes()));
-        cmd.append(&mut payload);
+    fn link_up(&mut self) {
+        if !self.plan.factory_reset {
+            self.request_version();
+            return;
+        }
 
-        // compute and append checksum
-        let ck = fletcher8(&cmd[UBX_CLASS_OFFSET..]);
-        cmd.append(&mut Vec::from(ck.to_le_bytes()));
+        // Only once, configure starts over when it's acked
+        self.plan.factory_reset = false;
 
-        self.serial_port.write(&cmd);
+        let mut reset = Configurator::new(vec![KeySet::factory_reset()]);
+        let cmd = reset.start(Instant::now());
+        self.reset = Some(reset);
+        self.serial_port.write(&cmd)
+            .expect("write");
     }
 
-    pub fn handle_incoming_ublox_msg(&mut self) {
-
+    // The defaults it loaded may have moved the receiver's uart, the link is set up again
+    fn handle_reset_state(&mut self) {
+        if self.reset.as_ref().is_some_and(|reset| reset.state() == ConfigState::Configured) {
+            info!("Receiver reset to its factory configuration");
+            self.reset = None;
+            self.configure();
+        }
     }
 
-    fn reset_read(&mut self, nbytes_to_keep: usize) {
-        self.buf_read_pos = nbytes_to_keep;
-        self.buf_read_count = UBX_MIN_LEN - nbytes_to_keep;
-        self.buf_read_progress = SearchForSync;
+    fn request_version(&mut self) {
+        self.version_request = Some((1, Instant::now()));
+        self.serial_port.write(&MonVer::poll())
+            .expect("write");
     }
 
-    fn parse_ublox_msg(&mut self) {
-        let buf_slice = &mut self.buf[self.buf_read_pos..self.buf_read_count];
-        let c = self.serial_port.read(buf_slice);
+    fn start_configurator(&mut self) {
+        self.version_request = None;
 
-        self.buf_read_pos += c;
-        self.buf_read_count -= c;
+        let mut configurator = Configurator::new(self.plan.key_sets(self.receiver.as_ref()));
+        let cmd = configurator.start(Instant::now());
+        self.configurator = Some(configurator);
+        self.serial_port.write(&cmd)
+            .expect("write");
+    }
 
-        if self.buf_read_count > 0 {
-            return // partial msg
+    fn apply(&mut self, step: LinkStep) {
+        if let Some(rate) = step.baud {
+            self.serial_port.configure(1, 1, termios_baud(rate).expect("uart baud rate"))
+                .expect("configure serial");
+            // What was read at the old rate is garbage
+            self.serial_port.flush_all()
+                .expect("tcflush");
+            self.framer = UbxFramer::new();
         }
 
-        if let SearchForSync = self.buf_read_progress {
-            match self.buf[..UBX_MIN_LEN].iter().position(|&b| b == UBX_SYNC_CHAR_1) {
-                Some(sync_pos) => {
-                    // the message starts at sync char 1
-                    self.buf_msg_start = sync_pos;
-                    self.buf_read_progress = ReadProgress::HandleHeaderPayload;
-                    if sync_pos > 0 {
-                        // since we had to skip n bytes to find sync char 1,
-                        // we need to fetch the next n bytes to again have UBX_MIN_LEN
-                        // bytes in our buffer
-                        self.buf_read_count = sync_pos;
-                        return // partial msg
-                    }
-                }
-                None => {
-                    // we didn't find sync char 1 in the available data
-                    self.reset_read(0);
-                    return
-                }
-            }
-        }
+        self.serial_port.write(&step.cmd)
+            .expect("write");
+    }
 
-        if let ReadProgress::HandleHeaderPayload = self.buf_read_progress {
-            if self.buf[self.buf_msg_start + 1] != UBX_SYNC_CHAR_2 {
-                // we expected sync char 2 immediately after sync char 1
-                // discard wrong sync char and look for sync char 1 again
-                let nbytes_to_keep = UBX_MIN_LEN - 1;
-                self.buf.copy_within(self.buf_msg_start + 1..nbytes_to_keep, 0);
-                self.reset_read(nbytes_to_keep);
+    // The keys the receiver didn't keep, once configured
+    pub fn config_mismatches(&self) -> &[CfgMismatch] {
+        self.configurator.as_ref().map_or(&[], Configurator::mismatches)
+    }
 
-                return // unknown data
-            }
+    // Whether the receiver measures at the rate it was set to, the lap times are only as precise as that
+    pub fn rate_is_configured(&self) -> bool {
+        !self.config_mismatches().iter().any(|mismatch| [CFG_RATE_MEAS, CFG_RATE_NAV].contains(&mismatch.key))
+    }
 
-            const uint16_t payload_len = as_uint16(buffer_read.ubx_msg + UBX_LEN_OFFSET);
+    // Reads what the receiver sent and returns the msgs it completed, bad ones are logged and skipped
+    pub fn handle_incoming_ublox_msg(&mut self) -> Vec<UbxMsg> {
+        let mut buf = [0u8; 256];
+        let c = self.serial_port.read(&mut buf)
+            .expect("read");
 
-            buffer_read.progress = VERIFY_CK;
+        self.framer.push(&buf[..c]);
 
-            if (payload_len != 0) {
-                buffer_read.requested_count = payload_len;
+        let mut msgs = Vec::new();
+        while let Some(frame) = self.framer.next_frame() {
+            match frame.and_then(UbxMsg::parse) {
+                Ok(msg) => {
+                    self.handle_cfg_msg(&msg);
+                    msgs.push(msg);
+                }
+                // Expected while probing the baud rate
+                Err(e) if self.link_state() == LinkState::Probing => debug!("{}", e),
+                Err(e) => warn!("{}", e)
+            }
+        }
 
-                if (buffer_read.offset + buffer_read.requested_count > sizeof(input_buffer)) {
-                    const uint8_t msg_class = buffer_read.ubx_msg[UBX_CLASS_OFFSET];
-                    const uint8_t msg_id = buffer_read.ubx_msg[UBX_ID_OFFSET];
+        msgs
+    }
 
-                    fprintf(stderr, "ubx msg class %d id %d len %d is too big for our %lu buffer\n", msg_class, msg_id,
-                            payload_len, sizeof(input_buffer));
+    fn handle_cfg_msg(&mut self, msg: &UbxMsg) {
+        if self.link_state() != LinkState::Synced {
+            if let Some(step) = self.link.as_mut().and_then(|link| link.handle_msg(msg, Instant::now())) {
+                self.apply(step);
+            }
+            if self.link_state() == LinkState::Synced {
+                self.link_up();
+            }
+        }
+        else if let Some(reset) = self.reset.as_mut() {
+            if let Some(cmd) = reset.handle_msg(msg, Instant::now()) {
+                self.serial_port.write(&cmd)
+                    .expect("write");
+            }
+            self.handle_reset_state();
+        }
+        else if self.version_request.is_some() {
+            if let UbxMsg::MonVer(ver) = msg {
+                let receiver = ReceiverInfo::from_mon_ver(ver);
+                info!("Receiver {}", receiver);
+                self.receiver = Some(receiver);
+                self.start_configurator();
+            }
+        }
+        else if let Some(cmd) = self.configurator.as_mut().and_then(|configurator| configurator.handle_msg(msg, Instant::now())) {
+            self.serial_port.write(&cmd)
+                .expect("write");
+        }
+    }
 
-                    reset_read(0);
+    pub fn handle_timer(&mut self) {
+        self.timer.wait()
+            .expect("timerfd wait");
 
-                    return -PAYLOAD_TOO_BIG;
-                }
+        let now = Instant::now();
 
-                return PARTIAL_MSG;
+        if self.link_state() != LinkState::Synced {
+            if let Some(step) = self.link.as_mut().and_then(|link| link.handle_timer(now)) {
+                self.apply(step);
+            }
+        }
+        else if let Some(reset) = self.reset.as_mut() {
+            if let Some(cmd) = reset.handle_timer(now) {
+                self.serial_port.write(&cmd)
+                    .expect("write");
+            }
+        }
+        else if let Some((attempts, sent_at)) = self.version_request {
+            if now.duration_since(sent_at) < ACK_TIMEOUT {
+                return;
             }
 
-            assert(buffer_read.progress == VERIFY_CK);
-            const uint16_t payload_len = as_uint16(buffer_read.ubx_msg + UBX_LEN_OFFSET);
-            const uint16_t expected_ck = as_uint16(buffer_read.ubx_msg + UBX_PAYLOAD_OFFSET + payload_len);
-
-            const uint16_t actual_ck = fletcher8(buffer_read.ubx_msg + UBX_CLASS_OFFSET, UBX_HEADER_LEN + payload_len);
-            if (actual_ck != expected_ck) {
-                fprintf(stderr, "ck actual %04x but expected %04x\n", actual_ck, expected_ck);
-                return -CK_FAIL;
+            if attempts >= MAX_CFG_ATTEMPTS {
+                // A receiver that answers nothing fails the configuration anyway
+                warn!("No MON-VER from the receiver after {} attempts, configuring it with CFG-VALSET", attempts);
+                self.start_configurator();
+            }
+            else {
+                self.version_request = Some((attempts + 1, now));
+                self.serial_port.write(&MonVer::poll())
+                    .expect("write");
             }
+        }
+        else if let Some(cmd) = self.configurator.as_mut().and_then(|configurator| configurator.handle_timer(now)) {
+            self.serial_port.write(&cmd)
+                .expect("write");
+        }
+    }
+}
 
-            *msg = buffer_read.ubx_msg;
-            reset_read(0);
+// What the service needs from the receiver on the port it's wired to,
+// into RAM and any of BBR and flash in layers
+fn service_key_sets(port: ReceiverPort, layers: u8, meas_rate_ms: u16) -> Vec<KeySet> {
+    const VALID: &str = "valid cfg value";
+
+    let (out_ubx, out_nmea, msgout_nav_pvt) = match port {
+        ReceiverPort::Usb => (CFG_USBOUTPROT_UBX, CFG_USBOUTPROT_NMEA, CFG_MSGOUT_UBX_NAV_PVT_USB),
+        ReceiverPort::Uart1 => (CFG_UART1OUTPROT_UBX, CFG_UART1OUTPROT_NMEA, CFG_MSGOUT_UBX_NAV_PVT_UART1)
+    };
+
+    // enable UBX, disable NMEA on the port
+    let mut output = CfgBuilder::new();
+    output.set(out_ubx, true as u64).expect(VALID)
+        .set(out_nmea, false as u64).expect(VALID);
+
+    // set fix mode to 2d, automotive dynamic profile
+    let mut navigation = CfgBuilder::new();
+    navigation.set(CFG_NAVSPG_FIXMODE, CFG_NAVSPG_FIXMODE_2DONLY).expect(VALID)
+        .set(CFG_NAVSPG_DYNMODEL, CFG_NAVSPG_DYNMODEL_AUTOMOT).expect(VALID);
+
+    // output a NAV-PVT every solution, measure as often as the receiver can
+    let mut msg_output = CfgBuilder::new();
+    msg_output.set(msgout_nav_pvt, 1).expect(VALID);
+
+    let mut rate = CfgBuilder::new();
+    rate.set(CFG_RATE_MEAS, meas_rate_ms as u64).expect(VALID)
+        .set(CFG_RATE_NAV, 1).expect(VALID);
+
+    vec![
+        KeySet::set("output protocol", layers, output),
+        KeySet::set("navigation", layers, navigation),
+        KeySet::set("msg output", layers, msg_output),
+        KeySet::set("rate", layers, rate)
+    ]
+}
 
-            return 0;
-        }
+// The same for the receivers before protocol 23, the default NMEA msgs are turned off one by one on the port
+fn legacy_key_sets(layers: u8, meas_rate_ms: u16) -> Vec<KeySet> {
+    let navigation = CfgNav5 {
+        mask: CfgNav5::MASK_DYN | CfgNav5::MASK_POS_FIX_MODE,
+        dyn_model: CFG_NAVSPG_DYNMODEL_AUTOMOT as u8,
+        fix_mode: CFG_NAVSPG_FIXMODE_2DONLY as u8
+    };
+
+    let mut key_sets: Vec<KeySet> = NMEA_DEFAULT_MSGS.iter()
+        .map(|&(name, msg_id)| KeySet::command(&format!("{} output", name), &CfgMsg { msg_class: NMEA_CLASS, msg_id, rate: 0 }))
+        .collect();
+    key_sets.extend([
+        KeySet::command("navigation", &navigation),
+        KeySet::command("msg output", &CfgMsg { msg_class: NavPvt::CLASS, msg_id: NavPvt::ID, rate: 1 }),
+        KeySet::command("rate", &CfgRate { meas_rate_ms, nav_rate: 1, time_ref: CFG_RATE_TIME_REF_GPS })
+    ]);
+
+    // Saves everything at once, the commands only go to RAM
+    let mut device_mask = 0;
+    if layers & CFG_LAYER_BBR != 0 {
+        device_mask |= CFG_CFG_DEV_BBR;
     }
+    if layers & CFG_LAYER_FLASH != 0 {
+        device_mask |= CFG_CFG_DEV_FLASH;
+    }
+    if device_mask != 0 {
+        key_sets.push(KeySet::command("save", &CfgCfg::save(device_mask)));
+    }
+
+    key_sets
 }
 
 impl Drop for Ublox {
     fn drop(&mut self) {
-        self.serial_port.set_access_nonexclusive();
+        self.serial_port.set_access_nonexclusive()
+            .expect("ioctl TIOCNXCL");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+    use nix::pty::openpty;
+    use nix::unistd::{read, ttyname, write};
+    use crate::ublox_config::KeyAction;
+    use crate::ubx::{encode_frame, UbxFrame};
+    use crate::ubx_messages::{AckAck, CfgValset, CFG_LAYER_RAM};
+    use super::*;
+
+    fn configure_items() -> Vec<(u32, u64)> {
+        service_key_sets(ReceiverPort::Usb, CFG_LAYER_RAM, MEAS_RATE_MS).into_iter()
+            .flat_map(|key_set| key_set.cfg.items().to_vec())
+            .map(|(key, value)| (key.id, value))
+            .collect()
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn config() {
+        // What the C service sent in one VALSET, with NAV-PVT enabled in place of NAV-POSLLH
+        let payload = [
+            0x00, 0x01, 0x00, 0x00,
+            0x01, 0x00, 0x78, 0x10, 0x01,
+            0x02, 0x00, 0x78, 0x10, 0x00,
+            0x11, 0x00, 0x11, 0x20, 0x01,
+            0x21, 0x00, 0x11, 0x20, 0x04,
+            0x09, 0x00, 0x91, 0x20, 0x01,
+            0x01, 0x00, 0x21, 0x30, 0x19, 0x00,
+            0x02, 0x00, 0x21, 0x30, 0x01, 0x00
+        ];
+
+        let cmd = CfgValset { layers: CFG_LAYER_RAM, items: configure_items() }.encode();
+        assert_eq!(cmd, encode_frame(CfgValset::CLASS, CfgValset::ID, &payload));
+    }
+
+    fn receiver(hw_version: &str, extensions: &[&str]) -> ReceiverInfo {
+        ReceiverInfo::from_mon_ver(&MonVer {
+            sw_version: String::from("ROM CORE 3.01 (107888)"),
+            hw_version: hw_version.to_string(),
+            extensions: extensions.iter().map(|extension| extension.to_string()).collect()
+        })
+    }
+
+    fn frame<M: UbxMessage>(msg: M) -> UbxFrame {
+        UbxFrame { class: M::CLASS, id: M::ID, payload: msg.payload() }
+    }
+
+    #[test]
+    fn plans_by_receiver() {
+        let mut plan = ConfigPlan { port: ReceiverPort::Usb, layers: CFG_LAYER_RAM | CFG_LAYER_BBR, factory_reset: false, extra_key_sets: Vec::new() };
+
+        // An M10 can't solve every 25 ms
+        let m10 = plan.key_sets(Some(&receiver("000A0000", &["PROTVER=34.10", "MOD=MAX-M10S"])));
+        assert_eq!(m10.len(), 4);
+        assert_eq!(m10[3].cfg.items(), &[(CFG_RATE_MEAS, 100), (CFG_RATE_NAV, 1)]);
+
+        let m8 = plan.key_sets(Some(&receiver("00080000", &["PROTVER 18.00"])));
+        let commands: Vec<(&str, UbxFrame)> = m8.iter()
+            .map(|key_set| match &key_set.action {
+                KeyAction::Command(frame) => (key_set.name.as_str(), frame.clone()),
+                action => panic!("{:?}", action)
+            })
+            .collect();
+
+        assert_eq!(commands, vec![
+            ("GGA output", frame(CfgMsg { msg_class: 0xf0, msg_id: 0x00, rate: 0 })),
+            ("GLL output", frame(CfgMsg { msg_class: 0xf0, msg_id: 0x01, rate: 0 })),
+            ("GSA output", frame(CfgMsg { msg_class: 0xf0, msg_id: 0x02, rate: 0 })),
+            ("GSV output", frame(CfgMsg { msg_class: 0xf0, msg_id: 0x03, rate: 0 })),
+            ("RMC output", frame(CfgMsg { msg_class: 0xf0, msg_id: 0x04, rate: 0 })),
+            ("VTG output", frame(CfgMsg { msg_class: 0xf0, msg_id: 0x05, rate: 0 })),
+            ("navigation", frame(CfgNav5 { mask: 0x05, dyn_model: 4, fix_mode: 1 })),
+            ("msg output", frame(CfgMsg { msg_class: 0x01, msg_id: 0x07, rate: 1 })),
+            ("rate", frame(CfgRate { meas_rate_ms: 100, nav_rate: 1, time_ref: 1 })),
+            ("save", frame(CfgCfg::save(CFG_CFG_DEV_BBR)))
+        ]);
+    }
+
+    // What the service sent to the receiver's end of the pty
+    fn sent(receiver: &OwnedFd) -> Vec<u8> {
+        let mut buf = [0u8; 256];
+        let n = read(receiver.as_raw_fd(), &mut buf).unwrap();
+        buf[..n].to_vec()
+    }
+
+    #[test]
+    fn sets_the_link_up_again_after_a_factory_reset() {
+        let pty = openpty(None, None).unwrap();
+        let port_name = ttyname(&pty.slave).unwrap();
+
+        let plan = ConfigPlan { port: ReceiverPort::Uart1, layers: CFG_LAYER_RAM, factory_reset: true, extra_key_sets: Vec::new() };
+        let link = BaudSwitcher::new(115200, CFG_LAYER_RAM);
+        let mut ublox = Ublox::new(port_name.to_str().unwrap(), BaudRate::B38400, Some(link), plan);
+
+        let at_target_rate = MonVer {
+            sw_version: String::from("ROM SPG 5.10 (7b202e)"),
+            hw_version: String::from("000A0000"),
+            extensions: vec![String::from("PROTVER=34.10")]
+        }.encode();
+        let receive = |ublox: &mut Ublox, bytes: &[u8]| {
+            write(&pty.master, bytes).unwrap();
+            ublox.handle_incoming_ublox_msg();
+        };
+
+        ublox.configure();
+        assert_eq!(sent(&pty.master), MonVer::poll());
+
+        // Reset once the receiver answers at the wanted rate
+        receive(&mut ublox, &at_target_rate);
+        assert_eq!(sent(&pty.master), CfgCfg::factory_reset().encode());
+        assert_eq!(ublox.config_state(), ConfigState::Setting);
+
+        // It loaded its default rate, the link starts over before the version is asked
+        receive(&mut ublox, &AckAck { class: CfgCfg::CLASS, id: CfgCfg::ID }.encode());
+        assert_eq!(ublox.link_state(), LinkState::Probing);
+        assert_eq!(sent(&pty.master), MonVer::poll());
+
+        // Then asks the version for the configuration
+        receive(&mut ublox, &at_target_rate);
+        assert_eq!(ublox.link_state(), LinkState::Synced);
+        assert_eq!(sent(&pty.master), MonVer::poll());
+        assert_eq!(ublox.config_state(), ConfigState::Idle);
+    }
+}
@@ -0,0 +1,554 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+use log::{error, info, warn};
+use crate::cfg_keys::{cfg_key, CfgBuilder, CfgKey};
+use crate::ubx::{encode_frame, UbxFrame};
+use crate::ubx_messages::{CfgCfg, CfgGetLayer, CfgValdel, CfgValget, CfgValset, UbxMessage, UbxMsg, CFG_LAYER_BBR, CFG_LAYER_FLASH, CFG_LAYER_RAM};
+
+// The receiver answers a CFG-VALSET or VALGET within a second
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+// Sends of a request before giving up on the receiver
+pub const MAX_CFG_ATTEMPTS: u32 = 3;
+// Keys in one CFG-VALSET, VALGET or VALDEL
+pub const MAX_CFG_KEYS: usize = 64;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum KeyAction {
+    Set,
+    // Back to the defaults, from BBR and flash only
+    Delete,
+    // A command acked like the others, a CFG-CFG or one of the older receivers' CFG-MSG, CFG-RATE...
+    Command(UbxFrame)
+}
+
+// Keys set or deleted together, the receiver applies all of them or none
+pub struct KeySet {
+    pub name: String,
+    // A mask of CFG_LAYER_*
+    pub layers: u8,
+    pub action: KeyAction,
+    pub cfg: CfgBuilder
+}
+
+impl KeySet {
+    // The receiver forgets what's only in RAM at power off, BBR lasts while it has a backup supply
+    pub fn set(name: &str, layers: u8, cfg: CfgBuilder) -> KeySet {
+        KeySet { name: name.to_string(), layers, action: KeyAction::Set, cfg }
+    }
+
+    pub fn command<M: UbxMessage>(name: &str, msg: &M) -> KeySet {
+        let frame = UbxFrame { class: M::CLASS, id: M::ID, payload: msg.payload() };
+        KeySet { name: name.to_string(), layers: 0, action: KeyAction::Command(frame), cfg: CfgBuilder::new() }
+    }
+
+    // Every key back to its default in all the layers
+    pub fn factory_reset() -> KeySet {
+        KeySet::command("factory reset", &CfgCfg::factory_reset())
+    }
+
+    fn request(&self) -> Vec<u8> {
+        match &self.action {
+            KeyAction::Set => self.cfg.valset(self.layers),
+            KeyAction::Delete => self.cfg.valdel(self.layers),
+            KeyAction::Command(frame) => encode_frame(frame.class, frame.id, &frame.payload)
+        }
+    }
+
+    fn request_id(&self) -> (u8, u8) {
+        match &self.action {
+            KeyAction::Set => (CfgValset::CLASS, CfgValset::ID),
+            KeyAction::Delete => (CfgValdel::CLASS, CfgValdel::ID),
+            KeyAction::Command(frame) => (frame.class, frame.id)
+        }
+    }
+
+    // Read back from the most volatile layer it was set in, deleted keys fall back to
+    // whatever the layers below hold so there's nothing to compare them with
+    fn verify_layer(&self) -> Option<CfgGetLayer> {
+        match &self.action {
+            KeyAction::Delete | KeyAction::Command(_) => None,
+            KeyAction::Set if self.layers & CFG_LAYER_RAM != 0 => Some(CfgGetLayer::Ram),
+            KeyAction::Set if self.layers & CFG_LAYER_BBR != 0 => Some(CfgGetLayer::Bbr),
+            KeyAction::Set => Some(CfgGetLayer::Flash)
+        }
+    }
+
+    fn valget(&self) -> Vec<u8> {
+        self.cfg.valget(self.verify_layer().expect("verified key set"))
+    }
+
+    fn describe_items(&self) -> Vec<String> {
+        self.cfg.items().iter()
+            .map(|(key, value)| match self.action {
+                KeyAction::Set => format!("{}={}", key.name, key.format(*value)),
+                KeyAction::Delete | KeyAction::Command(_) => key.name.to_string()
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct CfgFileError {
+    pub line: usize,
+    pub reason: String
+}
+
+impl fmt::Display for CfgFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cfg file line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for CfgFileError {}
+
+// Key sets from a config file, a line each of
+//   # a comment
+//   layers = ram bbr flash      where the following lines go, ram until given
+//   CFG-RATE-MEAS = 40          a key by name and its value, see CfgKey::parse_value
+//   delete CFG-RATE-MEAS        back to its default, from the bbr and flash layers given
+// Consecutive lines of the same kind are sent together.
+pub fn parse_cfg_file(text: &str) -> Result<Vec<KeySet>, CfgFileError> {
+    let mut key_sets: Vec<KeySet> = Vec::new();
+    let mut layers = CFG_LAYER_RAM;
+    // Whether the last key set still takes keys
+    let mut open = false;
+
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let error = |reason: String| CfgFileError { line: line_number, reason };
+
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (action, key, value) = if let Some(name) = line.strip_prefix("delete ") {
+            (KeyAction::Delete, cfg_key(name.trim()).map_err(|e| error(e.to_string()))?, 0)
+        }
+        else if let Some((name, value)) = line.split_once('=') {
+            let (name, value) = (name.trim(), value.trim());
+
+            if name == "layers" {
+                layers = parse_layers(value).map_err(error)?;
+                open = false;
+                continue;
+            }
+
+            let key = cfg_key(name).map_err(|e| error(e.to_string()))?;
+            let value = key.parse_value(value).map_err(|e| error(e.to_string()))?;
+            (KeyAction::Set, key, value)
+        }
+        else {
+            return Err(error(format!("expected KEY = value, delete KEY or layers = ..., got {}", line)));
+        };
+
+        let persistent_layers = layers & (CFG_LAYER_BBR | CFG_LAYER_FLASH);
+        let set_layers = if action == KeyAction::Delete { persistent_layers } else { layers };
+        if set_layers == 0 {
+            return Err(error(String::from("keys are only deleted from bbr or flash")));
+        }
+
+        let fits = key_sets.last().is_some_and(|key_set|
+            key_set.action == action && key_set.cfg.items().len() < MAX_CFG_KEYS);
+
+        if !open || !fits {
+            key_sets.push(KeySet { name: format!("cfg file line {}", line_number), layers: set_layers, action: action.clone(), cfg: CfgBuilder::new() });
+            open = true;
+        }
+
+        let cfg = &mut key_sets.last_mut().expect("key set").cfg;
+        if action == KeyAction::Set {
+            cfg.set(key, value).map_err(|e| error(e.to_string()))?;
+        }
+        else {
+            cfg.key(key);
+        }
+    }
+
+    Ok(key_sets)
+}
+
+// Layer names separated by spaces or commas, e.g. "bbr,flash"
+pub fn parse_layers(value: &str) -> Result<u8, String> {
+    let mut layers = 0;
+
+    for layer in value.split([' ', ',']).filter(|layer| !layer.is_empty()) {
+        layers |= match layer {
+            "ram" => CFG_LAYER_RAM,
+            "bbr" => CFG_LAYER_BBR,
+            "flash" => CFG_LAYER_FLASH,
+            _ => return Err(format!("unknown layer {}, expected ram, bbr or flash", layer))
+        };
+    }
+
+    if layers == 0 { Err(String::from("no layers")) } else { Ok(layers) }
+}
+
+// A key the receiver doesn't hold the value it was set to, e.g. an old firmware that acks keys it ignores
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CfgMismatch {
+    pub key: CfgKey,
+    pub expected: u64,
+    // None when the receiver didn't give it back
+    pub actual: Option<u64>
+}
+
+impl fmt::Display for CfgMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({:#010x}): expected {}, actual ", self.key.name, self.key.id, self.key.format(self.expected))?;
+
+        match self.actual {
+            Some(actual) => write!(f, "{}", self.key.format(actual)),
+            None => write!(f, "missing")
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConfigState {
+    Idle,
+    // Waiting for the ack of a key set's CFG-VALSET
+    Setting,
+    // Waiting for a key set's values
+    Verifying,
+    Configured,
+    Failed
+}
+
+// Sends the key sets one CFG-VALSET at a time, each waits for its ACK or NAK before the next,
+// then reads them back one CFG-VALGET at a time. The methods return what to send to the receiver.
+pub struct Configurator {
+    key_sets: Vec<KeySet>,
+    current: usize,
+    attempts: u32,
+    sent_at: Instant,
+    state: ConfigState,
+    mismatches: Vec<CfgMismatch>
+}
+
+impl Configurator {
+    pub fn new(key_sets: Vec<KeySet>) -> Configurator {
+        assert!(!key_sets.is_empty(), "no key sets to configure");
+
+        Configurator {
+            key_sets,
+            current: 0,
+            attempts: 0,
+            sent_at: Instant::now(),
+            state: ConfigState::Idle,
+            mismatches: Vec::new()
+        }
+    }
+
+    pub fn state(&self) -> ConfigState {
+        self.state
+    }
+
+    // What the read back found, once Configured
+    pub fn mismatches(&self) -> &[CfgMismatch] {
+        &self.mismatches
+    }
+
+    pub fn start(&mut self, now: Instant) -> Vec<u8> {
+        self.mismatches.clear();
+        self.state = ConfigState::Setting;
+        self.advance(0, now).expect("a key set to send")
+    }
+
+    fn send_current(&mut self, now: Instant) -> Vec<u8> {
+        self.attempts = 1;
+        self.sent_at = now;
+        self.current_request()
+    }
+
+    fn current_request(&self) -> Vec<u8> {
+        let key_set = &self.key_sets[self.current];
+
+        match self.state {
+            ConfigState::Setting => key_set.request(),
+            _ => key_set.valget()
+        }
+    }
+
+    // The first key set from this one on that the stage applies to, or the next stage
+    fn advance(&mut self, from: usize, now: Instant) -> Option<Vec<u8>> {
+        let verifying = self.state == ConfigState::Verifying;
+        let pending = (from..self.key_sets.len())
+            .find(|&i| !verifying || self.key_sets[i].verify_layer().is_some());
+
+        if let Some(i) = pending {
+            self.current = i;
+            return Some(self.send_current(now));
+        }
+
+        if !verifying {
+            self.state = ConfigState::Verifying;
+            return self.advance(0, now);
+        }
+
+        if self.mismatches.is_empty() {
+            info!("receiver configuration verified");
+        }
+        else {
+            warn!("receiver configuration differs from what was set:");
+            for mismatch in &self.mismatches {
+                warn!("  {}", mismatch);
+            }
+        }
+
+        self.state = ConfigState::Configured;
+        None
+    }
+
+    fn next(&mut self, now: Instant) -> Option<Vec<u8>> {
+        self.advance(self.current + 1, now)
+    }
+
+    fn waiting(&self) -> bool {
+        matches!(self.state, ConfigState::Setting | ConfigState::Verifying)
+    }
+
+    pub fn handle_msg(&mut self, msg: &UbxMsg, now: Instant) -> Option<Vec<u8>> {
+        if !self.waiting() {
+            return None;
+        }
+
+        let key_set = &self.key_sets[self.current];
+
+        match (self.state, msg) {
+            (ConfigState::Setting, UbxMsg::AckAck(ack)) if (ack.class, ack.id) == key_set.request_id() => {
+                info!("receiver set the {} keys", key_set.name);
+                self.next(now)
+            }
+            (ConfigState::Setting, UbxMsg::AckNak(nak)) if (nak.class, nak.id) == key_set.request_id() => {
+                // The same keys would be refused again
+                error!("receiver refused the {} keys: {}", key_set.name, key_set.describe_items().join(", "));
+
+                self.state = ConfigState::Failed;
+                None
+            }
+            (ConfigState::Verifying, UbxMsg::CfgValget(values)) => {
+                self.compare(values);
+                self.next(now)
+            }
+            (ConfigState::Verifying, UbxMsg::AckNak(nak)) if (nak.class, nak.id) == (CfgValget::CLASS, CfgValget::ID) => {
+                // Any key it doesn't know fails the whole VALGET
+                warn!("receiver couldn't read back the {} keys", key_set.name);
+
+                let missing = key_set.cfg.items().iter()
+                    .map(|&(key, expected)| CfgMismatch { key, expected, actual: None });
+                self.mismatches.extend(missing);
+
+                self.next(now)
+            }
+            _ => None
+        }
+    }
+
+    fn compare(&mut self, values: &CfgValget) {
+        for &(key, expected) in self.key_sets[self.current].cfg.items() {
+            let actual = values.items.iter()
+                .find(|(id, _)| *id == key.id)
+                .map(|(_, value)| *value);
+
+            if actual != Some(expected) {
+                self.mismatches.push(CfgMismatch { key, expected, actual });
+            }
+        }
+    }
+
+    pub fn handle_timer(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if !self.waiting() || now.duration_since(self.sent_at) < ACK_TIMEOUT {
+            return None;
+        }
+
+        let name = &self.key_sets[self.current].name;
+
+        if self.attempts >= MAX_CFG_ATTEMPTS {
+            error!("no answer for the {} keys after {} attempts while {:?}", name, self.attempts, self.state);
+            self.state = ConfigState::Failed;
+            return None;
+        }
+
+        warn!("no answer for the {} keys within {:?} while {:?}, asking again", name, ACK_TIMEOUT, self.state);
+
+        self.attempts += 1;
+        self.sent_at = now;
+        Some(self.current_request())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg_keys::{CFG_NAVSPG_DYNMODEL, CFG_RATE_MEAS as RATE_MEAS, CFG_RATE_NAV as RATE_NAV, CFG_USBOUTPROT_UBX as UBX};
+    use crate::ubx_messages::{AckAck, AckNak, CLASS_CFG, ID_CFG_VALGET};
+    use super::*;
+
+    fn key_set(name: &str, items: &[(CfgKey, u64)]) -> KeySet {
+        let mut cfg = CfgBuilder::new();
+        for &(key, value) in items {
+            cfg.set(key, value).unwrap();
+        }
+        KeySet::set(name, CFG_LAYER_RAM, cfg)
+    }
+
+    fn key_sets() -> Vec<KeySet> {
+        vec![
+            key_set("first", &[(UBX, 1)]),
+            key_set("second", &[(RATE_MEAS, 25), (RATE_NAV, 1)])
+        ]
+    }
+
+    fn valset(items: &[(u32, u64)]) -> Option<Vec<u8>> {
+        Some(CfgValset { layers: CFG_LAYER_RAM, items: items.to_vec() }.encode())
+    }
+
+    fn valget(keys: &[u32]) -> Option<Vec<u8>> {
+        Some(CfgValget::poll(CfgGetLayer::Ram, 0, keys))
+    }
+
+    fn values(items: &[(u32, u64)]) -> UbxMsg {
+        UbxMsg::CfgValget(CfgValget { layer: CfgGetLayer::Ram as u8, position: 0, items: items.to_vec() })
+    }
+
+    const ACK: UbxMsg = UbxMsg::AckAck(AckAck { class: CfgValset::CLASS, id: CfgValset::ID });
+    const NAK: UbxMsg = UbxMsg::AckNak(AckNak { class: CfgValset::CLASS, id: CfgValset::ID });
+
+    #[test]
+    fn sets_then_reads_back_the_key_sets_one_at_a_time() {
+        let now = Instant::now();
+        let mut configurator = Configurator::new(key_sets());
+
+        assert_eq!(Some(configurator.start(now)), valset(&[(UBX.id, 1)]));
+        assert_eq!(configurator.state(), ConfigState::Setting);
+
+        // Acks of other msgs don't count
+        assert_eq!(configurator.handle_msg(&UbxMsg::AckAck(AckAck { class: CLASS_CFG, id: ID_CFG_VALGET }), now), None);
+
+        assert_eq!(configurator.handle_msg(&ACK, now), valset(&[(RATE_MEAS.id, 25), (RATE_NAV.id, 1)]));
+        assert_eq!(configurator.handle_msg(&ACK, now), valget(&[UBX.id]));
+        assert_eq!(configurator.state(), ConfigState::Verifying);
+
+        assert_eq!(configurator.handle_msg(&values(&[(UBX.id, 1)]), now), valget(&[RATE_MEAS.id, RATE_NAV.id]));
+        assert_eq!(configurator.handle_msg(&values(&[(RATE_NAV.id, 1), (RATE_MEAS.id, 25)]), now), None);
+        assert_eq!(configurator.state(), ConfigState::Configured);
+        assert_eq!(configurator.mismatches(), &[]);
+
+        // Nothing is pending anymore
+        assert_eq!(configurator.handle_msg(&NAK, now), None);
+        assert_eq!(configurator.handle_timer(now + ACK_TIMEOUT * 10), None);
+        assert_eq!(configurator.state(), ConfigState::Configured);
+    }
+
+    #[test]
+    fn tells_what_the_receiver_ignored() {
+        let now = Instant::now();
+        let mut configurator = Configurator::new(key_sets());
+        configurator.start(now);
+        configurator.handle_msg(&ACK, now);
+        configurator.handle_msg(&ACK, now);
+
+        // Couldn't read the first set back, the rate kept its default
+        configurator.handle_msg(&UbxMsg::AckNak(AckNak { class: CLASS_CFG, id: ID_CFG_VALGET }), now);
+        configurator.handle_msg(&values(&[(RATE_MEAS.id, 1000), (RATE_NAV.id, 1)]), now);
+
+        assert_eq!(configurator.state(), ConfigState::Configured);
+        assert_eq!(configurator.mismatches(), &[
+            CfgMismatch { key: UBX, expected: 1, actual: None },
+            CfgMismatch { key: RATE_MEAS, expected: 25, actual: Some(1000) }
+        ]);
+        assert_eq!(configurator.mismatches()[1].to_string(), "CFG-RATE-MEAS (0x30210001): expected 25, actual 1000");
+
+        let dynmodel = CfgMismatch { key: CFG_NAVSPG_DYNMODEL, expected: 4, actual: Some(0) };
+        assert_eq!(dynmodel.to_string(), "CFG-NAVSPG-DYNMODEL (0x20110021): expected AUTOMOT (4), actual PORT (0)");
+    }
+
+    #[test]
+    fn deletes_without_reading_back() {
+        let now = Instant::now();
+        let mut delete = CfgBuilder::new();
+        delete.key(RATE_MEAS);
+        let mut configurator = Configurator::new(vec![
+            KeySet { name: String::from("reset"), layers: CFG_LAYER_BBR, action: KeyAction::Delete, cfg: delete },
+            KeySet { name: String::from("flash"), layers: CFG_LAYER_FLASH, action: KeyAction::Set, cfg: key_sets().remove(0).cfg }
+        ]);
+
+        assert_eq!(Some(configurator.start(now)), Some(CfgValdel { layers: CFG_LAYER_BBR, keys: vec![RATE_MEAS.id] }.encode()));
+        // A VALSET ack isn't the VALDEL's
+        assert_eq!(configurator.handle_msg(&ACK, now), None);
+
+        let valdel_ack = UbxMsg::AckAck(AckAck { class: CfgValdel::CLASS, id: CfgValdel::ID });
+        assert_eq!(configurator.handle_msg(&valdel_ack, now), Some(CfgValset { layers: CFG_LAYER_FLASH, items: vec![(UBX.id, 1)] }.encode()));
+        assert_eq!(configurator.handle_msg(&ACK, now), Some(CfgValget::poll(CfgGetLayer::Flash, 0, &[UBX.id])));
+        assert_eq!(configurator.handle_msg(&values(&[(UBX.id, 1)]), now), None);
+        assert_eq!(configurator.state(), ConfigState::Configured);
+    }
+
+    #[test]
+    fn factory_resets_before_setting() {
+        let now = Instant::now();
+        let mut configurator = Configurator::new(vec![KeySet::factory_reset(), key_sets().remove(0)]);
+
+        assert_eq!(configurator.start(now), CfgCfg::factory_reset().encode());
+        assert_eq!(configurator.handle_msg(&UbxMsg::AckAck(AckAck { class: CfgCfg::CLASS, id: CfgCfg::ID }), now), valset(&[(UBX.id, 1)]));
+        // Nothing to read back from the reset
+        assert_eq!(configurator.handle_msg(&ACK, now), valget(&[UBX.id]));
+    }
+
+    #[test]
+    fn parses_a_cfg_file() {
+        let key_sets = parse_cfg_file("\
+# faster
+CFG-RATE-MEAS = 40
+CFG-NAVSPG-DYNMODEL = automot   # on track
+
+layers = bbr flash
+CFG-USBOUTPROT-NMEA = false
+delete CFG-RATE-MEAS
+").unwrap();
+
+        let described: Vec<(&str, u8, KeyAction, Vec<String>)> = key_sets.iter()
+            .map(|key_set| (key_set.name.as_str(), key_set.layers, key_set.action.clone(), key_set.describe_items()))
+            .collect();
+        assert_eq!(described, vec![
+            ("cfg file line 2", CFG_LAYER_RAM, KeyAction::Set, vec![String::from("CFG-RATE-MEAS=40"), String::from("CFG-NAVSPG-DYNMODEL=AUTOMOT (4)")]),
+            ("cfg file line 6", CFG_LAYER_BBR | CFG_LAYER_FLASH, KeyAction::Set, vec![String::from("CFG-USBOUTPROT-NMEA=false")]),
+            ("cfg file line 7", CFG_LAYER_BBR | CFG_LAYER_FLASH, KeyAction::Delete, vec![String::from("CFG-RATE-MEAS")])
+        ]);
+
+        let error = |line, reason: &str| Some(CfgFileError { line, reason: reason.to_string() });
+        assert_eq!(parse_cfg_file("\n CFG-RATE-MEAS = 1").err(), error(2, "CFG-RATE-MEAS is out of range at 1"));
+        assert_eq!(parse_cfg_file("delete CFG-RATE-MEAS").err(), error(1, "keys are only deleted from bbr or flash"));
+        assert_eq!(parse_cfg_file("layers = rom").err(), error(1, "unknown layer rom, expected ram, bbr or flash"));
+        assert_eq!(parse_cfg_file("CFG-RATE-MEAS 40").err(), error(1, "expected KEY = value, delete KEY or layers = ..., got CFG-RATE-MEAS 40"));
+    }
+
+    #[test]
+    fn retries_then_fails_without_ack() {
+        let now = Instant::now();
+        let mut configurator = Configurator::new(key_sets());
+        configurator.start(now);
+
+        assert_eq!(configurator.handle_timer(now + ACK_TIMEOUT / 2), None);
+
+        let mut at = now;
+        for _ in 1..MAX_CFG_ATTEMPTS {
+            at += ACK_TIMEOUT;
+            assert_eq!(configurator.handle_timer(at), valset(&[(UBX.id, 1)]));
+        }
+
+        assert_eq!(configurator.handle_timer(at + ACK_TIMEOUT), None);
+        assert_eq!(configurator.state(), ConfigState::Failed);
+    }
+
+    #[test]
+    fn fails_on_nak() {
+        let now = Instant::now();
+        let mut configurator = Configurator::new(key_sets());
+        configurator.start(now);
+        configurator.handle_msg(&ACK, now);
+
+        assert_eq!(configurator.handle_msg(&NAK, now), None);
+        assert_eq!(configurator.state(), ConfigState::Failed);
+    }
+}
@@ -0,0 +1,287 @@
+// UBX framing: | 0xb5 0x62 | class | id | u16 payload len | payload | ck_a ck_b |
+// the checksum covers class to the end of the payload.
+
+use std::fmt;
+
+pub const UBX_SYNC_CHAR_1: u8 = 0xb5;
+pub const UBX_SYNC_CHAR_2: u8 = 0x62;
+
+const UBX_SYNC_LEN: usize = 2;
+const UBX_CLASS_LEN: usize = 1;
+const UBX_ID_LEN: usize = 1;
+const UBX_LEN_LEN: usize = 2;
+const UBX_HEADER_LEN: usize = UBX_CLASS_LEN + UBX_ID_LEN + UBX_LEN_LEN;
+const UBX_CK_LEN: usize = 2;
+pub const UBX_MIN_LEN: usize = UBX_SYNC_LEN + UBX_HEADER_LEN + UBX_CK_LEN;
+
+const UBX_CLASS_OFFSET: usize = UBX_SYNC_LEN;
+const UBX_ID_OFFSET: usize = UBX_CLASS_OFFSET + UBX_CLASS_LEN;
+const UBX_LEN_OFFSET: usize = UBX_ID_OFFSET + UBX_ID_LEN;
+const UBX_PAYLOAD_OFFSET: usize = UBX_LEN_OFFSET + UBX_LEN_LEN;
+
+// Larger than anything we poll or enable, a longer length is most likely noise that happened to contain the sync chars
+pub const UBX_MAX_PAYLOAD_LEN: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum UbxError {
+    PayloadTooBig { class: u8, id: u8, len: usize },
+    Checksum { class: u8, id: u8, expected: u16, actual: u16 },
+    // The payload doesn't have the length the msg has
+    PayloadLength { class: u8, id: u8, len: usize },
+    // Key value pairs cut short or with a key of unknown size
+    CfgItems { class: u8, id: u8 }
+}
+
+impl fmt::Display for UbxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UbxError::PayloadTooBig { class, id, len } =>
+                write!(f, "ubx msg class {:#04x} id {:#04x} len {} is over {} bytes", class, id, len, UBX_MAX_PAYLOAD_LEN),
+            UbxError::Checksum { class, id, expected, actual } =>
+                write!(f, "ubx msg class {:#04x} id {:#04x} ck is {:04x}, expected {:04x}", class, id, actual, expected),
+            UbxError::PayloadLength { class, id, len } =>
+                write!(f, "ubx msg class {:#04x} id {:#04x} can't be {} bytes long", class, id, len),
+            UbxError::CfgItems { class, id } =>
+                write!(f, "ubx msg class {:#04x} id {:#04x} has malformed cfg items", class, id)
+        }
+    }
+}
+
+impl std::error::Error for UbxError {}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct UbxFrame {
+    pub class: u8,
+    pub id: u8,
+    pub payload: Vec<u8>
+}
+
+// ck_a in the low byte, so that it goes first on the wire as a little endian u16
+pub fn fletcher8(buffer: &[u8]) -> u16 {
+    let mut ck_a: u8 = 0;
+    let mut ck_b: u8 = 0;
+
+    for &e in buffer {
+        ck_a = ck_a.wrapping_add(e);
+        ck_b = ck_b.wrapping_add(ck_a);
+    }
+
+    u16::from_le_bytes([ck_a, ck_b])
+}
+
+pub fn encode_frame(class: u8, id: u8, payload: &[u8]) -> Vec<u8> {
+    assert!(payload.len() <= UBX_MAX_PAYLOAD_LEN, "ubx payload too big");
+
+    let mut msg = Vec::with_capacity(UBX_MIN_LEN + payload.len());
+    msg.extend_from_slice(&[UBX_SYNC_CHAR_1, UBX_SYNC_CHAR_2, class, id]);
+    msg.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    msg.extend_from_slice(payload);
+
+    let ck = fletcher8(&msg[UBX_CLASS_OFFSET..]);
+    msg.extend_from_slice(&ck.to_le_bytes());
+
+    msg
+}
+
+// Cuts frames out of whatever the receiver sent, bytes can come in any chunks.
+// Anything that isn't a valid frame is skipped up to the next sync chars.
+#[derive(Default)]
+pub struct UbxFramer {
+    buf: Vec<u8>
+}
+
+impl UbxFramer {
+    pub fn new() -> UbxFramer {
+        UbxFramer::default()
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    // The next complete frame, None until more bytes are pushed.
+    // An error drops the bad frame's sync chars, the following calls resync after them.
+    pub fn next_frame(&mut self) -> Option<Result<UbxFrame, UbxError>> {
+        loop {
+            self.skip_to_sync();
+
+            if self.buf.len() < UBX_MIN_LEN {
+                return None;
+            }
+
+            if self.buf[1] != UBX_SYNC_CHAR_2 {
+                // sync char 1 alone, look for the next one
+                self.buf.drain(..1);
+                continue;
+            }
+
+            let class = self.buf[UBX_CLASS_OFFSET];
+            let id = self.buf[UBX_ID_OFFSET];
+            let len = u16::from_le_bytes([self.buf[UBX_LEN_OFFSET], self.buf[UBX_LEN_OFFSET + 1]]) as usize;
+
+            if len > UBX_MAX_PAYLOAD_LEN {
+                self.buf.drain(..UBX_SYNC_LEN);
+                return Some(Err(UbxError::PayloadTooBig { class, id, len }));
+            }
+
+            let ck_offset = UBX_PAYLOAD_OFFSET + len;
+            if self.buf.len() < ck_offset + UBX_CK_LEN {
+                return None; // partial msg
+            }
+
+            let expected = u16::from_le_bytes([self.buf[ck_offset], self.buf[ck_offset + 1]]);
+            let actual = fletcher8(&self.buf[UBX_CLASS_OFFSET..ck_offset]);
+
+            if actual != expected {
+                // The sync chars may have been noise and a real frame may start inside this one
+                self.buf.drain(..UBX_SYNC_LEN);
+                return Some(Err(UbxError::Checksum { class, id, expected, actual }));
+            }
+
+            let payload = self.buf[UBX_PAYLOAD_OFFSET..ck_offset].to_vec();
+            self.buf.drain(..ck_offset + UBX_CK_LEN);
+
+            return Some(Ok(UbxFrame { class, id, payload }));
+        }
+    }
+
+    fn skip_to_sync(&mut self) {
+        let start = self.buf.iter().position(|&b| b == UBX_SYNC_CHAR_1).unwrap_or(self.buf.len());
+        self.buf.drain(..start);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(framer: &mut UbxFramer) -> Vec<Result<UbxFrame, UbxError>> {
+        std::iter::from_fn(|| framer.next_frame()).collect()
+    }
+
+    fn frame(class: u8, id: u8, payload: &[u8]) -> UbxFrame {
+        UbxFrame { class, id, payload: payload.to_vec() }
+    }
+
+    #[test]
+    fn known_good_msg() {
+        // A CFG-CFG clearing nothing, saving everything, from the u-center msg view
+        let msg = [
+            0xb5, 0x62, 0x06, 0x09, 0x0d, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03,
+            0x1d, 0xab
+        ];
+
+        let mut framer = UbxFramer::new();
+        framer.push(&msg);
+
+        assert_eq!(frames(&mut framer), vec![Ok(frame(0x06, 0x09, &msg[6..19]))]);
+        assert_eq!(encode_frame(0x06, 0x09, &msg[6..19]), msg);
+    }
+
+    #[test]
+    fn no_payload() {
+        let mut framer = UbxFramer::new();
+        framer.push(&encode_frame(0x01, 0x02, &[]));
+
+        assert_eq!(frames(&mut framer), vec![Ok(frame(0x01, 0x02, &[]))]);
+    }
+
+    #[test]
+    fn partial_message() {
+        let msg = encode_frame(0x01, 0x02, &[0x11, 0x12, 0x13]);
+        let mut framer = UbxFramer::new();
+
+        for &byte in &msg[..msg.len() - 1] {
+            framer.push(&[byte]);
+            assert_eq!(framer.next_frame(), None);
+        }
+
+        framer.push(&msg[msg.len() - 1..]);
+        assert_eq!(frames(&mut framer), vec![Ok(frame(0x01, 0x02, &[0x11, 0x12, 0x13]))]);
+    }
+
+    #[test]
+    fn false_start() {
+        let mut framer = UbxFramer::new();
+        framer.push(&[UBX_SYNC_CHAR_1]);
+        framer.push(&encode_frame(0x01, 0x02, &[]));
+
+        assert_eq!(frames(&mut framer), vec![Ok(frame(0x01, 0x02, &[]))]);
+    }
+
+    #[test]
+    fn offset_start() {
+        let mut framer = UbxFramer::new();
+        framer.push(&[0x99, 0x98, 0x97]);
+        framer.push(&encode_frame(0x01, 0x02, &[]));
+
+        assert_eq!(frames(&mut framer), vec![Ok(frame(0x01, 0x02, &[]))]);
+    }
+
+    #[test]
+    fn payload() {
+        let mut framer = UbxFramer::new();
+        framer.push(&encode_frame(0x01, 0x02, &[0x11, 0x12, 0x13]));
+        framer.push(&encode_frame(0x05, 0x01, &[0x06, 0x8a]));
+
+        assert_eq!(frames(&mut framer), vec![
+            Ok(frame(0x01, 0x02, &[0x11, 0x12, 0x13])),
+            Ok(frame(0x05, 0x01, &[0x06, 0x8a]))
+        ]);
+    }
+
+    #[test]
+    fn garbage() {
+        let garbage: Vec<u8> = (0..50).collect();
+
+        let mut framer = UbxFramer::new();
+        framer.push(&garbage);
+        assert_eq!(frames(&mut framer), vec![]);
+
+        // Nothing of it is kept around
+        framer.push(&encode_frame(0x01, 0x02, &[]));
+        assert_eq!(frames(&mut framer), vec![Ok(frame(0x01, 0x02, &[]))]);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut msg = encode_frame(0x01, 0x02, &[0x11]);
+        *msg.last_mut().unwrap() ^= 0xff;
+
+        let mut framer = UbxFramer::new();
+        framer.push(&msg);
+        framer.push(&encode_frame(0x01, 0x03, &[]));
+
+        assert!(matches!(frames(&mut framer)[..], [
+            Err(UbxError::Checksum { class: 0x01, id: 0x02, .. }),
+            Ok(UbxFrame { class: 0x01, id: 0x03, .. })
+        ]));
+    }
+
+    #[test]
+    fn resyncs_inside_a_bad_frame() {
+        // Noise looking like a header whose length swallows the real frame
+        let mut framer = UbxFramer::new();
+        framer.push(&[UBX_SYNC_CHAR_1, UBX_SYNC_CHAR_2, 0x01, 0x02, 0x0a, 0x00]);
+        framer.push(&encode_frame(0x01, 0x03, &[0x42]));
+        framer.push(&[0; 4]);
+
+        assert!(matches!(frames(&mut framer)[..], [
+            Err(UbxError::Checksum { class: 0x01, id: 0x02, .. }),
+            Ok(UbxFrame { class: 0x01, id: 0x03, .. })
+        ]));
+    }
+
+    #[test]
+    fn payload_too_big() {
+        let mut framer = UbxFramer::new();
+        framer.push(&[UBX_SYNC_CHAR_1, UBX_SYNC_CHAR_2, 0x0a, 0x04, 0xff, 0xff]);
+        framer.push(&encode_frame(0x0a, 0x04, &[]));
+
+        assert_eq!(frames(&mut framer), vec![
+            Err(UbxError::PayloadTooBig { class: 0x0a, id: 0x04, len: 0xffff }),
+            Ok(frame(0x0a, 0x04, &[]))
+        ]);
+    }
+}
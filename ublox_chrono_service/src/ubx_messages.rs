@@ -0,0 +1,901 @@
+// Typed UBX payloads, per the u-blox M10 interface description.
+// parse takes the payload of a frame of the message's class and id, encode builds a whole frame.
+
+use crate::ubx::{encode_frame, UbxError, UbxFrame};
+
+pub const CLASS_NAV: u8 = 0x01;
+pub const CLASS_ACK: u8 = 0x05;
+pub const CLASS_CFG: u8 = 0x06;
+pub const CLASS_MON: u8 = 0x0a;
+
+pub const ID_NAV_POSLLH: u8 = 0x02;
+pub const ID_NAV_STATUS: u8 = 0x03;
+pub const ID_NAV_PVT: u8 = 0x07;
+pub const ID_ACK_NAK: u8 = 0x00;
+pub const ID_ACK_ACK: u8 = 0x01;
+pub const ID_MON_VER: u8 = 0x04;
+pub const ID_CFG_PRT: u8 = 0x00;
+pub const ID_CFG_MSG: u8 = 0x01;
+pub const ID_CFG_RATE: u8 = 0x08;
+pub const ID_CFG_CFG: u8 = 0x09;
+pub const ID_CFG_NAV5: u8 = 0x24;
+pub const ID_CFG_VALSET: u8 = 0x8a;
+pub const ID_CFG_VALGET: u8 = 0x8b;
+pub const ID_CFG_VALDEL: u8 = 0x8c;
+
+pub trait UbxMessage: Sized {
+    const CLASS: u8;
+    const ID: u8;
+
+    fn parse(payload: &[u8]) -> Result<Self, UbxError>;
+
+    fn payload(&self) -> Vec<u8>;
+
+    fn encode(&self) -> Vec<u8> {
+        encode_frame(Self::CLASS, Self::ID, &self.payload())
+    }
+}
+
+fn u16_at(payload: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(payload[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(payload: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(payload[offset..offset + 4].try_into().unwrap())
+}
+
+fn i32_at(payload: &[u8], offset: usize) -> i32 {
+    i32::from_le_bytes(payload[offset..offset + 4].try_into().unwrap())
+}
+
+fn check_len<M: UbxMessage>(payload: &[u8], ok: bool) -> Result<(), UbxError> {
+    if ok {
+        Ok(())
+    }
+    else {
+        Err(UbxError::PayloadLength { class: M::CLASS, id: M::ID, len: payload.len() })
+    }
+}
+
+// Position, lon/lat in 1e-7 deg, heights and accuracies in mm
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct NavPosllh {
+    pub i_tow_ms: u32,
+    pub lon: i32,
+    pub lat: i32,
+    pub height_mm: i32,
+    pub h_msl_mm: i32,
+    pub h_acc_mm: u32,
+    pub v_acc_mm: u32
+}
+
+impl UbxMessage for NavPosllh {
+    const CLASS: u8 = CLASS_NAV;
+    const ID: u8 = ID_NAV_POSLLH;
+
+    fn parse(payload: &[u8]) -> Result<NavPosllh, UbxError> {
+        check_len::<NavPosllh>(payload, payload.len() == 28)?;
+
+        Ok(NavPosllh {
+            i_tow_ms: u32_at(payload, 0),
+            lon: i32_at(payload, 4),
+            lat: i32_at(payload, 8),
+            height_mm: i32_at(payload, 12),
+            h_msl_mm: i32_at(payload, 16),
+            h_acc_mm: u32_at(payload, 20),
+            v_acc_mm: u32_at(payload, 24)
+        })
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        [
+            self.i_tow_ms.to_le_bytes(),
+            self.lon.to_le_bytes(),
+            self.lat.to_le_bytes(),
+            self.height_mm.to_le_bytes(),
+            self.h_msl_mm.to_le_bytes(),
+            self.h_acc_mm.to_le_bytes(),
+            self.v_acc_mm.to_le_bytes()
+        ].concat()
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum GnssFix {
+    #[default]
+    NoFix = 0,
+    DeadReckoning = 1,
+    Fix2D = 2,
+    Fix3D = 3,
+    GnssDeadReckoning = 4,
+    TimeOnly = 5
+}
+
+impl GnssFix {
+    // Reserved values are no fix
+    pub fn from_u8(fix: u8) -> GnssFix {
+        [GnssFix::DeadReckoning, GnssFix::Fix2D, GnssFix::Fix3D, GnssFix::GnssDeadReckoning, GnssFix::TimeOnly]
+            .into_iter()
+            .find(|known| *known as u8 == fix)
+            .unwrap_or(GnssFix::NoFix)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct NavStatus {
+    pub i_tow_ms: u32,
+    pub gps_fix: GnssFix,
+    pub flags: u8,
+    pub fix_stat: u8,
+    pub flags2: u8,
+    // Time to first fix and since startup
+    pub ttff_ms: u32,
+    pub msss_ms: u32
+}
+
+impl NavStatus {
+    pub const FLAGS_GPS_FIX_OK: u8 = 1 << 0;
+}
+
+impl UbxMessage for NavStatus {
+    const CLASS: u8 = CLASS_NAV;
+    const ID: u8 = ID_NAV_STATUS;
+
+    fn parse(payload: &[u8]) -> Result<NavStatus, UbxError> {
+        check_len::<NavStatus>(payload, payload.len() == 16)?;
+
+        Ok(NavStatus {
+            i_tow_ms: u32_at(payload, 0),
+            gps_fix: GnssFix::from_u8(payload[4]),
+            flags: payload[5],
+            fix_stat: payload[6],
+            flags2: payload[7],
+            ttff_ms: u32_at(payload, 8),
+            msss_ms: u32_at(payload, 12)
+        })
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        [
+            &self.i_tow_ms.to_le_bytes()[..],
+            &[self.gps_fix as u8, self.flags, self.fix_stat, self.flags2],
+            &self.ttff_ms.to_le_bytes(),
+            &self.msss_ms.to_le_bytes()
+        ].concat()
+    }
+}
+
+// Position, velocity and time in one, lon/lat in 1e-7 deg, distances in mm, speeds in mm/s, headings in 1e-5 deg
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct NavPvt {
+    pub i_tow_ms: u32,
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub min: u8,
+    pub sec: u8,
+    pub valid: u8,
+    pub t_acc_ns: u32,
+    pub nano: i32,
+    pub fix_type: GnssFix,
+    pub flags: u8,
+    pub flags2: u8,
+    pub num_sv: u8,
+    pub lon: i32,
+    pub lat: i32,
+    pub height_mm: i32,
+    pub h_msl_mm: i32,
+    pub h_acc_mm: u32,
+    pub v_acc_mm: u32,
+    pub vel_n_mm_s: i32,
+    pub vel_e_mm_s: i32,
+    pub vel_d_mm_s: i32,
+    pub g_speed_mm_s: i32,
+    pub head_mot: i32,
+    pub s_acc_mm_s: u32,
+    pub head_acc: u32,
+    // 0.01
+    pub p_dop: u16,
+    pub flags3: u16,
+    pub head_veh: i32,
+    pub mag_dec: i16,
+    pub mag_acc: u16
+}
+
+impl NavPvt {
+    pub const VALID_DATE: u8 = 1 << 0;
+    pub const VALID_TIME: u8 = 1 << 1;
+    pub const FLAGS_GNSS_FIX_OK: u8 = 1 << 0;
+    pub const FLAGS3_INVALID_LLH: u16 = 1 << 0;
+
+    // The UTC time of the solution in ns since the Unix epoch, None until the receiver knows the date and time
+    pub fn utc_ns(&self) -> Option<u64> {
+        let valid = NavPvt::VALID_DATE | NavPvt::VALID_TIME;
+        if self.valid & valid != valid {
+            return None;
+        }
+
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let secs = days * 86400 + self.hour as i64 * 3600 + self.min as i64 * 60 + self.sec as i64;
+
+        // nano is signed, the seconds are rounded
+        u64::try_from(secs * 1_000_000_000 + self.nano as i64).ok()
+    }
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date, http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+impl UbxMessage for NavPvt {
+    const CLASS: u8 = CLASS_NAV;
+    const ID: u8 = ID_NAV_PVT;
+
+    fn parse(payload: &[u8]) -> Result<NavPvt, UbxError> {
+        check_len::<NavPvt>(payload, payload.len() == 92)?;
+
+        Ok(NavPvt {
+            i_tow_ms: u32_at(payload, 0),
+            year: u16_at(payload, 4),
+            month: payload[6],
+            day: payload[7],
+            hour: payload[8],
+            min: payload[9],
+            sec: payload[10],
+            valid: payload[11],
+            t_acc_ns: u32_at(payload, 12),
+            nano: i32_at(payload, 16),
+            fix_type: GnssFix::from_u8(payload[20]),
+            flags: payload[21],
+            flags2: payload[22],
+            num_sv: payload[23],
+            lon: i32_at(payload, 24),
+            lat: i32_at(payload, 28),
+            height_mm: i32_at(payload, 32),
+            h_msl_mm: i32_at(payload, 36),
+            h_acc_mm: u32_at(payload, 40),
+            v_acc_mm: u32_at(payload, 44),
+            vel_n_mm_s: i32_at(payload, 48),
+            vel_e_mm_s: i32_at(payload, 52),
+            vel_d_mm_s: i32_at(payload, 56),
+            g_speed_mm_s: i32_at(payload, 60),
+            head_mot: i32_at(payload, 64),
+            s_acc_mm_s: u32_at(payload, 68),
+            head_acc: u32_at(payload, 72),
+            p_dop: u16_at(payload, 76),
+            flags3: u16_at(payload, 78),
+            // 80..84 reserved
+            head_veh: i32_at(payload, 84),
+            mag_dec: u16_at(payload, 88) as i16,
+            mag_acc: u16_at(payload, 90)
+        })
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        [
+            &self.i_tow_ms.to_le_bytes()[..],
+            &self.year.to_le_bytes(),
+            &[self.month, self.day, self.hour, self.min, self.sec, self.valid],
+            &self.t_acc_ns.to_le_bytes(),
+            &self.nano.to_le_bytes(),
+            &[self.fix_type as u8, self.flags, self.flags2, self.num_sv],
+            &self.lon.to_le_bytes(),
+            &self.lat.to_le_bytes(),
+            &self.height_mm.to_le_bytes(),
+            &self.h_msl_mm.to_le_bytes(),
+            &self.h_acc_mm.to_le_bytes(),
+            &self.v_acc_mm.to_le_bytes(),
+            &self.vel_n_mm_s.to_le_bytes(),
+            &self.vel_e_mm_s.to_le_bytes(),
+            &self.vel_d_mm_s.to_le_bytes(),
+            &self.g_speed_mm_s.to_le_bytes(),
+            &self.head_mot.to_le_bytes(),
+            &self.s_acc_mm_s.to_le_bytes(),
+            &self.head_acc.to_le_bytes(),
+            &self.p_dop.to_le_bytes(),
+            &self.flags3.to_le_bytes(),
+            &[0; 4],
+            &self.head_veh.to_le_bytes(),
+            &self.mag_dec.to_le_bytes(),
+            &self.mag_acc.to_le_bytes()
+        ].concat()
+    }
+}
+
+// The receiver accepted the msg of this class and id
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AckAck {
+    pub class: u8,
+    pub id: u8
+}
+
+// The receiver rejected it
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AckNak {
+    pub class: u8,
+    pub id: u8
+}
+
+macro_rules! impl_ack {
+    ($name:ident, $id:expr) => {
+        impl UbxMessage for $name {
+            const CLASS: u8 = CLASS_ACK;
+            const ID: u8 = $id;
+
+            fn parse(payload: &[u8]) -> Result<$name, UbxError> {
+                check_len::<$name>(payload, payload.len() == 2)?;
+                Ok($name { class: payload[0], id: payload[1] })
+            }
+
+            fn payload(&self) -> Vec<u8> {
+                vec![self.class, self.id]
+            }
+        }
+    };
+}
+
+impl_ack!(AckAck, ID_ACK_ACK);
+impl_ack!(AckNak, ID_ACK_NAK);
+
+const MON_VER_SW_LEN: usize = 30;
+const MON_VER_HW_LEN: usize = 10;
+const MON_VER_EXT_LEN: usize = 30;
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct MonVer {
+    pub sw_version: String,
+    pub hw_version: String,
+    // e.g. "PROTVER=34.10", "MOD=MAX-M10S"
+    pub extensions: Vec<String>
+}
+
+fn nul_terminated(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+fn nul_padded(s: &str, len: usize) -> Vec<u8> {
+    let mut field = s.as_bytes()[..s.len().min(len - 1)].to_vec();
+    field.resize(len, 0);
+    field
+}
+
+impl MonVer {
+    // The poll request, the receiver answers with a MON-VER
+    pub fn poll() -> Vec<u8> {
+        encode_frame(CLASS_MON, ID_MON_VER, &[])
+    }
+}
+
+impl UbxMessage for MonVer {
+    const CLASS: u8 = CLASS_MON;
+    const ID: u8 = ID_MON_VER;
+
+    fn parse(payload: &[u8]) -> Result<MonVer, UbxError> {
+        let ext_offset = MON_VER_SW_LEN + MON_VER_HW_LEN;
+        check_len::<MonVer>(payload, payload.len() >= ext_offset && (payload.len() - ext_offset).is_multiple_of(MON_VER_EXT_LEN))?;
+
+        Ok(MonVer {
+            sw_version: nul_terminated(&payload[..MON_VER_SW_LEN]),
+            hw_version: nul_terminated(&payload[MON_VER_SW_LEN..ext_offset]),
+            extensions: payload[ext_offset..].chunks_exact(MON_VER_EXT_LEN).map(nul_terminated).collect()
+        })
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = nul_padded(&self.sw_version, MON_VER_SW_LEN);
+        payload.extend(nul_padded(&self.hw_version, MON_VER_HW_LEN));
+        for extension in &self.extensions {
+            payload.extend(nul_padded(extension, MON_VER_EXT_LEN));
+        }
+        payload
+    }
+}
+
+// Bytes of a configuration value, from the size bits of its key id
+pub fn cfg_value_size(key: u32) -> Option<usize> {
+    match (key >> 28) & 0x7 {
+        1 | 2 => Some(1),
+        3 => Some(2),
+        4 => Some(4),
+        5 => Some(8),
+        _ => None
+    }
+}
+
+// key, value pairs, each value in the bytes its key says
+fn parse_cfg_items<M: UbxMessage>(mut data: &[u8]) -> Result<Vec<(u32, u64)>, UbxError> {
+    let mut items = Vec::new();
+    let malformed = || UbxError::CfgItems { class: M::CLASS, id: M::ID };
+
+    while !data.is_empty() {
+        let key = u32::from_le_bytes(data.get(..4).ok_or_else(malformed)?.try_into().unwrap());
+        let size = cfg_value_size(key).ok_or_else(malformed)?;
+        let value = data.get(4..4 + size).ok_or_else(malformed)?;
+
+        let mut bytes = [0u8; 8];
+        bytes[..size].copy_from_slice(value);
+        items.push((key, u64::from_le_bytes(bytes)));
+
+        data = &data[4 + size..];
+    }
+
+    Ok(items)
+}
+
+fn encode_cfg_items(payload: &mut Vec<u8>, items: &[(u32, u64)]) {
+    for (key, value) in items {
+        let size = cfg_value_size(*key).expect("cfg key size");
+        payload.extend_from_slice(&key.to_le_bytes());
+        payload.extend_from_slice(&value.to_le_bytes()[..size]);
+    }
+}
+
+// Layers of CFG-VALSET and VALDEL, a bit each, VALDEL only from BBR and flash
+pub const CFG_LAYER_RAM: u8 = 1 << 0;
+pub const CFG_LAYER_BBR: u8 = 1 << 1;
+pub const CFG_LAYER_FLASH: u8 = 1 << 2;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct CfgValset {
+    pub layers: u8,
+    pub items: Vec<(u32, u64)>
+}
+
+impl UbxMessage for CfgValset {
+    const CLASS: u8 = CLASS_CFG;
+    const ID: u8 = ID_CFG_VALSET;
+
+    fn parse(payload: &[u8]) -> Result<CfgValset, UbxError> {
+        check_len::<CfgValset>(payload, payload.len() >= 4 && payload[0] == 0)?;
+
+        Ok(CfgValset {
+            layers: payload[1],
+            items: parse_cfg_items::<CfgValset>(&payload[4..])?
+        })
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        // version 0, no transaction
+        let mut payload = vec![0, self.layers, 0, 0];
+        encode_cfg_items(&mut payload, &self.items);
+        payload
+    }
+}
+
+// What a layer of CFG-VALGET reads from, not a bit mask unlike the VALSET layers
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CfgGetLayer {
+    Ram = 0,
+    Bbr = 1,
+    Flash = 2
+}
+
+// Deletes keys from BBR or flash, the receiver comes up with their defaults next time
+#[derive(Clone, PartialEq, Debug)]
+pub struct CfgValdel {
+    pub layers: u8,
+    pub keys: Vec<u32>
+}
+
+impl UbxMessage for CfgValdel {
+    const CLASS: u8 = CLASS_CFG;
+    const ID: u8 = ID_CFG_VALDEL;
+
+    fn parse(payload: &[u8]) -> Result<CfgValdel, UbxError> {
+        check_len::<CfgValdel>(payload, payload.len() >= 4 && payload.len().is_multiple_of(4) && payload[0] == 0)?;
+
+        Ok(CfgValdel {
+            layers: payload[1],
+            keys: payload[4..].chunks(4).map(|key| u32::from_le_bytes(key.try_into().unwrap())).collect()
+        })
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        // version 0, no transaction
+        let mut payload = vec![0, self.layers, 0, 0];
+        for key in &self.keys {
+            payload.extend_from_slice(&key.to_le_bytes());
+        }
+        payload
+    }
+}
+
+// Clears, saves or loads the whole configuration. Of the masks any bit means every key,
+// the device mask says which of BBR and flash it applies to.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CfgCfg {
+    pub clear_mask: u32,
+    pub save_mask: u32,
+    pub load_mask: u32,
+    pub device_mask: u8
+}
+
+pub const CFG_CFG_ALL: u32 = 0xffff;
+pub const CFG_CFG_DEV_BBR: u8 = 1 << 0;
+pub const CFG_CFG_DEV_FLASH: u8 = 1 << 1;
+
+impl CfgCfg {
+    // Clears BBR and flash and loads the defaults into RAM
+    pub fn factory_reset() -> CfgCfg {
+        CfgCfg {
+            clear_mask: CFG_CFG_ALL,
+            save_mask: 0,
+            load_mask: CFG_CFG_ALL,
+            device_mask: CFG_CFG_DEV_BBR | CFG_CFG_DEV_FLASH
+        }
+    }
+
+    // Saves what's in RAM to the devices
+    pub fn save(device_mask: u8) -> CfgCfg {
+        CfgCfg { clear_mask: 0, save_mask: CFG_CFG_ALL, load_mask: 0, device_mask }
+    }
+}
+
+impl UbxMessage for CfgCfg {
+    const CLASS: u8 = CLASS_CFG;
+    const ID: u8 = ID_CFG_CFG;
+
+    fn parse(payload: &[u8]) -> Result<CfgCfg, UbxError> {
+        // The device mask is optional
+        check_len::<CfgCfg>(payload, payload.len() == 12 || payload.len() == 13)?;
+
+        Ok(CfgCfg {
+            clear_mask: u32_at(payload, 0),
+            save_mask: u32_at(payload, 4),
+            load_mask: u32_at(payload, 8),
+            device_mask: payload.get(12).copied().unwrap_or(0)
+        })
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(13);
+        payload.extend_from_slice(&self.clear_mask.to_le_bytes());
+        payload.extend_from_slice(&self.save_mask.to_le_bytes());
+        payload.extend_from_slice(&self.load_mask.to_le_bytes());
+        payload.push(self.device_mask);
+        payload
+    }
+}
+
+// The commands of the receivers before CFG-VALSET
+
+// A msg's rate on the port the command came in on, per navigation solution
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CfgMsg {
+    pub msg_class: u8,
+    pub msg_id: u8,
+    pub rate: u8
+}
+
+impl UbxMessage for CfgMsg {
+    const CLASS: u8 = CLASS_CFG;
+    const ID: u8 = ID_CFG_MSG;
+
+    fn parse(payload: &[u8]) -> Result<CfgMsg, UbxError> {
+        check_len::<CfgMsg>(payload, payload.len() == 3)?;
+
+        Ok(CfgMsg { msg_class: payload[0], msg_id: payload[1], rate: payload[2] })
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        vec![self.msg_class, self.msg_id, self.rate]
+    }
+}
+
+// As CFG-RATE-MEAS, NAV and TIMEREF
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CfgRate {
+    pub meas_rate_ms: u16,
+    pub nav_rate: u16,
+    pub time_ref: u16
+}
+
+impl UbxMessage for CfgRate {
+    const CLASS: u8 = CLASS_CFG;
+    const ID: u8 = ID_CFG_RATE;
+
+    fn parse(payload: &[u8]) -> Result<CfgRate, UbxError> {
+        check_len::<CfgRate>(payload, payload.len() == 6)?;
+
+        Ok(CfgRate {
+            meas_rate_ms: u16_at(payload, 0),
+            nav_rate: u16_at(payload, 2),
+            time_ref: u16_at(payload, 4)
+        })
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        [self.meas_rate_ms, self.nav_rate, self.time_ref].iter()
+            .flat_map(|field| field.to_le_bytes())
+            .collect()
+    }
+}
+
+// The navigation engine, only the parameters in the mask are applied
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CfgNav5 {
+    pub mask: u16,
+    // As CFG-NAVSPG-DYNMODEL and FIXMODE
+    pub dyn_model: u8,
+    pub fix_mode: u8
+}
+
+const CFG_NAV5_LEN: usize = 36;
+
+impl CfgNav5 {
+    pub const MASK_DYN: u16 = 1 << 0;
+    pub const MASK_POS_FIX_MODE: u16 = 1 << 2;
+}
+
+impl UbxMessage for CfgNav5 {
+    const CLASS: u8 = CLASS_CFG;
+    const ID: u8 = ID_CFG_NAV5;
+
+    fn parse(payload: &[u8]) -> Result<CfgNav5, UbxError> {
+        check_len::<CfgNav5>(payload, payload.len() == CFG_NAV5_LEN)?;
+
+        Ok(CfgNav5 { mask: u16_at(payload, 0), dyn_model: payload[2], fix_mode: payload[3] })
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = vec![0; CFG_NAV5_LEN];
+        payload[..2].copy_from_slice(&self.mask.to_le_bytes());
+        payload[2] = self.dyn_model;
+        payload[3] = self.fix_mode;
+        payload
+    }
+}
+
+// A port's settings, as CFG-UART1-* and CFG-UART1INPROT/OUTPROT-*
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CfgPrt {
+    pub port_id: u8,
+    // Char length, parity and stop bits
+    pub mode: u32,
+    pub baud_rate: u32,
+    pub in_proto_mask: u16,
+    pub out_proto_mask: u16
+}
+
+const CFG_PRT_LEN: usize = 20;
+
+pub const CFG_PRT_PORT_UART1: u8 = 1;
+pub const CFG_PRT_MODE_8N1: u32 = 0x08c0;
+pub const CFG_PRT_PROTO_UBX: u16 = 1 << 0;
+pub const CFG_PRT_PROTO_NMEA: u16 = 1 << 1;
+
+impl CfgPrt {
+    // UART1 at baud_rate, 8N1 with UBX and NMEA in and out as it defaults to
+    pub fn uart1(baud_rate: u32) -> CfgPrt {
+        CfgPrt {
+            port_id: CFG_PRT_PORT_UART1,
+            mode: CFG_PRT_MODE_8N1,
+            baud_rate,
+            in_proto_mask: CFG_PRT_PROTO_UBX | CFG_PRT_PROTO_NMEA,
+            out_proto_mask: CFG_PRT_PROTO_UBX | CFG_PRT_PROTO_NMEA
+        }
+    }
+}
+
+impl UbxMessage for CfgPrt {
+    const CLASS: u8 = CLASS_CFG;
+    const ID: u8 = ID_CFG_PRT;
+
+    fn parse(payload: &[u8]) -> Result<CfgPrt, UbxError> {
+        check_len::<CfgPrt>(payload, payload.len() == CFG_PRT_LEN)?;
+
+        Ok(CfgPrt {
+            port_id: payload[0],
+            mode: u32_at(payload, 4),
+            baud_rate: u32_at(payload, 8),
+            in_proto_mask: u16_at(payload, 12),
+            out_proto_mask: u16_at(payload, 14)
+        })
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = vec![0; CFG_PRT_LEN];
+        payload[0] = self.port_id;
+        payload[4..8].copy_from_slice(&self.mode.to_le_bytes());
+        payload[8..12].copy_from_slice(&self.baud_rate.to_le_bytes());
+        payload[12..14].copy_from_slice(&self.in_proto_mask.to_le_bytes());
+        payload[14..16].copy_from_slice(&self.out_proto_mask.to_le_bytes());
+        payload
+    }
+}
+
+// The answer to a VALGET poll
+#[derive(Clone, PartialEq, Debug)]
+pub struct CfgValget {
+    pub layer: u8,
+    // Of the first item, when asking with wildcards
+    pub position: u16,
+    pub items: Vec<(u32, u64)>
+}
+
+impl CfgValget {
+    pub fn poll(layer: CfgGetLayer, position: u16, keys: &[u32]) -> Vec<u8> {
+        let mut payload = vec![0, layer as u8];
+        payload.extend_from_slice(&position.to_le_bytes());
+        for key in keys {
+            payload.extend_from_slice(&key.to_le_bytes());
+        }
+
+        encode_frame(CLASS_CFG, ID_CFG_VALGET, &payload)
+    }
+}
+
+impl UbxMessage for CfgValget {
+    const CLASS: u8 = CLASS_CFG;
+    const ID: u8 = ID_CFG_VALGET;
+
+    fn parse(payload: &[u8]) -> Result<CfgValget, UbxError> {
+        // version 1 is the answer, 0 the poll
+        check_len::<CfgValget>(payload, payload.len() >= 4 && payload[0] == 1)?;
+
+        Ok(CfgValget {
+            layer: payload[1],
+            position: u16_at(payload, 2),
+            items: parse_cfg_items::<CfgValget>(&payload[4..])?
+        })
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = vec![1, self.layer];
+        payload.extend_from_slice(&self.position.to_le_bytes());
+        encode_cfg_items(&mut payload, &self.items);
+        payload
+    }
+}
+
+// Everything the service understands, anything else is Other
+#[derive(Clone, PartialEq, Debug)]
+pub enum UbxMsg {
+    NavPosllh(NavPosllh),
+    NavStatus(NavStatus),
+    NavPvt(NavPvt),
+    AckAck(AckAck),
+    AckNak(AckNak),
+    MonVer(MonVer),
+    CfgValget(CfgValget),
+    Other(UbxFrame)
+}
+
+impl UbxMsg {
+    pub fn parse(frame: UbxFrame) -> Result<UbxMsg, UbxError> {
+        let payload = &frame.payload;
+
+        let msg = match (frame.class, frame.id) {
+            (NavPosllh::CLASS, NavPosllh::ID) => UbxMsg::NavPosllh(NavPosllh::parse(payload)?),
+            (NavStatus::CLASS, NavStatus::ID) => UbxMsg::NavStatus(NavStatus::parse(payload)?),
+            (NavPvt::CLASS, NavPvt::ID) => UbxMsg::NavPvt(NavPvt::parse(payload)?),
+            (AckAck::CLASS, AckAck::ID) => UbxMsg::AckAck(AckAck::parse(payload)?),
+            (AckNak::CLASS, AckNak::ID) => UbxMsg::AckNak(AckNak::parse(payload)?),
+            (MonVer::CLASS, MonVer::ID) => UbxMsg::MonVer(MonVer::parse(payload)?),
+            (CfgValget::CLASS, CfgValget::ID) => UbxMsg::CfgValget(CfgValget::parse(payload)?),
+            _ => UbxMsg::Other(frame)
+        };
+
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ubx::UbxFramer;
+    use super::*;
+
+    fn frame(msg: &[u8]) -> UbxFrame {
+        let mut framer = UbxFramer::new();
+        framer.push(msg);
+        framer.next_frame().unwrap().unwrap()
+    }
+
+    fn round_trip<M: UbxMessage + PartialEq + std::fmt::Debug + Clone>(msg: M) {
+        let frame = frame(&msg.encode());
+        assert_eq!((frame.class, frame.id), (M::CLASS, M::ID));
+        assert_eq!(M::parse(&frame.payload), Ok(msg));
+    }
+
+    #[test]
+    fn parses_a_nav_pvt_from_the_receiver() {
+        // A receiver standing still with a 3d fix
+        let mut payload = vec![0u8; 92];
+        payload[0..4].copy_from_slice(&412_345_000u32.to_le_bytes());
+        payload[4..6].copy_from_slice(&2024u16.to_le_bytes());
+        payload[6..12].copy_from_slice(&[8, 14, 9, 30, 12, 0x07]);
+        payload[20..24].copy_from_slice(&[3, 0x01, 0xea, 11]);
+        payload[24..28].copy_from_slice(&23_456_789i32.to_le_bytes());
+        payload[28..32].copy_from_slice(&489_876_543i32.to_le_bytes());
+        payload[40..44].copy_from_slice(&1_850u32.to_le_bytes());
+        payload[60..64].copy_from_slice(&(-12i32).to_le_bytes());
+        payload[64..68].copy_from_slice(&18_000_000i32.to_le_bytes());
+        payload[78..80].copy_from_slice(&1u16.to_le_bytes());
+
+        let pvt = NavPvt::parse(&payload).unwrap();
+
+        assert_eq!((pvt.year, pvt.month, pvt.day, pvt.hour, pvt.min, pvt.sec), (2024, 8, 14, 9, 30, 12));
+        assert_eq!(pvt.valid, 0x07);
+        assert_eq!((pvt.fix_type, pvt.flags, pvt.num_sv), (GnssFix::Fix3D, 0x01, 11));
+        assert_eq!((pvt.lon, pvt.lat, pvt.h_acc_mm), (23_456_789, 489_876_543, 1_850));
+        assert_eq!((pvt.g_speed_mm_s, pvt.head_mot), (-12, 18_000_000));
+        assert_eq!(pvt.flags3, 1);
+        assert_eq!(pvt.payload(), payload);
+
+        assert_eq!(NavPvt::parse(&payload[..84]), Err(UbxError::PayloadLength { class: CLASS_NAV, id: ID_NAV_PVT, len: 84 }));
+    }
+
+    #[test]
+    fn nav_pvt_utc_time() {
+        let pvt = NavPvt { year: 2024, month: 8, day: 14, hour: 9, min: 30, sec: 12, nano: -5_000, valid: 0x07, ..NavPvt::default() };
+        assert_eq!(pvt.utc_ns(), Some(1_723_627_812_000_000_000 - 5_000));
+
+        // The date isn't known yet
+        assert_eq!(NavPvt { valid: NavPvt::VALID_TIME, ..pvt }.utc_ns(), None);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        round_trip(NavPosllh { i_tow_ms: 1, lon: -2, lat: 3, height_mm: 4, h_msl_mm: 5, h_acc_mm: 6, v_acc_mm: 7 });
+        round_trip(NavStatus { gps_fix: GnssFix::Fix2D, flags: NavStatus::FLAGS_GPS_FIX_OK, ttff_ms: 31_000, ..NavStatus::default() });
+        round_trip(NavPvt { fix_type: GnssFix::Fix3D, num_sv: 9, mag_dec: -3, ..NavPvt::default() });
+        round_trip(AckAck { class: CLASS_CFG, id: ID_CFG_VALSET });
+        round_trip(AckNak { class: CLASS_CFG, id: ID_CFG_VALGET });
+        round_trip(CfgValset { layers: CFG_LAYER_RAM, items: vec![(0x10780001, 1), (0x30210001, 40)] });
+        round_trip(CfgValget { layer: CfgGetLayer::Ram as u8, position: 0, items: vec![(0x20110021, 4), (0x40520001, 115_200)] });
+        round_trip(CfgCfg::factory_reset());
+        round_trip(CfgMsg { msg_class: CLASS_NAV, msg_id: ID_NAV_PVT, rate: 1 });
+        round_trip(CfgRate { meas_rate_ms: 100, nav_rate: 1, time_ref: 1 });
+        round_trip(CfgPrt::uart1(115_200));
+        round_trip(CfgNav5 { mask: CfgNav5::MASK_DYN | CfgNav5::MASK_POS_FIX_MODE, dyn_model: 4, fix_mode: 1 });
+        round_trip(CfgValdel { layers: CFG_LAYER_BBR | CFG_LAYER_FLASH, keys: vec![0x40520001, 0x20910009] });
+    }
+
+    #[test]
+    fn parses_mon_ver() {
+        let msg = MonVer {
+            sw_version: String::from("ROM SPG 5.10 (7b202e)"),
+            hw_version: String::from("000A0000"),
+            extensions: vec![String::from("FWVER=SPG 5.10"), String::from("PROTVER=34.10"), String::from("MOD=MAX-M10S")]
+        };
+        round_trip(msg.clone());
+
+        assert_eq!(MonVer::poll(), [0xb5, 0x62, 0x0a, 0x04, 0x00, 0x00, 0x0e, 0x34]);
+        assert!(MonVer::parse(&[0; 41]).is_err());
+    }
+
+    #[test]
+    fn parses_cfg_items_by_key_size() {
+        // CFG-RATE-MEAS is 2 bytes, CFG-UART1-BAUDRATE 4, a key of an unknown size can't be skipped
+        let payload = [1, 0, 0, 0, 0x01, 0x00, 0x21, 0x30, 0x28, 0x00, 0x01, 0x00, 0x52, 0x40, 0x00, 0xc2, 0x01, 0x00];
+        assert_eq!(CfgValget::parse(&payload), Ok(CfgValget { layer: 0, position: 0, items: vec![(0x30210001, 40), (0x40520001, 115_200)] }));
+
+        assert_eq!(CfgValget::parse(&payload[..16]), Err(UbxError::CfgItems { class: CLASS_CFG, id: ID_CFG_VALGET }));
+        assert_eq!(CfgValget::parse(&[1, 0, 0, 0, 0, 0, 0, 0x70, 0]), Err(UbxError::CfgItems { class: CLASS_CFG, id: ID_CFG_VALGET }));
+
+        assert_eq!(CfgValget::poll(CfgGetLayer::Ram, 0, &[0x30210001]), encode_frame(CLASS_CFG, ID_CFG_VALGET, &[0, 0, 0, 0, 0x01, 0x00, 0x21, 0x30]));
+    }
+
+    #[test]
+    fn dispatches_on_class_and_id() {
+        let ack = frame(&AckAck { class: CLASS_CFG, id: ID_CFG_VALSET }.encode());
+        assert_eq!(UbxMsg::parse(ack), Ok(UbxMsg::AckAck(AckAck { class: CLASS_CFG, id: ID_CFG_VALSET })));
+
+        let unknown = frame(&encode_frame(0x0d, 0x01, &[1, 2, 3]));
+        assert_eq!(UbxMsg::parse(unknown.clone()), Ok(UbxMsg::Other(unknown)));
+
+        let short = frame(&encode_frame(CLASS_NAV, ID_NAV_POSLLH, &[0; 20]));
+        assert_eq!(UbxMsg::parse(short), Err(UbxError::PayloadLength { class: CLASS_NAV, id: ID_NAV_POSLLH, len: 20 }));
+    }
+}